@@ -0,0 +1,231 @@
+// Summary statistics over a run of points. Summaries of adjacent (or
+// disjoint) runs can be merged, so summaries stored per table block can
+// answer queries without decoding the block.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Summary {
+    pub count: u64,
+    pub sum: u128,
+    pub min: u64,
+    pub max: u64,
+    pub first_ts: u64,
+    pub first: u64,
+    pub last_ts: u64,
+    pub last: u64,
+}
+
+impl Summary {
+    pub fn empty() -> Summary {
+        return Summary{
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+            first_ts: u64::MAX,
+            first: 0,
+            last_ts: 0,
+            last: 0,
+        };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.count == 0;
+    }
+
+    pub fn add(&mut self, ts: u64, v: u64) {
+        self.merge(&Summary{
+            count: 1,
+            sum: v as u128,
+            min: v,
+            max: v,
+            first_ts: ts,
+            first: v,
+            last_ts: ts,
+            last: v,
+        });
+    }
+
+    pub fn merge(&mut self, other: &Summary) {
+        if other.is_empty() {
+            return;
+        }
+        if self.is_empty() {
+            *self = *other;
+            return;
+        }
+
+        self.count += other.count;
+        self.sum += other.sum;
+        if other.min < self.min {
+            self.min = other.min;
+        }
+        if other.max > self.max {
+            self.max = other.max;
+        }
+        if other.first_ts < self.first_ts {
+            self.first_ts = other.first_ts;
+            self.first = other.first;
+        }
+        if other.last_ts >= self.last_ts {
+            self.last_ts = other.last_ts;
+            self.last = other.last;
+        }
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        if self.is_empty() {
+            return None;
+        }
+        return Some(self.sum as f64 / self.count as f64);
+    }
+
+    // Whether any point in this summary falls in [start, end).
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        return !self.is_empty() && self.first_ts < end && self.last_ts >= start;
+    }
+
+    // Whether every point in this summary falls in [start, end).
+    pub fn covered_by(&self, start: u64, end: u64) -> bool {
+        return !self.is_empty() && self.first_ts >= start && self.last_ts < end;
+    }
+}
+
+// A function computed over the points in each bucket of an aggregation.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Aggregator {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+    First,
+    Last,
+    // Population standard deviation.
+    Stddev,
+    // The value at a quantile between 0 and 1, e.g. 0.99 for p99, to within
+    // the accuracy of a Sketch.
+    Quantile(f64),
+}
+
+impl Aggregator {
+    // Whether the aggregate can be computed from a Summary, rather than
+    // needing the individual points.
+    pub fn uses_summary(&self) -> bool {
+        return !matches!(*self, Aggregator::Stddev | Aggregator::Quantile(_));
+    }
+
+    // The aggregate of the points in 'summary', or None if it's empty or
+    // this aggregator can't be computed from a summary.
+    pub fn apply(&self, summary: &Summary) -> Option<f64> {
+        if summary.is_empty() {
+            return None;
+        }
+
+        return match *self {
+            Aggregator::Sum => Some(summary.sum as f64),
+            Aggregator::Avg => summary.mean(),
+            Aggregator::Min => Some(summary.min as f64),
+            Aggregator::Max => Some(summary.max as f64),
+            Aggregator::Count => Some(summary.count as f64),
+            Aggregator::First => Some(summary.first as f64),
+            Aggregator::Last => Some(summary.last as f64),
+            Aggregator::Stddev | Aggregator::Quantile(_) => None,
+        };
+    }
+}
+
+// Running variance of a stream of values (Welford's algorithm).
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct Variance {
+    count: u64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Variance {
+    pub fn add(&mut self, v: u64) {
+        let v = v as f64;
+        self.count += 1;
+        let delta = v - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (v - self.mean);
+    }
+
+    pub fn stddev(&self) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        return Some((self.m2 / self.count as f64).sqrt());
+    }
+}
+
+// One aggregated value, for the bucket of points with timestamps in
+// [start, start + width).
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Bucket {
+    pub start: u64,
+    pub value: f64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::Aggregator;
+    use super::Summary;
+    use super::Variance;
+
+    #[test]
+    fn add_and_merge() {
+        let mut a = Summary::empty();
+        a.add(1, 10);
+        a.add(2, 30);
+
+        let mut b = Summary::empty();
+        b.add(3, 5);
+        b.add(4, 7);
+
+        let mut merged = Summary::empty();
+        merged.merge(&b);
+        merged.merge(&a);
+
+        assert_eq!(4, merged.count);
+        assert_eq!(52, merged.sum);
+        assert_eq!(5, merged.min);
+        assert_eq!(30, merged.max);
+        assert_eq!((1, 10), (merged.first_ts, merged.first));
+        assert_eq!((4, 7), (merged.last_ts, merged.last));
+        assert_eq!(Some(13.0), merged.mean());
+    }
+
+    #[test]
+    fn coverage() {
+        let mut s = Summary::empty();
+        assert!(!s.overlaps(0, 100));
+        s.add(10, 0);
+        s.add(20, 0);
+
+        assert!(s.overlaps(20, 21));
+        assert!(!s.overlaps(21, 100));
+        assert!(s.covered_by(10, 21));
+        assert!(!s.covered_by(10, 20));
+    }
+
+    #[test]
+    fn aggregators() {
+        let mut s = Summary::empty();
+        assert_eq!(None, Aggregator::Sum.apply(&s));
+
+        let mut v = Variance::default();
+        for (ts, x) in [(1, 2), (2, 4), (3, 4), (4, 4), (5, 5), (6, 5), (7, 7), (8, 9)] {
+            s.add(ts, x);
+            v.add(x);
+        }
+        assert_eq!(Some(40.0), Aggregator::Sum.apply(&s));
+        assert_eq!(Some(5.0), Aggregator::Avg.apply(&s));
+        assert_eq!(Some(2.0), Aggregator::Min.apply(&s));
+        assert_eq!(Some(9.0), Aggregator::Max.apply(&s));
+        assert_eq!(Some(8.0), Aggregator::Count.apply(&s));
+        assert_eq!(Some(2.0), Aggregator::First.apply(&s));
+        assert_eq!(Some(9.0), Aggregator::Last.apply(&s));
+        assert_eq!(None, Aggregator::Stddev.apply(&s));
+        assert_eq!(Some(2.0), v.stddev());
+    }
+}
@@ -0,0 +1,654 @@
+use block_storage::Device;
+use checksum;
+use format;
+
+use std::io;
+use std::ops;
+
+// Tracks which blocks of a Device are in use with a bitmap, one bit per
+// block, stored on the device itself.
+//
+// Two copies of the bitmap are kept, one after the other, starting at the
+// block given to new() or load(). Each is a header (magic, generation, block
+// count, crc32c) followed by the bits; commits alternate between them, so a
+// torn commit leaves the previous bitmap intact, and loading uses the valid
+// copy with the highest generation. The crc32c covers the rest of the header
+// and the crc32c of the bits in each block of the copy, so that a commit
+// only has to rewrite, and checksum, the blocks that changed since that copy
+// was last written.
+//
+// The allocator only knows which blocks are marked; it's up to its user to
+// order commits so that a crash can't lose track of blocks in use: commit
+// allocations before anything refers to the blocks, and free blocks only
+// once nothing refers to them any more. A crash in between leaks the blocks,
+// which fsck() finds.
+const BITMAP_MAGIC : u64 = 0x7274735f62697473;
+const HEADER_SIZE : usize = 32;
+
+// A run of 'count' blocks starting at block 'start'.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Extent {
+    pub start: u64,
+    pub count: u64,
+}
+
+impl Extent {
+    pub fn end(&self) -> u64 {
+        return self.start + self.count;
+    }
+}
+
+// Which free run an allocation is carved from.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Fit {
+    // The first run that is long enough.
+    First,
+    // The shortest run that is long enough, leaving long runs for large
+    // allocations.
+    Best,
+}
+
+// What fsck() found.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Fsck {
+    // Marked in use, but owned by nothing, e.g. after a crash between
+    // committing an allocation and using it.
+    pub leaked: Vec<Extent>,
+    // Owned, but not marked in use.
+    pub unmarked: Vec<Extent>,
+    // Owned more than once.
+    pub shared: Vec<Extent>,
+}
+
+impl Fsck {
+    pub fn is_clean(&self) -> bool {
+        return self.leaked.is_empty() && self.unmarked.is_empty() && self.shared.is_empty();
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidInput, msg);
+}
+
+// The bits stored in block 'block' of a copy, after the header in the first.
+fn bits_in(block: u64, block_size: u64, len: usize) -> ops::Range<usize> {
+    let start = (block * block_size).saturating_sub(HEADER_SIZE as u64) as usize;
+    let end = ((block + 1) * block_size - HEADER_SIZE as u64) as usize;
+    return start.min(len)..end.min(len);
+}
+
+fn header_crc(header: &[u8], block_crcs: &[u32]) -> u64 {
+    let mut crc = checksum::Crc32c::new();
+    crc.update(&header[0..24]);
+    for block_crc in block_crcs {
+        let mut word = [0; 8];
+        format::store(*block_crc as u64, &mut word);
+        crc.update(&word[0..4]);
+    }
+    return crc.finish() as u64;
+}
+
+// A bit per block, for fsck().
+struct Bitset {
+    words: Vec<u64>,
+}
+
+impl Bitset {
+    fn new(len: u64) -> Bitset {
+        return Bitset{words: vec![0; len.div_ceil(64) as usize]};
+    }
+
+    fn get(&self, i: u64) -> bool {
+        return self.words[(i / 64) as usize] & (1 << (i % 64)) != 0;
+    }
+
+    fn set(&mut self, i: u64) {
+        self.words[(i / 64) as usize] |= 1 << (i % 64);
+    }
+}
+
+// Collects ascending block numbers into runs.
+fn push_block(extents: &mut Vec<Extent>, block: u64) {
+    if let Some(last) = extents.last_mut() {
+        if last.end() == block {
+            last.count += 1;
+            return;
+        }
+    }
+    extents.push(Extent{start: block, count: 1});
+}
+
+pub struct BitmapAllocator {
+    // The first block of the first copy of the bitmap.
+    location: u64,
+    block_size: u64,
+    blocks: u64,
+    bits: Vec<u8>,
+    free: u64,
+    fit: Fit,
+    // Of the last committed copy.
+    generation: u64,
+    // Whether there are changes since the last commit.
+    dirty: bool,
+    // The crc32c of the bits in each block of a copy, as of the last commit.
+    block_crcs: Vec<u32>,
+    // For each copy, which of its blocks differ from 'bits'.
+    stale: [Vec<bool>; 2],
+}
+
+impl BitmapAllocator {
+    // How many blocks each copy of the bitmap for a device of 'blocks'
+    // blocks takes.
+    pub fn blocks_needed(blocks: u64, block_size: u64) -> u64 {
+        let bytes = HEADER_SIZE as u64 + blocks.div_ceil(8);
+        return bytes.div_ceil(block_size);
+    }
+
+    // An allocator with every block free, to be kept at 'location'. Nothing
+    // is written until commit(); the blocks holding the bitmap itself need
+    // to be reserved like any others.
+    pub fn new(location: u64, blocks: u64, block_size: u64, fit: Fit) -> BitmapAllocator {
+        let copy_blocks = BitmapAllocator::blocks_needed(blocks, block_size) as usize;
+        return BitmapAllocator{
+            location: location,
+            block_size: block_size,
+            blocks: blocks,
+            bits: vec![0; blocks.div_ceil(8) as usize],
+            free: blocks,
+            fit: fit,
+            generation: 0,
+            dirty: true,
+            block_crcs: vec![0; copy_blocks],
+            stale: [vec![true; copy_blocks], vec![true; copy_blocks]],
+        };
+    }
+
+    pub fn load(device: &mut dyn Device, location: u64, blocks: u64, fit: Fit) -> io::Result<BitmapAllocator> {
+        let block_size = device.block_size();
+        let copy_blocks = BitmapAllocator::blocks_needed(blocks, block_size);
+        let mut best : Option<(u64, Vec<u8>, Vec<u32>)> = None;
+        for copy in 0..2 {
+            let mut buf = vec![0; (copy_blocks * block_size) as usize];
+            let offset = (location + copy * copy_blocks) * block_size;
+            let read = match device.read(offset, buf.len() as u64, &mut buf) {
+                Ok(read) => read,
+                // E.g. a ChecksummedDevice found it damaged, so it's as good
+                // as garbled.
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => continue,
+                Err(e) => return Err(e),
+            };
+            if read != buf.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("Short read of {} bytes at {}", read, offset)));
+            }
+
+            let w = |i: usize| format::load(&buf[(i*8)..((i+1)*8)]);
+            if w(0) != BITMAP_MAGIC || w(2) != blocks {
+                continue;
+            }
+            let bits = &buf[HEADER_SIZE..(HEADER_SIZE + blocks.div_ceil(8) as usize)];
+            let block_crcs : Vec<u32> = (0..copy_blocks)
+                .map(|b| checksum::crc32c(&bits[bits_in(b, block_size, bits.len())]))
+                .collect();
+            if header_crc(&buf, &block_crcs) != w(3) {
+                continue;
+            }
+            if best.as_ref().map(|b| w(1) > b.0).unwrap_or(true) {
+                best = Some((w(1), bits.to_vec(), block_crcs));
+            }
+        }
+
+        let (generation, bits, block_crcs) = match best {
+            Some(best) => best,
+            None => return Err(io::Error::new(
+                io::ErrorKind::InvalidData, "No valid copy of the free space bitmap")),
+        };
+        let used : u64 = bits.iter().map(|b| b.count_ones() as u64).sum();
+        // The other copy may be older, or garbled, so it's rewritten whole.
+        let mut stale = [vec![true; copy_blocks as usize], vec![true; copy_blocks as usize]];
+        stale[(generation % 2) as usize] = vec![false; copy_blocks as usize];
+        return Ok(BitmapAllocator{
+            location: location,
+            block_size: block_size,
+            blocks: blocks,
+            bits: bits,
+            free: blocks - used,
+            fit: fit,
+            generation: generation,
+            dirty: false,
+            block_crcs: block_crcs,
+            stale: stale,
+        });
+    }
+
+    pub fn blocks(&self) -> u64 {
+        return self.blocks;
+    }
+
+    pub fn free_blocks(&self) -> u64 {
+        return self.free;
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        return self.dirty;
+    }
+
+    pub fn is_free(&self, block: u64) -> bool {
+        return self.bits[(block / 8) as usize] & (1 << (block % 8)) == 0;
+    }
+
+    fn set(&mut self, block: u64, used: bool) {
+        let stored = ((HEADER_SIZE as u64 + block / 8) / self.block_size) as usize;
+        self.stale[0][stored] = true;
+        self.stale[1][stored] = true;
+        if used {
+            self.bits[(block / 8) as usize] |= 1 << (block % 8);
+        } else {
+            self.bits[(block / 8) as usize] &= !(1 << (block % 8));
+        }
+    }
+
+    fn check_range(&self, extent: Extent) -> io::Result<()> {
+        if extent.start > self.blocks || extent.count > self.blocks - extent.start {
+            return Err(invalid(format!(
+                "Blocks [{}, {}) are past the end of the device", extent.start, extent.end())));
+        }
+        return Ok(());
+    }
+
+    // Marks 'extent' in use. Every block in it must be free.
+    pub fn reserve(&mut self, extent: Extent) -> io::Result<()> {
+        try!(self.check_range(extent));
+        if let Some(block) = (extent.start..extent.end()).find(|b| !self.is_free(*b)) {
+            return Err(invalid(format!("Block {} is already in use", block)));
+        }
+        for block in extent.start..extent.end() {
+            self.set(block, true);
+        }
+        self.free -= extent.count;
+        self.dirty = true;
+        return Ok(());
+    }
+
+    // Marks 'extent' free. Every block in it must be in use.
+    pub fn free(&mut self, extent: Extent) -> io::Result<()> {
+        try!(self.check_range(extent));
+        if let Some(block) = (extent.start..extent.end()).find(|b| self.is_free(*b)) {
+            return Err(invalid(format!("Block {} is already free", block)));
+        }
+        for block in extent.start..extent.end() {
+            self.set(block, false);
+        }
+        self.free += extent.count;
+        self.dirty = true;
+        return Ok(());
+    }
+
+    // The first run of free blocks at or after 'from'.
+    fn next_run(&self, from: u64) -> Option<Extent> {
+        let mut start = from;
+        while start < self.blocks {
+            // Skip whole bytes of used blocks.
+            if start.is_multiple_of(8) && self.bits[(start / 8) as usize] == 0xff {
+                start += 8;
+            } else if !self.is_free(start) {
+                start += 1;
+            } else {
+                break;
+            }
+        }
+        if start >= self.blocks {
+            return None;
+        }
+
+        let mut end = start + 1;
+        while end < self.blocks {
+            if end.is_multiple_of(8) && self.bits[(end / 8) as usize] == 0 && end + 8 <= self.blocks {
+                end += 8;
+            } else if self.is_free(end) {
+                end += 1;
+            } else {
+                break;
+            }
+        }
+        return Some(Extent{start: start, count: end - start});
+    }
+
+    // A run of 'count' contiguous blocks, if there is one.
+    pub fn allocate(&mut self, count: u64) -> Option<Extent> {
+        if count == 0 || count > self.free {
+            return None;
+        }
+
+        let mut chosen : Option<Extent> = None;
+        let mut from = 0;
+        while let Some(run) = self.next_run(from) {
+            from = run.end();
+            if run.count < count {
+                continue;
+            }
+            match self.fit {
+                Fit::First => {
+                    chosen = Some(run);
+                    break;
+                },
+                Fit::Best => {
+                    if chosen.map(|c| run.count < c.count).unwrap_or(true) {
+                        chosen = Some(run);
+                    }
+                    if run.count == count {
+                        break;
+                    }
+                },
+            }
+        }
+
+        let extent = match chosen {
+            Some(run) => Extent{start: run.start, count: count},
+            None => return None,
+        };
+        self.reserve(extent).expect("allocated blocks must be free");
+        return Some(extent);
+    }
+
+    // 'count' blocks, contiguous if possible, and otherwise from the first
+    // free runs.
+    pub fn allocate_fragmented(&mut self, count: u64) -> Option<Vec<Extent>> {
+        if count == 0 {
+            return Some(Vec::new());
+        }
+        if let Some(extent) = self.allocate(count) {
+            return Some(vec![extent]);
+        }
+        if count > self.free {
+            return None;
+        }
+
+        let mut extents = Vec::new();
+        let mut needed = count;
+        let mut from = 0;
+        while needed > 0 {
+            let run = self.next_run(from).expect("free blocks must be in a run");
+            let take = if run.count < needed { run.count } else { needed };
+            extents.push(Extent{start: run.start, count: take});
+            needed -= take;
+            from = run.end();
+        }
+        for extent in &extents {
+            self.reserve(*extent).expect("allocated blocks must be free");
+        }
+        return Some(extents);
+    }
+
+    // Writes the bitmap to the copy not holding the last commit: the header,
+    // and whichever blocks changed since that copy was last written. The
+    // header goes first, so a commit torn before the rest lands fails its
+    // checksum.
+    pub fn commit(&mut self, device: &mut dyn Device) -> io::Result<()> {
+        let generation = self.generation + 1;
+        let copy = (generation % 2) as usize;
+        let copy_blocks = BitmapAllocator::blocks_needed(self.blocks, self.block_size);
+        let bs = self.block_size;
+        for block in 0..copy_blocks {
+            if self.stale[copy][block as usize] {
+                let range = bits_in(block, bs, self.bits.len());
+                self.block_crcs[block as usize] = checksum::crc32c(&self.bits[range]);
+            }
+        }
+
+        let mut header = vec![0; HEADER_SIZE];
+        format::store(BITMAP_MAGIC, &mut header[0..8]);
+        format::store(generation, &mut header[8..16]);
+        format::store(self.blocks, &mut header[16..24]);
+        let crc = header_crc(&header, &self.block_crcs);
+        format::store(crc, &mut header[24..32]);
+        self.stale[copy][0] = true;
+
+        // Write runs of stale blocks, header first.
+        let base = self.location + copy as u64 * copy_blocks;
+        let mut block = 0;
+        while block < copy_blocks {
+            if !self.stale[copy][block as usize] {
+                block += 1;
+                continue;
+            }
+            let start = block;
+            while block < copy_blocks && self.stale[copy][block as usize] {
+                block += 1;
+            }
+            let mut buf = if start == 0 { header.clone() } else { Vec::new() };
+            let range = bits_in(start, bs, self.bits.len()).start..bits_in(block - 1, bs, self.bits.len()).end;
+            buf.extend_from_slice(&self.bits[range]);
+            buf.resize(((block - start) * bs) as usize, 0);
+            try!(device.write((base + start) * bs, &buf));
+        }
+
+        for stale in self.stale[copy].iter_mut() {
+            *stale = false;
+        }
+        self.generation = generation;
+        self.dirty = false;
+        return Ok(());
+    }
+
+    // Checks the bitmap against the extents that are actually in use,
+    // including the ones holding metadata and the bitmap itself.
+    pub fn fsck(&self, owned: &[Extent]) -> Fsck {
+        let mut once = Bitset::new(self.blocks);
+        let mut twice = Bitset::new(self.blocks);
+        let mut report = Fsck::default();
+        for extent in owned {
+            for block in extent.start..extent.end().min(self.blocks) {
+                if once.get(block) {
+                    twice.set(block);
+                }
+                once.set(block);
+            }
+        }
+
+        for block in 0..self.blocks {
+            if twice.get(block) {
+                push_block(&mut report.shared, block);
+            }
+            if !once.get(block) && !self.is_free(block) {
+                push_block(&mut report.leaked, block);
+            } else if once.get(block) && self.is_free(block) {
+                push_block(&mut report.unmarked, block);
+            }
+        }
+        return report;
+    }
+
+    // Frees leaked blocks and marks unmarked ones in use. Shared blocks
+    // can't be fixed here.
+    pub fn repair(&mut self, report: &Fsck) -> io::Result<()> {
+        for extent in &report.leaked {
+            try!(self.free(*extent));
+        }
+        for extent in &report.unmarked {
+            try!(self.reserve(*extent));
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use block_storage::Device;
+    use block_storage::InMemoryDevice;
+    use super::BitmapAllocator;
+    use super::Extent;
+    use super::Fit;
+    use super::Fsck;
+
+    use std::io;
+    use std::io::ErrorKind;
+
+    // Counts the bytes written to an InMemoryDevice.
+    struct CountingDevice {
+        device: InMemoryDevice,
+        written: u64,
+    }
+
+    impl Device for CountingDevice {
+        fn block_size(&self) -> u64 {
+            return self.device.block_size();
+        }
+
+        fn size(&self) -> u64 {
+            return self.device.size();
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+            self.written += data.len() as u64;
+            return self.device.write(offset, data);
+        }
+
+        fn read(&mut self, offset: u64, length: u64, buf: &mut [u8]) -> io::Result<usize> {
+            return self.device.read(offset, length, buf);
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return self.device.flush();
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            return self.device.sync();
+        }
+    }
+
+    fn extent(start: u64, count: u64) -> Extent {
+        return Extent{start: start, count: count};
+    }
+
+    #[test]
+    fn first_and_best_fit() {
+        for &fit in &[Fit::First, Fit::Best] {
+            let mut bitmap = BitmapAllocator::new(0, 100, 512, fit);
+            bitmap.reserve(extent(0, 2)).unwrap();
+            assert_eq!(98, bitmap.free_blocks());
+            // Leave free runs of 5 blocks at 10 and 3 blocks at 20.
+            bitmap.reserve(extent(2, 8)).unwrap();
+            bitmap.reserve(extent(15, 5)).unwrap();
+            bitmap.reserve(extent(23, 7)).unwrap();
+
+            let got = bitmap.allocate(3).unwrap();
+            match fit {
+                Fit::First => assert_eq!(extent(10, 3), got),
+                Fit::Best => assert_eq!(extent(20, 3), got),
+            }
+            assert_eq!(extent(30, 70), bitmap.allocate(70).unwrap());
+            assert_eq!(None, bitmap.allocate(6));
+
+            // Whatever is left, in pieces.
+            let pieces = bitmap.allocate_fragmented(5).unwrap();
+            assert_eq!(5, pieces.iter().map(|e| e.count).sum::<u64>());
+            assert_eq!(0, bitmap.free_blocks());
+            assert_eq!(None, bitmap.allocate_fragmented(1));
+        }
+    }
+
+    #[test]
+    fn double_free() {
+        let mut bitmap = BitmapAllocator::new(0, 64, 512, Fit::First);
+        let e = bitmap.allocate(10).unwrap();
+        assert_eq!(ErrorKind::InvalidInput, bitmap.reserve(extent(5, 1)).unwrap_err().kind());
+        bitmap.free(e).unwrap();
+        assert_eq!(ErrorKind::InvalidInput, bitmap.free(e).unwrap_err().kind());
+        assert_eq!(ErrorKind::InvalidInput, bitmap.reserve(extent(60, 5)).unwrap_err().kind());
+        assert_eq!(64, bitmap.free_blocks());
+    }
+
+    #[test]
+    fn persistence() {
+        let mut device : Box<dyn Device> = Box::new(InMemoryDevice::new(512 * 1000, 512));
+        assert_eq!(1, BitmapAllocator::blocks_needed(1000, 512));
+
+        let mut bitmap = BitmapAllocator::new(1, 1000, 512, Fit::First);
+        bitmap.reserve(extent(0, 3)).unwrap();
+        bitmap.commit(&mut *device).unwrap();
+        let a = bitmap.allocate(100).unwrap();
+        bitmap.commit(&mut *device).unwrap();
+        assert!(!bitmap.is_dirty());
+
+        let mut loaded = BitmapAllocator::load(&mut *device, 1, 1000, Fit::First).unwrap();
+        assert_eq!(bitmap.free_blocks(), loaded.free_blocks());
+        assert!(!loaded.is_free(a.start));
+
+        // A torn commit falls back to the previous copy.
+        loaded.allocate(100).unwrap();
+        loaded.commit(&mut *device).unwrap();
+        device.write(512 * 2, &[0xff; 512]).unwrap();
+        let loaded = BitmapAllocator::load(&mut *device, 1, 1000, Fit::First).unwrap();
+        assert_eq!(bitmap.free_blocks(), loaded.free_blocks());
+
+        device.write(512, &[0xff; 512]).unwrap();
+        assert_eq!(ErrorKind::InvalidData,
+                   BitmapAllocator::load(&mut *device, 1, 1000, Fit::First).err().unwrap().kind());
+    }
+
+    #[test]
+    fn commits_write_changed_blocks() {
+        let mut device = CountingDevice{device: InMemoryDevice::new(512 * 100000, 512), written: 0};
+        assert_eq!(25, BitmapAllocator::blocks_needed(100000, 512));
+        let mut bitmap = BitmapAllocator::new(1, 100000, 512, Fit::First);
+        bitmap.reserve(extent(0, 51)).unwrap();
+        // Both copies are written whole the first time.
+        bitmap.commit(&mut device).unwrap();
+        bitmap.commit(&mut device).unwrap();
+        assert_eq!(2 * 25 * 512, device.written);
+
+        // Then only the header, and the blocks holding changed bits.
+        device.written = 0;
+        bitmap.allocate(10).unwrap();
+        bitmap.commit(&mut device).unwrap();
+        assert_eq!(512, device.written);
+        device.written = 0;
+        bitmap.reserve(extent(50000, 1)).unwrap();
+        bitmap.commit(&mut device).unwrap();
+        assert_eq!(2 * 512, device.written);
+        // Copy 1 also catches up on the block copy 0 just got.
+        device.written = 0;
+        bitmap.reserve(extent(70000, 1)).unwrap();
+        bitmap.commit(&mut device).unwrap();
+        assert_eq!(3 * 512, device.written);
+
+        let loaded = BitmapAllocator::load(&mut device, 1, 100000, Fit::First).unwrap();
+        assert_eq!(bitmap.free_blocks(), loaded.free_blocks());
+        assert!(!loaded.is_free(50000) && !loaded.is_free(70000) && loaded.is_free(50001));
+
+        // A commit torn after its header falls back to the other copy.
+        let mut bitmap = loaded;
+        bitmap.reserve(extent(60000, 1)).unwrap();
+        // Generation 6 goes to copy 0.
+        let copy = 0;
+        let mut old = vec![0; 512];
+        device.read((1 + copy + 14) * 512, 512, &mut old).unwrap();
+        bitmap.commit(&mut device).unwrap();
+        device.write((1 + copy + 14) * 512, &old).unwrap();
+        let loaded = BitmapAllocator::load(&mut device, 1, 100000, Fit::First).unwrap();
+        assert!(loaded.is_free(60000) && !loaded.is_free(50000));
+    }
+
+    #[test]
+    fn fsck() {
+        let mut bitmap = BitmapAllocator::new(0, 64, 512, Fit::First);
+        bitmap.reserve(extent(0, 20)).unwrap();
+        let owned = vec![extent(0, 10), extent(8, 4), extent(30, 2)];
+
+        let report = bitmap.fsck(&owned);
+        assert_eq!(Fsck{
+            leaked: vec![extent(12, 8)],
+            unmarked: vec![extent(30, 2)],
+            shared: vec![extent(8, 2)],
+        }, report);
+        assert!(!report.is_clean());
+
+        bitmap.repair(&report).unwrap();
+        let report = bitmap.fsck(&owned);
+        assert_eq!(vec![extent(8, 2)], report.shared);
+        assert!(report.leaked.is_empty() && report.unmarked.is_empty());
+        assert_eq!(64 - 12 - 2, bitmap.free_blocks());
+    }
+}
@@ -0,0 +1,393 @@
+use env::FileContents;
+
+use std::fs;
+use std::io;
+use std::io::Seek;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread;
+
+// A read of 'len' bytes at 'offset'. Its Completion carries the same tag.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct ReadRequest {
+    pub tag: u64,
+    pub offset: u64,
+    pub len: usize,
+}
+
+#[derive(Debug)]
+pub struct Completion {
+    pub tag: u64,
+    pub result: io::Result<Vec<u8>>,
+}
+
+// Reads that are submitted, proceed concurrently in the background, and are
+// collected as they complete, in any order. Callers that mustn't block can
+// collect with complete(0) and do something else while reads are in flight.
+pub trait AsyncDevice: Send {
+    fn size(&self) -> u64;
+    fn submit(&mut self, request: ReadRequest) -> io::Result<()>;
+    // Every read that has completed, after waiting until there are at least
+    // 'min' of them (or fewer, if fewer are in flight).
+    fn complete(&mut self, min: usize) -> io::Result<Vec<Completion>>;
+    // Submitted reads that haven't been returned by complete() yet.
+    fn in_flight(&self) -> usize;
+}
+
+// Submits one read and waits for it, for the odd read a caller can't do
+// without. Other reads' completions are returned alongside it.
+pub fn read_now(device: &mut dyn AsyncDevice, request: ReadRequest) -> io::Result<(Vec<u8>, Vec<Completion>)> {
+    try!(device.submit(request));
+    let mut others = Vec::new();
+    loop {
+        for completion in try!(device.complete(1)) {
+            if completion.tag == request.tag {
+                return completion.result.map(|data| (data, others));
+            }
+            others.push(completion);
+        }
+    }
+}
+
+// Something that can serve positional reads from several threads at once.
+pub trait ReadAt: Send + Sync {
+    fn size(&self) -> io::Result<u64>;
+    // Fails with UnexpectedEof if there aren't buf.len() bytes at 'offset'.
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+}
+
+impl ReadAt for fs::File {
+    fn size(&self) -> io::Result<u64> {
+        // Works for block devices, whose metadata has a length of zero.
+        let mut file : &fs::File = self;
+        return file.seek(io::SeekFrom::End(0));
+    }
+
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        return FileExt::read_exact_at(self, buf, offset);
+    }
+}
+
+impl ReadAt for FileContents {
+    fn size(&self) -> io::Result<u64> {
+        return Ok(self.len() as u64);
+    }
+
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if offset > self.len() as u64 || buf.len() > self.len() - offset as usize {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      format!("Read of {} bytes at {} is past the end", buf.len(), offset)));
+        }
+        let offset = offset as usize;
+        buf.copy_from_slice(&self[offset..(offset + buf.len())]);
+        return Ok(());
+    }
+}
+
+// An AsyncDevice that does blocking reads on a pool of worker threads, so
+// it works anywhere, on anything that implements ReadAt.
+pub struct ThreadPoolDevice {
+    size: u64,
+    requests: Option<mpsc::Sender<ReadRequest>>,
+    completions: mpsc::Receiver<Completion>,
+    workers: Vec<thread::JoinHandle<()>>,
+    in_flight: usize,
+}
+
+impl ThreadPoolDevice {
+    pub fn new(source: Arc<dyn ReadAt>, threads: usize) -> io::Result<ThreadPoolDevice> {
+        let size = try!(source.size());
+        let (request_sender, request_receiver) = mpsc::channel::<ReadRequest>();
+        let (completion_sender, completion_receiver) = mpsc::channel();
+        let request_receiver = Arc::new(Mutex::new(request_receiver));
+
+        let mut workers = Vec::new();
+        for _ in 0..threads.max(1) {
+            let source = source.clone();
+            let requests = request_receiver.clone();
+            let completions = completion_sender.clone();
+            workers.push(thread::spawn(move || {
+                loop {
+                    // Workers exit once the device, and with it the
+                    // sending half of the queue, is gone.
+                    let request = match requests.lock().unwrap().recv() {
+                        Ok(request) => request,
+                        Err(_) => return,
+                    };
+                    let mut buf = vec![0; request.len];
+                    let result = source.read_exact_at(request.offset, &mut buf).map(|_| buf);
+                    if completions.send(Completion{tag: request.tag, result: result}).is_err() {
+                        return;
+                    }
+                }
+            }));
+        }
+
+        return Ok(ThreadPoolDevice{
+            size: size,
+            requests: Some(request_sender),
+            completions: completion_receiver,
+            workers: workers,
+            in_flight: 0,
+        });
+    }
+}
+
+impl AsyncDevice for ThreadPoolDevice {
+    fn size(&self) -> u64 {
+        return self.size;
+    }
+
+    fn submit(&mut self, request: ReadRequest) -> io::Result<()> {
+        try!(self.requests.as_ref().unwrap().send(request).map_err(
+            |_| io::Error::new(io::ErrorKind::BrokenPipe, "The I/O threads have exited")));
+        self.in_flight += 1;
+        return Ok(());
+    }
+
+    fn complete(&mut self, min: usize) -> io::Result<Vec<Completion>> {
+        let mut done = Vec::new();
+        while done.len() < min && self.in_flight > 0 {
+            done.push(try!(self.completions.recv().map_err(
+                |_| io::Error::new(io::ErrorKind::BrokenPipe, "The I/O threads have exited"))));
+            self.in_flight -= 1;
+        }
+        while let Ok(completion) = self.completions.try_recv() {
+            done.push(completion);
+            self.in_flight -= 1;
+        }
+        return Ok(done);
+    }
+
+    fn in_flight(&self) -> usize {
+        return self.in_flight;
+    }
+}
+
+impl Drop for ThreadPoolDevice {
+    fn drop(&mut self) {
+        self.requests = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(feature = "io-uring")]
+pub use self::uring::UringDevice;
+
+#[cfg(feature = "io-uring")]
+mod uring {
+    extern crate io_uring;
+
+    use super::AsyncDevice;
+    use super::Completion;
+    use super::ReadAt;
+    use super::ReadRequest;
+
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    // An AsyncDevice that reads a file (or block device) with io_uring,
+    // without any threads of its own. Needs Linux 5.6 or later.
+    pub struct UringDevice {
+        file: fs::File,
+        size: u64,
+        ring: io_uring::IoUring,
+        // The buffers of the reads in flight, by their user_data.
+        buffers: HashMap<u64, (u64, Vec<u8>)>,
+        next_id: u64,
+    }
+
+    impl UringDevice {
+        pub fn new(file: fs::File, queue_depth: u32) -> io::Result<UringDevice> {
+            let size = try!(file.size());
+            return Ok(UringDevice{
+                file: file,
+                size: size,
+                ring: try!(io_uring::IoUring::new(queue_depth.max(1))),
+                buffers: HashMap::new(),
+                next_id: 0,
+            });
+        }
+    }
+
+    impl AsyncDevice for UringDevice {
+        fn size(&self) -> u64 {
+            return self.size;
+        }
+
+        fn submit(&mut self, request: ReadRequest) -> io::Result<()> {
+            if request.len > u32::MAX as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Reads are limited to {} bytes, not {}", u32::MAX, request.len)));
+            }
+            let id = self.next_id;
+            self.next_id += 1;
+            let mut buf = vec![0; request.len];
+            let entry = io_uring::opcode::Read::new(
+                io_uring::types::Fd(self.file.as_raw_fd()), buf.as_mut_ptr(), request.len as u32)
+                .offset(request.offset)
+                .build()
+                .user_data(id);
+
+            loop {
+                let pushed = unsafe { self.ring.submission().push(&entry).is_ok() };
+                if pushed {
+                    break;
+                }
+                // The submission queue is full.
+                try!(self.ring.submit());
+            }
+            // The buffer stays put in 'buffers' (moving the Vec doesn't
+            // move its contents) until the read completes.
+            self.buffers.insert(id, (request.tag, buf));
+            if let Err(e) = self.ring.submit() {
+                // The read is no longer waited for, but it's still queued
+                // and may yet write into its buffer, so that's never freed.
+                if let Some((_, buf)) = self.buffers.remove(&id) {
+                    mem::forget(buf);
+                }
+                return Err(e);
+            }
+            return Ok(());
+        }
+
+        fn complete(&mut self, min: usize) -> io::Result<Vec<Completion>> {
+            let mut done = Vec::new();
+            loop {
+                for entry in self.ring.completion() {
+                    // Reads whose submission failed aren't waited for.
+                    let (tag, buf) = match self.buffers.remove(&entry.user_data()) {
+                        Some(read) => read,
+                        None => continue,
+                    };
+                    let result = if entry.result() < 0 {
+                        Err(io::Error::from_raw_os_error(-entry.result()))
+                    } else if entry.result() as usize != buf.len() {
+                        Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                           format!("Short read of {} bytes", entry.result())))
+                    } else {
+                        Ok(buf)
+                    };
+                    done.push(Completion{tag: tag, result: result});
+                }
+                if done.len() >= min || self.buffers.is_empty() {
+                    return Ok(done);
+                }
+                try!(self.ring.submit_and_wait(1));
+            }
+        }
+
+        fn in_flight(&self) -> usize {
+            return self.buffers.len();
+        }
+    }
+
+    impl Drop for UringDevice {
+        fn drop(&mut self) {
+            // The kernel may still write into the buffers of reads in
+            // flight, so they have to finish first.
+            while !self.buffers.is_empty() {
+                if self.complete(self.buffers.len()).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AsyncDevice;
+    use super::ReadAt;
+    use super::ReadRequest;
+    use super::ThreadPoolDevice;
+    use super::read_now;
+
+    use env;
+    use env::FileContents;
+
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    fn contents() -> Vec<u8> {
+        return (0..100000).map(|i| (i % 251) as u8).collect();
+    }
+
+    // Reads 64 scattered ranges at once, and a range past the end.
+    fn check_reads(device: &mut dyn AsyncDevice) {
+        let data = contents();
+        assert_eq!(data.len() as u64, device.size());
+
+        let mut expected = HashMap::new();
+        for tag in 0..64 {
+            let offset = tag * 1499;
+            let len = 100 + tag as usize * 13;
+            device.submit(ReadRequest{tag: tag, offset: offset, len: len}).unwrap();
+            expected.insert(tag, data[(offset as usize)..(offset as usize + len)].to_vec());
+        }
+        device.submit(ReadRequest{tag: 64, offset: 99990, len: 100}).unwrap();
+
+        let mut completed = 0;
+        while device.in_flight() > 0 {
+            for completion in device.complete(1).unwrap() {
+                completed += 1;
+                match expected.remove(&completion.tag) {
+                    Some(data) => assert_eq!(data, completion.result.unwrap()),
+                    None => assert_eq!(ErrorKind::UnexpectedEof, completion.result.unwrap_err().kind()),
+                }
+            }
+        }
+        assert_eq!(65, completed);
+        assert!(device.complete(10).unwrap().is_empty());
+
+        let request = ReadRequest{tag: 7, offset: 10, len: 5};
+        assert_eq!(data[10..15].to_vec(), read_now(device, request).unwrap().0);
+    }
+
+    #[test]
+    fn thread_pool_device() {
+        let data : Arc<dyn ReadAt> = Arc::new(FileContents::Memory(Arc::new(contents())));
+        check_reads(&mut ThreadPoolDevice::new(data, 4).unwrap());
+
+        let path = env::temp_path("async-io");
+        fs::File::create(&path).unwrap().write_all(&contents()).unwrap();
+        let file : Arc<dyn ReadAt> = Arc::new(fs::File::open(&path).unwrap());
+        check_reads(&mut ThreadPoolDevice::new(file, 4).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "io-uring")]
+    #[test]
+    fn uring_device() {
+        use super::UringDevice;
+
+        let path = env::temp_path("async-io-uring");
+        fs::File::create(&path).unwrap().write_all(&contents()).unwrap();
+        let file = fs::File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let mut device = match UringDevice::new(file, 16) {
+            Ok(device) => device,
+            // The kernel doesn't support io_uring, or it's disabled.
+            Err(ref e) if e.kind() == ErrorKind::Unsupported ||
+                e.kind() == ErrorKind::PermissionDenied => return,
+            Err(e) => panic!("{}", e),
+        };
+        check_reads(&mut device);
+
+        // Longer reads than io_uring takes are refused up front.
+        let request = ReadRequest{tag: 0, offset: 0, len: 1 << 32};
+        assert_eq!(ErrorKind::InvalidInput, device.submit(request).unwrap_err().kind());
+        assert_eq!(0, device.in_flight());
+    }
+}
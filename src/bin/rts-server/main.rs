@@ -0,0 +1,99 @@
+#![allow(clippy::needless_return, clippy::redundant_field_names)]
+
+extern crate libc;
+extern crate rts;
+
+use rts::db::Db;
+use rts::db::Options;
+use rts::server::Server;
+
+use std::env;
+use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+const USAGE : &str = "Usage: rts-server [options] DIRECTORY
+
+Serves the database in DIRECTORY (created if need be) over HTTP until
+interrupted, then flushes it.
+
+Options:
+  --listen ADDRESS          Where to listen (default 127.0.0.1:8086)
+  --ticks-per-second N      Timestamp units, used for rates (default 1)
+  --sync-writes             Sync every write to the log before answering
+  --bloom-bits-per-key N    Give new tables bloom filters";
+
+// Set by SIGINT and SIGTERM.
+static INTERRUPTED : AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+fn usage(error: &str) -> ! {
+    eprintln!("{}\n\n{}", error, USAGE);
+    process::exit(2);
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: Option<String>) -> T {
+    return match value.map(|v| v.parse()) {
+        Some(Ok(n)) => n,
+        _ => usage(&format!("{} needs a number", flag)),
+    };
+}
+
+fn main() {
+    let mut listen = "127.0.0.1:8086".to_string();
+    let mut options = Options::default();
+    let mut directory = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => listen = args.next().unwrap_or_else(|| usage("--listen needs an address")),
+            "--ticks-per-second" => options.ticks_per_second = parse_number(&arg, args.next()),
+            "--sync-writes" => options.sync_writes = true,
+            "--bloom-bits-per-key" => options.table.bloom_bits_per_key = Some(parse_number(&arg, args.next())),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            },
+            flag if flag.starts_with("--") => usage(&format!("Unknown option {}", flag)),
+            _ if directory.is_none() => directory = Some(arg),
+            _ => usage("Only one DIRECTORY, please"),
+        }
+    }
+    let directory = directory.unwrap_or_else(|| usage("Which DIRECTORY?"));
+
+    let db = Db::with_options(&directory, options).unwrap_or_else(|e| {
+        eprintln!("Can't open {}: {}", directory, e);
+        process::exit(1);
+    });
+    let server = Server::bind(&listen, db).unwrap_or_else(|e| {
+        eprintln!("Can't listen on {}: {}", listen, e);
+        process::exit(1);
+    });
+    eprintln!("Serving {} on http://{}", directory, server.local_addr().unwrap());
+
+    unsafe {
+        libc::signal(libc::SIGINT, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+    let shutdown = server.shutdown_flag();
+    thread::spawn(move || {
+        while !INTERRUPTED.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        shutdown.store(true, Ordering::SeqCst);
+    });
+
+    match server.run() {
+        Ok(_) => eprintln!("Flushed {}", directory),
+        Err(e) => {
+            eprintln!("Failed to flush {}: {}", directory, e);
+            process::exit(1);
+        },
+    }
+}
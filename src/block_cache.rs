@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::sync::atomic;
+
+// Capacity of the process-wide cache returned by BlockCache::shared().
+pub const DEFAULT_CAPACITY_BYTES : usize = 8 * 1024 * 1024;
+
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub inserts: u64,
+    pub evictions: u64,
+    pub usage_bytes: usize,
+    pub capacity_bytes: usize,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        if self.hits + self.misses == 0 {
+            return 0.0;
+        }
+        return self.hits as f64 / (self.hits + self.misses) as f64;
+    }
+}
+
+// (file, reader, block offset)
+type Key = (path::PathBuf, u64, u64);
+
+// Numbers the readers of table files. A file can be replaced by another of
+// the same name (e.g. when a directory is wiped and reused), so blocks are
+// only shared by users of the same open file, which has its own number.
+static READER_IDS : atomic::AtomicU64 = atomic::AtomicU64::new(0);
+
+pub fn next_reader_id() -> u64 {
+    return READER_IDS.fetch_add(1, atomic::Ordering::SeqCst);
+}
+
+struct Entry {
+    data: Arc<Vec<u8>>,
+    last_use: u64,
+}
+
+struct Lru {
+    entries: HashMap<Key, Entry>,
+    // Keys ordered by their last use, least recent first.
+    by_use: BTreeMap<u64, Key>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+// A cache of decoded table blocks, keyed by (table file, reader, block offset),
+// which evicts the least recently used blocks once it holds more than its
+// capacity. It's internally synchronized so that it can be shared between
+// databases.
+pub struct BlockCache {
+    lru: Mutex<Lru>,
+}
+
+impl BlockCache {
+    pub fn new(capacity_bytes: usize) -> BlockCache {
+        let stats = CacheStats{capacity_bytes: capacity_bytes, ..CacheStats::default()};
+
+        return BlockCache{
+            lru: Mutex::new(Lru{
+                entries: HashMap::new(),
+                by_use: BTreeMap::new(),
+                clock: 0,
+                stats: stats,
+            }),
+        };
+    }
+
+    // The process-wide cache that databases use unless they're given another.
+    pub fn shared() -> Arc<BlockCache> {
+        static SHARED : OnceLock<Arc<BlockCache>> = OnceLock::new();
+        return SHARED.get_or_init(
+            || Arc::new(BlockCache::new(DEFAULT_CAPACITY_BYTES))).clone();
+    }
+
+    pub fn get(&self, file: &path::Path, reader: u64, offset: u64) -> Option<Arc<Vec<u8>>> {
+        let mut lru = self.lru.lock().unwrap();
+        let key = (file.to_path_buf(), reader, offset);
+
+        lru.clock += 1;
+        let now = lru.clock;
+        let found = match lru.entries.get_mut(&key) {
+            Some(entry) => {
+                let prev_use = entry.last_use;
+                entry.last_use = now;
+                Some((prev_use, entry.data.clone()))
+            },
+            None => None,
+        };
+
+        match found {
+            Some((prev_use, data)) => {
+                lru.by_use.remove(&prev_use);
+                lru.by_use.insert(now, key);
+                lru.stats.hits += 1;
+                return Some(data);
+            },
+            None => {
+                lru.stats.misses += 1;
+                return None;
+            }
+        }
+    }
+
+    pub fn insert(&self, file: &path::Path, reader: u64, offset: u64, data: Arc<Vec<u8>>) {
+        let mut lru = self.lru.lock().unwrap();
+        let key = (file.to_path_buf(), reader, offset);
+        if data.len() > lru.stats.capacity_bytes {
+            return;
+        }
+
+        lru.clock += 1;
+        let now = lru.clock;
+        let size = data.len();
+        if let Some(old) = lru.entries.insert(key.clone(), Entry{data: data, last_use: now}) {
+            lru.by_use.remove(&old.last_use);
+            lru.stats.usage_bytes -= old.data.len();
+        }
+        lru.by_use.insert(now, key);
+        lru.stats.usage_bytes += size;
+        lru.stats.inserts += 1;
+
+        while lru.stats.usage_bytes > lru.stats.capacity_bytes {
+            let oldest = *lru.by_use.keys().next().unwrap();
+            let key = lru.by_use.remove(&oldest).unwrap();
+            let entry = lru.entries.remove(&key).unwrap();
+            lru.stats.usage_bytes -= entry.data.len();
+            lru.stats.evictions += 1;
+        }
+    }
+
+    // Drops every block from 'file', by any reader, e.g. because it has been
+    // deleted.
+    pub fn erase_file(&self, file: &path::Path) {
+        let mut lru = self.lru.lock().unwrap();
+        let keys : Vec<Key> = lru.entries.keys()
+            .filter(|k| k.0 == file).cloned().collect();
+        for key in keys {
+            let entry = lru.entries.remove(&key).unwrap();
+            lru.by_use.remove(&entry.last_use);
+            lru.stats.usage_bytes -= entry.data.len();
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        return self.lru.lock().unwrap().stats;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use super::BlockCache;
+
+    #[test]
+    fn hits_and_misses() {
+        let cache = BlockCache::new(1024);
+        assert_eq!(None, cache.get(Path::new("/a"), 1, 0));
+
+        cache.insert(Path::new("/a"), 1, 0, Arc::new(vec![1; 10]));
+        assert_eq!(Some(Arc::new(vec![1; 10])), cache.get(Path::new("/a"), 1, 0));
+        assert_eq!(None, cache.get(Path::new("/a"), 1, 10));
+        assert_eq!(None, cache.get(Path::new("/b"), 1, 0));
+        assert_eq!(None, cache.get(Path::new("/a"), 2, 0));
+
+        let stats = cache.stats();
+        assert_eq!(1, stats.hits);
+        assert_eq!(4, stats.misses);
+        assert_eq!(10, stats.usage_bytes);
+        assert_eq!(0.2, stats.hit_rate());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = BlockCache::new(300);
+        cache.insert(Path::new("/t"), 1, 0, Arc::new(vec![0; 100]));
+        cache.insert(Path::new("/t"), 1, 100, Arc::new(vec![0; 100]));
+        cache.insert(Path::new("/t"), 1, 200, Arc::new(vec![0; 100]));
+
+        // Touch the first block so that the second is the oldest.
+        assert!(cache.get(Path::new("/t"), 1, 0).is_some());
+        cache.insert(Path::new("/t"), 1, 300, Arc::new(vec![0; 100]));
+
+        assert!(cache.get(Path::new("/t"), 1, 0).is_some());
+        assert!(cache.get(Path::new("/t"), 1, 100).is_none());
+        assert!(cache.get(Path::new("/t"), 1, 200).is_some());
+        assert!(cache.get(Path::new("/t"), 1, 300).is_some());
+        assert_eq!(1, cache.stats().evictions);
+        assert_eq!(300, cache.stats().usage_bytes);
+
+        // Blocks bigger than the whole cache aren't kept.
+        cache.insert(Path::new("/t"), 1, 400, Arc::new(vec![0; 1000]));
+        assert!(cache.get(Path::new("/t"), 1, 400).is_none());
+
+        cache.insert(Path::new("/u"), 1, 0, Arc::new(vec![0; 100]));
+        cache.insert(Path::new("/t"), 2, 0, Arc::new(vec![0; 100]));
+        cache.erase_file(Path::new("/t"));
+        assert!(cache.get(Path::new("/t"), 2, 0).is_none());
+        assert!(cache.get(Path::new("/t"), 1, 0).is_none());
+        assert!(cache.get(Path::new("/u"), 1, 0).is_some());
+        assert_eq!(100, cache.stats().usage_bytes);
+    }
+}
@@ -1,11 +1,71 @@
+extern crate libc;
+
 use std::io;
 use std::cmp::min;
+use std::fs;
+use std::io::Seek;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path;
 
 pub trait Device {
     fn block_size(&self) -> u64;
     fn size(&self) -> u64;
-    fn write(&mut self, offset: u64, &[u8]) -> io::Result<()>;
+    fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
     fn read(&mut self, offset: u64, length: u64, buf: &mut [u8]) -> io::Result<usize>;
+    // Hands any buffered writes to the underlying storage.
+    fn flush(&mut self) -> io::Result<()>;
+    // Makes every write so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+// Writes, and reads short of the end of the device, must cover whole blocks.
+pub fn check_alignment(block_size: u64, offset: u64, len: u64) -> io::Result<()> {
+    if !len.is_multiple_of(block_size) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("Invalid length {}", len)))
+    }
+
+    if !offset.is_multiple_of(block_size) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("Invalid offset {}", offset)))
+    }
+    return Ok(())
+}
+
+pub fn check_bounds(size: u64, offset: u64, len: u64) -> io::Result<()> {
+    if offset > size || len > size - offset {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("Write of {} bytes at {} is past the end of the device", len, offset)))
+    }
+    return Ok(())
+}
+
+// So that wrappers generic over a Device can also wrap a boxed one.
+impl<D: Device + ?Sized> Device for Box<D> {
+    fn block_size(&self) -> u64 {
+        return (**self).block_size()
+    }
+
+    fn size(&self) -> u64 {
+        return (**self).size()
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        return (**self).write(offset, data)
+    }
+
+    fn read(&mut self, offset: u64, length: u64, buf: &mut [u8]) -> io::Result<usize> {
+        return (**self).read(offset, length, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return (**self).flush()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        return (**self).sync()
+    }
 }
 
 pub struct InMemoryDevice {
@@ -24,43 +84,32 @@ impl Device for InMemoryDevice {
     }
 
     fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
-        if (data.len() as u64) % self.block_size != 0 {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                      format!("Invalid length {}", data.len())))
-        }
+        try!(check_alignment(self.block_size, offset, data.len() as u64));
+        try!(check_bounds(self.size, offset, data.len() as u64));
 
-        if offset % self.block_size != 0 {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                      format!("Invalid offset {}", offset)))
-        }
-
-        let len = data.len() as usize;
         let offset = offset as usize;
-        for i in 0..len {
-            self.data[offset + i] = data[i]
-        }
+        self.data[offset..(offset + data.len())].copy_from_slice(data);
         return Ok(())
     }
 
     fn read(&mut self, offset: u64, length: u64, buf: &mut [u8]) -> io::Result<usize> {
         assert!(length <= (buf.len() as u64));
-        let len = min(length, self.size - offset);
-
-        if len % self.block_size != 0 {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                      format!("Invalid length {}", len)))
-        }
-
-        if offset % self.block_size != 0 {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                      format!("Invalid offset {}", offset)))
-        }
+        let len = min(length, self.size.saturating_sub(offset));
+        try!(check_alignment(self.block_size, offset, len));
 
         for i in 0..len {
             buf[i as usize] = self.data[(offset + i) as usize];
         }
         return Ok(len as usize);
     }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        return Ok(())
+    }
 }
 
 impl InMemoryDevice {
@@ -73,11 +122,157 @@ impl InMemoryDevice {
     }
 }
 
+#[derive(Clone,Copy,Debug)]
+pub struct FileDeviceOptions {
+    pub block_size: u64,
+    // Bypass the page cache with O_DIRECT. Not every filesystem supports it
+    // (e.g. tmpfs), and block_size must be a multiple of the device's
+    // logical block size.
+    pub direct: bool,
+}
+
+impl Default for FileDeviceOptions {
+    fn default() -> FileDeviceOptions {
+        return FileDeviceOptions{
+            block_size: 4096,
+            direct: false,
+        }
+    }
+}
+
+// A Device backed by a regular file or a block device node, accessed with
+// positional reads and writes.
+pub struct FileDevice {
+    file: fs::File,
+    size: u64,
+    block_size: u64,
+    direct: bool,
+}
+
+impl FileDevice {
+    // Opens an existing file or block device. Its size is rounded down to a
+    // whole number of blocks.
+    pub fn open<P: AsRef<path::Path>>(filename: P, options: &FileDeviceOptions) -> io::Result<FileDevice> {
+        let mut file = try!(FileDevice::open_options(options).open(filename));
+        // Block devices report a length of zero in their metadata, but can
+        // be seeked to the end.
+        let size = try!(file.seek(io::SeekFrom::End(0)));
+
+        return Ok(FileDevice{
+            file: file,
+            size: size - size % options.block_size,
+            block_size: options.block_size,
+            direct: options.direct,
+        })
+    }
+
+    // Creates (or truncates) a regular file of 'size' bytes to use as a
+    // device.
+    pub fn create<P: AsRef<path::Path>>(filename: P, size: u64, options: &FileDeviceOptions) -> io::Result<FileDevice> {
+        try!(check_alignment(options.block_size, 0, size));
+        let file = try!(FileDevice::open_options(options).create(true).truncate(true).open(filename));
+        try!(file.set_len(size));
+
+        return Ok(FileDevice{
+            file: file,
+            size: size,
+            block_size: options.block_size,
+            direct: options.direct,
+        })
+    }
+
+    fn open_options(options: &FileDeviceOptions) -> fs::OpenOptions {
+        let mut open_options = fs::OpenOptions::new();
+        open_options.read(true).write(true);
+        if options.direct {
+            open_options.custom_flags(libc::O_DIRECT);
+        }
+        return open_options
+    }
+}
+
+// O_DIRECT needs buffers aligned in memory as well as on disk, so direct
+// I/O goes through one of these.
+struct AlignedBuffer {
+    data: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    fn new(len: usize, alignment: usize) -> AlignedBuffer {
+        let data = vec![0; len + alignment];
+        let misalignment = data.as_ptr() as usize % alignment;
+        let start = if misalignment == 0 { 0 } else { alignment - misalignment };
+        return AlignedBuffer{data: data, start: start, len: len}
+    }
+
+    fn get(&self) -> &[u8] {
+        return &self.data[self.start..(self.start + self.len)]
+    }
+
+    fn get_mut(&mut self) -> &mut [u8] {
+        return &mut self.data[self.start..(self.start + self.len)]
+    }
+}
+
+impl Device for FileDevice {
+    fn size(&self) -> u64 {
+        return self.size
+    }
+
+    fn block_size(&self) -> u64 {
+        return self.block_size
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        try!(check_alignment(self.block_size, offset, data.len() as u64));
+        try!(check_bounds(self.size, offset, data.len() as u64));
+
+        if self.direct {
+            let mut buf = AlignedBuffer::new(data.len(), self.block_size as usize);
+            buf.get_mut().copy_from_slice(data);
+            return self.file.write_all_at(buf.get(), offset)
+        }
+        return self.file.write_all_at(data, offset)
+    }
+
+    fn read(&mut self, offset: u64, length: u64, buf: &mut [u8]) -> io::Result<usize> {
+        assert!(length <= (buf.len() as u64));
+        let len = min(length, self.size.saturating_sub(offset));
+        try!(check_alignment(self.block_size, offset, len));
+
+        let len = len as usize;
+        if self.direct {
+            let mut aligned = AlignedBuffer::new(len, self.block_size as usize);
+            try!(self.file.read_exact_at(aligned.get_mut(), offset));
+            buf[0..len].copy_from_slice(aligned.get());
+        } else {
+            try!(self.file.read_exact_at(&mut buf[0..len], offset));
+        }
+        return Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Writes go straight to the file, so there's nothing buffered here.
+        return Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        return self.file.sync_data()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::Device;
+    use super::FileDevice;
+    use super::FileDeviceOptions;
     use super::InMemoryDevice;
 
+    use env;
+
+    use std::fs;
     use std::io::ErrorKind;
 
     #[test]
@@ -108,4 +303,48 @@ mod test {
         assert_eq!(ErrorKind::InvalidInput,
                    dev.read(2, 4, &mut buf).unwrap_err().kind());
     }
+
+    #[test]
+    fn file_device() {
+        let options = FileDeviceOptions{block_size: 512, direct: false};
+        let path = env::temp_path("filedevice");
+        {
+            let mut dev = FileDevice::create(&path, 8192, &options).unwrap();
+            assert_eq!(8192, dev.size());
+            dev.write(512, &[7; 1024]).unwrap();
+            dev.sync().unwrap();
+
+            assert_eq!(ErrorKind::InvalidInput, dev.write(100, &[0; 512]).unwrap_err().kind());
+            assert_eq!(ErrorKind::InvalidInput, dev.write(0, &[0; 100]).unwrap_err().kind());
+            assert_eq!(ErrorKind::InvalidInput, dev.write(8192, &[0; 512]).unwrap_err().kind());
+        }
+
+        let mut dev = FileDevice::open(&path, &options).unwrap();
+        assert_eq!(8192, dev.size());
+        let mut buf = [0; 2048];
+        assert_eq!(2048, dev.read(0, 2048, &mut buf).unwrap());
+        assert_eq!([0; 512], buf[0..512]);
+        assert_eq!([7; 1024], buf[512..1536]);
+        // Reads are cut short at the end of the device.
+        assert_eq!(512, dev.read(7680, 2048, &mut buf).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn direct_file_device() {
+        let options = FileDeviceOptions{block_size: 4096, direct: true};
+        let path = env::temp_path("filedevice-direct");
+        let created = FileDevice::create(&path, 16384, &options);
+        let _ = fs::remove_file(&path);
+        let mut dev = match created {
+            Ok(dev) => dev,
+            // The filesystem doesn't support O_DIRECT.
+            Err(ref e) if e.kind() == ErrorKind::InvalidInput => return,
+            Err(e) => panic!("{}", e),
+        };
+        dev.write(4096, &[3; 4096]).unwrap();
+        let mut buf = vec![0; 8192];
+        assert_eq!(8192, dev.read(0, 8192, &mut buf).unwrap());
+        assert_eq!(vec![3; 4096], &buf[4096..]);
+    }
 }
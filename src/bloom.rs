@@ -0,0 +1,113 @@
+// A bloom filter over u64 keys, using double hashing to derive the probes
+// (see Kirsch & Mitzenmacher, "Less Hashing, Same Performance").
+//
+// Serialized form is the bit array followed by one byte holding the number of
+// probes, so readers don't need to know the bits-per-key it was built with.
+
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    num_probes: u32,
+}
+
+fn hash(key: u64) -> u64 {
+    // splitmix64 finalizer
+    let mut h = key.wrapping_add(0x9E3779B97F4A7C15);
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D049BB133111EB);
+    return h ^ (h >> 31);
+}
+
+impl BloomFilter {
+    pub fn build(keys: &[u64], bits_per_key: usize) -> BloomFilter {
+        // ln(2) * bits_per_key minimizes the false positive rate.
+        let num_probes = ((bits_per_key as f64) * 0.69) as u32;
+        let num_probes = num_probes.clamp(1, 30);
+
+        let mut num_bits = keys.len() * bits_per_key;
+        if num_bits < 64 {
+            num_bits = 64;
+        }
+        let num_bytes = num_bits.div_ceil(8);
+
+        let mut filter = BloomFilter{
+            bits: vec![0; num_bytes],
+            num_probes: num_probes,
+        };
+        for key in keys {
+            filter.add(*key);
+        }
+        return filter;
+    }
+
+    fn add(&mut self, key: u64) {
+        let num_bits = (self.bits.len() * 8) as u64;
+        let h = hash(key);
+        let mut h1 = h & 0xFFFFFFFF;
+        let h2 = h >> 32;
+        for _ in 0..self.num_probes {
+            let bit = h1 % num_bits;
+            self.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+            h1 = h1.wrapping_add(h2);
+        }
+    }
+
+    pub fn may_contain(&self, key: u64) -> bool {
+        let num_bits = (self.bits.len() * 8) as u64;
+        if num_bits == 0 {
+            return true;
+        }
+
+        let h = hash(key);
+        let mut h1 = h & 0xFFFFFFFF;
+        let h2 = h >> 32;
+        for _ in 0..self.num_probes {
+            let bit = h1 % num_bits;
+            if self.bits[(bit / 8) as usize] & (1 << (bit % 8)) == 0 {
+                return false;
+            }
+            h1 = h1.wrapping_add(h2);
+        }
+        return true;
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = self.bits.clone();
+        buf.push(self.num_probes as u8);
+        return buf;
+    }
+
+    pub fn decode(buf: &[u8]) -> BloomFilter {
+        if buf.is_empty() {
+            return BloomFilter{bits: Vec::new(), num_probes: 0};
+        }
+
+        return BloomFilter{
+            bits: buf[0..(buf.len() - 1)].to_vec(),
+            num_probes: buf[buf.len() - 1] as u32,
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BloomFilter;
+
+    #[test]
+    fn no_false_negatives() {
+        let keys : Vec<u64> = (0..1000).map(|k| k * 1000).collect();
+        let filter = BloomFilter::decode(&BloomFilter::build(&keys, 10).encode());
+        for k in &keys {
+            assert!(filter.may_contain(*k));
+        }
+    }
+
+    #[test]
+    fn false_positive_rate() {
+        let keys : Vec<u64> = (0..10000).collect();
+        let filter = BloomFilter::build(&keys, 10);
+
+        let false_positives = (10000..20000).filter(|k| filter.may_contain(*k)).count();
+        // ~1% is expected at 10 bits per key.
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+}
@@ -0,0 +1,303 @@
+use db;
+use db::Db;
+use env;
+use expr;
+use format;
+use rules;
+use rules::Rule;
+
+use std::collections::HashMap;
+use std::io;
+use std::path;
+
+// A directory of named series, each stored in its own Db under
+// 'series/', along with the recording rules that derive new series from
+// existing ones. Everything is kept in the options' Env.
+pub struct Catalog {
+    root: path::PathBuf,
+    options: db::Options,
+    series: HashMap<String, Db>,
+    rules: Vec<Rule>,
+}
+
+// Whether 'name' can name a series. Names may contain anything but
+// whitespace and quotes, e.g. "cpu,host=a".
+pub fn valid_series_name(name: &str) -> bool {
+    return !name.is_empty() &&
+        !name.chars().any(|c| c.is_whitespace() || c.is_control() || c == '"' || c == '\'');
+}
+
+// Series directories are named by percent-encoding everything but
+// [A-Za-z0-9_.-] in the series name.
+fn encode_name(name: &str) -> String {
+    let mut encoded = String::new();
+    for b in name.bytes() {
+        if b.is_ascii_alphanumeric() || b == b'_' || b == b'.' || b == b'-' {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    return encoded;
+}
+
+fn decode_name(encoded: &str) -> Option<String> {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = encoded.get((i + 1)..(i + 3))?;
+            decoded.push(match u8::from_str_radix(hex, 16) {
+                Ok(b) => b,
+                Err(_) => return None,
+            });
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    return String::from_utf8(decoded).ok();
+}
+
+fn invalid_name(name: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidInput,
+                          format!("'{}' is not a valid series name.", name));
+}
+
+impl Catalog {
+    pub fn open<P: AsRef<path::Path>>(directory: P, options: db::Options) -> io::Result<Catalog> {
+        let root = directory.as_ref().to_path_buf();
+        try!(env::create_dir_all(&*options.env, &root.join("series")));
+        let rules = try!(rules::load(&*options.env, root.join("rules")));
+
+        return Ok(Catalog{
+            root: root,
+            options: options,
+            series: HashMap::new(),
+            rules: rules,
+        });
+    }
+
+    fn series_path(&self, name: &str) -> path::PathBuf {
+        return self.root.join("series").join(encode_name(name));
+    }
+
+    // The series called 'name', creating it if it doesn't exist.
+    pub fn series(&mut self, name: &str) -> io::Result<&mut Db> {
+        if !valid_series_name(name) {
+            return Err(invalid_name(name));
+        }
+        if !self.series.contains_key(name) {
+            let db = try!(Db::with_options(self.series_path(name), self.options.clone()));
+            self.series.insert(name.to_string(), db);
+        }
+        return Ok(self.series.get_mut(name).unwrap());
+    }
+
+    // The series called 'name', if it exists.
+    pub fn get(&mut self, name: &str) -> io::Result<Option<&mut Db>> {
+        if !self.series.contains_key(name) {
+            if !valid_series_name(name) {
+                return Ok(None);
+            }
+            match self.options.env.is_dir(&self.series_path(name)) {
+                Ok(true) => {},
+                Ok(false) => return Ok(None),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+        return self.series(name).map(Some);
+    }
+
+    pub fn series_names(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for path in try!(self.options.env.list_dirs(&self.root.join("series"))) {
+            if let Some(name) = path.file_name().and_then(|n| n.to_str()).and_then(decode_name) {
+                names.push(name);
+            }
+        }
+        names.sort();
+        return Ok(names);
+    }
+
+    pub fn options(&self) -> &db::Options {
+        return &self.options;
+    }
+
+    pub fn record(&mut self, name: &str, rec: &format::Rec) -> io::Result<()> {
+        return try!(self.series(name)).record(rec);
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        return &self.rules;
+    }
+
+    // Adds a rule writing 'expr', evaluated every 'interval', to the series
+    // 'name'.
+    pub fn add_rule(&mut self, name: &str, interval: u64, expr: &str) -> io::Result<()> {
+        if !valid_series_name(name) {
+            return Err(invalid_name(name));
+        }
+        if interval == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Rule interval must be positive"));
+        }
+        if self.rules.iter().any(|r| r.name == name) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      format!("There is already a rule for '{}'.", name)));
+        }
+
+        self.rules.push(Rule{
+            name: name.to_string(),
+            interval: interval,
+            expr: try!(expr::parse(expr)),
+            next: None,
+        });
+        return rules::save(&*self.options.env, self.root.join("rules"), &self.rules);
+    }
+
+    pub fn remove_rule(&mut self, name: &str) -> io::Result<()> {
+        let count = self.rules.len();
+        self.rules.retain(|r| r.name != name);
+        if self.rules.len() == count {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("There is no rule for '{}'.", name)));
+        }
+        return rules::save(&*self.options.env, self.root.join("rules"), &self.rules);
+    }
+
+    // Evaluates each rule at every multiple of its interval up to 'now' that
+    // it's due at, over the preceding interval, so rules catch up on any
+    // evaluations missed while the catalog was closed. A new rule is first
+    // evaluated at the latest multiple of its interval. Missing results
+    // aren't recorded. Returns the number of points written.
+    //
+    // Points hold unsigned integers, so a result that is negative, fractional
+    // or too large is an InvalidData error rather than being rounded or
+    // dropped; the failing rule stays due at that time. Rules computing
+    // ratios should scale them explicitly, e.g. "sum(errors) / sum(requests)
+    // * 100". Results within floating point error of an integer are rounded.
+    //
+    // Evaluating a rule twice at the same time rewrites the same point, so
+    // a crash before the rules' progress is saved can't cause duplicates.
+    pub fn run_rules(&mut self, now: u64) -> io::Result<usize> {
+        let mut written = 0;
+        for i in 0..self.rules.len() {
+            let rule = self.rules[i].clone();
+            let mut t = rule.next.unwrap_or(now - now % rule.interval);
+            while t <= now {
+                let value = try!(rule.expr.evaluate(self, t.saturating_sub(rule.interval), t));
+                if let Some(v) = value {
+                    let v = try!(representable(&rule.name, t, v));
+                    try!(self.record(&rule.name, &format::Rec{timestamp: t, value: v}));
+                    written += 1;
+                }
+                t += rule.interval;
+            }
+            self.rules[i].next = Some(t);
+            try!(rules::save(&*self.options.env, self.root.join("rules"), &self.rules));
+        }
+        return Ok(written);
+    }
+}
+
+// Converts a rule's result to a point value, if it's an integer that fits.
+fn representable(name: &str, t: u64, v: f64) -> io::Result<u64> {
+    let rounded = v.round();
+    let integral = (v - rounded).abs() <= 1e-9 * rounded.max(1.0);
+    if !v.is_finite() || rounded < 0.0 || rounded >= u64::MAX as f64 || !integral {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Rule '{}' evaluated to {} at {}, which isn't a non-negative integer.", name, v, t)));
+    }
+    return Ok(rounded as u64);
+}
+
+#[cfg(test)]
+mod test {
+    use db;
+    use env::MemEnv;
+    use format;
+    use std::io;
+    use std::sync::Arc;
+
+    use super::Catalog;
+    use super::decode_name;
+    use super::encode_name;
+
+    fn mem_options() -> db::Options {
+        return db::Options{env: Arc::new(MemEnv::new()), ..db::Options::default()};
+    }
+
+    #[test]
+    fn series() {
+        let options = mem_options();
+        {
+            let mut catalog = Catalog::open("/catalog", options.clone()).unwrap();
+            catalog.record("cpu,host=a", &format::Rec{timestamp: 1, value: 2}).unwrap();
+            catalog.record("mem", &format::Rec{timestamp: 1, value: 3}).unwrap();
+            assert!(catalog.get("disk").unwrap().is_none());
+            assert_eq!(io::ErrorKind::InvalidInput,
+                       catalog.series("a b").err().unwrap().kind());
+        }
+
+        let mut catalog = Catalog::open("/catalog", options).unwrap();
+        assert_eq!(vec!["cpu,host=a".to_string(), "mem".to_string()],
+                   catalog.series_names().unwrap());
+        assert_eq!(2, catalog.get("cpu,host=a").unwrap().unwrap().lookup(1).unwrap());
+
+        assert_eq!("cpu%2Chost%3Da", encode_name("cpu,host=a"));
+        assert_eq!(Some("cpu,host=a".to_string()), decode_name("cpu%2Chost%3Da"));
+    }
+
+    #[test]
+    fn recording_rules() {
+        let options = mem_options();
+        {
+            let mut catalog = Catalog::open("/catalog", options.clone()).unwrap();
+            for ts in 0..100 {
+                catalog.record("requests", &format::Rec{timestamp: ts, value: 10}).unwrap();
+                catalog.record("errors", &format::Rec{timestamp: ts, value: ts / 50}).unwrap();
+            }
+
+            catalog.add_rule("error_percent", 10, "sum(errors) / sum(requests) * 100").unwrap();
+            assert_eq!(io::ErrorKind::AlreadyExists,
+                       catalog.add_rule("error_percent", 10, "1").unwrap_err().kind());
+            let err = catalog.add_rule("bad", 10, "sum(errors) /").unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+            assert_eq!("Expected a number, series or function but found the end of the query at column 14",
+                       err.to_string());
+
+            // A new rule starts at the latest multiple of its interval.
+            assert_eq!(1, catalog.run_rules(35).unwrap());
+            assert_eq!(3, catalog.run_rules(60).unwrap());
+            assert_eq!(0, catalog.run_rules(69).unwrap());
+        }
+
+        // After a restart, the rule picks up where it left off.
+        let mut catalog = Catalog::open("/catalog", options).unwrap();
+        assert_eq!(Some(70), catalog.rules()[0].next);
+        assert_eq!(2, catalog.run_rules(85).unwrap());
+
+        let points : Vec<(u64, u64)> = catalog.series("error_percent").unwrap()
+            .scan(0, 1000).unwrap().collect();
+        assert_eq!(vec![(30, 0), (40, 0), (50, 0), (60, 10), (70, 10), (80, 10)], points);
+
+        // Results that points can't hold are errors, not rounded or dropped.
+        catalog.add_rule("error_ratio", 10, "sum(errors) / sum(requests)").unwrap();
+        let err = catalog.run_rules(95).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert_eq!("Rule 'error_ratio' evaluated to 0.1 at 90, which isn't a non-negative integer.",
+                   err.to_string());
+        assert_eq!(None, catalog.rules()[1].next);
+        catalog.remove_rule("error_ratio").unwrap();
+        catalog.add_rule("negative", 10, "0 - max(errors)").unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, catalog.run_rules(95).unwrap_err().kind());
+        catalog.remove_rule("negative").unwrap();
+
+        catalog.remove_rule("error_percent").unwrap();
+        assert!(catalog.rules().is_empty());
+    }
+}
@@ -0,0 +1,76 @@
+// CRC32C (Castagnoli), as used by iSCSI, ext4 and LevelDB.
+const POLY : u32 = 0x82F63B78;
+
+const TABLE : [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            if crc & 1 == 1 {
+                crc = (crc >> 1) ^ POLY;
+            } else {
+                crc = crc >> 1;
+            }
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    return table;
+}
+
+pub struct Crc32c {
+    crc: u32,
+}
+
+impl Default for Crc32c {
+    fn default() -> Crc32c {
+        return Crc32c::new();
+    }
+}
+
+impl Crc32c {
+    pub fn new() -> Crc32c {
+        return Crc32c{
+            crc: !0,
+        };
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for b in data {
+            self.crc = TABLE[((self.crc as u8) ^ *b) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        return !self.crc;
+    }
+}
+
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = Crc32c::new();
+    crc.update(data);
+    return crc.finish();
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn known_values() {
+        assert_eq!(0, super::crc32c(&[]));
+        assert_eq!(0xE3069283, super::crc32c(b"123456789"));
+        assert_eq!(0x8A9136AA, super::crc32c(&[0; 32]));
+    }
+
+    #[test]
+    fn incremental() {
+        let mut crc = super::Crc32c::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(super::crc32c(b"123456789"), crc.finish());
+    }
+}
@@ -0,0 +1,187 @@
+#[cfg(feature = "lz4")]
+extern crate lz4_flex;
+#[cfg(feature = "snappy")]
+extern crate snap;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+
+use std::io;
+
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL : i32 = 3;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Compression {
+    None,
+    Lz4,
+    Zstd,
+    Snappy,
+}
+
+impl Compression {
+    pub fn id(&self) -> u64 {
+        return match *self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+            Compression::Snappy => 3,
+        };
+    }
+
+    pub fn from_id(id: u64) -> io::Result<Compression> {
+        return match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            3 => Ok(Compression::Snappy),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown compression codec {}", id))),
+        };
+    }
+}
+
+#[cfg(not(all(feature = "lz4", feature = "zstd", feature = "snappy")))]
+fn unsupported(codec: Compression) -> io::Error {
+    return io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{:?} compression support was not compiled in", codec));
+}
+
+pub fn compress(codec: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+    match codec {
+        Compression::None => return Ok(data.to_vec()),
+        Compression::Lz4 => return compress_lz4(data),
+        Compression::Zstd => return compress_zstd(data),
+        Compression::Snappy => return compress_snappy(data),
+    }
+}
+
+// 'raw_len' is the expected size of the decompressed data. It's an error for
+// the decompressed block to be any other size.
+pub fn decompress(codec: Compression, data: &[u8], raw_len: usize) -> io::Result<Vec<u8>> {
+    let raw = try!(match codec {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Lz4 => decompress_lz4(data, raw_len),
+        Compression::Zstd => decompress_zstd(data, raw_len),
+        Compression::Snappy => decompress_snappy(data),
+    });
+
+    if raw.len() != raw_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Bad decompressed size. Expected: {}. Got: {}",
+                    raw_len, raw.len())));
+    }
+    return Ok(raw);
+}
+
+#[cfg(feature = "lz4")]
+fn compress_lz4(data: &[u8]) -> io::Result<Vec<u8>> {
+    return Ok(lz4_flex::block::compress(data));
+}
+
+#[cfg(not(feature = "lz4"))]
+fn compress_lz4(_: &[u8]) -> io::Result<Vec<u8>> {
+    return Err(unsupported(Compression::Lz4));
+}
+
+#[cfg(feature = "lz4")]
+fn decompress_lz4(data: &[u8], raw_len: usize) -> io::Result<Vec<u8>> {
+    return lz4_flex::block::decompress(data, raw_len).map_err(
+        |e| io::Error::new(io::ErrorKind::InvalidData, format!("{}", e)));
+}
+
+#[cfg(not(feature = "lz4"))]
+fn decompress_lz4(_: &[u8], _: usize) -> io::Result<Vec<u8>> {
+    return Err(unsupported(Compression::Lz4));
+}
+
+#[cfg(feature = "zstd")]
+fn compress_zstd(data: &[u8]) -> io::Result<Vec<u8>> {
+    return zstd::bulk::compress(data, ZSTD_LEVEL);
+}
+
+#[cfg(not(feature = "zstd"))]
+fn compress_zstd(_: &[u8]) -> io::Result<Vec<u8>> {
+    return Err(unsupported(Compression::Zstd));
+}
+
+#[cfg(feature = "zstd")]
+fn decompress_zstd(data: &[u8], raw_len: usize) -> io::Result<Vec<u8>> {
+    return zstd::bulk::decompress(data, raw_len);
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress_zstd(_: &[u8], _: usize) -> io::Result<Vec<u8>> {
+    return Err(unsupported(Compression::Zstd));
+}
+
+#[cfg(feature = "snappy")]
+fn compress_snappy(data: &[u8]) -> io::Result<Vec<u8>> {
+    return snap::raw::Encoder::new().compress_vec(data).map_err(
+        |e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}", e)));
+}
+
+#[cfg(not(feature = "snappy"))]
+fn compress_snappy(_: &[u8]) -> io::Result<Vec<u8>> {
+    return Err(unsupported(Compression::Snappy));
+}
+
+#[cfg(feature = "snappy")]
+fn decompress_snappy(data: &[u8]) -> io::Result<Vec<u8>> {
+    return snap::raw::Decoder::new().decompress_vec(data).map_err(
+        |e| io::Error::new(io::ErrorKind::InvalidData, format!("{}", e)));
+}
+
+#[cfg(not(feature = "snappy"))]
+fn decompress_snappy(_: &[u8]) -> io::Result<Vec<u8>> {
+    return Err(unsupported(Compression::Snappy));
+}
+
+#[cfg(test)]
+mod test {
+    use super::Compression;
+
+    fn round_trip(codec: Compression) {
+        let mut data = Vec::new();
+        for i in 0..4096 {
+            data.push((i % 7) as u8);
+        }
+
+        let compressed = super::compress(codec, &data).unwrap();
+        assert_eq!(data, super::decompress(codec, &compressed, data.len()).unwrap());
+    }
+
+    #[test]
+    fn codec_ids() {
+        for codec in &[Compression::None, Compression::Lz4,
+                       Compression::Zstd, Compression::Snappy] {
+            assert_eq!(*codec, Compression::from_id(codec.id()).unwrap());
+        }
+        assert!(Compression::from_id(99).is_err());
+    }
+
+    #[test]
+    fn no_compression() {
+        round_trip(Compression::None);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4() {
+        round_trip(Compression::Lz4);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd() {
+        round_trip(Compression::Zstd);
+    }
+
+    #[cfg(feature = "snappy")]
+    #[test]
+    fn snappy() {
+        round_trip(Compression::Snappy);
+    }
+}
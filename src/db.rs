@@ -1,66 +1,831 @@
+use aggregate::Aggregator;
+use aggregate::Bucket;
+use aggregate::Summary;
+use aggregate::Variance;
+use async_io::ThreadPoolDevice;
+use block_cache::BlockCache;
+use block_storage::Device;
+use device_fs::DeviceEnv;
+use device_fs::DeviceFs;
+use env;
+use env::Env;
+use env::PosixEnv;
 use filemanager;
 use format;
+use log::FileLogReader;
+use log::FileLogWriter;
 use memtable;
+use merge;
+use rollup;
+use rollup::Rollup;
+use rollup::RollupBuilder;
+use sketch::Sketch;
 use table;
+use table_cache;
+use window::WindowFunction;
 
 use std::path;
 use std::io;
+use std::mem;
+use std::sync::Arc;
+
+pub const DEVICE_MAX_OPEN_TABLES : usize = 16;
 
 pub struct Db {
     filemanager: Box<filemanager::FileManager>,
     memtable: Box<memtable::MemTable>,
+    tables: table_cache::TableCache,
+    options: Options,
+    // One per tier in options.rollups. Shared with running aggregations.
+    rollups: Vec<Arc<Rollup>>,
+    stats: Stats,
+}
+
+#[derive(Clone)]
+pub struct Options {
+    // Where files are kept: the local filesystem, by default.
+    pub env: Arc<dyn Env>,
+    // If set, each record is synced to the log before Db::record returns,
+    // so that it survives a crash. Otherwise only Db::sync makes records
+    // durable.
+    pub sync_writes: bool,
+    pub table: table::TableOptions,
+    pub read: table::ReadOptions,
+    // Decoded table blocks. Defaults to a cache shared by the whole process;
+    // None disables caching.
+    pub block_cache: Option<Arc<BlockCache>>,
+    // How many blocks of each table a scan reads ahead on a background
+    // thread, so that MergingIterator::poll_ready can say when the next
+    // point is ready. 0 reads blocks as they're needed.
+    pub read_ahead: usize,
+    // How many tables to keep open between queries.
+    pub max_open_tables: usize,
+    // Timestamps are in units of 1/ticks_per_second seconds. Only used to
+    // report rates per second.
+    pub ticks_per_second: u64,
+    // Coarser summaries of the points, rebuilt when tables are compacted and
+    // used by aggregations whose buckets they fit.
+    pub rollups: Vec<rollup::Tier>,
+    // How far behind the newest point raw points are kept; older points are
+    // dropped when tables are compacted. None keeps them forever.
+    pub raw_retention: Option<u64>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        return Options{
+            env: PosixEnv::shared(),
+            sync_writes: false,
+            table: table::TableOptions::default(),
+            read: table::ReadOptions::default(),
+            block_cache: Some(BlockCache::shared()),
+            read_ahead: 0,
+            max_open_tables: 1000,
+            ticks_per_second: 1,
+            rollups: Vec::new(),
+            raw_retention: None,
+        };
+    }
+}
+
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct Stats {
+    // Tables whose bloom filter was consulted by a point lookup.
+    pub bloom_checks: u64,
+    // Checks where the filter ruled the table out.
+    pub bloom_negatives: u64,
+    // Checks where the filter said 'maybe' but the table didn't have the key.
+    pub bloom_false_positives: u64,
+}
+
+impl Stats {
+    pub fn bloom_false_positive_rate(&self) -> f64 {
+        let absent = self.bloom_negatives + self.bloom_false_positives;
+        if absent == 0 {
+            return 0.0;
+        }
+        return self.bloom_false_positives as f64 / absent as f64;
+    }
+}
+
+// How a point lookup treats timestamps that don't have a sample.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Lookup {
+    // Only a sample at exactly the requested timestamp.
+    Exact,
+    // The latest sample at or before the timestamp.
+    Floor,
+    // The earliest sample at or after the timestamp.
+    Ceiling,
+    // The closest sample no further than this from the timestamp. Ties go to
+    // the earlier sample.
+    Nearest(u64),
+    // The value of the latest sample at or before the timestamp, held until
+    // the next sample.
+    Step,
+    // The value on the line between the samples either side of the
+    // timestamp, rounded to the nearest integer. Halves are rounded away
+    // from the earlier sample's value, so 15.5 becomes 16 on the way up
+    // from 10 but 15 on the way down from 20.
+    Linear,
+}
+
+// The value at 'ts' on the line through (t0, v0) and (t1, v1), where
+// t0 < ts < t1.
+fn interpolate(t0: u64, v0: u64, t1: u64, v1: u64, ts: u64) -> u64 {
+    let dv = v1 as i128 - v0 as i128;
+    let dt = (t1 - t0) as i128;
+    match dv.checked_mul((ts - t0) as i128) {
+        Some(offset) => {
+            // Round halves away from v0.
+            let delta = (offset.abs() * 2 + dt) / (2 * dt) * offset.signum();
+            return (v0 as i128 + delta) as u64;
+        },
+        None => {
+            // f64::round also rounds halves away from zero, i.e. from v0,
+            // but isn't exact, so it can overshoot the samples.
+            let fraction = (ts - t0) as f64 / dt as f64;
+            let value = v0 as i128 + (dv as f64 * fraction).round() as i128;
+            return value.clamp(v0.min(v1) as i128, v0.max(v1) as i128) as u64;
+        },
+    }
+}
+
+// Writes a table under a temporary name, and renames it into place once
+// it's durable, so that a crash never leaves part of a table behind. The
+// rename is durable too when this returns.
+fn write_table<I: Iterator<Item=(u64, u64)>>(env: &dyn Env, filename: &str, data: I, options: &table::TableOptions) -> io::Result<()> {
+    let tmp = format!("{}.tmp", filename);
+    try!(table::TableBuilder::write_records_with_env(env, &tmp, data, options));
+    try!(env.rename(path::Path::new(&tmp), path::Path::new(filename)));
+    return env::sync_parent(env, path::Path::new(filename));
+}
+
+// Deletes the logs before the latest one. Only call this once the latest
+// is durable: until then, reopening replays the one before it. A deletion
+// that a crash undoes is harmless, since only the latest log is replayed.
+fn delete_obsolete_logs(fm: &mut filemanager::FileManager) -> io::Result<()> {
+    for log in fm.obsolete_logs() {
+        try!(fm.delete_log(&log));
+    }
+    return Ok(());
 }
 
 // TODO(mrjones): concurrency
 impl Db {
     pub fn new<P: AsRef<path::Path>>(directory: P) -> io::Result<Db> {
-        let mut fm = Box::new(try!(
-            filemanager::FileManager::open_or_create(directory)));
-
-        match fm.latest_log() {
-            Some(filename) => {
-                let data = try!(memtable::MemTable::replay(filename));
-                println!("Compacting recovered log: {:?}", data);
-
-                
-                try!(table::TableBuilder::write(
-                    fm.new_table_file(), data.iter()));
-                // Delete obsolete log?
-            },
-            None => (),
+        return Db::with_options(directory, Options::default());
+    }
+
+    pub fn with_options<P: AsRef<path::Path>>(directory: P, options: Options) -> io::Result<Db> {
+        let env = options.env.clone();
+        let mut fm = Box::new(try!(filemanager::FileManager::open_or_create_with_env(
+            env.clone(), directory)));
+
+        if let Some(filename) = fm.latest_log() {
+            let data = try!(memtable::MemTable::replay_log(
+                &mut try!(FileLogReader::create_with_env(&*env, filename, 16))));
+            if !data.is_empty() {
+                let table_file = fm.new_table_file();
+                try!(write_table(
+                    &*env, &table_file, data.iter().map(|(k, v)| (*k, *v)), &options.table));
+            }
+        }
+
+        let mut rollups = Vec::new();
+        for tier in &options.rollups {
+            if tier.resolution == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Rollup resolution must be positive"));
+            }
+            rollups.push(Arc::new(match Rollup::read_with_env(&*env, fm.rollup_path(tier.resolution)) {
+                Ok(rollup) => rollup,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Rollup::empty(tier.resolution),
+                Err(e) => return Err(e),
+            }));
         }
 
         let log_file_name = fm.new_log_file();
+        let logger = try!(FileLogWriter::create_with_env(&*env, &log_file_name, 16));
+        // Otherwise a crash could lose the log along with records it synced.
+        try!(env::sync_parent(&*env, path::Path::new(&log_file_name)));
+        try!(delete_obsolete_logs(&mut fm));
+        let tables = table_cache::TableCache::with_env(
+            env, options.read, options.block_cache.clone(), options.max_open_tables);
         
         return Ok(Db{
             filemanager: fm,
-            memtable: Box::new(try!(memtable::MemTable::create(log_file_name))),
+            memtable: Box::new(memtable::MemTable::with_logger(Box::new(logger))),
+            tables: tables,
+            options: options,
+            rollups: rollups,
+            stats: Stats::default(),
         });
     }
 
+    // Keeps the whole database on 'device' rather than in a directory,
+    // formatting the device if it has never been used. Tables on a device
+    // are held in memory while they're open, so at most
+    // DEVICE_MAX_OPEN_TABLES of them are kept open.
+    pub fn with_device(device: Box<dyn Device + Send>, mut options: Options) -> io::Result<Db> {
+        let fs = try!(DeviceFs::open_or_format(device));
+        options.env = Arc::new(DeviceEnv::new(fs));
+        if options.max_open_tables > DEVICE_MAX_OPEN_TABLES {
+            options.max_open_tables = DEVICE_MAX_OPEN_TABLES;
+        }
+        return Db::with_options("/", options);
+    }
+
+    // Records a point, replacing any other at the same timestamp. The
+    // largest value, format::TOMBSTONE, is reserved.
     pub fn record(&mut self, rec: &format::Rec) -> io::Result<()> {
+        return self.record_batch(::std::slice::from_ref(rec));
+    }
+
+    // Records several points, in order, syncing once at the end if
+    // sync_writes is set. Nothing is recorded if any point has the reserved
+    // value, but an I/O error can leave some of the points recorded.
+    pub fn record_batch(&mut self, recs: &[format::Rec]) -> io::Result<()> {
+        if recs.iter().any(|rec| rec.value == format::TOMBSTONE) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "The largest value is reserved for deletions"));
+        }
+        for rec in recs {
+            try!(self.memtable.record(rec.timestamp, rec.value));
+        }
+        if self.options.sync_writes {
+            try!(self.memtable.sync());
+        }
+        return Ok(());
+    }
+
+    // Deletes the point at 'ts', if there is one. Older tables keep their
+    // copy until the next compaction, but a tombstone hides it.
+    pub fn delete(&mut self, ts: u64) -> io::Result<()> {
+        return self.append(ts, format::TOMBSTONE);
+    }
+
+    fn append(&mut self, ts: u64, value: u64) -> io::Result<()> {
         // TODO(mrjones): periodically compact the log
         // TODO(mrjones): periodically merge tables
-        return self.memtable.record(rec.timestamp, rec.value);
+        try!(self.memtable.record(ts, value));
+        if self.options.sync_writes {
+            try!(self.memtable.sync());
+        }
+        return Ok(());
+    }
+
+    // Writes the memtable out as a new table and starts a new log, so that
+    // reopening doesn't have to replay it.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+
+        let table_file = self.filemanager.new_table_file();
+        let written = write_table(&*self.options.env, &table_file,
+                                  self.memtable.iter().map(|(k, v)| (*k, *v)), &self.options.table);
+        if written.is_err() {
+            let _ = self.remove_table(&table_file);
+            return written;
+        }
+
+        let log_file_name = self.filemanager.new_log_file();
+        let logger = try!(FileLogWriter::create_with_env(&*self.options.env, &log_file_name, 16));
+        try!(env::sync_parent(&*self.options.env, path::Path::new(&log_file_name)));
+        *self.memtable = memtable::MemTable::with_logger(Box::new(logger));
+        return delete_obsolete_logs(&mut self.filemanager);
+    }
+
+    // Makes every record so far durable.
+    pub fn sync(&mut self) -> io::Result<()> {
+        return self.memtable.sync();
     }
 
     pub fn lookup(&mut self, ts: u64) -> io::Result<u64> {
         match self.memtable.lookup(ts) {
+            Some(&format::TOMBSTONE) => return Err(io::Error::new(io::ErrorKind::NotFound, "No Matching TS")),
             Some(v) => return Ok(*v),
             None => (),
         }
 
         // TODO(mrjones): binary search the tables
-        for filename in self.filemanager.table_paths() {
-            for (k, v) in try!(table::TableIterator::new(filename)) {
-                if k == ts {
-                    return Ok(v);
+        for filename in self.filemanager.table_paths().iter().rev() {
+            let reader = try!(self.tables.get(filename));
+            if reader.has_filter() {
+                self.stats.bloom_checks += 1;
+                if !reader.may_contain(ts) {
+                    self.stats.bloom_negatives += 1;
+                    continue;
                 }
             }
+
+            match try!(reader.get(ts)) {
+                Some(format::TOMBSTONE) => break,
+                Some(v) => return Ok(v),
+                None => (),
+            }
+
+            if reader.has_filter() {
+                self.stats.bloom_false_positives += 1;
+            }
         }
 
         return Err(io::Error::new(io::ErrorKind::NotFound, "No Matching TS"));
     }
+
+    // Looks up the value at 'ts' according to 'mode', returning the
+    // timestamp of the sample that was used along with its value. Modes that
+    // interpolate return 'ts' itself.
+    pub fn lookup_with_mode(&mut self, ts: u64, mode: Lookup) -> io::Result<(u64, u64)> {
+        let found = match mode {
+            Lookup::Exact => return self.lookup(ts).map(|v| (ts, v)),
+            Lookup::Floor => try!(self.floor(ts)),
+            Lookup::Ceiling => try!(self.ceiling(ts)),
+            Lookup::Nearest(tolerance) => {
+                let before = try!(self.floor(ts)).filter(|&(k, _)| ts - k <= tolerance);
+                let after = match before {
+                    Some((k, _)) if k == ts => None,
+                    _ => try!(self.ceiling(ts)).filter(|&(k, _)| k - ts <= tolerance),
+                };
+                match (before, after) {
+                    (Some(b), Some(a)) => if a.0 - ts < ts - b.0 { Some(a) } else { Some(b) },
+                    (b, a) => b.or(a),
+                }
+            },
+            Lookup::Step => try!(self.floor(ts)).map(|(_, v)| (ts, v)),
+            Lookup::Linear => match try!(self.floor(ts)) {
+                Some((k, v)) if k == ts => Some((k, v)),
+                Some((t0, v0)) => try!(self.ceiling(ts))
+                    .map(|(t1, v1)| (ts, interpolate(t0, v0, t1, v1, ts))),
+                None => None,
+            },
+        };
+
+        return found.ok_or(io::Error::new(io::ErrorKind::NotFound, "No Matching TS"));
+    }
+
+    // Summarizes all points with timestamps in [start, end). Table blocks that
+    // fall entirely inside the range are answered from the summaries in the
+    // table index; only blocks straddling the edges are read.
+    pub fn summarize(&mut self, start: u64, end: u64) -> io::Result<Summary> {
+        let mut tables = Vec::new();
+        for filename in self.filemanager.table_paths() {
+            tables.push(try!(self.tables.get(&filename)));
+        }
+
+        // The extent covers tombstones too, as they shadow older points.
+        let mut memtable_extent = Summary::empty();
+        let mut memtable_summary = Summary::empty();
+        for (k, v) in self.memtable.range(start, end) {
+            memtable_extent.add(*k, *v);
+            if *v != format::TOMBSTONE {
+                memtable_summary.add(*k, *v);
+            }
+        }
+
+        // Summaries can't account for the same timestamp appearing in more
+        // than one place, so if sources overlap, merge them point by point.
+        let mut extents = vec!(memtable_extent);
+        for t in &tables {
+            let mut extent = Summary::empty();
+            for handle in &t.index()[t.blocks_in(start, end)] {
+                extent.merge(&handle.summary);
+            }
+            extents.push(extent);
+        }
+        for i in 0..extents.len() {
+            for j in (i + 1)..extents.len() {
+                if extents[i].overlaps(extents[j].first_ts, extents[j].last_ts.saturating_add(1)) {
+                    return self.summarize_merged(start, end);
+                }
+            }
+        }
+
+        let mut summary = memtable_summary;
+        for t in &tables {
+            let index = t.index();
+            for i in t.blocks_in(start, end) {
+                let block = &index[i].summary;
+                // Tombstones are the largest value, so only blocks without
+                // any can be summarized from the index.
+                if block.covered_by(start, end) && block.max != format::TOMBSTONE {
+                    summary.merge(block);
+                } else if block.overlaps(start, end) {
+                    let records = try!(t.block(i));
+                    for j in records.lower_bound(start)..records.len() {
+                        let (k, v) = records.get(j);
+                        if k >= end {
+                            break;
+                        }
+                        if v != format::TOMBSTONE {
+                            summary.add(k, v);
+                        }
+                    }
+                }
+            }
+        }
+
+        return Ok(summary);
+    }
+
+    fn summarize_merged(&mut self, start: u64, end: u64) -> io::Result<Summary> {
+        let mut summary = Summary::empty();
+        let mut merged = try!(self.scan(start, end));
+        for (k, v) in &mut merged {
+            summary.add(k, v);
+        }
+        try!(merged.take_status());
+        return Ok(summary);
+    }
+
+    // Streams 'aggregator' over buckets of 'width' starting at 'start', up
+    // to 'end'. Only buckets holding at least one point are returned.
+    pub fn aggregate(&mut self, start: u64, end: u64, width: u64, aggregator: Aggregator) -> io::Result<Aggregation<'_>> {
+        if width == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Bucket width must be positive"));
+        }
+
+        // Use the coarsest rollup tier whose buckets fit evenly into ours.
+        let mut rollup : Option<Arc<Rollup>> = None;
+        if aggregator.uses_summary() {
+            for r in &self.rollups {
+                if start.is_multiple_of(r.resolution) && width.is_multiple_of(r.resolution) &&
+                    rollup.as_ref().map(|best| r.resolution > best.resolution).unwrap_or(true) {
+                    rollup = Some(r.clone());
+                }
+            }
+        }
+        // A rollup bucket can't answer for part of itself, so one that ends
+        // past 'end' is left to the raw points.
+        let mut rolled_end = rollup.as_ref()
+            .map(|r| r.covered_end.min(end - end % r.resolution).max(start))
+            .unwrap_or(start);
+        if try!(self.written_since_rollups(start, rolled_end)) {
+            rollup = None;
+            rolled_end = start;
+        }
+
+        return Ok(Aggregation{
+            points: try!(self.scan(rolled_end, end)),
+            next_point: None,
+            rollup: rollup,
+            rolled_end: rolled_end,
+            next_start: start,
+            end: end,
+            width: width,
+            aggregator: aggregator,
+            status: Ok(()),
+        });
+    }
+
+    // Whether anything in [start, end) has been recorded or deleted since the
+    // rollups were built. If so, they may count points that are gone or have
+    // changed, and until the next compaction the raw points are the only
+    // answer.
+    fn written_since_rollups(&mut self, start: u64, end: u64) -> io::Result<bool> {
+        if start >= end {
+            return Ok(false);
+        }
+        if self.memtable.ceiling(start).map(|(k, _)| k < end).unwrap_or(false) {
+            return Ok(true);
+        }
+        // Rollups are built by compactions, whose output is older than every
+        // table written since.
+        for filename in self.filemanager.table_paths().iter().skip(1) {
+            let reader = try!(self.tables.get(filename));
+            if try!(reader.ceiling(start)).map(|(k, _)| k < end).unwrap_or(false) {
+                return Ok(true);
+            }
+        }
+        return Ok(false);
+    }
+
+    // A quantile sketch of the points in [start, end).
+    pub fn sketch(&mut self, start: u64, end: u64) -> io::Result<Sketch> {
+        let mut sketch = Sketch::default();
+        let mut merged = try!(self.scan(start, end));
+        for (_, v) in &mut merged {
+            sketch.add(v);
+        }
+        try!(merged.take_status());
+        return Ok(sketch);
+    }
+
+    // Evaluates 'function' over the points in [start, end), or None if there
+    // are too few points.
+    pub fn evaluate_window(&mut self, function: WindowFunction, start: u64, end: u64) -> io::Result<Option<f64>> {
+        let mut points = Vec::new();
+        if function == WindowFunction::Irate {
+            // Only the last two points matter.
+            let mut before = end;
+            while points.len() < 2 {
+                match try!(self.last_before(before)) {
+                    Some((k, v)) if k >= start => {
+                        points.insert(0, (k, v));
+                        before = k;
+                    },
+                    _ => break,
+                }
+            }
+        } else {
+            let mut merged = try!(self.scan(start, end));
+            points.extend(&mut merged);
+            try!(merged.take_status());
+        }
+
+        return Ok(function.evaluate(&points, start, end, self.options.ticks_per_second));
+    }
+
+    // Iterates over points with timestamps in [start, end), in either
+    // direction. Newer tables shadow older ones, and the memtable shadows
+    // them all.
+    pub fn scan<'a>(&'a mut self, start: u64, end: u64) -> io::Result<merge::MergingIterator<'a>> {
+        let mut sources : Vec<Box<dyn merge::Source + 'a>> = Vec::new();
+        for filename in self.filemanager.table_paths() {
+            let reader = try!(self.tables.get(&filename));
+            let range = reader.range(start, end);
+            if self.options.read_ahead == 0 {
+                sources.push(Box::new(range));
+                continue;
+            }
+            let device = try!(ThreadPoolDevice::new(reader.contents(), 1));
+            sources.push(Box::new(range.with_read_ahead(Box::new(device), self.options.read_ahead)));
+        }
+        sources.push(Box::new(merge::Infallible(
+            self.memtable.range(start, end).map(|(k, v)| (*k, *v)))));
+        return Ok(merge::MergingIterator::new(sources));
+    }
+
+    // The most recent point.
+    pub fn last(&mut self) -> io::Result<Option<(u64, u64)>> {
+        return self.floor(u64::MAX);
+    }
+
+    // The most recent point with a timestamp before 'ts'.
+    pub fn last_before(&mut self, ts: u64) -> io::Result<Option<(u64, u64)>> {
+        if ts == 0 {
+            return Ok(None);
+        }
+        return self.floor(ts - 1);
+    }
+
+    // The earliest point with a timestamp after 'ts'.
+    pub fn first_after(&mut self, ts: u64) -> io::Result<Option<(u64, u64)>> {
+        if ts == u64::MAX {
+            return Ok(None);
+        }
+        return self.ceiling(ts + 1);
+    }
+
+    // The point with the largest timestamp <= 'ts'.
+    fn floor(&mut self, ts: u64) -> io::Result<Option<(u64, u64)>> {
+        let mut ts = ts;
+        loop {
+            match try!(self.floor_record(ts)) {
+                Some((0, format::TOMBSTONE)) => return Ok(None),
+                Some((k, format::TOMBSTONE)) => ts = k - 1,
+                found => return Ok(found),
+            }
+        }
+    }
+
+    // The point with the smallest timestamp >= 'ts'.
+    fn ceiling(&mut self, ts: u64) -> io::Result<Option<(u64, u64)>> {
+        let mut ts = ts;
+        loop {
+            match try!(self.ceiling_record(ts)) {
+                Some((k, format::TOMBSTONE)) if k == u64::MAX => return Ok(None),
+                Some((k, format::TOMBSTONE)) => ts = k + 1,
+                found => return Ok(found),
+            }
+        }
+    }
+
+    // The newest record, which may be a tombstone, with the largest
+    // timestamp <= 'ts'. Tables are visited newest first, and any that can't
+    // beat the best record so far (judging by their index) are skipped
+    // without reading a block.
+    fn floor_record(&mut self, ts: u64) -> io::Result<Option<(u64, u64)>> {
+        let mut best = self.memtable.floor(ts);
+        for filename in self.filemanager.table_paths().iter().rev() {
+            let reader = try!(self.tables.get(filename));
+            let bound = match (reader.first_ts(), reader.last_ts()) {
+                (Some(first), Some(last)) if first <= ts => if last < ts { last } else { ts },
+                _ => continue,
+            };
+            if best.map(|(k, _)| bound <= k).unwrap_or(false) {
+                continue;
+            }
+
+            if let Some((k, v)) = try!(reader.floor(ts)) {
+                if best.map(|(bk, _)| k > bk).unwrap_or(true) {
+                    best = Some((k, v));
+                }
+            }
+        }
+        return Ok(best);
+    }
+
+    // The newest record with the smallest timestamp >= 'ts'.
+    fn ceiling_record(&mut self, ts: u64) -> io::Result<Option<(u64, u64)>> {
+        let mut best = self.memtable.ceiling(ts);
+        for filename in self.filemanager.table_paths().iter().rev() {
+            let reader = try!(self.tables.get(filename));
+            let bound = match (reader.first_ts(), reader.last_ts()) {
+                (Some(first), Some(last)) if last >= ts => if first > ts { first } else { ts },
+                _ => continue,
+            };
+            if best.map(|(k, _)| bound >= k).unwrap_or(false) {
+                continue;
+            }
+
+            if let Some((k, v)) = try!(reader.ceiling(ts)) {
+                if best.map(|(bk, _)| k < bk).unwrap_or(true) {
+                    best = Some((k, v));
+                }
+            }
+        }
+        return Ok(best);
+    }
+
+    // Merges all tables into one, dropping points shadowed by newer tables.
+    // Rollup tiers are rebuilt from the merged points, and points that have
+    // outlived the raw retention are dropped.
+    pub fn compact(&mut self) -> io::Result<()> {
+        let inputs = self.filemanager.table_paths();
+        let maintained = !self.options.rollups.is_empty() || self.options.raw_retention.is_some();
+        if inputs.is_empty() || (inputs.len() < 2 && !maintained) {
+            return Ok(());
+        }
+
+        let mut readers = Vec::new();
+        for filename in &inputs {
+            readers.push(try!(self.tables.get(filename)));
+        }
+
+        let newest = readers.iter().filter_map(|r| r.last_ts()).max().unwrap_or(0);
+        let cutoff = self.options.raw_retention.map(|r| newest.saturating_sub(r)).unwrap_or(0);
+        let mut builders : Vec<RollupBuilder> = self.options.rollups.iter().zip(&self.rollups)
+            .map(|(tier, old)| RollupBuilder::new((**old).clone(), *tier))
+            .collect();
+
+        // Don't let a full pass over the data evict everything else.
+        let mut options = self.options.read;
+        options.fill_cache = false;
+
+        let output = self.filemanager.new_table_file();
+        let written = {
+            let sources = readers.iter()
+                .map(|r| Box::new(r.iter_with_options(&options)) as Box<dyn merge::Source>)
+                .collect();
+            let mut merged = merge::MergingIterator::new(sources);
+            let points = (&mut merged)
+                .inspect(|&(k, v)| for b in builders.iter_mut() { b.add(k, v); })
+                .filter(|&(k, _)| k >= cutoff);
+            write_table(&*self.options.env, &output, points, &self.options.table)
+                .and_then(|_| merged.take_status())
+        };
+        if written.is_err() {
+            let _ = self.remove_table(&output);
+            return written;
+        }
+
+        // Until the inputs are removed, the rollups may overlap the raw
+        // points they were built from; that's fine, as a rebuild only keeps
+        // old buckets from before the first raw point.
+        let rollups : Vec<Rollup> = builders.into_iter().map(|b| b.finish(newest, cutoff)).collect();
+        for rollup in &rollups {
+            let written = rollup.write_with_env(
+                &*self.options.env, self.filemanager.rollup_path(rollup.resolution));
+            if written.is_err() {
+                let _ = self.remove_table(&output);
+                return written;
+            }
+        }
+        self.rollups = rollups.into_iter().map(Arc::new).collect();
+
+        // write_table and write_with_env have synced the output's directory,
+        // so a crash from here on can't lose both it and the inputs.
+        for filename in inputs {
+            try!(self.remove_table(&filename));
+        }
+        return env::sync_parent(&*self.options.env, path::Path::new(&output));
+    }
+
+    fn remove_table(&mut self, filename: &str) -> io::Result<()> {
+        self.tables.evict(filename);
+        return self.filemanager.delete_table(filename);
+    }
+
+    pub fn stats(&self) -> Stats {
+        return self.stats;
+    }
+
+    pub fn options(&self) -> &Options {
+        return &self.options;
+    }
+}
+
+// The buckets of an aggregation, in time order. The points are read in one
+// pass, merged from every table and the memtable, and aggregators that can be
+// computed from summaries use a rollup tier for the buckets it covers.
+pub struct Aggregation<'a> {
+    // The points from 'rolled_end' on.
+    points: merge::MergingIterator<'a>,
+    // The next of 'points', if it's been read but not yet aggregated.
+    next_point: Option<(u64, u64)>,
+    // The rollup tier answering buckets before 'rolled_end', if any.
+    rollup: Option<Arc<Rollup>>,
+    rolled_end: u64,
+    next_start: u64,
+    end: u64,
+    width: u64,
+    aggregator: Aggregator,
+    status: io::Result<()>,
+}
+
+impl<'a> Aggregation<'a> {
+    fn peek(&mut self) -> io::Result<Option<(u64, u64)>> {
+        if self.next_point.is_none() {
+            self.next_point = self.points.next();
+            if self.next_point.is_none() {
+                try!(self.points.take_status());
+            }
+        }
+        return Ok(self.next_point);
+    }
+
+    fn next_bucket(&mut self) -> io::Result<Option<Bucket>> {
+        while self.next_start < self.end {
+            // Skip empty buckets by jumping straight to the one holding the
+            // next point.
+            let raw_next = try!(self.peek()).map(|(ts, _)| ts);
+            let rolled_next = self.rollup.as_ref()
+                .and_then(|r| r.next_bucket(self.next_start))
+                .filter(|ts| *ts < self.rolled_end);
+            let ts = match raw_next.into_iter().chain(rolled_next).min() {
+                Some(ts) if ts < self.end => ts,
+                _ => break,
+            };
+            let start = self.next_start + (ts - self.next_start) / self.width * self.width;
+            let end = start.saturating_add(self.width).min(self.end);
+            self.next_start = end;
+
+            let mut summary = Summary::empty();
+            if let Some(ref rollup) = self.rollup {
+                if start < self.rolled_end {
+                    summary = rollup.summarize(start, end.min(self.rolled_end));
+                }
+            }
+            let mut variance = Variance::default();
+            let mut sketch = Sketch::default();
+            while let Some((k, v)) = try!(self.peek()) {
+                if k >= end {
+                    break;
+                }
+                self.next_point = None;
+                match self.aggregator {
+                    Aggregator::Stddev => variance.add(v),
+                    Aggregator::Quantile(_) => sketch.add(v),
+                    _ => summary.add(k, v),
+                }
+            }
+
+            let value = match self.aggregator {
+                Aggregator::Stddev => variance.stddev(),
+                Aggregator::Quantile(q) => sketch.quantile(q),
+                a => a.apply(&summary),
+            };
+            if let Some(value) = value {
+                return Ok(Some(Bucket{start: start, value: value}));
+            }
+        }
+
+        self.next_start = self.end;
+        return Ok(None);
+    }
+
+    pub fn take_status(&mut self) -> io::Result<()> {
+        return mem::replace(&mut self.status, Ok(()));
+    }
+}
+
+impl<'a> Iterator for Aggregation<'a> {
+    type Item = Bucket;
+
+    fn next(&mut self) -> Option<Bucket> {
+        if self.status.is_err() {
+            return None;
+        }
+
+        match self.next_bucket() {
+            Ok(bucket) => return bucket,
+            Err(e) => {
+                self.status = Err(e);
+                return None;
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -72,7 +837,22 @@ mod test {
     use std::fs;
     use std::io;
     
+    use aggregate::Aggregator;
+    use aggregate::Bucket;
+    use super::DEVICE_MAX_OPEN_TABLES;
     use super::Db;
+    use super::Lookup;
+    use super::Options;
+    use rollup::Tier;
+    use window::WindowFunction;
+
+    use block_cache::BlockCache;
+    use block_storage::FileDevice;
+    use block_storage::FileDeviceOptions;
+    use env;
+    use env::MemEnv;
+    use std::path::Path;
+    use std::sync::Arc;
 
     fn accept_not_found(err: io::Error) -> io::Result<()> {
         if err.kind() == io::ErrorKind::NotFound {
@@ -81,12 +861,14 @@ mod test {
         return Err(err);
     }
 
+    fn in_memory() -> Options {
+        return Options{env: Arc::new(MemEnv::new()), ..Options::default()};
+    }
+
     #[test]
     fn db_test() {
-        fs::remove_dir_all("/tmp/db").or_else(accept_not_found).unwrap();
-
-        let mut db = Db::new("/tmp/db")
-            .expect("Db::new");
+        let mut db = Db::with_options("/db", in_memory())
+            .expect("Db::with_options");
         db.record(&format::Rec{timestamp: 1234567890, value: 257}).unwrap();
         db.record(&format::Rec{timestamp: 1111111111, value: 1}).unwrap();
 
@@ -98,10 +880,11 @@ mod test {
 
     #[test]
     fn recovery() {
-        fs::remove_dir_all("/tmp/db2").or_else(accept_not_found).unwrap();
+        let path = env::temp_path("db-recovery");
+        fs::remove_dir_all(&path).or_else(accept_not_found).unwrap();
 
         {
-            let mut db = Db::new("/tmp/db2").expect("Db::new");
+            let mut db = Db::new(&path).expect("Db::new");
             db.record(&format::Rec{timestamp: 1234567890, value: 257}).unwrap();
             db.record(&format::Rec{timestamp: 1111111111, value: 1}).unwrap();
 
@@ -112,12 +895,544 @@ mod test {
         }
 
         {
-            let mut db = Db::new("/tmp/db2").expect("Db::new");
+            let mut db = Db::new(&path).expect("Db::new");
 
             assert_eq!(257, db.lookup(1234567890).unwrap());
             assert_eq!(1,   db.lookup(1111111111).unwrap());
             assert_eq!(io::ErrorKind::NotFound,
                        db.lookup(2222222222).unwrap_err().kind());
         }
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn bloom_filter_stats() {
+        let mut options = in_memory();
+        options.table.bloom_bits_per_key = Some(10);
+        {
+            let mut db = Db::with_options("/db-bloom", options.clone()).unwrap();
+            for i in 0..100 {
+                db.record(&format::Rec{timestamp: i * 10, value: i}).unwrap();
+            }
+        }
+
+        let mut db = Db::with_options("/db-bloom", options).unwrap();
+        assert_eq!(7, db.lookup(70).unwrap());
+        for i in 0..100 {
+            assert_eq!(io::ErrorKind::NotFound,
+                       db.lookup(i * 10 + 5).unwrap_err().kind());
+        }
+
+        let stats = db.stats();
+        assert_eq!(101, stats.bloom_checks);
+        assert_eq!(100, stats.bloom_negatives + stats.bloom_false_positives);
+        assert!(stats.bloom_false_positive_rate() < 0.1);
+    }
+
+    #[test]
+    fn block_cache() {
+        let cache = Arc::new(BlockCache::new(1024 * 1024));
+        let mut options = in_memory();
+        options.block_cache = Some(cache.clone());
+        {
+            let mut db = Db::with_options("/db-block-cache", options.clone()).unwrap();
+            for i in 0..3 {
+                db.record(&format::Rec{timestamp: i, value: i * 2}).unwrap();
+            }
+        }
+
+        // Lookups at a block's edges are answered from the index, so ask
+        // for a point in the middle of one.
+        let mut db = Db::with_options("/db-block-cache", options).unwrap();
+        for _ in 0..10 {
+            assert_eq!(2, db.lookup(1).unwrap());
+        }
+        assert_eq!(1, cache.stats().misses);
+        assert_eq!(9, cache.stats().hits);
+    }
+
+    #[test]
+    fn compact() {
+        let mut options = in_memory();
+        options.max_open_tables = 1;
+        for session in 0..3 {
+            let mut db = Db::with_options("/db-compact", options.clone()).unwrap();
+            for i in 0..100 {
+                db.record(&format::Rec{timestamp: session * 50 + i, value: session}).unwrap();
+            }
+        }
+
+        let mut db = Db::with_options("/db-compact", options.clone()).unwrap();
+        let before = db.filemanager.table_paths();
+        assert_eq!(3, before.len());
+        // Newer tables shadow older ones, even with only one table open.
+        assert_eq!(0, db.lookup(10).unwrap());
+        assert_eq!(1, db.lookup(60).unwrap());
+        assert_eq!(2, db.lookup(120).unwrap());
+
+        db.compact().unwrap();
+        assert_eq!(1, db.filemanager.table_paths().len());
+        for filename in before {
+            assert!(options.env.open(Path::new(&filename)).is_err());
+        }
+        assert_eq!(0, db.lookup(10).unwrap());
+        assert_eq!(1, db.lookup(60).unwrap());
+        assert_eq!(2, db.lookup(120).unwrap());
+        assert_eq!(200, db.summarize(0, 1000).unwrap().count);
+    }
+
+    #[test]
+    fn deletes() {
+        let options = in_memory();
+        let mut db = Db::with_options("/db-deletes", options.clone()).unwrap();
+        for i in 0..10 {
+            db.record(&format::Rec{timestamp: i, value: i + 100}).unwrap();
+        }
+        db.flush().unwrap();
+        db.delete(3).unwrap();
+        db.delete(4).unwrap();
+        db.delete(20).unwrap();
+        assert_eq!(io::ErrorKind::InvalidInput,
+                   db.record(&format::Rec{timestamp: 5, value: format::TOMBSTONE}).unwrap_err().kind());
+        let batch = [format::Rec{timestamp: 6, value: 1}, format::Rec{timestamp: 7, value: format::TOMBSTONE}];
+        assert_eq!(io::ErrorKind::InvalidInput, db.record_batch(&batch).unwrap_err().kind());
+
+        let check = |db: &mut Db| {
+            assert_eq!(io::ErrorKind::NotFound, db.lookup(3).unwrap_err().kind());
+            assert_eq!(105, db.lookup(5).unwrap());
+            assert_eq!((2, 102), db.lookup_with_mode(4, Lookup::Floor).unwrap());
+            assert_eq!((5, 105), db.lookup_with_mode(3, Lookup::Ceiling).unwrap());
+            let points : Vec<(u64, u64)> = db.scan(2, 6).unwrap().collect();
+            assert_eq!(vec![(2, 102), (5, 105)], points);
+            assert_eq!(8, db.summarize(0, 100).unwrap().count);
+            assert_eq!(Some((9, 109)), db.last().unwrap());
+        };
+        check(&mut db);
+        // Tombstones in a table hide points in older ones.
+        db.flush().unwrap();
+        assert_eq!(2, db.filemanager.table_paths().len());
+        check(&mut db);
+
+        db.delete(9).unwrap();
+        let mut db = Db::with_options("/db-deletes", options.clone()).unwrap();
+        assert_eq!(Some((8, 108)), db.last().unwrap());
+        db.compact().unwrap();
+        assert_eq!(Some((8, 108)), db.last().unwrap());
+        assert_eq!(7, db.summarize(0, 100).unwrap().count);
+        assert_eq!(7, db.tables.get(&db.filemanager.table_paths()[0]).unwrap().num_records());
+    }
+
+    #[test]
+    fn reopening_and_flushing_leave_one_log() {
+        let options = in_memory();
+        let files = |prefix: &str| options.env.list(Path::new("/db-logs")).unwrap().iter()
+            .filter(|f| f.file_name().unwrap().to_str().unwrap().starts_with(prefix)).count();
+        for i in 0..5 {
+            let mut db = Db::with_options("/db-logs", options.clone()).unwrap();
+            db.record(&format::Rec{timestamp: i, value: i}).unwrap();
+            db.flush().unwrap();
+            db.flush().unwrap();
+        }
+        assert_eq!(1, files("log_"));
+        assert_eq!(5, files("table_"));
+
+        // Reopening after a flush has nothing to replay, so adds no table.
+        for _ in 0..3 {
+            drop(Db::with_options("/db-logs", options.clone()).unwrap());
+        }
+        assert_eq!(1, files("log_"));
+        assert_eq!(5, files("table_"));
+
+        let mut db = Db::with_options("/db-logs", options.clone()).unwrap();
+        db.record(&format::Rec{timestamp: 10, value: 10}).unwrap();
+        drop(db);
+        let mut db = Db::with_options("/db-logs", options.clone()).unwrap();
+        assert_eq!(1, files("log_"));
+        assert_eq!(6, files("table_"));
+        assert_eq!(6, db.summarize(0, 100).unwrap().count);
+    }
+
+    #[test]
+    fn scan_with_read_ahead() {
+        let options = Options{read_ahead: 4, ..in_memory()};
+        let mut db = Db::with_options("/db-read-ahead", options).unwrap();
+        for i in 0..20000 {
+            db.record(&format::Rec{timestamp: i * 2, value: i}).unwrap();
+        }
+        db.flush().unwrap();
+        for i in 0..20000 {
+            db.record(&format::Rec{timestamp: i * 2 + 1, value: i}).unwrap();
+        }
+        db.flush().unwrap();
+        for i in 100..30000 {
+            db.delete(i).unwrap();
+        }
+
+        let mut expected : Vec<(u64, u64)> = (0..100).map(|i| (i, i / 2)).collect();
+        expected.extend((30000..40000).map(|i| (i, i / 2)));
+        let mut merged = db.scan(0, 50000).unwrap();
+        let mut points = Vec::new();
+        loop {
+            // Polling skips the deleted points without waiting for reads.
+            while merged.poll_ready().is_pending() {}
+            match merged.next() {
+                Some(point) => points.push(point),
+                None => break,
+            }
+        }
+        merged.take_status().unwrap();
+        assert_eq!(expected, points);
+    }
+
+    #[test]
+    fn scan_and_latest() {
+        let options = in_memory();
+        {
+            let mut db = Db::with_options("/db-scan", options.clone()).unwrap();
+            for i in 0..5000 {
+                db.record(&format::Rec{timestamp: i * 2, value: 1}).unwrap();
+            }
+        }
+        {
+            let mut db = Db::with_options("/db-scan", options.clone()).unwrap();
+            for i in 0..10 {
+                db.record(&format::Rec{timestamp: i * 1000 + 1, value: 2}).unwrap();
+            }
+            db.record(&format::Rec{timestamp: 4000, value: 2}).unwrap();
+        }
+
+        let mut db = Db::with_options("/db-scan", options.clone()).unwrap();
+        db.record(&format::Rec{timestamp: 3000, value: 3}).unwrap();
+
+        let forwards : Vec<(u64, u64)> = db.scan(2998, 4002).unwrap().collect();
+        let mut backwards : Vec<(u64, u64)> = db.scan(2998, 4002).unwrap().rev().collect();
+        backwards.reverse();
+        assert_eq!(forwards, backwards);
+        assert_eq!(vec![(2998, 1), (3000, 3), (3001, 2), (3002, 1)],
+                   forwards[0..4].to_vec());
+        assert_eq!(vec![(3998, 1), (4000, 2), (4001, 2)],
+                   forwards[(forwards.len() - 3)..].to_vec());
+
+        assert_eq!(Some((9998, 1)), db.last().unwrap());
+        assert_eq!(Some((9001, 2)), db.last_before(9002).unwrap());
+        assert_eq!(Some((4000, 2)), db.last_before(4001).unwrap());
+        assert_eq!(Some((3000, 3)), db.last_before(3001).unwrap());
+        assert_eq!(None, db.last_before(0).unwrap());
+        assert_eq!(Some((3000, 3)), db.first_after(2998).unwrap());
+        assert_eq!(Some((1, 2)), db.first_after(0).unwrap());
+        assert_eq!(None, db.first_after(9998).unwrap());
+
+        db.record(&format::Rec{timestamp: 20000, value: 4}).unwrap();
+        assert_eq!(Some((20000, 4)), db.last().unwrap());
+    }
+
+    #[test]
+    fn lookup_modes() {
+        let options = in_memory();
+        {
+            let mut db = Db::with_options("/db-lookup-modes", options.clone()).unwrap();
+            db.record(&format::Rec{timestamp: 100, value: 10}).unwrap();
+            db.record(&format::Rec{timestamp: 110, value: 20}).unwrap();
+        }
+        let mut db = Db::with_options("/db-lookup-modes", options.clone()).unwrap();
+        db.record(&format::Rec{timestamp: 120, value: 5}).unwrap();
+
+        assert_eq!(io::ErrorKind::NotFound,
+                   db.lookup_with_mode(104, Lookup::Exact).unwrap_err().kind());
+        assert_eq!((110, 20), db.lookup_with_mode(110, Lookup::Exact).unwrap());
+        assert_eq!((100, 10), db.lookup_with_mode(104, Lookup::Floor).unwrap());
+        assert_eq!((110, 20), db.lookup_with_mode(104, Lookup::Ceiling).unwrap());
+        assert_eq!((120, 5), db.lookup_with_mode(111, Lookup::Ceiling).unwrap());
+        assert_eq!(io::ErrorKind::NotFound,
+                   db.lookup_with_mode(99, Lookup::Floor).unwrap_err().kind());
+        assert_eq!(io::ErrorKind::NotFound,
+                   db.lookup_with_mode(121, Lookup::Ceiling).unwrap_err().kind());
+
+        assert_eq!((100, 10), db.lookup_with_mode(104, Lookup::Nearest(5)).unwrap());
+        assert_eq!((110, 20), db.lookup_with_mode(106, Lookup::Nearest(5)).unwrap());
+        // Ties go to the earlier sample.
+        assert_eq!((110, 20), db.lookup_with_mode(115, Lookup::Nearest(5)).unwrap());
+        assert_eq!((120, 5), db.lookup_with_mode(123, Lookup::Nearest(5)).unwrap());
+        assert_eq!(io::ErrorKind::NotFound,
+                   db.lookup_with_mode(104, Lookup::Nearest(3)).unwrap_err().kind());
+
+        assert_eq!((104, 10), db.lookup_with_mode(104, Lookup::Step).unwrap());
+        assert_eq!((500, 5), db.lookup_with_mode(500, Lookup::Step).unwrap());
+        assert_eq!(io::ErrorKind::NotFound,
+                   db.lookup_with_mode(99, Lookup::Step).unwrap_err().kind());
+
+        assert_eq!((104, 14), db.lookup_with_mode(104, Lookup::Linear).unwrap());
+        assert_eq!((110, 20), db.lookup_with_mode(110, Lookup::Linear).unwrap());
+        assert_eq!((112, 17), db.lookup_with_mode(112, Lookup::Linear).unwrap());
+        // 15.5, rounded away from the earlier sample.
+        assert_eq!((113, 15), db.lookup_with_mode(113, Lookup::Linear).unwrap());
+        assert_eq!(io::ErrorKind::NotFound,
+                   db.lookup_with_mode(121, Lookup::Linear).unwrap_err().kind());
+    }
+
+    #[test]
+    fn interpolation_rounding() {
+        // 15.5 either way.
+        assert_eq!(16, super::interpolate(0, 10, 2, 21, 1));
+        assert_eq!(15, super::interpolate(0, 20, 2, 11, 1));
+        // Too big to compute exactly.
+        assert!(super::interpolate(0, 0, u64::MAX, u64::MAX, u64::MAX - 1) > u64::MAX - 4096);
+        assert!(super::interpolate(0, u64::MAX, u64::MAX, 0, 1) > u64::MAX - 4096);
+    }
+
+    #[test]
+    fn aggregate() {
+        let options = in_memory();
+        // One point a second for 20 minutes, then a gap, then one more
+        // minute. The values cycle 0..9.
+        {
+            let mut db = Db::with_options("/db-aggregate", options.clone()).unwrap();
+            for i in (0..1200).chain(3000..3060) {
+                db.record(&format::Rec{timestamp: i, value: i % 10}).unwrap();
+            }
+        }
+        let mut db = Db::with_options("/db-aggregate", options.clone()).unwrap();
+        // Newer points shadow older ones.
+        db.record(&format::Rec{timestamp: 60, value: 100}).unwrap();
+
+        let buckets : Vec<Bucket> = db.aggregate(0, 3600, 60, Aggregator::Max).unwrap().collect();
+        assert_eq!(21, buckets.len());
+        assert_eq!(Bucket{start: 0, value: 9.0}, buckets[0]);
+        assert_eq!(Bucket{start: 60, value: 100.0}, buckets[1]);
+        assert_eq!(Bucket{start: 3000, value: 9.0}, buckets[20]);
+
+        let counts : Vec<f64> = db.aggregate(30, 200, 60, Aggregator::Count).unwrap()
+            .map(|b| b.value).collect();
+        assert_eq!(vec![60.0, 60.0, 50.0], counts);
+
+        let avg : Vec<Bucket> = db.aggregate(120, 240, 60, Aggregator::Avg).unwrap().collect();
+        assert_eq!(vec![Bucket{start: 120, value: 4.5}, Bucket{start: 180, value: 4.5}], avg);
+
+        let sum : Vec<f64> = db.aggregate(0, 120, 60, Aggregator::Sum).unwrap()
+            .map(|b| b.value).collect();
+        assert_eq!(vec![270.0, 370.0], sum);
+
+        let first : Vec<f64> = db.aggregate(55, 65, 5, Aggregator::First).unwrap()
+            .map(|b| b.value).collect();
+        let last : Vec<f64> = db.aggregate(55, 65, 5, Aggregator::Last).unwrap()
+            .map(|b| b.value).collect();
+        assert_eq!(vec![5.0, 100.0], first);
+        assert_eq!(vec![9.0, 4.0], last);
+
+        let stddev : Vec<f64> = db.aggregate(0, 20, 10, Aggregator::Stddev).unwrap()
+            .map(|b| b.value).collect();
+        assert_eq!(2, stddev.len());
+        assert!((stddev[0] - 8.25f64.sqrt()).abs() < 1e-9);
+
+        let p90 : Vec<f64> = db.aggregate(0, 120, 60, Aggregator::Quantile(0.9)).unwrap()
+            .map(|b| b.value).collect();
+        assert_eq!(2, p90.len());
+        assert!((p90[0] - 8.0).abs() <= 0.08, "{}", p90[0]);
+        assert!((db.sketch(0, 3600).unwrap().quantile(1.0).unwrap() - 100.0).abs() < 1e-9);
+
+        assert_eq!(io::ErrorKind::InvalidInput,
+                   db.aggregate(0, 10, 0, Aggregator::Sum).err().unwrap().kind());
+    }
+
+    #[test]
+    fn aggregate_reads_each_block_once() {
+        let cache = Arc::new(BlockCache::new(1024 * 1024));
+        let options = Options{block_cache: Some(cache.clone()), ..in_memory()};
+        // Three overlapping tables of 5000 points, a few blocks each.
+        for session in 0..3 {
+            let mut db = Db::with_options("/db-aggregate-once", options.clone()).unwrap();
+            for i in 0..5000 {
+                db.record(&format::Rec{timestamp: session * 1000 + i, value: session}).unwrap();
+            }
+        }
+
+        let mut db = Db::with_options("/db-aggregate-once", options.clone()).unwrap();
+        let buckets : Vec<Bucket> = db.aggregate(0, 7000, 10, Aggregator::Sum).unwrap().collect();
+        assert_eq!(700, buckets.len());
+        assert_eq!(Bucket{start: 6990, value: 20.0}, buckets[699]);
+        let stats = cache.stats();
+        assert!(stats.hits + stats.misses <= 12, "{:?}", stats);
+    }
+
+    #[test]
+    fn window_functions() {
+        let mut options = in_memory();
+        options.ticks_per_second = 1000;
+        {
+            let mut db = Db::with_options("/db-window", options.clone()).unwrap();
+            // A counter going up by 10 a second, reset at 30s.
+            for i in 0..6 {
+                db.record(&format::Rec{timestamp: i * 10000 + 5000, value: (i % 3 + 1) * 100}).unwrap();
+            }
+        }
+        let mut db = Db::with_options("/db-window", options).unwrap();
+        db.record(&format::Rec{timestamp: 60000, value: 310}).unwrap();
+
+        let rate = db.evaluate_window(WindowFunction::Rate, 0, 60000).unwrap().unwrap();
+        assert!((rate - 10.0).abs() < 1e-9, "{}", rate);
+        assert_eq!(Some(2.0), db.evaluate_window(WindowFunction::Irate, 0, 60001).unwrap());
+        assert_eq!(Some(10.0), db.evaluate_window(WindowFunction::Irate, 0, 60000).unwrap());
+        assert_eq!(None, db.evaluate_window(WindowFunction::Irate, 50000, 60000).unwrap());
+        assert_eq!(None, db.evaluate_window(WindowFunction::Increase, 60000, 70000).unwrap());
+    }
+
+    #[test]
+    fn rollups() {
+        let mut options = in_memory();
+        options.rollups = vec![Tier{resolution: 10, retention: Some(1000)},
+                               Tier{resolution: 100, retention: None}];
+        options.raw_retention = Some(500);
+        for session in 0..2 {
+            let mut db = Db::with_options("/db-rollups", options.clone()).unwrap();
+            for i in (session * 1000)..((session + 1) * 1000) {
+                db.record(&format::Rec{timestamp: i, value: 1}).unwrap();
+            }
+        }
+
+        let mut db = Db::with_options("/db-rollups", options.clone()).unwrap();
+        db.compact().unwrap();
+        // Raw points before 1499 have aged out.
+        assert_eq!(Some((1499, 1)), db.first_after(0).unwrap());
+
+        for _ in 0..2 {
+            // The coarse tier keeps everything.
+            let counts : Vec<Bucket> = db.aggregate(0, 2000, 100, Aggregator::Count).unwrap().collect();
+            assert_eq!(20, counts.len());
+            assert!(counts.iter().all(|b| b.value == 100.0));
+
+            // The fine tier only keeps buckets from 990 on.
+            let counts : Vec<Bucket> = db.aggregate(0, 2000, 10, Aggregator::Sum).unwrap().collect();
+            assert_eq!(101, counts.len());
+            assert_eq!(990, counts[0].start);
+            assert!(counts.iter().all(|b| b.value == 10.0));
+
+            // Unaligned buckets can only use raw points.
+            let counts : Vec<Bucket> = db.aggregate(5, 2000, 10, Aggregator::Count).unwrap().collect();
+            assert_eq!(Bucket{start: 1495, value: 6.0}, counts[0]);
+
+            // As can a bucket cut short by an unaligned end.
+            let counts : Vec<Bucket> = db.aggregate(0, 1850, 100, Aggregator::Count).unwrap().collect();
+            assert_eq!(Bucket{start: 1800, value: 50.0}, counts[18]);
+
+            // Rollups survive reopening.
+            db = Db::with_options("/db-rollups", options.clone()).unwrap();
+        }
+    }
+
+    #[test]
+    fn rollups_after_writes() {
+        let mut options = in_memory();
+        options.rollups = vec![Tier{resolution: 100, retention: None}];
+        let mut db = Db::with_options("/db-rollups-writes", options.clone()).unwrap();
+        for i in 0..200 {
+            db.record(&format::Rec{timestamp: i, value: 1}).unwrap();
+        }
+        db.flush().unwrap();
+        db.compact().unwrap();
+        let sum = |db: &mut Db| db.aggregate(0, 100, 100, Aggregator::Sum).unwrap().next().unwrap().value;
+        assert_eq!(100.0, sum(&mut db));
+
+        // Changes since the compaction aren't in the rollup, whether they're
+        // in the memtable or in newer tables.
+        db.delete(5).unwrap();
+        db.record(&format::Rec{timestamp: 6, value: 100}).unwrap();
+        assert_eq!(198, db.summarize(0, 100).unwrap().sum);
+        assert_eq!(198.0, sum(&mut db));
+        db.flush().unwrap();
+        assert_eq!(198.0, sum(&mut db));
+
+        // Until the next compaction folds them in.
+        db.compact().unwrap();
+        assert_eq!(198.0, sum(&mut db));
+        assert_eq!(198, db.rollups[0].summarize(0, 100).sum);
+
+        // Even if they empty the first buckets. (The last holds the newest
+        // point, so it isn't rolled up yet.)
+        for i in 0..100 {
+            db.delete(i).unwrap();
+        }
+        db.flush().unwrap();
+        db.compact().unwrap();
+        assert_eq!(None, db.rollups[0].next_bucket(0));
+        let counts : Vec<Bucket> = db.aggregate(0, 200, 100, Aggregator::Count).unwrap().collect();
+        assert_eq!(vec![Bucket{start: 100, value: 100.0}], counts);
+    }
+
+    #[test]
+    fn summarize() {
+        let options = in_memory();
+        {
+            let mut db = Db::with_options("/db-summarize", options.clone()).unwrap();
+            for i in 0..10000 {
+                db.record(&format::Rec{timestamp: i, value: i % 100}).unwrap();
+            }
+        }
+
+        let mut db = Db::with_options("/db-summarize", options.clone()).unwrap();
+        for i in 10000..10100 {
+            db.record(&format::Rec{timestamp: i, value: 1000}).unwrap();
+        }
+
+        let s = db.summarize(50, 10050).unwrap();
+        assert_eq!(10000, s.count);
+        assert_eq!(1000, s.max);
+        assert_eq!(0, s.min);
+        assert_eq!((50, 50), (s.first_ts, s.first));
+        assert_eq!((10049, 1000), (s.last_ts, s.last));
+        assert_eq!((50..10000).map(|i| (i % 100) as u128).sum::<u128>() + 50 * 1000,
+                   s.sum);
+
+        assert!(db.summarize(20000, 30000).unwrap().is_empty());
+
+        // Overwriting a point in the memtable shadows the table's copy.
+        db.record(&format::Rec{timestamp: 99, value: 5000}).unwrap();
+        let s = db.summarize(0, 100).unwrap();
+        assert_eq!(100, s.count);
+        assert_eq!(5000, s.max);
+    }
+
+    #[test]
+    fn device() {
+        let device_options = FileDeviceOptions{block_size: 4096, direct: false};
+        let path = env::temp_path("db-device");
+        let open = |create: bool| -> Db {
+            let device = if create {
+                FileDevice::create(&path, 4 << 20, &device_options).unwrap()
+            } else {
+                FileDevice::open(&path, &device_options).unwrap()
+            };
+            let options = Options{rollups: vec![Tier{resolution: 100, retention: None}], ..Options::default()};
+            return Db::with_device(Box::new(device), options).unwrap();
+        };
+
+        for session in 0..3 {
+            let mut db = open(session == 0);
+            for i in (session * 1000)..((session + 1) * 1000) {
+                db.record(&format::Rec{timestamp: i, value: i % 10}).unwrap();
+            }
+            assert_eq!(5, db.lookup(session * 1000 + 5).unwrap());
+        }
+
+        let mut db = open(false);
+        assert_eq!(DEVICE_MAX_OPEN_TABLES, db.options.max_open_tables);
+        assert_eq!(3, db.filemanager.table_paths().len());
+        assert_eq!(1999 % 10, db.lookup(1999).unwrap());
+        assert_eq!(3000, db.summarize(0, 3000).unwrap().count);
+
+        db.compact().unwrap();
+        assert_eq!(1, db.filemanager.table_paths().len());
+        let sums : Vec<Bucket> = db.aggregate(0, 3000, 100, Aggregator::Sum).unwrap().collect();
+        assert_eq!(30, sums.len());
+        assert!(sums.iter().all(|b| b.value == 450.0));
+
+        // The compacted table and the rollup are found again, and the empty
+        // log doesn't add a table.
+        let mut db = open(false);
+        assert_eq!(1, db.filemanager.table_paths().len());
+        assert_eq!(7, db.lookup(2997).unwrap());
+        assert_eq!(13500.0, db.aggregate(0, 3000, 3000, Aggregator::Sum).unwrap()
+                   .next().unwrap().value);
+        drop(db);
+        fs::remove_file(&path).unwrap();
     }
 }
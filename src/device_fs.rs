@@ -0,0 +1,1407 @@
+use allocator::BitmapAllocator;
+use allocator::Extent;
+use allocator::Fit;
+use allocator::Fsck;
+use block_storage::Device;
+use checksum;
+use env;
+use env::Env;
+use env::FileContents;
+use env::ReadableFile;
+use env::WritableFile;
+use format;
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io;
+use std::path;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+// A small namespace of files and directories, stored directly on a Device
+// so that a Db can run without a filesystem underneath it. Names are paths
+// relative to the root directory, "", with components separated by '/'.
+//
+// Device layout, in blocks:
+//   [superblock][metadata slot 0][metadata slot 1][bitmap 0][bitmap 1][data...]
+//
+// The superblock is written once, when the device is formatted, and holds
+// (magic, version, block size, block count, blocks per slot, blocks per
+// bitmap, crc32c). Each metadata slot holds a header (magic, generation,
+// namespace length, crc32c) followed by the namespace: for every file, its
+// name, its length in bytes, the extents holding its whole blocks and the
+// block holding its partial last block, if any. Commits alternate between
+// the slots, so a torn commit leaves the previous namespace intact, and
+// mounting uses the valid slot with the highest generation. Directories are
+// stored like empty files, with a '/' after their names.
+//
+// A namespace can't outgrow its slot, SLOT_BYTES long. Each file takes 32
+// bytes, its name and 16 bytes per extent, so a slot holds around a
+// thousand files. A change that would need more is refused with
+// StorageFull before any of its data is written, leaving the files as they
+// were; deleting files makes room again.
+//
+// Nothing a committed namespace refers to is overwritten: new data goes to
+// fresh blocks, or to blocks past the end of a file's whole blocks, and
+// appends write the new partial last block to a fresh block. Free space is
+// tracked by a BitmapAllocator. Before each commit, the data and the bitmap
+// (marking any new blocks in use) are synced, so the namespace can't refer
+// to blocks that didn't make it to the device; blocks the commit stops
+// using are only freed once the next sync has made it durable.
+//
+// Appends don't commit by themselves: their data is written straight away,
+// but the namespace only records it at the next commit, made by sync() or
+// any other change. So a log appended to many times costs one commit per
+// sync, and appends since the last sync may be lost in a crash.
+const SUPERBLOCK_MAGIC : u64 = 0x7274735f73757072;
+const SLOT_MAGIC : u64 = 0x7274735f6e616d65;
+const VERSION : u64 = 3;
+const SUPERBLOCK_SIZE : usize = 56;
+const SLOT_HEADER_SIZE : usize = 32;
+// Room for the namespace (and its header) in each slot.
+const SLOT_BYTES : u64 = 65536;
+const MIN_BLOCK_SIZE : u64 = 64;
+
+#[derive(Clone,Debug)]
+struct Inode {
+    len: u64,
+    // The file's whole blocks. May hold more blocks than 'len' needs, so
+    // that appends don't have to allocate every time.
+    extents: Vec<Extent>,
+    // The block holding the rest of the file, if its length isn't a
+    // multiple of the block size.
+    tail: Option<u64>,
+}
+
+impl Inode {
+    fn blocks(&self) -> u64 {
+        return self.extents.iter().map(|e| e.count).sum();
+    }
+
+    // Every block the file uses.
+    fn owned(&self) -> Vec<Extent> {
+        let mut owned = self.extents.clone();
+        owned.extend(self.tail.map(|t| Extent{start: t, count: 1}));
+        return owned;
+    }
+}
+
+fn corrupt(msg: String) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, msg);
+}
+
+fn not_found(name: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::NotFound, format!("No file '{}'", name));
+}
+
+fn bad_name(name: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidInput, format!("Bad file name '{}'", name));
+}
+
+// The directory holding 'name'.
+fn parent(name: &str) -> &str {
+    return name.rfind('/').map(|i| &name[0..i]).unwrap_or("");
+}
+
+fn blocks_for(len: u64, block_size: u64) -> u64 {
+    return len.div_ceil(block_size);
+}
+
+fn read_exact(device: &mut dyn Device, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    let len = buf.len() as u64;
+    let read = try!(device.read(offset, len, buf));
+    if read as u64 != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("Short read of {} bytes at {}", read, offset)));
+    }
+    return Ok(());
+}
+
+pub struct DeviceFs {
+    device: Box<dyn Device + Send>,
+    block_size: u64,
+    slot_blocks: u64,
+    // Everything before this holds the superblock, metadata and bitmaps.
+    data_start: u64,
+    // Of the last committed namespace.
+    generation: u64,
+    files: BTreeMap<String, Inode>,
+    // Every directory but the root.
+    dirs: BTreeSet<String>,
+    allocator: BitmapAllocator,
+    // Blocks the committed namespace no longer uses, to be freed once it's
+    // durable.
+    unreferenced: Vec<Extent>,
+    // Blocks the namespace in memory no longer uses, but the committed one
+    // might; they join 'unreferenced' at the next commit.
+    uncommitted: Vec<Extent>,
+    // Whether the namespace in memory has changes the committed one lacks.
+    dirty: bool,
+}
+
+impl DeviceFs {
+    // Writes an empty namespace to 'device', discarding anything on it.
+    pub fn format(mut device: Box<dyn Device + Send>) -> io::Result<DeviceFs> {
+        let block_size = device.block_size();
+        if block_size < MIN_BLOCK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Block size {} is below the minimum of {}", block_size, MIN_BLOCK_SIZE)));
+        }
+        let blocks = device.size() / block_size;
+        let slot_blocks = blocks_for(SLOT_BYTES, block_size);
+        let bitmap_blocks = BitmapAllocator::blocks_needed(blocks, block_size);
+        let data_start = 1 + 2 * slot_blocks + 2 * bitmap_blocks;
+        if blocks <= data_start {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("A device of {} blocks is too small", blocks)));
+        }
+
+        let mut superblock = vec![0; block_size as usize];
+        let words = [SUPERBLOCK_MAGIC, VERSION, block_size, blocks, slot_blocks, bitmap_blocks];
+        for i in 0..words.len() {
+            format::store(words[i], &mut superblock[(i*8)..((i+1)*8)]);
+        }
+        let crc = checksum::crc32c(&superblock[0..48]) as u64;
+        format::store(crc, &mut superblock[48..SUPERBLOCK_SIZE]);
+
+        // Clear the slots and bitmaps first, so that nothing from an earlier
+        // format can be mounted.
+        let blank = vec![0; ((data_start - 1) * block_size) as usize];
+        try!(device.write(block_size, &blank));
+
+        let mut allocator = BitmapAllocator::new(1 + 2 * slot_blocks, blocks, block_size, Fit::First);
+        try!(allocator.reserve(Extent{start: 0, count: data_start}));
+        try!(allocator.commit(&mut *device));
+        try!(device.write(0, &superblock));
+
+        let mut fs = DeviceFs{
+            device: device,
+            block_size: block_size,
+            slot_blocks: slot_blocks,
+            data_start: data_start,
+            generation: 0,
+            files: BTreeMap::new(),
+            dirs: BTreeSet::new(),
+            allocator: allocator,
+            unreferenced: Vec::new(),
+            uncommitted: Vec::new(),
+            dirty: false,
+        };
+        try!(fs.commit());
+        try!(fs.device.sync());
+        return Ok(fs);
+    }
+
+    // Mounts a device written by format(). Blocks leaked by a crash (see
+    // BitmapAllocator) are reclaimed.
+    pub fn mount(mut device: Box<dyn Device + Send>) -> io::Result<DeviceFs> {
+        let block_size = device.block_size();
+        let mut superblock = vec![0; block_size as usize];
+        try!(read_exact(&mut *device, 0, &mut superblock));
+
+        let w = |b: &[u8], i: usize| format::load(&b[(i*8)..((i+1)*8)]);
+        if w(&superblock, 0) != SUPERBLOCK_MAGIC {
+            return Err(corrupt("Bad superblock magic".to_string()));
+        }
+        if checksum::crc32c(&superblock[0..48]) as u64 != w(&superblock, 6) {
+            return Err(corrupt("Superblock checksum mismatch".to_string()));
+        }
+        if w(&superblock, 1) != VERSION {
+            return Err(corrupt(format!("Unsupported version {}", w(&superblock, 1))));
+        }
+        if w(&superblock, 2) != block_size {
+            return Err(corrupt(format!(
+                "Formatted with {} byte blocks, but the device has {} byte blocks",
+                w(&superblock, 2), block_size)));
+        }
+        let blocks = w(&superblock, 3);
+        let slot_blocks = w(&superblock, 4);
+        let bitmap_blocks = w(&superblock, 5);
+        let data_start = 1 + 2 * slot_blocks + 2 * bitmap_blocks;
+        if blocks > device.size() / block_size || blocks <= data_start ||
+            bitmap_blocks != BitmapAllocator::blocks_needed(blocks, block_size) {
+            return Err(corrupt(format!("Bad device geometry: {} blocks", blocks)));
+        }
+
+        let mut best : Option<(u64, Vec<u8>)> = None;
+        for slot in 0..2 {
+            let mut buf = vec![0; (slot_blocks * block_size) as usize];
+            match read_exact(&mut *device, (1 + slot * slot_blocks) * block_size, &mut buf) {
+                Ok(()) => (),
+                // E.g. a ChecksummedDevice found it damaged, so it's as good
+                // as garbled.
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => continue,
+                Err(e) => return Err(e),
+            }
+            if let Some((generation, namespace)) = DeviceFs::parse_slot(&buf) {
+                if best.as_ref().map(|b| generation > b.0).unwrap_or(true) {
+                    best = Some((generation, namespace.to_vec()));
+                }
+            }
+        }
+        let (generation, namespace) = match best {
+            Some(best) => best,
+            None => return Err(corrupt("No valid metadata slot".to_string())),
+        };
+
+        let (files, dirs) = try!(DeviceFs::decode_namespace(&namespace, block_size));
+        let allocator = try!(BitmapAllocator::load(
+            &mut *device, 1 + 2 * slot_blocks, blocks, Fit::First));
+        let mut fs = DeviceFs{
+            device: device,
+            block_size: block_size,
+            slot_blocks: slot_blocks,
+            data_start: data_start,
+            generation: generation,
+            files: files,
+            dirs: dirs,
+            allocator: allocator,
+            unreferenced: Vec::new(),
+            uncommitted: Vec::new(),
+            dirty: false,
+        };
+
+        let report = fs.fsck();
+        if !report.shared.is_empty() {
+            return Err(corrupt(format!("Blocks used twice: {:?}", report.shared)));
+        }
+        if !report.is_clean() {
+            try!(fs.allocator.repair(&report));
+            try!(fs.allocator.commit(&mut *fs.device));
+        }
+        return Ok(fs);
+    }
+
+    // Mounts 'device', formatting it first if it has never been used (its
+    // first block is all zeroes).
+    pub fn open_or_format(mut device: Box<dyn Device + Send>) -> io::Result<DeviceFs> {
+        let mut first = vec![0; device.block_size() as usize];
+        try!(read_exact(&mut *device, 0, &mut first));
+        if first.iter().all(|b| *b == 0) {
+            return DeviceFs::format(device);
+        }
+        return DeviceFs::mount(device);
+    }
+
+    // Gives the device back, e.g. to mount it again.
+    pub fn into_device(self) -> Box<dyn Device + Send> {
+        return self.device;
+    }
+
+    pub fn block_size(&self) -> u64 {
+        return self.block_size;
+    }
+
+    // Blocks not used by the superblock, the metadata or any file. Blocks a
+    // file stopped using only count once a sync has made that durable.
+    pub fn free_blocks(&self) -> u64 {
+        return self.allocator.free_blocks();
+    }
+
+    // Checks the free space bitmap against the blocks the namespace uses.
+    pub fn fsck(&self) -> Fsck {
+        let mut owned = vec![Extent{start: 0, count: self.data_start}];
+        for inode in self.files.values() {
+            owned.extend(inode.owned());
+        }
+        owned.extend_from_slice(&self.unreferenced);
+        owned.extend_from_slice(&self.uncommitted);
+        return self.allocator.fsck(&owned);
+    }
+
+    // The files, not directories, in 'dir'.
+    pub fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        if !try!(self.is_dir(dir)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' isn't a directory", dir)));
+        }
+        return Ok(self.files.keys().filter(|n| parent(n) == dir).cloned().collect());
+    }
+
+    // The directories in 'dir'.
+    pub fn list_dirs(&self, dir: &str) -> io::Result<Vec<String>> {
+        if !try!(self.is_dir(dir)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' isn't a directory", dir)));
+        }
+        return Ok(self.dirs.iter().filter(|n| parent(n) == dir).cloned().collect());
+    }
+
+    // Fails with NotFound if there's no file or directory called 'name'.
+    pub fn is_dir(&self, name: &str) -> io::Result<bool> {
+        if name.is_empty() || self.dirs.contains(name) {
+            return Ok(true);
+        }
+        if self.files.contains_key(name) {
+            return Ok(false);
+        }
+        return Err(not_found(name));
+    }
+
+    pub fn create_dir(&mut self, name: &str) -> io::Result<()> {
+        if self.files.contains_key(name) || self.dirs.contains(name) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("'{}' already exists", name)));
+        }
+        try!(self.check_name(name));
+        self.dirs.insert(name.to_string());
+        let committed = self.commit();
+        if committed.is_err() {
+            self.dirs.remove(name);
+        }
+        return committed;
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        return self.files.contains_key(name);
+    }
+
+    pub fn len(&self, name: &str) -> io::Result<u64> {
+        return self.files.get(name).map(|f| f.len).ok_or(not_found(name));
+    }
+
+    // The extents holding the file's whole blocks, in order.
+    pub fn extents(&self, name: &str) -> io::Result<Vec<Extent>> {
+        return self.files.get(name).map(|f| f.extents.clone()).ok_or(not_found(name));
+    }
+
+    pub fn read(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        let inode = match self.files.get(name) {
+            Some(inode) => inode.clone(),
+            None => return Err(not_found(name)),
+        };
+        let whole = (inode.len - inode.len % self.block_size) as usize;
+        let mut data = vec![0; (blocks_for(inode.len, self.block_size) * self.block_size) as usize];
+        try!(self.transfer(&inode.extents, 0, Transfer::Read(&mut data[0..whole])));
+        if let Some(tail) = inode.tail {
+            let tail = [Extent{start: tail, count: 1}];
+            try!(self.transfer(&tail, 0, Transfer::Read(&mut data[whole..])));
+        }
+        data.truncate(inode.len as usize);
+        return Ok(data);
+    }
+
+    // Reads from 'name' at 'offset' into 'buf', reading only the blocks
+    // the range covers. Returns how many bytes were read, 0 at the end of the
+    // file.
+    pub fn read_at(&mut self, name: &str, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let inode = match self.files.get(name) {
+            Some(inode) => inode.clone(),
+            None => return Err(not_found(name)),
+        };
+        if offset >= inode.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let bs = self.block_size;
+        let len = if (buf.len() as u64) < inode.len - offset { buf.len() as u64 } else { inode.len - offset };
+        let first = offset / bs;
+        let end = blocks_for(offset + len, bs);
+        let whole_end = if end < inode.len / bs { end } else { inode.len / bs };
+        let mut data = vec![0; ((end - first) * bs) as usize];
+        let split = (whole_end.saturating_sub(first) * bs) as usize;
+        try!(self.transfer(&inode.extents, first, Transfer::Read(&mut data[0..split])));
+        if end > whole_end {
+            let tail = match inode.tail {
+                Some(tail) => [Extent{start: tail, count: 1}],
+                None => return Err(corrupt(format!("'{}' has no partial last block", name))),
+            };
+            try!(self.transfer(&tail, 0, Transfer::Read(&mut data[split..])));
+        }
+        let skip = (offset - first * bs) as usize;
+        buf[0..(len as usize)].copy_from_slice(&data[skip..(skip + len as usize)]);
+        return Ok(len as usize);
+    }
+
+    // Replaces the contents of 'name' (creating it if need be) with 'data'.
+    // The new contents are written to fresh blocks before the namespace is
+    // committed, so a crash leaves either the old file or the new one.
+    pub fn write(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        try!(self.check_name(name));
+        let count = blocks_for(data.len() as u64, self.block_size);
+        let mut extents = try!(self.allocate(count));
+        let allocated = extents.clone();
+
+        // The last block is the tail, if it's partial.
+        let mut tail = None;
+        if !(data.len() as u64).is_multiple_of(self.block_size) {
+            let last = extents.last_mut().expect("a partial block was allocated");
+            last.count -= 1;
+            tail = Some(last.end());
+            if last.count == 0 {
+                extents.pop();
+            }
+        }
+
+        let inode = Inode{len: data.len() as u64, extents: extents, tail: tail};
+        if let Err(e) = self.check_room(name, &inode) {
+            self.release(&allocated);
+            return Err(e);
+        }
+
+        let mut padded = data.to_vec();
+        padded.resize((count * self.block_size) as usize, 0);
+        let written = self.transfer(&allocated, 0, Transfer::Write(&padded))
+            .and_then(|_| self.commit_allocator());
+        if written.is_err() {
+            self.release(&allocated);
+            return written;
+        }
+
+        let old = self.files.insert(name.to_string(), inode);
+        let committed = self.commit();
+        if committed.is_err() {
+            self.restore(name, old);
+            self.release(&allocated);
+            return committed;
+        }
+        if let Some(old) = old {
+            self.unreferenced.extend(old.owned());
+        }
+        return Ok(());
+    }
+
+    // Appends 'data' to 'name', creating it if need be. Whole blocks go
+    // after the file's whole blocks, which grow by doubling, and what's
+    // left, with the old partial last block, goes to a fresh tail block.
+    // Nothing is committed until the next sync() or other change.
+    pub fn append(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        try!(self.check_name(name));
+        let mut inode = self.files.get(name).cloned().unwrap_or(Inode{len: 0, extents: Vec::new(), tail: None});
+
+        let bs = self.block_size;
+        let mut buf = vec![0; bs as usize];
+        if let Some(tail) = inode.tail {
+            try!(self.transfer(&[Extent{start: tail, count: 1}], 0, Transfer::Read(&mut buf)));
+        }
+        buf.truncate((inode.len % bs) as usize);
+        buf.extend_from_slice(data);
+
+        let first = inode.len / bs;
+        let len = inode.len + data.len() as u64;
+        let whole = len / bs - first;
+        let have = inode.blocks();
+        let needed = len / bs;
+        let mut grown = Vec::new();
+        if needed > have {
+            grown = match self.allocator.allocate_fragmented(if needed - have > have { needed - have } else { have }) {
+                Some(extents) => extents,
+                None => try!(self.allocate(needed - have)),
+            };
+            for extent in &grown {
+                match inode.extents.last_mut() {
+                    Some(last) if last.end() == extent.start => last.count += extent.count,
+                    _ => inode.extents.push(*extent),
+                }
+            }
+        }
+        let mut tail = Vec::new();
+        if !len.is_multiple_of(bs) {
+            tail = match self.allocate(1) {
+                Ok(tail) => tail,
+                Err(e) => {
+                    self.release(&grown);
+                    return Err(e);
+                },
+            };
+        }
+
+        let grown_inode = Inode{len: len, extents: inode.extents.clone(), tail: tail.first().map(|t| t.start)};
+        if let Err(e) = self.check_room(name, &grown_inode) {
+            self.release(&grown);
+            self.release(&tail);
+            return Err(e);
+        }
+
+        let split = (whole * bs) as usize;
+        buf.resize(split + tail.len() * bs as usize, 0);
+        let written = self.transfer(&inode.extents, first, Transfer::Write(&buf[0..split]))
+            .and_then(|_| self.transfer(&tail, 0, Transfer::Write(&buf[split..])));
+        if written.is_err() {
+            self.release(&grown);
+            self.release(&tail);
+            return written;
+        }
+
+        self.uncommitted.extend(inode.tail.map(|t| Extent{start: t, count: 1}));
+        inode.len = len;
+        inode.tail = tail.first().map(|t| t.start);
+        self.files.insert(name.to_string(), inode);
+        self.dirty = true;
+        return Ok(());
+    }
+
+    pub fn delete(&mut self, name: &str) -> io::Result<()> {
+        let old = match self.files.remove(name) {
+            Some(old) => old,
+            None => return Err(not_found(name)),
+        };
+        let committed = self.commit();
+        if committed.is_err() {
+            self.files.insert(name.to_string(), old);
+            return committed;
+        }
+        self.unreferenced.extend(old.owned());
+        return Ok(());
+    }
+
+    // Renames 'from' to 'to' in a single commit, replacing any existing
+    // file called 'to'.
+    pub fn rename(&mut self, from: &str, to: &str) -> io::Result<()> {
+        try!(self.check_name(to));
+        let inode = match self.files.remove(from) {
+            Some(inode) => inode,
+            None => return Err(not_found(from)),
+        };
+        let old = self.files.insert(to.to_string(), inode.clone());
+        let committed = self.commit();
+        if committed.is_err() {
+            self.restore(to, old);
+            self.files.insert(from.to_string(), inode);
+            return committed;
+        }
+        if let Some(old) = old {
+            self.unreferenced.extend(old.owned());
+        }
+        return Ok(());
+    }
+
+    // Commits any appends since the last commit, and makes everything so
+    // far durable.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.dirty {
+            try!(self.commit());
+        }
+        try!(self.commit_allocator());
+        return self.barrier();
+    }
+
+    // Names for new files and directories must be in an existing directory,
+    // and not be one.
+    fn check_name(&self, name: &str) -> io::Result<()> {
+        if name.split('/').any(|c| c.is_empty() || c == "." || c == "..") {
+            return Err(bad_name(name));
+        }
+        if self.dirs.contains(name) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' is a directory", name)));
+        }
+        if !try!(self.is_dir(parent(name))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' isn't a directory", parent(name))));
+        }
+        return Ok(());
+    }
+
+    fn allocate(&mut self, count: u64) -> io::Result<Vec<Extent>> {
+        return self.allocator.allocate_fragmented(count).ok_or(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!("No room for {} more blocks on the device", count)));
+    }
+
+    // Frees blocks in memory; the bitmap on the device catches up at the
+    // next commit.
+    fn release(&mut self, extents: &[Extent]) {
+        for extent in extents {
+            self.allocator.free(*extent).expect("released blocks must be in use");
+        }
+    }
+
+    // Makes every write so far durable, including the last commit, so the
+    // blocks it stopped using can be reused.
+    fn barrier(&mut self) -> io::Result<()> {
+        try!(self.device.sync());
+        let unreferenced : Vec<Extent> = self.unreferenced.drain(..).collect();
+        self.release(&unreferenced);
+        return Ok(());
+    }
+
+    // Persists allocations, and any frees since the last commit.
+    fn commit_allocator(&mut self) -> io::Result<()> {
+        if self.allocator.is_dirty() {
+            return self.allocator.commit(&mut *self.device);
+        }
+        return Ok(());
+    }
+
+    // Fails with StorageFull if the namespace wouldn't fit in a slot once
+    // 'name' is 'inode', so that changes that can't be committed are refused
+    // before their data is written.
+    fn check_room(&mut self, name: &str, inode: &Inode) -> io::Result<()> {
+        let old = self.files.insert(name.to_string(), inode.clone());
+        let needed = self.encode_namespace().len();
+        self.restore(name, old);
+        return self.fits(needed);
+    }
+
+    fn fits(&self, namespace_len: usize) -> io::Result<()> {
+        let capacity = (self.slot_blocks * self.block_size) as usize;
+        if SLOT_HEADER_SIZE + namespace_len > capacity {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!("The namespace needs {} bytes, but only {} fit",
+                        namespace_len, capacity - SLOT_HEADER_SIZE)));
+        }
+        return Ok(());
+    }
+
+    fn restore(&mut self, name: &str, old: Option<Inode>) {
+        match old {
+            Some(old) => self.files.insert(name.to_string(), old),
+            None => self.files.remove(name),
+        };
+    }
+
+    // Reads or writes whole blocks of a file, starting at its block 'first'.
+    fn transfer(&mut self, extents: &[Extent], first: u64, mut buf: Transfer) -> io::Result<()> {
+        let bs = self.block_size;
+        let mut remaining = buf.len() as u64 / bs;
+        let mut skip = first;
+        let mut ptr = 0;
+        for extent in extents {
+            if remaining == 0 {
+                break;
+            }
+            if skip >= extent.count {
+                skip -= extent.count;
+                continue;
+            }
+            let count = if extent.count - skip < remaining { extent.count - skip } else { remaining };
+            let offset = (extent.start + skip) * bs;
+            let range = ptr..(ptr + (count * bs) as usize);
+            match buf {
+                Transfer::Read(ref mut data) => try!(read_exact(&mut *self.device, offset, &mut data[range])),
+                Transfer::Write(data) => try!(self.device.write(offset, &data[range])),
+            }
+            ptr += (count * bs) as usize;
+            remaining -= count;
+            skip = 0;
+        }
+        if remaining > 0 {
+            return Err(corrupt(format!("File extents end {} blocks early", remaining)));
+        }
+        return Ok(());
+    }
+
+    // Writes the namespace to the slot not holding the current one, once
+    // everything it refers to is durable.
+    fn commit(&mut self) -> io::Result<()> {
+        let namespace = self.encode_namespace();
+        try!(self.fits(namespace.len()));
+        try!(self.commit_allocator());
+
+        let generation = self.generation + 1;
+        let mut buf = vec![0; SLOT_HEADER_SIZE];
+        format::store(SLOT_MAGIC, &mut buf[0..8]);
+        format::store(generation, &mut buf[8..16]);
+        format::store(namespace.len() as u64, &mut buf[16..24]);
+        buf.extend_from_slice(&namespace);
+        let mut crc = checksum::Crc32c::new();
+        crc.update(&buf[0..24]);
+        crc.update(&buf[SLOT_HEADER_SIZE..]);
+        let crc = crc.finish() as u64;
+        format::store(crc, &mut buf[24..32]);
+        let blocks = blocks_for(buf.len() as u64, self.block_size);
+        buf.resize((blocks * self.block_size) as usize, 0);
+
+        try!(self.barrier());
+        let slot = generation % 2;
+        try!(self.device.write((1 + slot * self.slot_blocks) * self.block_size, &buf));
+        self.generation = generation;
+        self.dirty = false;
+        self.unreferenced.append(&mut self.uncommitted);
+        return Ok(());
+    }
+
+    // The generation and namespace in a slot, if it holds a valid one.
+    fn parse_slot(buf: &[u8]) -> Option<(u64, &[u8])> {
+        let w = |i: usize| format::load(&buf[(i*8)..((i+1)*8)]);
+        if w(0) != SLOT_MAGIC {
+            return None;
+        }
+        let len = w(2);
+        if len > (buf.len() - SLOT_HEADER_SIZE) as u64 {
+            return None;
+        }
+        let namespace = &buf[SLOT_HEADER_SIZE..(SLOT_HEADER_SIZE + len as usize)];
+        let mut crc = checksum::Crc32c::new();
+        crc.update(&buf[0..24]);
+        crc.update(namespace);
+        if crc.finish() as u64 != w(3) {
+            return None;
+        }
+        return Some((w(1), namespace));
+    }
+
+    // For each file: (name length, name, length, tail block, extent count),
+    // then the (start, count) of each extent. Every number is a word, and a
+    // tail block of 0 (the superblock) means there's no tail. Then each
+    // directory, as an empty file named with a trailing '/'.
+    fn encode_namespace(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let word = |buf: &mut Vec<u8>, n: u64| {
+            let mut w = [0; 8];
+            format::store(n, &mut w);
+            buf.extend_from_slice(&w);
+        };
+        for (name, inode) in &self.files {
+            word(&mut buf, name.len() as u64);
+            buf.extend_from_slice(name.as_bytes());
+            word(&mut buf, inode.len);
+            word(&mut buf, inode.tail.unwrap_or(0));
+            word(&mut buf, inode.extents.len() as u64);
+            for extent in &inode.extents {
+                word(&mut buf, extent.start);
+                word(&mut buf, extent.count);
+            }
+        }
+        for dir in &self.dirs {
+            word(&mut buf, dir.len() as u64 + 1);
+            buf.extend_from_slice(dir.as_bytes());
+            buf.push(b'/');
+            for _ in 0..3 {
+                word(&mut buf, 0);
+            }
+        }
+        return buf;
+    }
+
+    fn decode_namespace(buf: &[u8], block_size: u64) -> io::Result<(BTreeMap<String, Inode>, BTreeSet<String>)> {
+        let truncated = || corrupt("Truncated namespace".to_string());
+        let mut ptr = 0;
+        let word = |ptr: &mut usize| -> io::Result<u64> {
+            if *ptr + 8 > buf.len() {
+                return Err(truncated());
+            }
+            *ptr += 8;
+            return Ok(format::load(&buf[(*ptr - 8)..*ptr]));
+        };
+
+        let mut files = BTreeMap::new();
+        let mut dirs = BTreeSet::new();
+        while ptr < buf.len() {
+            let name_len = try!(word(&mut ptr)) as usize;
+            if name_len > buf.len() - ptr {
+                return Err(truncated());
+            }
+            let name = match String::from_utf8(buf[ptr..(ptr + name_len)].to_vec()) {
+                Ok(name) => name,
+                Err(_) => return Err(corrupt("File name isn't UTF-8".to_string())),
+            };
+            ptr += name_len;
+
+            let len = try!(word(&mut ptr));
+            let tail = try!(word(&mut ptr));
+            let count = try!(word(&mut ptr));
+            let mut extents = Vec::new();
+            for _ in 0..count {
+                let start = try!(word(&mut ptr));
+                let count = try!(word(&mut ptr));
+                extents.push(Extent{start: start, count: count});
+            }
+            if name.ends_with('/') {
+                if len != 0 || tail != 0 || count != 0 {
+                    return Err(corrupt(format!("Directory '{}' has contents", name)));
+                }
+                dirs.insert(name.trim_end_matches('/').to_string());
+                continue;
+            }
+            let inode = Inode{len: len, extents: extents, tail: if tail == 0 { None } else { Some(tail) }};
+            if len / block_size > inode.blocks() {
+                return Err(corrupt(format!("'{}' is longer than its extents", name)));
+            }
+            if inode.tail.is_some() == len.is_multiple_of(block_size) {
+                return Err(corrupt(format!("'{}' has a bad tail block", name)));
+            }
+            files.insert(name, inode);
+        }
+        return Ok((files, dirs));
+    }
+}
+
+// Runs a Db (or anything else that uses an Env) on a DeviceFs. A device
+// has no working directory, so relative paths are taken from the root,
+// like absolute ones.
+pub struct DeviceEnv {
+    id: usize,
+    fs: Arc<Mutex<DeviceFs>>,
+}
+
+impl DeviceEnv {
+    pub fn new(fs: DeviceFs) -> DeviceEnv {
+        return DeviceEnv{
+            id: env::next_env_id(),
+            fs: Arc::new(Mutex::new(fs)),
+        };
+    }
+
+    // The DeviceFs name for 'path'.
+    fn name(path: &path::Path) -> io::Result<String> {
+        let mut names = Vec::new();
+        for component in path.components() {
+            match component {
+                path::Component::RootDir | path::Component::CurDir => {},
+                path::Component::Normal(name) => match name.to_str() {
+                    Some(name) => names.push(name),
+                    None => return Err(bad_name(&path.to_string_lossy())),
+                },
+                _ => return Err(bad_name(&path.to_string_lossy())),
+            }
+        }
+        return Ok(names.join("/"));
+    }
+}
+
+const BUFFER_BYTES : usize = 1 << 20;
+
+// Appends everything written to it to a file on a DeviceFs. Writes are
+// buffered, and appended by flush(), or once BUFFER_BYTES have built up;
+// sync() and dropping the file commit them.
+struct DeviceFile {
+    fs: Arc<Mutex<DeviceFs>>,
+    name: String,
+    buf: Vec<u8>,
+}
+
+impl io::Write for DeviceFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buf.len() >= BUFFER_BYTES {
+            try!(io::Write::flush(self));
+        }
+        self.buf.extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        try!(self.fs.lock().unwrap().append(&self.name, &self.buf));
+        self.buf.clear();
+        return Ok(());
+    }
+}
+
+impl WritableFile for DeviceFile {
+    fn sync(&mut self) -> io::Result<()> {
+        try!(io::Write::flush(self));
+        return self.fs.lock().unwrap().sync();
+    }
+}
+
+impl Drop for DeviceFile {
+    fn drop(&mut self) {
+        let _ = WritableFile::sync(self);
+    }
+}
+
+// Reads a file on a DeviceFs a few blocks at a time, rather than copying
+// all of it into memory when it's opened.
+struct DeviceReader {
+    fs: Arc<Mutex<DeviceFs>>,
+    name: String,
+    // When the file was opened.
+    len: u64,
+    pos: u64,
+}
+
+impl io::Read for DeviceReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = try!(self.fs.lock().unwrap().read_at(&self.name, self.pos, buf));
+        self.pos += read as u64;
+        return Ok(read);
+    }
+}
+
+impl io::Seek for DeviceReader {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            io::SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = try!(pos.ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput, "Seek to before the start of the file")));
+        return Ok(self.pos);
+    }
+}
+
+impl Env for DeviceEnv {
+    fn create(&self, path: &path::Path) -> io::Result<Box<dyn WritableFile>> {
+        let name = try!(DeviceEnv::name(path));
+        try!(self.fs.lock().unwrap().write(&name, &[]));
+        return Ok(Box::new(DeviceFile{fs: self.fs.clone(), name: name, buf: Vec::new()}));
+    }
+
+    fn open(&self, path: &path::Path) -> io::Result<Box<dyn ReadableFile>> {
+        let name = try!(DeviceEnv::name(path));
+        let len = try!(self.fs.lock().unwrap().len(&name));
+        let file = DeviceReader{fs: self.fs.clone(), name: name, len: len, pos: 0};
+        return Ok(Box::new(io::BufReader::new(file)));
+    }
+
+    fn read_all(&self, path: &path::Path) -> io::Result<FileContents> {
+        let data = try!(self.fs.lock().unwrap().read(&try!(DeviceEnv::name(path))));
+        return Ok(FileContents::Memory(Arc::new(data)));
+    }
+
+    fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        return self.fs.lock().unwrap().rename(
+            &try!(DeviceEnv::name(from)), &try!(DeviceEnv::name(to)));
+    }
+
+    fn delete(&self, path: &path::Path) -> io::Result<()> {
+        return self.fs.lock().unwrap().delete(&try!(DeviceEnv::name(path)));
+    }
+
+    // Namespace changes are durable once the device has been synced.
+    fn sync_dir(&self, dir: &path::Path) -> io::Result<()> {
+        let mut fs = self.fs.lock().unwrap();
+        if !try!(fs.is_dir(&try!(DeviceEnv::name(dir)))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} isn't a directory", dir)));
+        }
+        return fs.sync();
+    }
+
+    fn list(&self, dir: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+        let names = try!(self.fs.lock().unwrap().list(&try!(DeviceEnv::name(dir))));
+        return Ok(names.iter().map(|n| dir.join(n.rsplit('/').next().unwrap())).collect());
+    }
+
+    fn list_dirs(&self, dir: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+        let names = try!(self.fs.lock().unwrap().list_dirs(&try!(DeviceEnv::name(dir))));
+        return Ok(names.iter().map(|n| dir.join(n.rsplit('/').next().unwrap())).collect());
+    }
+
+    fn create_dir(&self, dir: &path::Path) -> io::Result<()> {
+        let name = try!(DeviceEnv::name(dir));
+        if name.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "The root directory already exists"));
+        }
+        return self.fs.lock().unwrap().create_dir(&name);
+    }
+
+    fn is_dir(&self, path: &path::Path) -> io::Result<bool> {
+        return self.fs.lock().unwrap().is_dir(&try!(DeviceEnv::name(path)));
+    }
+
+    fn cache_key(&self, path: &path::Path) -> path::PathBuf {
+        return path::PathBuf::from(format!("device{}:{}", self.id, path.display()));
+    }
+}
+
+enum Transfer<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+impl<'a> Transfer<'a> {
+    fn len(&self) -> usize {
+        return match *self {
+            Transfer::Read(ref data) => data.len(),
+            Transfer::Write(data) => data.len(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use block_storage::Device;
+    use block_storage::InMemoryDevice;
+    use allocator::Extent;
+    use env::Env;
+    use super::DeviceEnv;
+    use super::DeviceFs;
+
+    use std::io;
+    use std::io::ErrorKind;
+    use std::io::Read;
+    use std::io::Seek;
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    fn device(blocks: u64) -> Box<dyn Device + Send> {
+        return Box::new(InMemoryDevice::new(blocks * 512, 512));
+    }
+
+    #[derive(Clone,Copy,Debug,PartialEq)]
+    enum Op {
+        // The first block written.
+        Write(u64),
+        Sync,
+    }
+
+    // Records the writes and syncs made to an InMemoryDevice.
+    struct RecordingDevice {
+        device: InMemoryDevice,
+        ops: Arc<Mutex<Vec<Op>>>,
+    }
+
+    impl Device for RecordingDevice {
+        fn block_size(&self) -> u64 {
+            return self.device.block_size();
+        }
+
+        fn size(&self) -> u64 {
+            return self.device.size();
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+            self.ops.lock().unwrap().push(Op::Write(offset / 512));
+            return self.device.write(offset, data);
+        }
+
+        fn read(&mut self, offset: u64, length: u64, buf: &mut [u8]) -> io::Result<usize> {
+            return self.device.read(offset, length, buf);
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return self.device.flush();
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            self.ops.lock().unwrap().push(Op::Sync);
+            return self.device.sync();
+        }
+    }
+
+    #[test]
+    fn files() {
+        let mut fs = DeviceFs::format(device(1024)).unwrap();
+        // The superblock, two 128 block slots and two copies of the bitmap.
+        assert_eq!(1024 - 259, fs.free_blocks());
+
+        fs.write("table_0", &[7; 1000]).unwrap();
+        fs.append("log_0", &[1, 2, 3]).unwrap();
+        fs.append("log_0", &[4; 600]).unwrap();
+        assert_eq!(vec!["log_0", "table_0"], fs.list("").unwrap());
+        assert_eq!(1000, fs.len("table_0").unwrap());
+        assert_eq!(603, fs.len("log_0").unwrap());
+
+        let log = fs.read("log_0").unwrap();
+        assert_eq!([1, 2, 3], log[0..3]);
+        assert_eq!(vec![4; 600], &log[3..]);
+
+        // Replacing a file frees its old blocks, once that's durable.
+        fs.sync().unwrap();
+        let free = fs.free_blocks();
+        fs.write("table_0", &[8; 10]).unwrap();
+        assert_eq!(free - 1, fs.free_blocks());
+        fs.sync().unwrap();
+        assert_eq!(free + 1, fs.free_blocks());
+        assert_eq!(vec![8; 10], fs.read("table_0").unwrap());
+
+        fs.delete("table_0").unwrap();
+        assert_eq!(ErrorKind::NotFound, fs.read("table_0").unwrap_err().kind());
+        assert_eq!(ErrorKind::NotFound, fs.delete("table_0").unwrap_err().kind());
+        assert_eq!(ErrorKind::InvalidInput, fs.write("a//b", &[]).unwrap_err().kind());
+        assert_eq!(ErrorKind::NotFound, fs.write("a/b", &[]).unwrap_err().kind());
+
+        fs.sync().unwrap();
+        let free = fs.free_blocks();
+        let mut fs = DeviceFs::mount(fs.into_device()).unwrap();
+        assert_eq!(vec!["log_0"], fs.list("").unwrap());
+        assert_eq!(603, fs.read("log_0").unwrap().len());
+        assert_eq!(free, fs.free_blocks());
+    }
+
+    #[test]
+    fn namespace_full() {
+        let mut fs = DeviceFs::format(device(4096)).unwrap();
+        let name = |i: usize| format!("file_{:040}", i);
+        let mut files = 0;
+        let full = loop {
+            match fs.write(&name(files), &[1; 100]) {
+                Ok(()) => files += 1,
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(ErrorKind::StorageFull, full.kind());
+        // Each file takes 32 bytes and its 45 byte name. Files shorter than
+        // a block have no extents, just a tail.
+        assert_eq!((super::SLOT_BYTES as usize - super::SLOT_HEADER_SIZE) / (32 + 45), files);
+
+        // Nothing was written for the refused file, and the others are intact.
+        fs.sync().unwrap();
+        let free = fs.free_blocks();
+        assert_eq!(ErrorKind::StorageFull, fs.write(&name(files), &[1; 1000]).unwrap_err().kind());
+        assert_eq!(ErrorKind::StorageFull, fs.append(&name(files), &[1; 1000]).unwrap_err().kind());
+        assert_eq!(free, fs.free_blocks());
+        assert!(!fs.exists(&name(files)));
+        assert_eq!(vec![1; 100], fs.read(&name(0)).unwrap());
+
+        // Deleting a file makes room for another.
+        fs.delete(&name(0)).unwrap();
+        fs.write(&name(files), &[2; 100]).unwrap();
+        let mut fs = DeviceFs::mount(fs.into_device()).unwrap();
+        assert_eq!(files, fs.list("").unwrap().len());
+        assert_eq!(vec![2; 100], fs.read(&name(files)).unwrap());
+    }
+
+    #[test]
+    fn appends_grow_by_doubling() {
+        let mut fs = DeviceFs::format(device(1024)).unwrap();
+        for i in 0..100 {
+            fs.append("log", &[i as u8; 100]).unwrap();
+        }
+        // 10000 bytes need 19 whole blocks, allocated 1 + 1 + 2 + 4 + 8 + 16
+        // at a time.
+        let extents = fs.extents("log").unwrap();
+        assert_eq!(32, extents.iter().map(|e| e.count).sum::<u64>());
+        assert!(extents.len() <= 6);
+
+        let data = fs.read("log").unwrap();
+        for i in 0..100 {
+            assert_eq!(vec![i as u8; 100], &data[(i*100)..((i+1)*100)]);
+        }
+    }
+
+    #[test]
+    fn commit_ordering() {
+        let ops = Arc::new(Mutex::new(Vec::new()));
+        let device = RecordingDevice{device: InMemoryDevice::new(1024 * 512, 512), ops: ops.clone()};
+        let mut fs = DeviceFs::format(Box::new(device)).unwrap();
+        let data_written = |ops: &Arc<Mutex<Vec<Op>>>| -> Vec<u64> {
+            return ops.lock().unwrap().drain(..).filter_map(|op| match op {
+                Op::Write(block) if block >= 259 => Some(block),
+                _ => None,
+            }).collect();
+        };
+
+        fs.append("log", &[1; 100]).unwrap();
+        let first = data_written(&ops);
+        assert_eq!(1, first.len());
+
+        // The appended data, and the old partial block, go to a fresh block,
+        // and the namespace isn't written until the sync, once they're
+        // durable.
+        fs.append("log", &[2; 100]).unwrap();
+        assert!(!ops.lock().unwrap().iter().any(|op| matches!(*op, Op::Write(1) | Op::Write(129))));
+        fs.sync().unwrap();
+        {
+            let ops = ops.lock().unwrap();
+            let n = ops.len();
+            assert_eq!(Op::Sync, ops[n - 1]);
+            assert!(matches!(ops[n - 2], Op::Write(1) | Op::Write(129)));
+            assert_eq!(Op::Sync, ops[n - 3]);
+        }
+        let second = data_written(&ops);
+        assert_eq!(1, second.len());
+        assert!(second[0] != first[0]);
+        let mut data = vec![1; 100];
+        data.extend_from_slice(&[2; 100]);
+        assert_eq!(data, fs.read("log").unwrap());
+
+        // Deleted blocks aren't reused until the delete is durable.
+        fs.delete("log").unwrap();
+        ops.lock().unwrap().clear();
+        fs.write("x", &[3; 100]).unwrap();
+        assert!(!data_written(&ops).contains(&second[0]));
+        fs.sync().unwrap();
+        fs.write("y", &[4; 100]).unwrap();
+        assert_eq!(vec![second[0]], data_written(&ops));
+
+        // Nor is an old partial block while only an uncommitted append has
+        // stopped using it.
+        fs.append("z", &[5; 100]).unwrap();
+        fs.sync().unwrap();
+        let tail = data_written(&ops);
+        fs.append("z", &[6; 100]).unwrap();
+        fs.write("w", &[7; 100]).unwrap();
+        assert!(!data_written(&ops).contains(&tail[0]));
+    }
+
+    #[test]
+    fn positional_reads() {
+        let mut fs = DeviceFs::format(device(1024)).unwrap();
+        let data : Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        fs.write("a", &data[0..700]).unwrap();
+        fs.append("a", &data[700..]).unwrap();
+        for &(offset, len) in &[(0, 1500), (0, 10), (500, 100), (510, 600), (1020, 480), (1499, 10)] {
+            let mut buf = vec![0; len];
+            let read = fs.read_at("a", offset as u64, &mut buf).unwrap();
+            let end = if offset + len < 1500 { offset + len } else { 1500 };
+            assert_eq!(&data[offset..end], &buf[0..read]);
+        }
+        assert_eq!(0, fs.read_at("a", 1500, &mut [0; 10]).unwrap());
+        assert_eq!(ErrorKind::NotFound, fs.read_at("b", 0, &mut [0; 10]).unwrap_err().kind());
+
+        let env = DeviceEnv::new(fs);
+        let mut file = env.open(Path::new("/a")).unwrap();
+        file.seek(io::SeekFrom::End(-100)).unwrap();
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(&data[1400..], &buf[..]);
+        file.seek(io::SeekFrom::Start(3)).unwrap();
+        file.read_exact(&mut buf[0..4]).unwrap();
+        assert_eq!(&data[3..7], &buf[0..4]);
+    }
+
+    #[test]
+    fn fragmentation() {
+        let mut fs = DeviceFs::format(device(259 + 8)).unwrap();
+        fs.write("a", &[1; 1024]).unwrap();
+        fs.write("b", &[2; 1024]).unwrap();
+        fs.write("c", &[3; 1024]).unwrap();
+        fs.delete("b").unwrap();
+        fs.sync().unwrap();
+        // Only split runs are left.
+        fs.write("d", &[4; 2048]).unwrap();
+        assert_eq!(vec![Extent{start: 261, count: 2}, Extent{start: 265, count: 2}],
+                   fs.extents("d").unwrap());
+        assert_eq!(vec![4; 2048], fs.read("d").unwrap());
+
+        assert_eq!(ErrorKind::StorageFull, fs.write("e", &[5; 512]).unwrap_err().kind());
+        // A failed write leaves everything as it was.
+        assert!(!fs.exists("e"));
+        assert_eq!(0, fs.free_blocks());
+    }
+
+    #[test]
+    fn torn_commit() {
+        let mut fs = DeviceFs::format(device(1024)).unwrap();
+        fs.write("a", &[1; 10]).unwrap();
+        let free = fs.free_blocks();
+        fs.write("b", &[2; 10]).unwrap();
+        assert!(fs.fsck().is_clean());
+
+        // Garble the newest slot; the previous namespace survives, and the
+        // block allocated for 'b' is reclaimed.
+        let mut device = fs.into_device();
+        device.write(129 * 512, &[0xff; 512]).unwrap();
+        let mut fs = DeviceFs::mount(device).unwrap();
+        assert_eq!(vec!["a"], fs.list("").unwrap());
+        assert_eq!(vec![1; 10], fs.read("a").unwrap());
+        assert_eq!(free, fs.free_blocks());
+        assert!(fs.fsck().is_clean());
+
+        // Both slots garbled.
+        let mut device = fs.into_device();
+        device.write(512, &[0xff; 512]).unwrap();
+        assert_eq!(ErrorKind::InvalidData, DeviceFs::mount(device).err().unwrap().kind());
+    }
+
+    // Fails reads of one block, as a ChecksummedDevice does when the block
+    // is damaged.
+    struct UnreadableBlock {
+        device: Box<dyn Device + Send>,
+        block: u64,
+    }
+
+    impl Device for UnreadableBlock {
+        fn block_size(&self) -> u64 {
+            return self.device.block_size();
+        }
+
+        fn size(&self) -> u64 {
+            return self.device.size();
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+            return self.device.write(offset, data);
+        }
+
+        fn read(&mut self, offset: u64, length: u64, buf: &mut [u8]) -> io::Result<usize> {
+            if offset <= self.block * 512 && self.block * 512 < offset + length {
+                return Err(io::Error::new(ErrorKind::InvalidData, "Checksum mismatch"));
+            }
+            return self.device.read(offset, length, buf);
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return self.device.flush();
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            return self.device.sync();
+        }
+    }
+
+    #[test]
+    fn unreadable_copies() {
+        // Either slot, or either copy of the bitmap, can fail to read and
+        // the other is used instead.
+        for &block in &[1, 129, 257, 258] {
+            let mut fs = DeviceFs::format(device(1024)).unwrap();
+            fs.write("a", &[1; 10]).unwrap();
+            fs.write("b", &[2; 10]).unwrap();
+            let unreadable = Box::new(UnreadableBlock{device: fs.into_device(), block: block});
+            let mut fs = DeviceFs::mount(unreadable).unwrap_or_else(|e| panic!("block {}: {}", block, e));
+            assert!(fs.list("").unwrap().contains(&"a".to_string()), "block {}", block);
+            assert_eq!(vec![1; 10], fs.read("a").unwrap());
+            assert!(fs.fsck().is_clean());
+        }
+    }
+
+    #[test]
+    fn open_or_format() {
+        let mut fs = DeviceFs::open_or_format(device(1024)).unwrap();
+        fs.write("a", &[1]).unwrap();
+        let fs = DeviceFs::open_or_format(fs.into_device()).unwrap();
+        assert_eq!(vec!["a"], fs.list("").unwrap());
+
+        let tiny = Box::new(InMemoryDevice::new(1024, 4));
+        assert_eq!(ErrorKind::InvalidInput, DeviceFs::format(tiny).err().unwrap().kind());
+        assert_eq!(ErrorKind::InvalidInput, DeviceFs::format(device(259)).err().unwrap().kind());
+    }
+
+    #[test]
+    fn directories() {
+        let env = DeviceEnv::new(DeviceFs::format(device(1024)).unwrap());
+        let root = Path::new("/");
+        assert!(env.is_dir(root).unwrap());
+        assert_eq!(ErrorKind::NotFound, env.create(Path::new("/a/log_0")).err().unwrap().kind());
+        env.create_dir(Path::new("/a")).unwrap();
+        env.create_dir(Path::new("/a/b")).unwrap();
+        assert_eq!(ErrorKind::AlreadyExists, env.create_dir(Path::new("/a")).unwrap_err().kind());
+        assert_eq!(ErrorKind::AlreadyExists, env.create_dir(root).unwrap_err().kind());
+
+        // Files with the same name in different directories are different.
+        env.create(Path::new("/a/log_0")).unwrap().write_all(&[1]).unwrap();
+        env.create(Path::new("/a/b/log_0")).unwrap().write_all(&[2, 2]).unwrap();
+        assert_eq!(vec![Path::new("/a/log_0")], env.list(Path::new("/a")).unwrap());
+        assert_eq!(vec![Path::new("/a/b/log_0")], env.list(Path::new("/a/b")).unwrap());
+        assert!(env.list(root).unwrap().is_empty());
+        assert_eq!(vec![Path::new("/a")], env.list_dirs(root).unwrap());
+        assert_eq!(vec![Path::new("/a/b")], env.list_dirs(Path::new("/a")).unwrap());
+        assert!(!env.is_dir(Path::new("/a/log_0")).unwrap());
+        assert_eq!(ErrorKind::NotFound, env.is_dir(Path::new("/a/c")).unwrap_err().kind());
+        assert_eq!(ErrorKind::InvalidInput, env.create(Path::new("/a/b")).err().unwrap().kind());
+        assert_eq!(ErrorKind::InvalidInput, env.create(Path::new("/a/../c")).err().unwrap().kind());
+
+        env.rename(Path::new("/a/b/log_0"), Path::new("/log_0")).unwrap();
+        let fs = Arc::try_unwrap(env.fs).ok().unwrap().into_inner().unwrap();
+        let env = DeviceEnv::new(DeviceFs::mount(fs.into_device()).unwrap());
+        assert!(env.is_dir(Path::new("/a/b")).unwrap());
+        assert!(env.list(Path::new("/a/b")).unwrap().is_empty());
+        assert_eq!(2, env.read_all(Path::new("/log_0")).unwrap().len());
+        assert_eq!(1, env.read_all(Path::new("a/log_0")).unwrap().len());
+    }
+
+    #[test]
+    fn buffered_files() {
+        let ops = Arc::new(Mutex::new(Vec::new()));
+        let device = RecordingDevice{device: InMemoryDevice::new(1024 * 512, 512), ops: ops.clone()};
+        let env = DeviceEnv::new(DeviceFs::format(Box::new(device)).unwrap());
+        let commits = |ops: &Arc<Mutex<Vec<Op>>>| -> usize {
+            return ops.lock().unwrap().drain(..)
+                .filter(|op| matches!(*op, Op::Write(1) | Op::Write(129))).count();
+        };
+        commits(&ops);
+
+        let mut file = env.create(Path::new("/log_0")).unwrap();
+        assert_eq!(1, commits(&ops));
+        for i in 0..100 {
+            file.write_all(&[i as u8; 12]).unwrap();
+        }
+        assert_eq!(0, commits(&ops));
+        file.sync().unwrap();
+        assert_eq!(1, commits(&ops));
+        file.write_all(&[100; 12]).unwrap();
+        drop(file);
+        assert_eq!(1, commits(&ops));
+
+        let data = env.read_all(Path::new("/log_0")).unwrap();
+        assert_eq!(101 * 12, data.len());
+        assert_eq!([100; 12], data[1200..]);
+    }
+}
@@ -0,0 +1,447 @@
+extern crate memmap2;
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::ops;
+use std::path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic;
+
+// Everything the database needs from a filesystem, so that it can run on
+// something else: a device (see device_fs::DeviceEnv), or memory (MemEnv).
+pub trait Env: Send + Sync {
+    // Creates a file to write, replacing any existing one.
+    fn create(&self, path: &path::Path) -> io::Result<Box<dyn WritableFile>>;
+    fn open(&self, path: &path::Path) -> io::Result<Box<dyn ReadableFile>>;
+    // The whole contents of a file, mapped into memory where possible.
+    fn read_all(&self, path: &path::Path) -> io::Result<FileContents>;
+    // Replaces 'to', if it exists, atomically.
+    fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()>;
+    fn delete(&self, path: &path::Path) -> io::Result<()>;
+    // Makes the creation, renaming and deletion of files in 'dir' durable.
+    // Until then, a crash may undo them, even for files that were synced.
+    fn sync_dir(&self, dir: &path::Path) -> io::Result<()>;
+    // The paths of the files (but not directories) in 'dir'.
+    fn list(&self, dir: &path::Path) -> io::Result<Vec<path::PathBuf>>;
+    // The paths of the directories in 'dir'.
+    fn list_dirs(&self, dir: &path::Path) -> io::Result<Vec<path::PathBuf>>;
+    fn create_dir(&self, dir: &path::Path) -> io::Result<()>;
+    // Fails with NotFound if nothing is at 'path'.
+    fn is_dir(&self, path: &path::Path) -> io::Result<bool>;
+
+    // Distinguishes the file at 'path' from same-named files in other Envs,
+    // e.g. in the process-wide block cache.
+    fn cache_key(&self, path: &path::Path) -> path::PathBuf {
+        return path.to_path_buf();
+    }
+}
+
+pub trait WritableFile: io::Write + Send {
+    // Makes everything written so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+pub trait ReadableFile: io::Read + io::Seek + Send {}
+
+impl<T: io::Read + io::Seek + Send> ReadableFile for T {}
+
+// The contents of a whole file: mapped from disk, or held in memory.
+pub enum FileContents {
+    Mapped(memmap2::Mmap),
+    Memory(Arc<Vec<u8>>),
+}
+
+impl ops::Deref for FileContents {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        return match *self {
+            FileContents::Mapped(ref map) => &map[..],
+            FileContents::Memory(ref data) => &data[..],
+        };
+    }
+}
+
+impl AsRef<[u8]> for FileContents {
+    fn as_ref(&self) -> &[u8] {
+        return &self[..];
+    }
+}
+
+// Numbers the Envs that aren't the real filesystem; see cache_key.
+static ENV_IDS : atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+pub fn next_env_id() -> usize {
+    return ENV_IDS.fetch_add(1, atomic::Ordering::SeqCst);
+}
+
+// The local filesystem.
+pub struct PosixEnv;
+
+impl PosixEnv {
+    pub fn shared() -> Arc<dyn Env> {
+        return Arc::new(PosixEnv);
+    }
+}
+
+impl WritableFile for fs::File {
+    fn sync(&mut self) -> io::Result<()> {
+        return self.sync_data();
+    }
+}
+
+impl Env for PosixEnv {
+    fn create(&self, path: &path::Path) -> io::Result<Box<dyn WritableFile>> {
+        return Ok(Box::new(try!(fs::File::create(path))));
+    }
+
+    fn open(&self, path: &path::Path) -> io::Result<Box<dyn ReadableFile>> {
+        return Ok(Box::new(try!(fs::File::open(path))));
+    }
+
+    fn read_all(&self, path: &path::Path) -> io::Result<FileContents> {
+        let f = try!(fs::File::open(path));
+        // Mapping an empty file fails on some platforms.
+        if try!(f.metadata()).len() == 0 {
+            return Ok(FileContents::Memory(Arc::new(Vec::new())));
+        }
+        // The database never modifies a file it reads whole, so nothing
+        // will change the mapped bytes out from under us.
+        return Ok(FileContents::Mapped(try!(unsafe { memmap2::Mmap::map(&f) })));
+    }
+
+    fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        return fs::rename(from, to);
+    }
+
+    fn delete(&self, path: &path::Path) -> io::Result<()> {
+        return fs::remove_file(path);
+    }
+
+    fn sync_dir(&self, dir: &path::Path) -> io::Result<()> {
+        return try!(fs::File::open(dir)).sync_all();
+    }
+
+    fn list(&self, dir: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in try!(fs::read_dir(dir)) {
+            let entry = try!(entry);
+            if !try!(fs::metadata(entry.path())).is_dir() {
+                paths.push(entry.path());
+            }
+        }
+        return Ok(paths);
+    }
+
+    fn list_dirs(&self, dir: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in try!(fs::read_dir(dir)) {
+            let entry = try!(entry);
+            if try!(fs::metadata(entry.path())).is_dir() {
+                paths.push(entry.path());
+            }
+        }
+        return Ok(paths);
+    }
+
+    fn create_dir(&self, dir: &path::Path) -> io::Result<()> {
+        return fs::create_dir(dir);
+    }
+
+    fn is_dir(&self, path: &path::Path) -> io::Result<bool> {
+        return fs::metadata(path).map(|md| md.is_dir());
+    }
+}
+
+// Creates 'dir' and any missing parents, like fs::create_dir_all.
+pub fn create_dir_all(env: &dyn Env, dir: &path::Path) -> io::Result<()> {
+    match env.is_dir(dir) {
+        Ok(true) => return Ok(()),
+        Ok(false) => return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists, format!("{:?} is a file", dir))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e),
+    }
+    if let Some(parent) = dir.parent() {
+        if !parent.as_os_str().is_empty() {
+            try!(create_dir_all(env, parent));
+        }
+    }
+    return env.create_dir(dir);
+}
+
+// Makes the creation or renaming of 'path' durable.
+pub fn sync_parent(env: &dyn Env, path: &path::Path) -> io::Result<()> {
+    return match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => env.sync_dir(dir),
+        _ => env.sync_dir(path::Path::new(".")),
+    };
+}
+
+// A path under the system's temporary directory that no other test process
+// uses, for tests that need real files.
+#[cfg(test)]
+pub fn temp_path(name: &str) -> path::PathBuf {
+    return ::std::env::temp_dir().join(format!("rts-{}-{}", ::std::process::id(), name));
+}
+
+// Files are shared between names and open handles, so that (as on POSIX)
+// a file that is renamed or deleted while being written keeps its data.
+type MemFile = Arc<Mutex<Vec<u8>>>;
+
+#[derive(Default)]
+struct MemState {
+    files: BTreeMap<path::PathBuf, MemFile>,
+    dirs: BTreeSet<path::PathBuf>,
+}
+
+// Keeps files in memory, e.g. so that tests can run without touching the
+// disk or each other. Clones share the same files.
+#[derive(Clone)]
+pub struct MemEnv {
+    id: usize,
+    state: Arc<Mutex<MemState>>,
+}
+
+fn not_found(path: &path::Path) -> io::Error {
+    return io::Error::new(io::ErrorKind::NotFound, format!("{:?} not found", path));
+}
+
+impl Default for MemEnv {
+    fn default() -> MemEnv {
+        return MemEnv::new();
+    }
+}
+
+impl MemEnv {
+    pub fn new() -> MemEnv {
+        let mut state = MemState::default();
+        state.dirs.insert(path::PathBuf::from("/"));
+        return MemEnv{
+            id: next_env_id(),
+            state: Arc::new(Mutex::new(state)),
+        };
+    }
+
+    fn file(&self, path: &path::Path) -> io::Result<MemFile> {
+        return self.state.lock().unwrap().files.get(path).cloned().ok_or(not_found(path));
+    }
+
+    fn check_parent(state: &MemState, path: &path::Path) -> io::Result<()> {
+        match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() && !state.dirs.contains(dir) => Err(not_found(dir)),
+            _ => Ok(()),
+        }
+    }
+}
+
+struct MemWriter {
+    file: MemFile,
+}
+
+impl io::Write for MemWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.lock().unwrap().extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl WritableFile for MemWriter {
+    fn sync(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl Env for MemEnv {
+    fn create(&self, path: &path::Path) -> io::Result<Box<dyn WritableFile>> {
+        let mut state = self.state.lock().unwrap();
+        try!(MemEnv::check_parent(&state, path));
+        if state.dirs.contains(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput, format!("{:?} is a directory", path)));
+        }
+        let file = Arc::new(Mutex::new(Vec::new()));
+        state.files.insert(path.to_path_buf(), file.clone());
+        return Ok(Box::new(MemWriter{file: file}));
+    }
+
+    fn open(&self, path: &path::Path) -> io::Result<Box<dyn ReadableFile>> {
+        let file = try!(self.file(path));
+        let data = file.lock().unwrap().clone();
+        return Ok(Box::new(io::Cursor::new(data)));
+    }
+
+    fn read_all(&self, path: &path::Path) -> io::Result<FileContents> {
+        let file = try!(self.file(path));
+        let data = file.lock().unwrap().clone();
+        return Ok(FileContents::Memory(Arc::new(data)));
+    }
+
+    fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        try!(MemEnv::check_parent(&state, to));
+        let file = match state.files.remove(from) {
+            Some(file) => file,
+            None => return Err(not_found(from)),
+        };
+        state.files.insert(to.to_path_buf(), file);
+        return Ok(());
+    }
+
+    fn delete(&self, path: &path::Path) -> io::Result<()> {
+        return self.state.lock().unwrap().files.remove(path).map(|_| ()).ok_or(not_found(path));
+    }
+
+    fn sync_dir(&self, dir: &path::Path) -> io::Result<()> {
+        if !self.state.lock().unwrap().dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        return Ok(());
+    }
+
+    fn list(&self, dir: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+        let state = self.state.lock().unwrap();
+        if !state.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        return Ok(state.files.keys()
+                  .filter(|p| p.parent() == Some(dir))
+                  .cloned()
+                  .collect());
+    }
+
+    fn list_dirs(&self, dir: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+        let state = self.state.lock().unwrap();
+        if !state.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        return Ok(state.dirs.iter()
+                  .filter(|p| p.parent() == Some(dir))
+                  .cloned()
+                  .collect());
+    }
+
+    fn create_dir(&self, dir: &path::Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        try!(MemEnv::check_parent(&state, dir));
+        if state.dirs.contains(dir) || state.files.contains_key(dir) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists, format!("{:?} already exists", dir)));
+        }
+        state.dirs.insert(dir.to_path_buf());
+        return Ok(());
+    }
+
+    fn is_dir(&self, path: &path::Path) -> io::Result<bool> {
+        let state = self.state.lock().unwrap();
+        if state.dirs.contains(path) {
+            return Ok(true);
+        }
+        if state.files.contains_key(path) {
+            return Ok(false);
+        }
+        return Err(not_found(path));
+    }
+
+    fn cache_key(&self, path: &path::Path) -> path::PathBuf {
+        return path::PathBuf::from(format!("mem{}:{}", self.id, path.display()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Env;
+    use super::MemEnv;
+    use super::PosixEnv;
+
+    use std::fs;
+    use std::io;
+    use std::io::Read;
+    use std::io::Seek;
+    use std::io::Write;
+    use std::path::Path;
+
+    fn accept_not_found(err: io::Error) -> io::Result<()> {
+        if err.kind() == io::ErrorKind::NotFound {
+            return Ok(());
+        }
+        return Err(err);
+    }
+
+    fn exercise(env: &dyn Env, root: &str) {
+        let root = Path::new(root);
+        let a = root.join("a");
+        let b = root.join("b");
+
+        assert_eq!(io::ErrorKind::NotFound, env.is_dir(root).unwrap_err().kind());
+        env.create_dir(root).unwrap();
+        assert!(env.is_dir(root).unwrap());
+        assert_eq!(io::ErrorKind::AlreadyExists, env.create_dir(root).unwrap_err().kind());
+
+        {
+            let mut f = env.create(&a).unwrap();
+            f.write_all(b"hello ").unwrap();
+            f.write_all(b"world").unwrap();
+            f.sync().unwrap();
+        }
+        assert!(!env.is_dir(&a).unwrap());
+        assert_eq!(b"hello world", &env.read_all(&a).unwrap()[..]);
+
+        let mut f = env.open(&a).unwrap();
+        f.seek(io::SeekFrom::Start(6)).unwrap();
+        let mut s = String::new();
+        f.read_to_string(&mut s).unwrap();
+        assert_eq!("world", s);
+
+        env.create_dir(&root.join("dir")).unwrap();
+        env.create(&b).unwrap();
+        let mut files = env.list(root).unwrap();
+        files.sort();
+        assert_eq!(vec![a.clone(), b.clone()], files);
+        assert_eq!(vec![root.join("dir")], env.list_dirs(root).unwrap());
+        super::create_dir_all(env, &root.join("dir/x/y")).unwrap();
+        assert!(env.is_dir(&root.join("dir/x")).unwrap());
+        assert_eq!(io::ErrorKind::AlreadyExists,
+                   super::create_dir_all(env, &a).unwrap_err().kind());
+
+        // Renaming replaces the target.
+        env.rename(&a, &b).unwrap();
+        env.sync_dir(root).unwrap();
+        assert_eq!(io::ErrorKind::NotFound, env.sync_dir(&root.join("missing")).unwrap_err().kind());
+        assert_eq!(vec![b.clone()], env.list(root).unwrap());
+        assert_eq!(11, env.read_all(&b).unwrap().len());
+        assert_eq!(io::ErrorKind::NotFound, env.open(&a).err().unwrap().kind());
+
+        env.delete(&b).unwrap();
+        assert_eq!(io::ErrorKind::NotFound, env.delete(&b).unwrap_err().kind());
+        assert!(env.list(root).unwrap().is_empty());
+        assert_eq!(io::ErrorKind::NotFound,
+                   env.create(&root.join("missing/c")).err().unwrap().kind());
+    }
+
+    #[test]
+    fn posix_env() {
+        let root = super::temp_path("env");
+        fs::remove_dir_all(&root).or_else(accept_not_found).unwrap();
+        exercise(&PosixEnv, root.to_str().unwrap());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn mem_env() {
+        let env = MemEnv::new();
+        exercise(&env, "/env");
+        // Clones share files; separate Envs don't.
+        env.create(Path::new("/x")).unwrap();
+        assert!(env.clone().read_all(Path::new("/x")).is_ok());
+        assert!(MemEnv::new().read_all(Path::new("/x")).is_err());
+        assert!(env.cache_key(Path::new("/x")) != Path::new("/x"));
+    }
+}
@@ -0,0 +1,542 @@
+use aggregate::Aggregator;
+use catalog::Catalog;
+use window::WindowFunction;
+
+use std::fmt;
+use std::io;
+
+// An error in a query or expression, at a byte offset into its text.
+#[derive(Clone,Debug,PartialEq)]
+pub struct ParseError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{} at column {}", self.message, self.position + 1);
+    }
+}
+
+impl From<ParseError> for io::Error {
+    fn from(err: ParseError) -> io::Error {
+        return io::Error::new(io::ErrorKind::InvalidInput, err.to_string());
+    }
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum TokenKind {
+    Number(f64),
+    Ident(String),
+    Str(String),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match *self {
+            TokenKind::Number(n) => write!(f, "{}", n),
+            TokenKind::Ident(ref s) => write!(f, "{}", s),
+            TokenKind::Str(ref s) => write!(f, "'{}'", s),
+            TokenKind::Symbol(s) => write!(f, "{}", s),
+        };
+    }
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub position: usize,
+    pub end: usize,
+}
+
+// Longest first, so that e.g. '<=' isn't read as '<'.
+const SYMBOLS : [&str; 15] = [
+    "<=", ">=", "!=", "(", ")", ",", "+", "-", "*", "/", "=", "<", ">", "{", "}"];
+
+fn is_ident_start(c: char) -> bool {
+    return c.is_ascii_alphabetic() || c == '_';
+}
+
+fn is_ident_char(c: char) -> bool {
+    return c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == ':';
+}
+
+pub fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !c.is_ascii_digit() && c != '.' {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            match input[start..end].parse::<f64>() {
+                Ok(n) => tokens.push(Token{kind: TokenKind::Number(n), position: start, end: end}),
+                Err(_) => return Err(ParseError{
+                    position: start,
+                    message: format!("Invalid number '{}'", &input[start..end]),
+                }),
+            }
+        } else if is_ident_start(c) {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !is_ident_char(c) {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            tokens.push(Token{kind: TokenKind::Ident(input[start..end].to_string()), position: start, end: end});
+        } else if c == '\'' || c == '"' {
+            chars.next();
+            let mut value = String::new();
+            let mut end = None;
+            for (i, d) in chars.by_ref() {
+                if d == c {
+                    end = Some(i + 1);
+                    break;
+                }
+                value.push(d);
+            }
+            match end {
+                Some(end) => tokens.push(Token{kind: TokenKind::Str(value), position: start, end: end}),
+                None => return Err(ParseError{position: start, message: "Unterminated string".to_string()}),
+            }
+        } else {
+            match SYMBOLS.iter().find(|s| input[start..].starts_with(*s)) {
+                Some(s) => {
+                    for _ in 0..s.len() {
+                        chars.next();
+                    }
+                    tokens.push(Token{kind: TokenKind::Symbol(s), position: start, end: start + s.len()});
+                },
+                None => return Err(ParseError{
+                    position: start,
+                    message: format!("Unexpected character '{}'", c),
+                }),
+            }
+        }
+    }
+    return Ok(tokens);
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Function {
+    Aggregate(Aggregator),
+    Window(WindowFunction),
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum Expr {
+    Number(f64),
+    // The latest value of a series.
+    Series(String),
+    // A function of the points of a series.
+    Call(Function, String),
+    Neg(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+fn function_name(function: &Function) -> &'static str {
+    return match *function {
+        Function::Aggregate(Aggregator::Sum) => "sum",
+        Function::Aggregate(Aggregator::Avg) => "avg",
+        Function::Aggregate(Aggregator::Min) => "min",
+        Function::Aggregate(Aggregator::Max) => "max",
+        Function::Aggregate(Aggregator::Count) => "count",
+        Function::Aggregate(Aggregator::First) => "first",
+        Function::Aggregate(Aggregator::Last) => "last",
+        Function::Aggregate(Aggregator::Stddev) => "stddev",
+        Function::Aggregate(Aggregator::Quantile(_)) => "quantile",
+        Function::Window(WindowFunction::Rate) => "rate",
+        Function::Window(WindowFunction::Irate) => "irate",
+        Function::Window(WindowFunction::Increase) => "increase",
+        Function::Window(WindowFunction::Delta) => "delta",
+    };
+}
+
+// Series names that aren't plain identifiers, e.g. "cpu,host=a", are quoted.
+fn series_text(name: &str) -> String {
+    if name.starts_with(is_ident_start) && name.chars().all(is_ident_char) {
+        return name.to_string();
+    }
+    return format!("\"{}\"", name);
+}
+
+// Parseable text for the expression.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match *self {
+            Expr::Number(n) => write!(f, "{}", n),
+            Expr::Series(ref name) => write!(f, "{}", series_text(name)),
+            Expr::Call(Function::Aggregate(Aggregator::Quantile(q)), ref series) =>
+                write!(f, "quantile({}, {})", q, series_text(series)),
+            Expr::Call(ref function, ref series) =>
+                write!(f, "{}({})", function_name(function), series_text(series)),
+            Expr::Neg(ref e) => write!(f, "-{}", e),
+            Expr::Binary(op, ref a, ref b) => {
+                let op = match op {
+                    BinaryOp::Add => "+",
+                    BinaryOp::Sub => "-",
+                    BinaryOp::Mul => "*",
+                    BinaryOp::Div => "/",
+                };
+                write!(f, "({} {} {})", a, op, b)
+            },
+        };
+    }
+}
+
+// A recursive descent parser over the tokens of some text. Expressions are
+// arithmetic over numbers, series names and function calls, e.g.
+//   rate(errors) / rate(requests) * 100
+// Series names that aren't identifiers can be quoted: max("cpu,host=a").
+pub struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+    end: usize,
+}
+
+impl Parser {
+    pub fn new(input: &str) -> Result<Parser, ParseError> {
+        return Ok(Parser{
+            tokens: try!(tokenize(input)),
+            next: 0,
+            end: input.len(),
+        });
+    }
+
+    pub fn peek(&self) -> Option<&TokenKind> {
+        return self.tokens.get(self.next).map(|t| &t.kind);
+    }
+
+    // Where the next token starts, or the end of the text.
+    pub fn position(&self) -> usize {
+        return self.tokens.get(self.next).map(|t| t.position).unwrap_or(self.end);
+    }
+
+    // Whether the next token follows the previous one without a gap, as
+    // the unit in '5m' does.
+    pub fn adjacent(&self) -> bool {
+        return self.next > 0 && self.next < self.tokens.len() &&
+            self.tokens[self.next].position == self.tokens[self.next - 1].end;
+    }
+
+    pub fn error<T>(&self, message: String) -> Result<T, ParseError> {
+        return Err(ParseError{position: self.position(), message: message});
+    }
+
+    // An error describing the next token as unexpected.
+    pub fn unexpected<T>(&self, wanted: &str) -> Result<T, ParseError> {
+        return match self.peek() {
+            Some(kind) => self.error(format!("Expected {} but found '{}'", wanted, kind)),
+            None => self.error(format!("Expected {} but found the end of the query", wanted)),
+        };
+    }
+
+    pub fn next_token(&mut self) -> Option<TokenKind> {
+        let token = self.tokens.get(self.next).map(|t| t.kind.clone());
+        if token.is_some() {
+            self.next += 1;
+        }
+        return token;
+    }
+
+    pub fn eat_symbol(&mut self, symbol: &str) -> bool {
+        match self.peek() {
+            Some(&TokenKind::Symbol(s)) if s == symbol => {
+                self.next += 1;
+                return true;
+            },
+            _ => return false,
+        }
+    }
+
+    pub fn expect_symbol(&mut self, symbol: &str) -> Result<(), ParseError> {
+        if self.eat_symbol(symbol) {
+            return Ok(());
+        }
+        return self.unexpected(&format!("'{}'", symbol));
+    }
+
+    pub fn expect_ident(&mut self) -> Result<String, ParseError> {
+        if let Some(TokenKind::Ident(s)) = self.peek() {
+            let s = s.clone();
+            self.next += 1;
+            return Ok(s);
+        }
+        return self.unexpected("a name");
+    }
+
+    // Consumes the next token if it's the keyword 'keyword', in any case.
+    pub fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(TokenKind::Ident(s)) if s.eq_ignore_ascii_case(keyword) => (),
+            _ => return false,
+        }
+        self.next += 1;
+        return true;
+    }
+
+    pub fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        if self.eat_keyword(keyword) {
+            return Ok(());
+        }
+        return self.unexpected(keyword);
+    }
+
+    pub fn expect_string(&mut self) -> Result<String, ParseError> {
+        if let Some(TokenKind::Str(s)) = self.peek() {
+            let s = s.clone();
+            self.next += 1;
+            return Ok(s);
+        }
+        return self.unexpected("a quoted string");
+    }
+
+    // A series name, either bare or quoted.
+    pub fn expect_series(&mut self) -> Result<String, ParseError> {
+        if let Some(&TokenKind::Str(_)) = self.peek() {
+            return self.expect_string();
+        }
+        return self.expect_ident();
+    }
+
+    pub fn expect_number(&mut self) -> Result<f64, ParseError> {
+        if let Some(&TokenKind::Number(n)) = self.peek() {
+            self.next += 1;
+            return Ok(n);
+        }
+        return self.unexpected("a number");
+    }
+
+    // Fails unless every token has been consumed.
+    pub fn finish(&self) -> Result<(), ParseError> {
+        if self.peek().is_some() {
+            return self.unexpected("the end of the query");
+        }
+        return Ok(());
+    }
+
+    pub fn expr(&mut self) -> Result<Expr, ParseError> {
+        let mut e = try!(self.term());
+        loop {
+            let op = if self.eat_symbol("+") {
+                BinaryOp::Add
+            } else if self.eat_symbol("-") {
+                BinaryOp::Sub
+            } else {
+                return Ok(e);
+            };
+            e = Expr::Binary(op, Box::new(e), Box::new(try!(self.term())));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, ParseError> {
+        let mut e = try!(self.factor());
+        loop {
+            let op = if self.eat_symbol("*") {
+                BinaryOp::Mul
+            } else if self.eat_symbol("/") {
+                BinaryOp::Div
+            } else {
+                return Ok(e);
+            };
+            e = Expr::Binary(op, Box::new(e), Box::new(try!(self.factor())));
+        }
+    }
+
+    fn factor(&mut self) -> Result<Expr, ParseError> {
+        if self.eat_symbol("-") {
+            return Ok(Expr::Neg(Box::new(try!(self.factor()))));
+        }
+        if self.eat_symbol("(") {
+            let e = try!(self.expr());
+            try!(self.expect_symbol(")"));
+            return Ok(e);
+        }
+        if let Some(&TokenKind::Number(n)) = self.peek() {
+            self.next += 1;
+            return Ok(Expr::Number(n));
+        }
+        if let Some(&TokenKind::Str(_)) = self.peek() {
+            return Ok(Expr::Series(try!(self.expect_series())));
+        }
+
+        let position = self.position();
+        let name = match self.peek() {
+            Some(&TokenKind::Ident(_)) => try!(self.expect_ident()),
+            _ => return self.unexpected("a number, series or function"),
+        };
+        if !self.eat_symbol("(") {
+            return Ok(Expr::Series(name));
+        }
+
+        let function = match parse_function(&name) {
+            Some(f) => f,
+            None if name == "quantile" => {
+                let q_position = self.position();
+                let q = try!(self.expect_number());
+                if !(0.0..=1.0).contains(&q) {
+                    return Err(ParseError{
+                        position: q_position,
+                        message: "Quantile must be between 0 and 1".to_string(),
+                    });
+                }
+                try!(self.expect_symbol(","));
+                Function::Aggregate(Aggregator::Quantile(q))
+            },
+            None => return Err(ParseError{
+                position: position,
+                message: format!("Unknown function '{}'", name),
+            }),
+        };
+        let series = try!(self.expect_series());
+        try!(self.expect_symbol(")"));
+        return Ok(Expr::Call(function, series));
+    }
+}
+
+// The function with the given name, other than quantile (which takes an
+// extra argument).
+pub fn parse_function(name: &str) -> Option<Function> {
+    return match name {
+        "sum" => Some(Function::Aggregate(Aggregator::Sum)),
+        "avg" | "mean" => Some(Function::Aggregate(Aggregator::Avg)),
+        "min" => Some(Function::Aggregate(Aggregator::Min)),
+        "max" => Some(Function::Aggregate(Aggregator::Max)),
+        "count" => Some(Function::Aggregate(Aggregator::Count)),
+        "first" => Some(Function::Aggregate(Aggregator::First)),
+        "last" => Some(Function::Aggregate(Aggregator::Last)),
+        "stddev" => Some(Function::Aggregate(Aggregator::Stddev)),
+        "rate" => Some(Function::Window(WindowFunction::Rate)),
+        "irate" => Some(Function::Window(WindowFunction::Irate)),
+        "increase" => Some(Function::Window(WindowFunction::Increase)),
+        "delta" => Some(Function::Window(WindowFunction::Delta)),
+        _ => None,
+    };
+}
+
+pub fn parse(input: &str) -> Result<Expr, ParseError> {
+    let mut parser = try!(Parser::new(input));
+    let e = try!(parser.expr());
+    try!(parser.finish());
+    return Ok(e);
+}
+
+impl Expr {
+    // Evaluates the expression over the window [start, end). Functions are
+    // computed over the series' points in the window, and series on their
+    // own take their latest value before 'end'. Returns None if a series has
+    // no value or the result isn't a finite number.
+    pub fn evaluate(&self, catalog: &mut Catalog, start: u64, end: u64) -> io::Result<Option<f64>> {
+        let value = match *self {
+            Expr::Number(n) => Some(n),
+            Expr::Series(ref name) => match try!(catalog.get(name)) {
+                Some(db) => try!(db.last_before(end)).map(|(_, v)| v as f64),
+                None => None,
+            },
+            Expr::Call(function, ref name) => {
+                let db = match try!(catalog.get(name)) {
+                    Some(db) => db,
+                    None => return Ok(None),
+                };
+                if start >= end {
+                    return Ok(None);
+                }
+                match function {
+                    Function::Aggregate(aggregator) => {
+                        let mut buckets = try!(db.aggregate(start, end, end - start, aggregator));
+                        let value = buckets.next().map(|b| b.value);
+                        try!(buckets.take_status());
+                        value
+                    },
+                    Function::Window(function) => try!(db.evaluate_window(function, start, end)),
+                }
+            },
+            Expr::Neg(ref e) => try!(e.evaluate(catalog, start, end)).map(|v| -v),
+            Expr::Binary(op, ref a, ref b) => {
+                let a = match try!(a.evaluate(catalog, start, end)) {
+                    Some(a) => a,
+                    None => return Ok(None),
+                };
+                let b = match try!(b.evaluate(catalog, start, end)) {
+                    Some(b) => b,
+                    None => return Ok(None),
+                };
+                Some(match op {
+                    BinaryOp::Add => a + b,
+                    BinaryOp::Sub => a - b,
+                    BinaryOp::Mul => a * b,
+                    BinaryOp::Div => a / b,
+                })
+            },
+        };
+        return Ok(value.filter(|v| v.is_finite()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use aggregate::Aggregator;
+    use window::WindowFunction;
+
+    use super::BinaryOp;
+    use super::Expr;
+    use super::Function;
+    use super::parse;
+
+    #[test]
+    fn parse_expressions() {
+        assert_eq!(Expr::Binary(
+            BinaryOp::Mul,
+            Box::new(Expr::Binary(
+                BinaryOp::Div,
+                Box::new(Expr::Call(Function::Window(WindowFunction::Rate), "errors".to_string())),
+                Box::new(Expr::Call(Function::Window(WindowFunction::Rate), "requests".to_string())))),
+            Box::new(Expr::Number(100.0))),
+                   parse("rate(errors) / rate(requests) * 100").unwrap());
+
+        assert_eq!(Expr::Binary(
+            BinaryOp::Sub,
+            Box::new(Expr::Series("a".to_string())),
+            Box::new(Expr::Binary(BinaryOp::Mul,
+                                  Box::new(Expr::Neg(Box::new(Expr::Series("b".to_string())))),
+                                  Box::new(Expr::Number(2.5))))),
+                   parse("a - -b * 2.5").unwrap());
+
+        let e = parse("quantile(0.99, latency) + (avg(cpu.user) - 1)").unwrap();
+        assert_eq!(Expr::Call(Function::Aggregate(Aggregator::Quantile(0.99)), "latency".to_string()),
+                   match e { Expr::Binary(_, ref a, _) => (**a).clone(), _ => panic!() });
+        assert_eq!(e, parse(&e.to_string()).unwrap());
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!("Expected ')' but found the end of the query at column 10",
+                   parse("rate(cpu ").unwrap_err().to_string());
+        assert_eq!("Unknown function 'bogus' at column 5",
+                   parse("1 + bogus(cpu)").unwrap_err().to_string());
+        assert_eq!("Expected the end of the query but found 'b' at column 3",
+                   parse("a b").unwrap_err().to_string());
+        assert_eq!("Unexpected character '#' at column 3",
+                   parse("a #").unwrap_err().to_string());
+        assert_eq!("Quantile must be between 0 and 1 at column 10",
+                   parse("quantile(1.5, x)").unwrap_err().to_string());
+    }
+}
@@ -0,0 +1,700 @@
+use env::Env;
+use env::FileContents;
+use env::ReadableFile;
+use env::WritableFile;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+// Torn writes keep or lose whole sectors.
+pub const SECTOR_SIZE : u64 = 512;
+
+// What goes wrong. Operations are numbered from zero, in the order they're
+// made: creates, writes, renames and deletes all count as writes.
+#[derive(Clone,Copy,Debug,Default)]
+pub struct Faults {
+    // The write with this number fails, and so does everything after it
+    // that would change a file, as if the process had died there.
+    pub fail_write: Option<u64>,
+    // Likewise for syncs.
+    pub fail_sync: Option<u64>,
+    // If set, a crash keeps a random number of whole sectors of the data
+    // written to each file since it was last synced, and a random subset of
+    // the creates, renames and deletes since their directory was last
+    // synced, in any order, rather than none.
+    pub torn_writes: bool,
+    // Flips bit .1 (modulo the length of the file, in bits) of what the
+    // read (open or read_all) numbered .0 returns.
+    pub flip_bit: Option<(u64, u64)>,
+    // Seeds the choices made by torn writes.
+    pub seed: u64,
+}
+
+// How much has been written to a file, and how much of it is durable.
+struct Progress {
+    written: u64,
+    synced: u64,
+}
+
+// A change to a directory that isn't durable yet, with what a crash needs
+// to undo it: the durable contents of any file it replaced or deleted.
+enum Change {
+    Created{path: path::PathBuf, replaced: Option<Vec<u8>>},
+    Renamed{from: path::PathBuf, to: path::PathBuf, replaced: Option<Vec<u8>>},
+    Deleted{path: path::PathBuf, data: Vec<u8>},
+}
+
+impl Change {
+    // The directory that has to be synced to make the change durable; for
+    // a rename, the target's.
+    fn dir(&self) -> Option<&path::Path> {
+        return match *self {
+            Change::Created{ref path, ..} => path.parent(),
+            Change::Renamed{ref to, ..} => to.parent(),
+            Change::Deleted{ref path, ..} => path.parent(),
+        };
+    }
+}
+
+struct FaultState {
+    faults: Faults,
+    writes: u64,
+    syncs: u64,
+    reads: u64,
+    // Set once an injected failure fires; cleared by a crash.
+    dead: bool,
+    // Counts crashes, so that writers opened before one stop working.
+    generation: u64,
+    // Files with data that might not be durable.
+    files: BTreeMap<path::PathBuf, Arc<Mutex<Progress>>>,
+    // Creates, renames and deletes that might not be durable, oldest first.
+    changes: Vec<Change>,
+    random: u64,
+}
+
+// Wraps another Env (normally a MemEnv) to simulate failures and crashes:
+// injected errors, losing data that was never synced, losing creates,
+// renames and deletes in directories that were never synced, torn writes
+// and bit flips. Clones share the same state.
+#[derive(Clone)]
+pub struct FaultEnv {
+    inner: Arc<dyn Env>,
+    state: Arc<Mutex<FaultState>>,
+}
+
+fn injected(what: &str, n: u64) -> io::Error {
+    return io::Error::other(format!("Injected failure of {} {}", what, n));
+}
+
+fn dead() -> io::Error {
+    return io::Error::other("Injected failure: the process has crashed");
+}
+
+// xorshift64*: deterministic for a given seed, which is all a test needs.
+pub fn next_random(state: &mut u64) -> u64 {
+    if *state == 0 {
+        *state = 0x9e3779b97f4a7c15;
+    }
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    return state.wrapping_mul(0x2545f4914f6cdd1d);
+}
+
+impl FaultEnv {
+    pub fn new(inner: Arc<dyn Env>) -> FaultEnv {
+        return FaultEnv{
+            inner: inner,
+            state: Arc::new(Mutex::new(FaultState{
+                faults: Faults::default(),
+                writes: 0,
+                syncs: 0,
+                reads: 0,
+                dead: false,
+                generation: 0,
+                files: BTreeMap::new(),
+                changes: Vec::new(),
+                random: 0,
+            })),
+        };
+    }
+
+    pub fn set_faults(&self, faults: Faults) {
+        let mut state = self.state.lock().unwrap();
+        state.faults = faults;
+        state.random = faults.seed;
+    }
+
+    // The number of writes and syncs made so far, e.g. to find out how many
+    // injection points a workload has.
+    pub fn counts(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        return (state.writes, state.syncs);
+    }
+
+    // Whether an injected failure has fired since the last crash.
+    pub fn has_failed(&self) -> bool {
+        return self.state.lock().unwrap().dead;
+    }
+
+    // Simulates losing power: directory changes and data that were never
+    // synced are undone (or, with torn writes, partly kept), and files open
+    // for writing stop working. The faults are cleared, so that the
+    // restarted process runs cleanly until set_faults is called again.
+    pub fn crash(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let changes = std::mem::take(&mut state.changes);
+        for change in changes.into_iter().rev() {
+            if state.faults.torn_writes && next_random(&mut state.random).is_multiple_of(2) {
+                continue;
+            }
+            try!(self.undo(&mut state, change));
+        }
+
+        let files = std::mem::take(&mut state.files);
+        for (path, progress) in files {
+            let progress = progress.lock().unwrap();
+            let mut keep = progress.synced;
+            if state.faults.torn_writes && progress.written > progress.synced {
+                // Any sector boundary past the synced data, or the end.
+                let first = progress.synced.div_ceil(SECTOR_SIZE);
+                let last = progress.written / SECTOR_SIZE;
+                let choices = last.saturating_sub(first) + 2;
+                let choice = next_random(&mut state.random) % choices;
+                keep = if choice == 0 {
+                    progress.synced
+                } else if choice == choices - 1 {
+                    progress.written
+                } else {
+                    (first + choice - 1) * SECTOR_SIZE
+                };
+                keep = ::std::cmp::max(keep, progress.synced);
+            }
+
+            let data = try!(self.inner.read_all(&path));
+            if (keep as usize) < data.len() {
+                let mut file = try!(self.inner.create(&path));
+                try!(file.write_all(&data[0..(keep as usize)]));
+                try!(file.sync());
+            }
+        }
+        state.faults = Faults::default();
+        state.dead = false;
+        state.generation += 1;
+        return Ok(());
+    }
+
+    fn undo(&self, state: &mut FaultState, change: Change) -> io::Result<()> {
+        let ignore_missing = |result: io::Result<()>| match result {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        };
+        match change {
+            Change::Created{path, replaced} => {
+                try!(ignore_missing(self.inner.delete(&path)));
+                state.files.remove(&path);
+                if let Some(data) = replaced {
+                    try!(self.restore(state, &path, &data));
+                }
+            },
+            Change::Renamed{from, to, replaced} => {
+                try!(ignore_missing(self.inner.rename(&to, &from)));
+                if let Some(progress) = state.files.remove(&to) {
+                    state.files.insert(from, progress);
+                }
+                if let Some(data) = replaced {
+                    try!(self.restore(state, &to, &data));
+                }
+            },
+            Change::Deleted{path, data} => try!(self.restore(state, &path, &data)),
+        }
+        return Ok(());
+    }
+
+    fn restore(&self, state: &mut FaultState, path: &path::Path, data: &[u8]) -> io::Result<()> {
+        let mut file = try!(self.inner.create(path));
+        try!(file.write_all(data));
+        try!(file.sync());
+        state.files.remove(path);
+        return Ok(());
+    }
+
+    // The durable contents of the file at 'path', if there is one.
+    fn durable(&self, state: &FaultState, path: &path::Path) -> io::Result<Option<Vec<u8>>> {
+        let data = match self.inner.read_all(path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let synced = state.files.get(path).map(|p| p.lock().unwrap().synced as usize);
+        return Ok(Some(data[0..synced.unwrap_or(data.len()).min(data.len())].to_vec()));
+    }
+
+    fn check_write(state: &mut FaultState) -> io::Result<()> {
+        if state.dead {
+            return Err(dead());
+        }
+        let n = state.writes;
+        state.writes += 1;
+        if state.faults.fail_write == Some(n) {
+            state.dead = true;
+            return Err(injected("write", n));
+        }
+        return Ok(());
+    }
+
+    fn check_sync(state: &mut FaultState) -> io::Result<()> {
+        if state.dead {
+            return Err(dead());
+        }
+        let n = state.syncs;
+        state.syncs += 1;
+        if state.faults.fail_sync == Some(n) {
+            state.dead = true;
+            return Err(injected("sync", n));
+        }
+        return Ok(());
+    }
+
+    // The bit to flip in the next read, if it's the one to corrupt.
+    fn check_read(&self) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        let n = state.reads;
+        state.reads += 1;
+        return match state.faults.flip_bit {
+            Some((read, bit)) if read == n => Some(bit),
+            _ => None,
+        };
+    }
+}
+
+fn flip(data: &mut [u8], bit: u64) {
+    if !data.is_empty() {
+        let bit = bit % (data.len() as u64 * 8);
+        data[(bit / 8) as usize] ^= 1 << (bit % 8);
+    }
+}
+
+struct FaultFile {
+    file: Box<dyn WritableFile>,
+    state: Arc<Mutex<FaultState>>,
+    progress: Arc<Mutex<Progress>>,
+    generation: u64,
+}
+
+impl FaultFile {
+    fn check_alive(&self, state: &FaultState) -> io::Result<()> {
+        if state.generation != self.generation {
+            return Err(dead());
+        }
+        return Ok(());
+    }
+}
+
+impl io::Write for FaultFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        try!(self.check_alive(&state));
+        try!(FaultEnv::check_write(&mut state));
+        try!(self.file.write_all(buf));
+        self.progress.lock().unwrap().written += buf.len() as u64;
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl WritableFile for FaultFile {
+    fn sync(&mut self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        try!(self.check_alive(&state));
+        try!(FaultEnv::check_sync(&mut state));
+        try!(self.file.sync());
+        let mut progress = self.progress.lock().unwrap();
+        progress.synced = progress.written;
+        return Ok(());
+    }
+}
+
+impl Env for FaultEnv {
+    fn create(&self, path: &path::Path) -> io::Result<Box<dyn WritableFile>> {
+        let mut state = self.state.lock().unwrap();
+        try!(FaultEnv::check_write(&mut state));
+        let replaced = try!(self.durable(&state, path));
+        let file = try!(self.inner.create(path));
+        state.changes.push(Change::Created{path: path.to_path_buf(), replaced: replaced});
+        let progress = Arc::new(Mutex::new(Progress{written: 0, synced: 0}));
+        state.files.insert(path.to_path_buf(), progress.clone());
+        return Ok(Box::new(FaultFile{
+            file: file,
+            state: self.state.clone(),
+            progress: progress,
+            generation: state.generation,
+        }));
+    }
+
+    fn open(&self, path: &path::Path) -> io::Result<Box<dyn ReadableFile>> {
+        let bit = match self.check_read() {
+            Some(bit) => bit,
+            None => return self.inner.open(path),
+        };
+        let mut data = Vec::new();
+        try!(try!(self.inner.open(path)).read_to_end(&mut data));
+        flip(&mut data, bit);
+        return Ok(Box::new(io::Cursor::new(data)));
+    }
+
+    fn read_all(&self, path: &path::Path) -> io::Result<FileContents> {
+        let bit = match self.check_read() {
+            Some(bit) => bit,
+            None => return self.inner.read_all(path),
+        };
+        let mut data = try!(self.inner.read_all(path)).to_vec();
+        flip(&mut data, bit);
+        return Ok(FileContents::Memory(Arc::new(data)));
+    }
+
+    fn rename(&self, from: &path::Path, to: &path::Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        try!(FaultEnv::check_write(&mut state));
+        let replaced = try!(self.durable(&state, to));
+        try!(self.inner.rename(from, to));
+        state.changes.push(Change::Renamed{
+            from: from.to_path_buf(), to: to.to_path_buf(), replaced: replaced});
+        match state.files.remove(from) {
+            Some(progress) => state.files.insert(to.to_path_buf(), progress),
+            None => state.files.remove(to),
+        };
+        return Ok(());
+    }
+
+    fn delete(&self, path: &path::Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        try!(FaultEnv::check_write(&mut state));
+        let data = try!(self.durable(&state, path));
+        try!(self.inner.delete(path));
+        state.changes.push(Change::Deleted{path: path.to_path_buf(), data: data.unwrap_or_default()});
+        state.files.remove(path);
+        return Ok(());
+    }
+
+    fn sync_dir(&self, dir: &path::Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        try!(FaultEnv::check_sync(&mut state));
+        try!(self.inner.sync_dir(dir));
+        state.changes.retain(|c| c.dir() != Some(dir));
+        return Ok(());
+    }
+
+    fn list(&self, dir: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+        return self.inner.list(dir);
+    }
+
+    fn list_dirs(&self, dir: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+        return self.inner.list_dirs(dir);
+    }
+
+    fn create_dir(&self, dir: &path::Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        try!(FaultEnv::check_write(&mut state));
+        return self.inner.create_dir(dir);
+    }
+
+    fn is_dir(&self, path: &path::Path) -> io::Result<bool> {
+        return self.inner.is_dir(path);
+    }
+
+    // A restarted process starts with a cold cache.
+    fn cache_key(&self, path: &path::Path) -> path::PathBuf {
+        let generation = self.state.lock().unwrap().generation;
+        let mut key = self.inner.cache_key(path).into_os_string();
+        key.push(format!("@{}", generation));
+        return path::PathBuf::from(key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FaultEnv;
+    use super::Faults;
+    use super::next_random;
+
+    use db::Db;
+    use db::Options;
+    use env::Env;
+    use env::MemEnv;
+    use format;
+    use table;
+
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    fn fault_env() -> FaultEnv {
+        return FaultEnv::new(Arc::new(MemEnv::new()));
+    }
+
+    #[test]
+    fn crash_drops_unsynced_data() {
+        let env = fault_env();
+        let mut f = env.create(Path::new("/a")).unwrap();
+        f.write_all(&[1; 100]).unwrap();
+        f.sync().unwrap();
+        env.sync_dir(Path::new("/")).unwrap();
+        f.write_all(&[2; 100]).unwrap();
+        let mut g = env.create(Path::new("/b")).unwrap();
+        g.write_all(&[3; 10]).unwrap();
+        g.sync().unwrap();
+        assert_eq!(200, env.read_all(Path::new("/a")).unwrap().len());
+
+        env.crash().unwrap();
+        assert_eq!(vec![1; 100], env.read_all(Path::new("/a")).unwrap().to_vec());
+        // /b was synced, but its directory wasn't.
+        assert_eq!(Some(io::ErrorKind::NotFound), env.read_all(Path::new("/b")).err().map(|e| e.kind()));
+        // Writers from before the crash are gone.
+        assert!(f.write_all(&[4]).is_err());
+    }
+
+    #[test]
+    fn crash_drops_unsynced_directory_changes() {
+        let env = fault_env();
+        for name in &["/a", "/b", "/c"] {
+            let mut f = env.create(Path::new(name)).unwrap();
+            f.write_all(name.as_bytes()).unwrap();
+            f.sync().unwrap();
+        }
+        env.sync_dir(Path::new("/")).unwrap();
+
+        // Without a sync_dir, the rename over /b and the delete are undone.
+        env.rename(Path::new("/a"), Path::new("/b")).unwrap();
+        env.delete(Path::new("/c")).unwrap();
+        env.crash().unwrap();
+        assert_eq!(b"/a".to_vec(), env.read_all(Path::new("/a")).unwrap().to_vec());
+        assert_eq!(b"/b".to_vec(), env.read_all(Path::new("/b")).unwrap().to_vec());
+        assert_eq!(b"/c".to_vec(), env.read_all(Path::new("/c")).unwrap().to_vec());
+
+        // With one, they're kept.
+        env.rename(Path::new("/a"), Path::new("/b")).unwrap();
+        env.delete(Path::new("/c")).unwrap();
+        env.sync_dir(Path::new("/")).unwrap();
+        env.crash().unwrap();
+        assert_eq!(vec!["/b".to_string()], env.list(Path::new("/")).unwrap()
+            .iter().map(|p| p.to_string_lossy().into_owned()).collect::<Vec<_>>());
+        assert_eq!(b"/a".to_vec(), env.read_all(Path::new("/b")).unwrap().to_vec());
+    }
+
+    #[test]
+    fn torn_writes() {
+        let mut lengths = Vec::new();
+        for seed in 0..20 {
+            let env = fault_env();
+            env.set_faults(Faults{torn_writes: true, seed: seed, ..Faults::default()});
+            let mut f = env.create(Path::new("/a")).unwrap();
+            f.write_all(&[1; 100]).unwrap();
+            f.sync().unwrap();
+            env.sync_dir(Path::new("/")).unwrap();
+            f.write_all(&[2; 2000]).unwrap();
+            env.crash().unwrap();
+            lengths.push(env.read_all(Path::new("/a")).unwrap().len());
+        }
+        lengths.sort();
+        lengths.dedup();
+        // Lost, kept to some sector boundary or kept whole.
+        assert!(lengths.len() > 2, "{:?}", lengths);
+        assert!(lengths.iter().all(|&l| l == 100 || l == 2100 || l % 512 == 0), "{:?}", lengths);
+    }
+
+    #[test]
+    fn fail_nth() {
+        let env = fault_env();
+        env.set_faults(Faults{fail_write: Some(2), ..Faults::default()});
+        let mut f = env.create(Path::new("/a")).unwrap();
+        f.write_all(&[1]).unwrap();
+        assert!(!env.has_failed());
+        assert!(f.write_all(&[2]).is_err());
+        assert!(env.has_failed());
+        // Everything after the failure fails too.
+        assert!(f.sync().is_err());
+        assert!(env.delete(Path::new("/a")).is_err());
+
+        env.crash().unwrap();
+        env.set_faults(Faults{fail_sync: Some(1), ..Faults::default()});
+        let mut f = env.create(Path::new("/a")).unwrap();
+        f.sync().unwrap();
+        assert!(f.sync().is_err());
+        assert_eq!((4, 2), env.counts());
+    }
+
+    #[test]
+    fn bit_flips_are_caught() {
+        let env = fault_env();
+        let points : Vec<(u64, u64)> = (0..10000).map(|i| (i, i * 3)).collect();
+        table::TableBuilder::write_records_with_env(
+            &env, "/t", points.iter().cloned(), &table::TableOptions::default()).unwrap();
+        let data_len = table::TableReader::open_with_env(
+            &env, "/t", &table::ReadOptions::default(), None).unwrap()
+            .index().iter().map(|h| h.length).sum::<u64>();
+
+        // Reads are numbered from the first one above.
+        let mut random = 1;
+        for read in 1..21 {
+            let bit = next_random(&mut random) % (data_len * 8);
+            env.set_faults(Faults{flip_bit: Some((read, bit)), ..Faults::default()});
+            let reader = table::TableReader::open_with_env(
+                &env, "/t", &table::ReadOptions::default(), None).unwrap();
+            let mut errors = 0;
+            for b in 0..reader.index().len() {
+                match reader.block(b) {
+                    Ok(block) => {
+                        for r in 0..block.len() {
+                            let (k, v) = block.get(r);
+                            assert_eq!(k * 3, v);
+                        }
+                    },
+                    Err(e) => {
+                        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+                        errors += 1;
+                    },
+                }
+            }
+            assert_eq!(1, errors);
+        }
+    }
+
+    const KEYS : u64 = 50;
+
+    // What a workload has done: the writes it has been told are durable,
+    // and the one in progress when it failed, which may or may not be.
+    #[derive(Default)]
+    struct Outcome {
+        acked: BTreeMap<u64, u64>,
+        pending: Option<(u64, u64)>,
+    }
+
+    fn options(env: &FaultEnv) -> Options {
+        return Options{
+            env: Arc::new(env.clone()),
+            sync_writes: true,
+            max_open_tables: 2,
+            ..Options::default()
+        };
+    }
+
+    // Records random points, now and then compacting or reopening the Db,
+    // until the workload is done or something fails.
+    fn workload(env: &FaultEnv, seed: u64, outcome: &mut Outcome) -> io::Result<()> {
+        let mut random = seed;
+        let mut db = try!(Db::with_options("/db", options(env)));
+        for _ in 0..80 {
+            match next_random(&mut random) % 25 {
+                0 => try!(db.compact()),
+                1 => db = try!(Db::with_options("/db", options(env))),
+                _ => {
+                    let ts = next_random(&mut random) % KEYS;
+                    let value = next_random(&mut random);
+                    outcome.pending = Some((ts, value));
+                    try!(db.record(&format::Rec{timestamp: ts, value: value}));
+                    outcome.acked.insert(ts, value);
+                    outcome.pending = None;
+                },
+            }
+        }
+        return Ok(());
+    }
+
+    fn check_recovery(env: &FaultEnv, outcome: &Outcome, what: &str) {
+        let mut db = Db::with_options("/db", options(env)).expect(what);
+        for ts in 0..KEYS {
+            let found = match db.lookup(ts) {
+                Ok(v) => Some(v),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => panic!("{}: lookup({}): {}", what, ts, e),
+            };
+            let acked = outcome.acked.get(&ts).cloned();
+            match outcome.pending {
+                Some((pending_ts, value)) if pending_ts == ts =>
+                    assert!(found == acked || found == Some(value),
+                            "{}: {} is {:?}, not {:?} or {}", what, ts, found, acked, value),
+                _ => assert_eq!(acked, found, "{}: {}", what, ts),
+            }
+        }
+    }
+
+    #[test]
+    fn crash_at_every_injection_point() {
+        for seed in 1..3 {
+            let env = fault_env();
+            workload(&env, seed, &mut Outcome::default()).unwrap();
+            let (writes, syncs) = env.counts();
+
+            let runs = (0..writes).map(|n| Faults{fail_write: Some(n), ..Faults::default()})
+                .chain((0..syncs).map(|n| Faults{fail_sync: Some(n), ..Faults::default()}));
+            for (i, mut faults) in runs.enumerate() {
+                faults.torn_writes = i % 2 == 1;
+                faults.seed = i as u64;
+
+                let env = fault_env();
+                env.set_faults(faults);
+                let mut outcome = Outcome::default();
+                assert!(workload(&env, seed, &mut outcome).is_err());
+                assert!(env.has_failed());
+                env.crash().unwrap();
+                check_recovery(&env, &outcome, &format!("seed {}, {:?}", seed, faults));
+            }
+        }
+    }
+
+    // Fills a few tables, so that there's something to compact.
+    fn compaction_setup(env: &FaultEnv) -> Outcome {
+        let mut outcome = Outcome::default();
+        for session in 0..3 {
+            let mut db = Db::with_options("/db", options(env)).unwrap();
+            for ts in (session..KEYS).step_by(2) {
+                db.record(&format::Rec{timestamp: ts, value: session}).unwrap();
+                outcome.acked.insert(ts, session);
+            }
+        }
+        return outcome;
+    }
+
+    #[test]
+    fn crash_during_compaction() {
+        let env = fault_env();
+        let outcome = compaction_setup(&env);
+        let mut db = Db::with_options("/db", options(&env)).unwrap();
+        let (writes, syncs) = env.counts();
+        db.compact().unwrap();
+        let (all_writes, all_syncs) = env.counts();
+        env.crash().unwrap();
+        check_recovery(&env, &outcome, "after compaction");
+
+        let runs = (writes..all_writes).map(|n| Faults{fail_write: Some(n), ..Faults::default()})
+            .chain((syncs..all_syncs).map(|n| Faults{fail_sync: Some(n), ..Faults::default()}));
+        // Several torn crashes at each point, as each keeps a different
+        // subset of the unsynced changes.
+        let runs = runs.flat_map(|faults| (0..8).map(move |seed| Faults{
+            torn_writes: seed > 0, seed: seed, ..faults}));
+        for faults in runs {
+            let env = fault_env();
+            env.set_faults(faults);
+            let outcome = compaction_setup(&env);
+            let mut db = Db::with_options("/db", options(&env)).unwrap();
+            assert!(db.compact().is_err());
+            env.crash().unwrap();
+            let what = format!("{:?}", faults);
+            check_recovery(&env, &outcome, &what);
+            // A later compaction cleans up whatever was left behind.
+            Db::with_options("/db", options(&env)).unwrap().compact().expect(&what);
+            check_recovery(&env, &outcome, &what);
+        }
+    }
+}
@@ -1,13 +1,18 @@
 extern crate regex;
 
-use std::fs;
+use env::Env;
+use env::PosixEnv;
+
 use std::io;
 use std::path;
+use std::sync::Arc;
 use std::vec::Vec;
 
 pub struct FileManager {
     root: path::PathBuf,
-    log_path: Option<String>,
+    env: Arc<dyn Env>,
+    // Oldest first, so the last is the one in use.
+    log_paths: Vec<String>,
     log_version: usize,
 
     table_paths: Vec<String>,
@@ -15,37 +20,37 @@ pub struct FileManager {
 }
 
 fn log_file_version(filename: &str) -> Option<usize> {
-    let log_re = regex::Regex::new(r".*/log_([0-9]+)").unwrap();
+    let log_re = regex::Regex::new(r".*/log_([0-9]+)$").unwrap();
         
     return log_re.captures(filename).and_then(
-        |caps| caps.at(1).and_then(
-            (|val| return val.parse::<usize>().ok())));
+        |caps| caps.get(1).map(|m| m.as_str()).and_then(
+            |val| return val.parse::<usize>().ok()));
 }
 
 fn table_file_version(filename: &str) -> Option<usize> {
-    let log_re = regex::Regex::new(r".*/table_([0-9]+)").unwrap();
+    let log_re = regex::Regex::new(r".*/table_([0-9]+)$").unwrap();
         
     return log_re.captures(filename).and_then(
-        |caps| caps.at(1).and_then(
-            (|val| return val.parse::<usize>().ok())));
+        |caps| caps.get(1).map(|m| m.as_str()).and_then(
+            |val| return val.parse::<usize>().ok()));
 }
 
 impl FileManager {
     pub fn open_or_create<P: AsRef<path::Path>>(dir: P) -> io::Result<FileManager> {
-        let md = fs::metadata(dir.as_ref());
+        return FileManager::open_or_create_with_env(PosixEnv::shared(), dir);
+    }
 
-        match md {
+    pub fn open_or_create_with_env<P: AsRef<path::Path>>(env: Arc<dyn Env>, dir: P) -> io::Result<FileManager> {
+        match env.is_dir(dir.as_ref()) {
             Err(err) => {
                 if err.kind() != io::ErrorKind::NotFound {
                     return Err(err);
                 } else {
-                    println!("Creating new FileManager dir");
-                    try!(fs::create_dir(dir.as_ref()));
+                    try!(env.create_dir(dir.as_ref()));
                 }
             },
-            Ok(md) => {
-                println!("Recovering old FileManager dir");
-                if !md.is_dir() {
+            Ok(is_dir) => {
+                if !is_dir {
                     return Err(io::Error::new(
                         io::ErrorKind::InvalidInput,
                         format!("'{:?}' is not a directory.", dir.as_ref())));
@@ -53,47 +58,43 @@ impl FileManager {
             }
         }
 
+        let root = dir.as_ref().to_path_buf();
+
         let mut max_log_version : Option<usize> = None;
-        let mut max_log_path = None;
+        let mut log_paths = Vec::new();
         let mut max_table_version : Option<usize> = None;
         let mut table_paths = Vec::new();
         
-        for entry in try!(fs::read_dir(dir.as_ref())) {
-            let entry = try!(entry);
-            println!("Examining: {:?}", entry.path());
-            if !try!(fs::metadata(entry.path())).is_dir() {
-                match entry.path().to_str().and_then(log_file_version) {
-                    Some(v) => {
-                        println!("Extracted log: {}", v);
-                        if max_log_version.is_none() ||
-                            v > max_log_version.unwrap() {
-                                max_log_version = Some(v);
-                                max_log_path = entry.path().to_str()
-                                    .and_then(|s| Some(s.to_string()));
-                        }
-                    }
-                    None => (),
+        for path in try!(env.list(&root)) {
+            let path = match path.to_str() {
+                Some(path) => path.to_string(),
+                None => continue,
+            };
+            if let Some(v) = log_file_version(&path) {
+                log_paths.push(path.clone());
+                if max_log_version.is_none() ||
+                    v > max_log_version.unwrap() {
+                        max_log_version = Some(v);
                 }
-                match entry.path().to_str().and_then(table_file_version) {
-                    Some(v) => {
-                        println!("Extracted table: {}", v);
-                        table_paths.push(
-                            entry.path().to_str().unwrap().to_string());
-                        if max_table_version.is_none() ||
-                            v > max_table_version.unwrap() {
-                            max_table_version = Some(v);
-                        }
-                    }
-                    None => (),
+            }
+            if let Some(v) = table_file_version(&path) {
+                table_paths.push(path.clone());
+                if max_table_version.is_none() ||
+                    v > max_table_version.unwrap() {
+                    max_table_version = Some(v);
                 }
-
             }
         }
         
+        // Newer tables shadow older ones, so keep them in creation order.
+        table_paths.sort_by_key(|p| table_file_version(p));
+        log_paths.sort_by_key(|p| log_file_version(p));
+
         return Ok(FileManager{
-            root: dir.as_ref().to_path_buf(),
+            root: root,
+            env: env,
             log_version: max_log_version.map(|v| v + 1).unwrap_or(0),
-            log_path: max_log_path,
+            log_paths: log_paths,
             table_count: max_table_version.map(|v| v + 1).unwrap_or(0),
             table_paths: table_paths,
         });
@@ -118,38 +119,80 @@ impl FileManager {
         let mut buf = self.root.clone();
         buf.push(format!("log_{}", v));
         let path = buf.to_str().unwrap().to_string();
-        self.log_path = Some(path.clone());
+        self.log_paths.push(path.clone());
         return path;
     }
     
+    // Where the rollup tier with the given bucket width is kept.
+    pub fn rollup_path(&self, resolution: u64) -> String {
+        let mut buf = self.root.clone();
+        buf.push(format!("rollup_{}", resolution));
+        return buf.to_str().unwrap().to_string();
+    }
+
     pub fn latest_log(&self) -> Option<String> {
-        return self.log_path.clone();
+        return self.log_paths.last().cloned();
+    }
+
+    // Every log but the latest, whose records have all been written to
+    // tables since.
+    pub fn obsolete_logs(&self) -> Vec<String> {
+        return self.log_paths[..self.log_paths.len().saturating_sub(1)].to_vec();
     }
 
     pub fn table_paths(&self) -> Vec<String> {
         return self.table_paths.clone();
     }
+
+    pub fn delete_table(&mut self, path: &str) -> io::Result<()> {
+        if !self.table_paths.iter().any(|p| p == path) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("'{}' is not a table.", path)));
+        }
+
+        // A table that was never written (e.g. because a compaction
+        // failed) is just forgotten.
+        match self.env.delete(path::Path::new(path)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            result => try!(result),
+        }
+        self.table_paths.retain(|p| p != path);
+        return Ok(());
+    }
+
+    pub fn delete_log(&mut self, path: &str) -> io::Result<()> {
+        if !self.log_paths.iter().any(|p| p == path) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("'{}' is not a log.", path)));
+        }
+
+        match self.env.delete(path::Path::new(path)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            result => try!(result),
+        }
+        self.log_paths.retain(|p| p != path);
+        return Ok(());
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use std::fs;
-    use std::fs::File;
+    use env::Env;
+    use env::MemEnv;
+
     use std::io;
+    use std::path::Path;
+    use std::sync::Arc;
 
-    fn accept_not_found(err: io::Error) -> io::Result<()> {
-        if err.kind() == io::ErrorKind::NotFound {
-            return Ok(());
-        }
-        return Err(err);
-    }
-    
     #[test]
     fn basic() {
-        fs::remove_dir_all("/tmp/filemanager").or_else(accept_not_found).unwrap();
+        let env = Arc::new(MemEnv::new());
+        let open = || super::FileManager::open_or_create_with_env(env.clone(), "/tmp/filemanager");
+        env.create_dir(Path::new("/tmp")).unwrap();
         {
-            let mut fm = super::FileManager::open_or_create("/tmp/filemanager")
-                .expect("FileManager::open #1");
+            let mut fm = open().expect("FileManager::open #1");
             assert_eq!(None, fm.latest_log());
             assert_eq!("/tmp/filemanager/log_0", fm.new_log_file());
             assert_eq!("/tmp/filemanager/log_1", fm.new_log_file());
@@ -164,17 +207,14 @@ mod test {
                             "/tmp/filemanager/table_1"], fm.table_paths());
 
             
-            File::create("/tmp/filemanager/log_0").unwrap();
-            File::create("/tmp/filemanager/log_1").unwrap();
-            File::create("/tmp/filemanager/table_0").unwrap();
-            File::create("/tmp/filemanager/table_1").unwrap();
+            env.create(Path::new("/tmp/filemanager/log_0")).unwrap();
+            env.create(Path::new("/tmp/filemanager/log_1")).unwrap();
+            env.create(Path::new("/tmp/filemanager/table_0")).unwrap();
+            env.create(Path::new("/tmp/filemanager/table_1")).unwrap();
         }
 
-        println!("Recovering...");
-        
         {
-            let mut fm = super::FileManager::open_or_create("/tmp/filemanager")
-                .expect("FileManager::open #2");
+            let mut fm = open().expect("FileManager::open #2");
 
             assert_eq!("/tmp/filemanager/log_1", fm.latest_log().unwrap());
             assert_eq!("/tmp/filemanager/log_2", fm.new_log_file());
@@ -184,22 +224,36 @@ mod test {
             assert_eq!("/tmp/filemanager/table_2", fm.new_table_file());
 
 
-            File::create("/tmp/filemanager/log_2").unwrap();
-            File::create("/tmp/filemanager/table_2").unwrap();
+            env.create(Path::new("/tmp/filemanager/log_2")).unwrap();
+            env.create(Path::new("/tmp/filemanager/table_2")).unwrap();
         }
 
-        println!("Recovering...");
-
         {
-            let fm = super::FileManager::open_or_create("/tmp/filemanager")
-                .expect("FileManager::open #3");
+            let fm = open().expect("FileManager::open #3");
             assert_eq!("/tmp/filemanager/log_2", fm.latest_log().unwrap());
             assert_eq!(vec!["/tmp/filemanager/table_0",
                             "/tmp/filemanager/table_1",
                             "/tmp/filemanager/table_2",], fm.table_paths());
         }
 
-        fs::remove_dir_all("/tmp/filemanager").unwrap();
+        {
+            let mut fm = open().expect("FileManager::open #4");
+            fm.delete_table("/tmp/filemanager/table_1").unwrap();
+            assert_eq!(io::ErrorKind::NotFound,
+                       fm.delete_table("/tmp/filemanager/log_2").unwrap_err().kind());
+            assert_eq!(vec!["/tmp/filemanager/table_0",
+                            "/tmp/filemanager/table_2",], fm.table_paths());
+            assert!(env.open(Path::new("/tmp/filemanager/table_1")).is_err());
+
+            assert_eq!(vec!["/tmp/filemanager/log_0",
+                            "/tmp/filemanager/log_1"], fm.obsolete_logs());
+            fm.delete_log("/tmp/filemanager/log_0").unwrap();
+            assert_eq!(io::ErrorKind::NotFound,
+                       fm.delete_log("/tmp/filemanager/table_0").unwrap_err().kind());
+            assert_eq!(vec!["/tmp/filemanager/log_1"], fm.obsolete_logs());
+            assert_eq!("/tmp/filemanager/log_2", fm.latest_log().unwrap());
+            assert!(env.open(Path::new("/tmp/filemanager/log_0")).is_err());
+        }
     }
 }
 
@@ -1,6 +1,13 @@
 pub const VAL_WIDTH: usize = 8;
 pub const REC_WIDTH: usize = 2 * VAL_WIDTH;
 
+// The value that marks a deleted point. It shadows older values at the same
+// timestamp like any other, and is dropped when points are read or merged.
+// Tables that may hold it carry a newer magic number than those that came
+// before it, so an older table can't have a point read as a deletion. Logs
+// aren't versioned, and must be replayed by the version that wrote them.
+pub const TOMBSTONE: u64 = u64::MAX;
+
 #[derive(Debug,PartialEq)]
 pub struct Rec {
     pub timestamp: u64,
@@ -27,8 +34,8 @@ pub fn load(buf: &[u8]) -> u64 {
     assert_eq!(VAL_WIDTH, buf.len());
 
     let mut acc : u64 = 0;
-    for b in 0..VAL_WIDTH {
-        acc += (buf[b] as u64) << (8 * b);
+    for (b, byte) in buf.iter().enumerate() {
+        acc += (*byte as u64) << (8 * b);
     }
     return acc;
 }
@@ -36,8 +43,8 @@ pub fn load(buf: &[u8]) -> u64 {
 pub fn store(n: u64, buf: &mut [u8]) {
     assert_eq!(VAL_WIDTH, buf.len());
 
-    for b in 0..VAL_WIDTH {
-        buf[b] = ((n >> (8 * b)) % 256) as u8;
+    for (b, byte) in buf.iter_mut().enumerate() {
+        *byte = ((n >> (8 * b)) % 256) as u8;
     }
 }
 
@@ -57,7 +64,7 @@ mod test {
         assert_eq!(255, round_trip(255));
         assert_eq!(256, round_trip(256));
         assert_eq!(1234567890, round_trip(1234567890));
-        assert_eq!(u64::max_value(), round_trip(u64::max_value()));
+        assert_eq!(u64::MAX, round_trip(u64::MAX));
 
     }
 
@@ -0,0 +1,386 @@
+use block_storage::Device;
+use block_storage::check_alignment;
+use block_storage::check_bounds;
+use checksum;
+use format;
+
+use std::cmp::min;
+use std::io;
+
+// Each block's checksum is stored as a format::VAL_WIDTH value.
+const SUM_WIDTH : u64 = 8;
+
+fn corrupt(msg: String) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, msg);
+}
+
+// Zero for a block of zeroes, so that a zeroed device needs no setup. The
+// block number is included, so that a block (and its sum) written to or
+// read from the wrong place doesn't pass.
+fn block_sum(block_no: u64, block: &[u8]) -> u64 {
+    if block.iter().all(|b| *b == 0) {
+        return 0;
+    }
+    let mut crc = checksum::Crc32c::new();
+    let mut buf = [0; 8];
+    format::store(block_no, &mut buf);
+    crc.update(&buf);
+    crc.update(block);
+    return crc.finish() as u64;
+}
+
+fn read_exact(device: &mut dyn Device, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    let len = buf.len() as u64;
+    let read = try!(device.read(offset, len, buf));
+    if read as u64 != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("Short read of {} bytes at {}", read, offset)));
+    }
+    return Ok(());
+}
+
+// A Device that keeps a crc32c of every block of another Device, and fails
+// reads of blocks that don't match theirs with ErrorKind::InvalidData.
+//
+// The checksums live in a sidecar area at the end of the underlying device,
+// which is hidden from callers:
+//   [data blocks...][checksum blocks...]
+// and are also kept in memory, having been read when the device was opened.
+// A block that's all zeroes with a zero checksum has never been written, so
+// a zeroed device is valid as it is. Data and checksum aren't written
+// atomically, so a crash between the two leaves a block that fails to read.
+pub struct ChecksummedDevice<D: Device> {
+    device: D,
+    blocks: u64,
+    sums: Vec<u64>,
+}
+
+impl<D: Device> ChecksummedDevice<D> {
+    pub fn open(mut device: D) -> io::Result<ChecksummedDevice<D>> {
+        let block_size = device.block_size();
+        if !block_size.is_multiple_of(SUM_WIDTH) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Block size {} isn't a multiple of {}", block_size, SUM_WIDTH)));
+        }
+        let sums_per_block = block_size / SUM_WIDTH;
+        let total = device.size() / block_size;
+        let blocks = total * sums_per_block / (sums_per_block + 1);
+
+        let sum_blocks = total - blocks;
+        let mut buf = vec![0; (sum_blocks * block_size) as usize];
+        try!(read_exact(&mut device, blocks * block_size, &mut buf));
+        let sums = buf.chunks(SUM_WIDTH as usize).take(blocks as usize).map(format::load).collect();
+
+        return Ok(ChecksummedDevice{
+            device: device,
+            blocks: blocks,
+            sums: sums,
+        })
+    }
+
+    pub fn get_ref(&self) -> &D {
+        return &self.device
+    }
+
+    // Accesses the underlying device directly, e.g. to damage it in tests.
+    pub fn get_mut(&mut self) -> &mut D {
+        return &mut self.device
+    }
+
+    pub fn into_inner(self) -> D {
+        return self.device
+    }
+
+    // Writes the checksum blocks holding the sums of blocks [first, last].
+    fn write_sums(&mut self, first: u64, last: u64) -> io::Result<()> {
+        let block_size = self.device.block_size();
+        let sums_per_block = block_size / SUM_WIDTH;
+        let first_sum_block = first / sums_per_block;
+        let last_sum_block = last / sums_per_block;
+
+        let start = first_sum_block * sums_per_block;
+        let end = min((last_sum_block + 1) * sums_per_block, self.blocks);
+        let mut buf = vec![0; ((last_sum_block - first_sum_block + 1) * block_size) as usize];
+        for (i, sum) in self.sums[(start as usize)..(end as usize)].iter().enumerate() {
+            let pos = i * SUM_WIDTH as usize;
+            format::store(*sum, &mut buf[pos..(pos + SUM_WIDTH as usize)]);
+        }
+        return self.device.write((self.blocks + first_sum_block) * block_size, &buf)
+    }
+}
+
+impl<D: Device> Device for ChecksummedDevice<D> {
+    fn block_size(&self) -> u64 {
+        return self.device.block_size()
+    }
+
+    fn size(&self) -> u64 {
+        return self.blocks * self.block_size()
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let block_size = self.block_size();
+        try!(check_alignment(block_size, offset, data.len() as u64));
+        try!(check_bounds(self.size(), offset, data.len() as u64));
+        if data.is_empty() {
+            return Ok(())
+        }
+
+        try!(self.device.write(offset, data));
+        let first = offset / block_size;
+        for (i, block) in data.chunks(block_size as usize).enumerate() {
+            self.sums[first as usize + i] = block_sum(first + i as u64, block);
+        }
+        let last = first + data.len() as u64 / block_size - 1;
+        return self.write_sums(first, last)
+    }
+
+    fn read(&mut self, offset: u64, length: u64, buf: &mut [u8]) -> io::Result<usize> {
+        assert!(length <= (buf.len() as u64));
+        let block_size = self.block_size();
+        let len = min(length, self.size().saturating_sub(offset));
+        try!(check_alignment(block_size, offset, len));
+
+        let len = len as usize;
+        try!(read_exact(&mut self.device, offset, &mut buf[0..len]));
+        for (i, block) in buf[0..len].chunks(block_size as usize).enumerate() {
+            let block_no = offset / block_size + i as u64;
+            if block_sum(block_no, block) != self.sums[block_no as usize] {
+                return Err(corrupt(format!("Checksum mismatch in the block at {}", block_no * block_size)));
+            }
+        }
+        return Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.device.flush()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        return self.device.sync()
+    }
+}
+
+// A Device that writes everything to two others, and reads from the first
+// unless a block can't be read from it, in which case it reads that block
+// from the second and rewrites the first copy with it. The devices should
+// detect corruption themselves, e.g. by being ChecksummedDevices, since a
+// mirror can't tell which of two differing copies is right.
+pub struct MirroredDevice<D: Device> {
+    devices: (D, D),
+    repairs: u64,
+}
+
+impl<D: Device> MirroredDevice<D> {
+    pub fn new(first: D, second: D) -> io::Result<MirroredDevice<D>> {
+        if first.block_size() != second.block_size() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Block sizes {} and {} differ",
+                                              first.block_size(), second.block_size())));
+        }
+        return Ok(MirroredDevice{
+            devices: (first, second),
+            repairs: 0,
+        })
+    }
+
+    pub fn get_mut(&mut self) -> (&mut D, &mut D) {
+        return (&mut self.devices.0, &mut self.devices.1)
+    }
+
+    pub fn into_inner(self) -> (D, D) {
+        return self.devices
+    }
+
+    // The number of blocks rewritten from the other copy.
+    pub fn repairs(&self) -> u64 {
+        return self.repairs
+    }
+
+    // Reads every block from both devices, repairing whichever copy is bad.
+    // Fails if both are.
+    pub fn scrub(&mut self) -> io::Result<()> {
+        let block_size = self.block_size();
+        let mut buf = vec![0; block_size as usize];
+        let mut offset = 0;
+        while offset < self.size() {
+            if let Err(e) = read_exact(&mut self.devices.1, offset, &mut buf) {
+                if e.kind() != io::ErrorKind::InvalidData {
+                    return Err(e)
+                }
+                try!(read_exact(&mut self.devices.0, offset, &mut buf));
+                try!(self.devices.1.write(offset, &buf));
+                self.repairs += 1;
+            }
+            try!(self.read_block(offset, &mut buf));
+            offset += block_size;
+        }
+        return Ok(())
+    }
+
+    // Reads the block at 'offset' from the first device, or failing that
+    // from the second, repairing the first.
+    fn read_block(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        match read_exact(&mut self.devices.0, offset, buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {},
+            result => return result,
+        }
+        try!(read_exact(&mut self.devices.1, offset, buf));
+        try!(self.devices.0.write(offset, buf));
+        self.repairs += 1;
+        return Ok(())
+    }
+}
+
+impl<D: Device> Device for MirroredDevice<D> {
+    fn block_size(&self) -> u64 {
+        return self.devices.0.block_size()
+    }
+
+    fn size(&self) -> u64 {
+        return min(self.devices.0.size(), self.devices.1.size())
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        try!(check_bounds(self.size(), offset, data.len() as u64));
+        try!(self.devices.0.write(offset, data));
+        return self.devices.1.write(offset, data)
+    }
+
+    fn read(&mut self, offset: u64, length: u64, buf: &mut [u8]) -> io::Result<usize> {
+        assert!(length <= (buf.len() as u64));
+        let block_size = self.block_size();
+        let len = min(length, self.size().saturating_sub(offset));
+        try!(check_alignment(block_size, offset, len));
+
+        let len = len as usize;
+        match read_exact(&mut self.devices.0, offset, &mut buf[0..len]) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {},
+            result => return result.map(|_| len),
+        }
+        // Find the bad blocks.
+        for (i, block) in buf[0..len].chunks_mut(block_size as usize).enumerate() {
+            try!(self.read_block(offset + i as u64 * block_size, block));
+        }
+        return Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.devices.0.flush());
+        return self.devices.1.flush()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        try!(self.devices.0.sync());
+        return self.devices.1.sync()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ChecksummedDevice;
+    use super::MirroredDevice;
+
+    use block_storage::Device;
+    use block_storage::InMemoryDevice;
+    use db::Db;
+    use db::Options;
+    use format::Rec;
+
+    use std::io::ErrorKind;
+
+    fn checksummed(blocks: u64) -> ChecksummedDevice<InMemoryDevice> {
+        return ChecksummedDevice::open(InMemoryDevice::new(blocks * 512, 512)).unwrap();
+    }
+
+    fn read(dev: &mut dyn Device, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        assert_eq!(len, dev.read(offset, len as u64, &mut buf).unwrap());
+        return buf;
+    }
+
+    #[test]
+    fn checksummed_device() {
+        // 64 sums fit in a block, so 130 blocks hold 128 of data.
+        let mut dev = checksummed(130);
+        assert_eq!(128 * 512, dev.size());
+        assert_eq!(vec![0; 1024], read(&mut dev, 0, 1024));
+
+        dev.write(512, &[1; 1024]).unwrap();
+        dev.write(127 * 512, &[2; 512]).unwrap();
+        assert_eq!([1; 1024], read(&mut dev, 512, 1024)[..]);
+        assert_eq!(ErrorKind::InvalidInput, dev.write(128 * 512, &[0; 512]).unwrap_err().kind());
+
+        // The sums survive reopening.
+        let mut dev = ChecksummedDevice::open(dev.into_inner()).unwrap();
+        assert_eq!([2; 512], read(&mut dev, 127 * 512, 512)[..]);
+
+        let mut damaged = read(dev.get_mut(), 1024, 512);
+        damaged[10] ^= 1;
+        dev.get_mut().write(1024, &damaged).unwrap();
+        assert_eq!([1; 512], read(&mut dev, 512, 512)[..]);
+        assert_eq!(ErrorKind::InvalidData, dev.read(0, 2048, &mut [0; 2048]).unwrap_err().kind());
+
+        // So does damage to the sums.
+        let sums = 128 * 512;
+        dev.get_mut().write(sums, &[3; 512]).unwrap();
+        let mut dev = ChecksummedDevice::open(dev.into_inner()).unwrap();
+        assert_eq!(ErrorKind::InvalidData, dev.read(0, 512, &mut [0; 512]).unwrap_err().kind());
+    }
+
+    #[test]
+    fn misplaced_blocks() {
+        let mut dev = checksummed(130);
+        dev.write(512, &[5; 512]).unwrap();
+
+        // Block 1 and its sum land on block 2 as well.
+        let block = read(dev.get_mut(), 512, 512);
+        dev.get_mut().write(1024, &block).unwrap();
+        let sums = 128 * 512;
+        let mut sum_block = read(dev.get_mut(), sums, 512);
+        sum_block.copy_within(8..16, 16);
+        dev.get_mut().write(sums, &sum_block).unwrap();
+
+        let mut dev = ChecksummedDevice::open(dev.into_inner()).unwrap();
+        assert_eq!([5; 512], read(&mut dev, 512, 512)[..]);
+        assert_eq!(ErrorKind::InvalidData, dev.read(1024, 512, &mut [0; 512]).unwrap_err().kind());
+    }
+
+    #[test]
+    fn mirrored_device() {
+        let mut dev = MirroredDevice::new(checksummed(65), checksummed(65)).unwrap();
+        assert_eq!(64 * 512, dev.size());
+        dev.write(0, &[4; 4096]).unwrap();
+
+        // Damage the first copy of block 1 and the second copy of block 3.
+        dev.get_mut().0.get_mut().write(512, &[9; 512]).unwrap();
+        dev.get_mut().1.get_mut().write(1536, &[9; 512]).unwrap();
+
+        assert_eq!(vec![4; 4096], read(&mut dev, 0, 4096));
+        assert_eq!(1, dev.repairs());
+        assert_eq!([4; 512], read(dev.get_mut().0, 512, 512)[..]);
+        assert_eq!(ErrorKind::InvalidData, dev.get_mut().1.read(1536, 512, &mut [0; 512]).unwrap_err().kind());
+
+        dev.scrub().unwrap();
+        assert_eq!(2, dev.repairs());
+        assert_eq!(vec![4; 4096], read(dev.get_mut().1, 0, 4096));
+
+        // Neither copy is any good.
+        dev.get_mut().0.get_mut().write(2048, &[9; 512]).unwrap();
+        dev.get_mut().1.get_mut().write(2048, &[9; 512]).unwrap();
+        assert_eq!(ErrorKind::InvalidData, dev.read(0, 4096, &mut [0; 4096]).unwrap_err().kind());
+        assert_eq!(ErrorKind::InvalidData, dev.scrub().unwrap_err().kind());
+    }
+
+    #[test]
+    fn db_on_mirrored_device() {
+        let mirrored = MirroredDevice::new(checksummed(8200), checksummed(8200)).unwrap();
+        let mut db = Db::with_device(Box::new(mirrored), Options::default()).unwrap();
+        for i in 0..1000 {
+            db.record(&Rec{timestamp: i, value: i * 2}).unwrap();
+        }
+        db.flush().unwrap();
+        assert_eq!(1000, db.lookup(500).unwrap());
+    }
+}
@@ -1,7 +1,44 @@
+// The code base ends functions with an explicit return.
+#![allow(clippy::needless_return, clippy::redundant_field_names)]
+
+// Errors are propagated with try!, which std deprecates in favour of ?.
+// This one does the same, so the deprecation doesn't have to be allowed.
+macro_rules! try {
+    ($e:expr) => (match $e {
+        Ok(value) => value,
+        Err(e) => return Err(::std::convert::From::from(e)),
+    });
+}
+
+pub mod aggregate;
+pub mod allocator;
+pub mod async_io;
+pub mod block_cache;
 pub mod block_storage;
+pub mod bloom;
+pub mod catalog;
+pub mod checksum;
+pub mod compression;
 pub mod db;
+pub mod device_fs;
+pub mod env;
+pub mod expr;
+#[cfg(any(test, feature = "testing"))]
+pub mod fault_env;
 pub mod filemanager;
 pub mod format;
+pub mod integrity;
 pub mod log;
 pub mod memtable;
+pub mod merge;
+pub mod page_cache;
+pub mod query;
+pub mod rollup;
+pub mod rules;
+pub mod server;
+#[cfg(any(test, feature = "testing"))]
+pub mod simulation;
+pub mod sketch;
 pub mod table;
+pub mod table_cache;
+pub mod window;
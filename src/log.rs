@@ -1,4 +1,8 @@
-use std::fs;
+use env::Env;
+use env::PosixEnv;
+use env::ReadableFile;
+use env::WritableFile;
+
 use std::io;
 use std::io::Read;
 use std::io::Write;
@@ -6,8 +10,10 @@ use std::path;
 
 const BLOCK_SIZE_BYTES : usize = 32768;
 
-pub trait LogWriter {
+pub trait LogWriter: Send {
     fn append(&mut self, buf: &[u8]) -> io::Result<()>;
+    // Makes every record appended so far durable.
+    fn sync(&mut self) -> io::Result<()>;
 }
 
 pub trait LogReader {
@@ -15,19 +21,22 @@ pub trait LogReader {
 }
 
 pub struct FileLogWriter {
-    file: fs::File,
+    file: Box<dyn WritableFile>,
     record_size_bytes: usize,
     block_ptr: usize,
 }
 
 impl FileLogWriter {
     pub fn create<P: AsRef<path::Path>>(path: P, record_size_bytes: usize) -> io::Result<FileLogWriter> {
-        let f = try!(fs::File::create(path));
+        return FileLogWriter::create_with_env(&PosixEnv, path, record_size_bytes);
+    }
+
+    pub fn create_with_env<P: AsRef<path::Path>>(env: &dyn Env, path: P, record_size_bytes: usize) -> io::Result<FileLogWriter> {
         return Ok(FileLogWriter{
-            file: f,
+            file: try!(env.create(path.as_ref())),
             record_size_bytes: record_size_bytes,
             block_ptr: 0,
-        })
+        });
     }
 }
 
@@ -47,12 +56,18 @@ impl LogWriter for FileLogWriter {
                         self.record_size_bytes, buf.len())));
         }
         
-        return self.file.write_all(buf);
+        try!(self.file.write_all(buf));
+        self.block_ptr += buf.len();
+        return Ok(());
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        return self.file.sync();
     }
 }
 
 pub struct FileLogReader {
-    file: fs::File,
+    file: Box<dyn ReadableFile>,
     record_size_bytes: usize,
     buf: [u8; BLOCK_SIZE_BYTES],
     buf_ptr: usize,
@@ -62,15 +77,18 @@ pub struct FileLogReader {
 
 impl FileLogReader {
     pub fn create<P: AsRef<path::Path>>(path: P, record_size_bytes: usize) -> io::Result<FileLogReader> {
-        let f = try!(fs::File::open(path));
+        return FileLogReader::create_with_env(&PosixEnv, path, record_size_bytes);
+    }
+
+    pub fn create_with_env<P: AsRef<path::Path>>(env: &dyn Env, path: P, record_size_bytes: usize) -> io::Result<FileLogReader> {
         return Ok(FileLogReader{
-            file: f,
+            file: try!(env.open(path.as_ref())),
             record_size_bytes: record_size_bytes,
             buf: [0; BLOCK_SIZE_BYTES],
             buf_ptr: 0,
             buf_size: 0,
             read_last_block: false,
-        })
+        });
     }
 }
 
@@ -89,11 +107,14 @@ impl LogReader for FileLogReader {
             }
 
             try!(self.read_next_block());
+            // The log ended exactly at the end of the previous block.
+            if self.current_block_expired() {
+                return Ok(false);
+            }
         }
 
-        for i in 0..self.record_size_bytes {
-            result[i] = self.buf[self.buf_ptr + i];
-        }
+        result[..self.record_size_bytes].copy_from_slice(
+            &self.buf[self.buf_ptr..(self.buf_ptr + self.record_size_bytes)]);
 
         self.buf_ptr += self.record_size_bytes;
         return Ok(true);
@@ -112,10 +133,15 @@ impl FileLogReader {
     }
 
     fn current_block_expired(&self) -> bool {
-        return self.buf_ptr >= (self.buf_size - self.block_padding())
+        return self.buf_ptr + self.record_size_bytes > self.buf_size - self.block_padding();
     }
 
+    // Only full blocks end in padding; the last block of the log ends with
+    // its last record.
     fn block_padding(&self) -> usize {
+        if self.buf_size < BLOCK_SIZE_BYTES {
+            return 0;
+        }
         return BLOCK_SIZE_BYTES % self.record_size_bytes;
     }
 }
@@ -126,61 +152,92 @@ mod test {
     use super::FileLogWriter;
     use super::LogReader;
     use super::LogWriter;
+    use env;
+    use env::MemEnv;
 
     use std::fs;
     use std::io::ErrorKind;
 
     #[test]
     fn invalid_record_size() {
-        let mut log = FileLogWriter::create("/tmp/filelog-bad", 4).unwrap();
+        let env = MemEnv::new();
+        let mut log = FileLogWriter::create_with_env(&env, "/filelog-bad", 4).unwrap();
         assert_eq!(ErrorKind::InvalidInput,
                    log.append(&[0]).unwrap_err().kind());
-        
-        fs::remove_file("/tmp/filelog-bad")
-            .expect("Should have cleaned up the file");
     }
     
     #[test]
     fn single_log_replay() {
+        let path = env::temp_path("filelog");
         {
-            let mut writer = FileLogWriter::create("/tmp/filelog", 4)
+            let mut writer = FileLogWriter::create(&path, 4)
                 .expect("Should have created a new writer");
             writer.append(&[0,1,2,3])
                 .expect("Should have written 0,1,2,3");
         }
 
-        let mut reader = FileLogReader::create("/tmp/filelog", 4)
+        let mut reader = FileLogReader::create(&path, 4)
             .expect("Should have opened the existing filelog");
         let mut buf = [0; 4];
-        assert_eq!(true, reader.next_record(&mut buf).unwrap());
+        assert!(reader.next_record(&mut buf).unwrap());
         assert_eq!([0,1,2,3], buf);
         // TODO(mrjones): i'm not sure these are the semantics I want
-        assert_eq!(false, reader.next_record(&mut buf).unwrap());
+        assert!(!reader.next_record(&mut buf).unwrap());
 
-        fs::remove_file("/tmp/filelog").unwrap();
+        fs::remove_file(&path).unwrap();
     }
 
     #[test]
     fn multiple_block_replay() {
         let records = 2 * ((super::BLOCK_SIZE_BYTES + 4) / 4);
+        let env = MemEnv::new();
         
         {
-            let mut writer = FileLogWriter::create("/tmp/filelog.multi", 4).unwrap();
+            let mut writer = FileLogWriter::create_with_env(&env, "/filelog.multi", 4).unwrap();
             for i in 0..records {
                 let v = (i % 256) as u8;
                 writer.append(&[v, v, v, v]).unwrap();
             }
         }
 
-        let mut reader = FileLogReader::create("/tmp/filelog.multi", 4).unwrap();
+        let mut reader = FileLogReader::create_with_env(&env, "/filelog.multi", 4).unwrap();
         let mut buf = [0; 4];
         for i in 0..records {
             let v = (i % 256) as u8;
-            assert_eq!(true, reader.next_record(&mut buf).unwrap());
+            assert!(reader.next_record(&mut buf).unwrap());
             assert_eq!([v, v, v, v], buf);
         }
-        assert_eq!(false, reader.next_record(&mut buf).unwrap());
+        assert!(!reader.next_record(&mut buf).unwrap());
+    }
 
-        fs::remove_file("/tmp/filelog.multi").unwrap();
+    #[test]
+    fn padded_blocks() {
+        // Records don't fit blocks exactly, so each block ends in padding.
+        const _: () = assert!(!super::BLOCK_SIZE_BYTES.is_multiple_of(12));
+        let records = 3 * super::BLOCK_SIZE_BYTES / 12;
+        let env = MemEnv::new();
+        {
+            let mut writer = FileLogWriter::create_with_env(&env, "/filelog.padded", 12).unwrap();
+            for i in 0..records {
+                let v = (i % 256) as u8;
+                writer.append(&[v; 12]).unwrap();
+            }
+        }
+
+        let mut reader = FileLogReader::create_with_env(&env, "/filelog.padded", 12).unwrap();
+        let mut buf = [0; 12];
+        for i in 0..records {
+            assert!(reader.next_record(&mut buf).unwrap());
+            assert_eq!([(i % 256) as u8; 12], buf);
+        }
+        assert!(!reader.next_record(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn empty_log_replay() {
+        let env = MemEnv::new();
+        FileLogWriter::create_with_env(&env, "/filelog.empty", 4).unwrap();
+        let mut reader = FileLogReader::create_with_env(&env, "/filelog.empty", 4).unwrap();
+        assert!(!reader.next_record(&mut [0; 4]).unwrap());
     }
 }
@@ -5,12 +5,13 @@ use log::FileLogWriter;
 use log::LogReader;
 use log::LogWriter;
 use std::collections::BTreeMap;
+use std::collections::btree_map;
 use std::io;
 use std::path;
 
 pub struct MemTable {
-    logger: Box<LogWriter>,
-    data: Box<BTreeMap<u64, u64>>
+    logger: Box<dyn LogWriter>,
+    data: BTreeMap<u64, u64>
 }
 
 impl MemTable {
@@ -23,21 +24,61 @@ impl MemTable {
         return Ok(());
     }
 
+    // Makes every record so far durable.
+    pub fn sync(&mut self) -> io::Result<()> {
+        return self.logger.sync();
+    }
+
     pub fn lookup(&self, k: u64) -> Option<&u64> {
         return self.data.get(&k);
     }
 
+    // The record with the largest key <= 'k'.
+    pub fn floor(&self, k: u64) -> Option<(u64, u64)> {
+        return self.data.range(..=k).next_back().map(|(k, v)| (*k, *v));
+    }
+
+    // The record with the smallest key >= 'k'.
+    pub fn ceiling(&self, k: u64) -> Option<(u64, u64)> {
+        return self.data.range(k..).next().map(|(k, v)| (*k, *v));
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<'_, u64, u64> {
+        return self.data.iter();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.data.is_empty();
+    }
+
+    // All records with keys in [start, end).
+    pub fn range(&self, start: u64, end: u64) -> btree_map::Range<'_, u64, u64> {
+        if start >= end {
+            return self.data.range(0..0);
+        }
+        return self.data.range(start..end);
+    }
+
     pub fn create<P: AsRef<path::Path>>(filename: P) -> io::Result<MemTable> {
-        return Ok(MemTable{
-            logger: Box::new(try!(FileLogWriter::create(filename, 16))),
-            data: Box::new(BTreeMap::new()),
-        })
+        return Ok(MemTable::with_logger(
+            Box::new(try!(FileLogWriter::create(filename, 16)))));
+    }
+
+    // Logs records to 'logger', which must take 16 byte records.
+    pub fn with_logger(logger: Box<dyn LogWriter>) -> MemTable {
+        return MemTable{
+            logger: logger,
+            data: BTreeMap::new(),
+        }
     }
     
     pub fn replay<P: AsRef<path::Path>>(filename: P) -> io::Result<BTreeMap<u64, u64>> {
+        return MemTable::replay_log(&mut try!(FileLogReader::create(&filename, 16)));
+    }
+
+    pub fn replay_log(reader: &mut dyn LogReader) -> io::Result<BTreeMap<u64, u64>> {
         let mut data : BTreeMap<u64, u64> = BTreeMap::new();
         {
-            let mut reader = try!(FileLogReader::create(&filename, 16));
             let mut buf : [u8; 16] = [0; 16];
             while try!(reader.next_record(&mut buf)) {
                 let k : u64 = format::load(&buf[0..8]);
@@ -53,20 +94,21 @@ impl MemTable {
 
 #[cfg(test)]
 mod test {
+    use env;
     use std::fs;
     use super::MemTable;
     
     #[test]
     fn single_recovery() {
-        let filename = "/tmp/memtable";
+        let filename = env::temp_path("memtable");
         {
-            let mut memtable = MemTable::create(filename).unwrap();
+            let mut memtable = MemTable::create(&filename).unwrap();
             memtable.record(1234, 5678).unwrap();
             assert_eq!(5678, *memtable.lookup(1234).unwrap());
         }
 
         {
-            let map = MemTable::replay(filename)
+            let map = MemTable::replay(&filename)
                 .expect("MemTable::replay");
             assert_eq!(Some(&5678), map.get(&1234));
         }
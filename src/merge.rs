@@ -0,0 +1,340 @@
+use format;
+
+use std::io;
+use std::mem;
+use std::task::Poll;
+
+// A sorted stream of (timestamp, value) records that may stop early because
+// of an I/O error. Sources can be read from either end.
+pub trait Source : DoubleEndedIterator<Item=(u64, u64)> {
+    // The error that ended iteration, if any.
+    fn take_status(&mut self) -> io::Result<()>;
+
+    // Ready once next() can return without waiting for a read. Errors are
+    // left for next() and take_status to report. Only reads from the front
+    // are ever pending.
+    fn poll_ready(&mut self) -> Poll<()> {
+        return Poll::Ready(());
+    }
+}
+
+// Adapts an iterator that can't fail into a Source.
+pub struct Infallible<I>(pub I);
+
+impl<I: Iterator<Item=(u64, u64)>> Iterator for Infallible<I> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        return self.0.next();
+    }
+}
+
+impl<I: DoubleEndedIterator<Item=(u64, u64)>> DoubleEndedIterator for Infallible<I> {
+    fn next_back(&mut self) -> Option<(u64, u64)> {
+        return self.0.next_back();
+    }
+}
+
+impl<I: DoubleEndedIterator<Item=(u64, u64)>> Source for Infallible<I> {
+    fn take_status(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+// The record at one end of a source. Heads are only read when they're
+// needed, so e.g. iterating backwards never touches the start of a source.
+#[derive(Clone,Copy)]
+enum Head {
+    Unread,
+    Record(u64, u64),
+    Done,
+}
+
+// Merges sorted sources into a single sorted stream. When several sources
+// have the same timestamp, the value from the last of them wins, so sources
+// should be given oldest first. A winning tombstone is dropped, along with
+// everything it shadows.
+pub struct MergingIterator<'a> {
+    sources: Vec<Box<dyn Source + 'a>>,
+    fronts: Vec<Head>,
+    backs: Vec<Head>,
+    status: io::Result<()>,
+}
+
+impl<'a> MergingIterator<'a> {
+    pub fn new(sources: Vec<Box<dyn Source + 'a>>) -> MergingIterator<'a> {
+        return MergingIterator{
+            fronts: vec![Head::Unread; sources.len()],
+            backs: vec![Head::Unread; sources.len()],
+            sources: sources,
+            status: Ok(()),
+        };
+    }
+
+    fn read(&mut self, i: usize, from_back: bool) -> Head {
+        let record = if from_back {
+            self.sources[i].next_back()
+        } else {
+            self.sources[i].next()
+        };
+
+        match record {
+            Some((k, v)) => return Head::Record(k, v),
+            None => {
+                let status = self.sources[i].take_status();
+                if status.is_err() && self.status.is_ok() {
+                    self.status = status;
+                }
+                return Head::Done;
+            }
+        }
+    }
+
+    // The next record source 'i' has to offer from one end. Once a source
+    // runs dry from one end, the record held at the other end (if any) is
+    // its last one.
+    fn peek(&mut self, i: usize, from_back: bool) -> Option<(u64, u64)> {
+        let head = if from_back { self.backs[i] } else { self.fronts[i] };
+        let head = match head {
+            Head::Unread => {
+                let h = self.read(i, from_back);
+                if from_back { self.backs[i] = h; } else { self.fronts[i] = h; }
+                h
+            },
+            h => h,
+        };
+
+        match head {
+            Head::Record(k, v) => return Some((k, v)),
+            Head::Done => {
+                return match if from_back { self.fronts[i] } else { self.backs[i] } {
+                    Head::Record(k, v) => Some((k, v)),
+                    _ => None,
+                };
+            },
+            Head::Unread => unreachable!(),
+        }
+    }
+
+    // Consumes source 'i''s record with key 'k', from whichever end has it.
+    fn consume(&mut self, i: usize, k: u64, from_back: bool) {
+        let (near, far) = if from_back {
+            (self.backs[i], self.fronts[i])
+        } else {
+            (self.fronts[i], self.backs[i])
+        };
+
+        match near {
+            // The next record is read when it's needed, so that poll_ready
+            // can tell whether that would wait.
+            Head::Record(nk, _) if nk == k => {
+                if from_back { self.backs[i] = Head::Unread; } else { self.fronts[i] = Head::Unread; }
+            },
+            _ => match far {
+                Head::Record(fk, _) if fk == k => {
+                    if from_back { self.fronts[i] = Head::Done; } else { self.backs[i] = Head::Done; }
+                },
+                _ => (),
+            },
+        }
+    }
+
+    fn step(&mut self, from_back: bool) -> Option<(u64, u64)> {
+        loop {
+            match self.step_raw(from_back) {
+                Some((_, v)) if v == format::TOMBSTONE => continue,
+                record => return record,
+            }
+        }
+    }
+
+    // Ready once next() can return without waiting for a read. Tombstones in
+    // the way are skipped here, as far as that can be done without waiting.
+    pub fn poll_ready(&mut self) -> Poll<()> {
+        loop {
+            if self.status.is_err() {
+                return Poll::Ready(());
+            }
+            let mut ready = true;
+            for i in 0..self.sources.len() {
+                if let Head::Unread = self.fronts[i] {
+                    // Every source is polled, so they all get to start reading.
+                    ready &= self.sources[i].poll_ready().is_ready();
+                }
+            }
+            if !ready {
+                return Poll::Pending;
+            }
+
+            match self.winner(false) {
+                Some((k, v)) if v == format::TOMBSTONE && self.status.is_ok() => {
+                    self.consume_all(k, false);
+                },
+                _ => return Poll::Ready(()),
+            }
+        }
+    }
+
+    fn step_raw(&mut self, from_back: bool) -> Option<(u64, u64)> {
+        if self.status.is_err() {
+            return None;
+        }
+
+        let winner = self.winner(from_back);
+        if self.status.is_err() {
+            return None;
+        }
+
+        let (k, _) = winner?;
+        self.consume_all(k, from_back);
+        return winner;
+    }
+
+    // The record next() or next_back() would return, before dropping
+    // tombstones.
+    fn winner(&mut self, from_back: bool) -> Option<(u64, u64)> {
+        let mut winner : Option<(u64, u64)> = None;
+        for i in 0..self.sources.len() {
+            if let Some((k, v)) = self.peek(i, from_back) {
+                let better = match winner {
+                    None => true,
+                    Some((wk, _)) => if from_back { k >= wk } else { k <= wk },
+                };
+                if better {
+                    winner = Some((k, v));
+                }
+            }
+        }
+        return winner;
+    }
+
+    // Consumes every source's record with key 'k'. Their heads were all
+    // peeked by winner(), so this doesn't read.
+    fn consume_all(&mut self, k: u64, from_back: bool) {
+        for i in 0..self.sources.len() {
+            if self.peek(i, from_back).map(|(ck, _)| ck == k).unwrap_or(false) {
+                self.consume(i, k, from_back);
+            }
+        }
+    }
+
+    pub fn take_status(&mut self) -> io::Result<()> {
+        return mem::replace(&mut self.status, Ok(()));
+    }
+}
+
+impl<'a> Iterator for MergingIterator<'a> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        return self.step(false);
+    }
+}
+
+impl<'a> DoubleEndedIterator for MergingIterator<'a> {
+    fn next_back(&mut self) -> Option<(u64, u64)> {
+        return self.step(true);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Infallible;
+    use super::MergingIterator;
+    use super::Source;
+    use format::TOMBSTONE;
+
+    use std::io;
+
+    struct Failing(u64);
+
+    impl Iterator for Failing {
+        type Item = (u64, u64);
+
+        fn next(&mut self) -> Option<(u64, u64)> {
+            if self.0 == 0 {
+                return None;
+            }
+            self.0 -= 1;
+            return Some((self.0, 0));
+        }
+    }
+
+    impl DoubleEndedIterator for Failing {
+        fn next_back(&mut self) -> Option<(u64, u64)> {
+            return None;
+        }
+    }
+
+    impl Source for Failing {
+        fn take_status(&mut self) -> io::Result<()> {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "failed"));
+        }
+    }
+
+    #[test]
+    fn newest_source_wins() {
+        let old = vec![(1, 10), (3, 30), (5, 50)];
+        let new = vec![(2, 200), (3, 300)];
+        let merged : Vec<(u64, u64)> = MergingIterator::new(vec![
+            Box::new(Infallible(old.into_iter())),
+            Box::new(Infallible(new.into_iter())),
+        ]).collect();
+
+        assert_eq!(vec![(1, 10), (2, 200), (3, 300), (5, 50)], merged);
+    }
+
+    #[test]
+    fn reverse() {
+        let old = vec![(1, 10), (3, 30), (5, 50)];
+        let new = vec![(2, 200), (3, 300)];
+        let merged : Vec<(u64, u64)> = MergingIterator::new(vec![
+            Box::new(Infallible(old.into_iter())),
+            Box::new(Infallible(new.into_iter())),
+        ]).rev().collect();
+
+        assert_eq!(vec![(5, 50), (3, 300), (2, 200), (1, 10)], merged);
+    }
+
+    #[test]
+    fn both_ends() {
+        let old = vec![(1, 10), (3, 30), (5, 50)];
+        let new = vec![(3, 300)];
+        let mut merged = MergingIterator::new(vec![
+            Box::new(Infallible(old.into_iter())),
+            Box::new(Infallible(new.into_iter())),
+        ]);
+
+        assert_eq!(Some((1, 10)), merged.next());
+        assert_eq!(Some((5, 50)), merged.next_back());
+        assert_eq!(Some((3, 300)), merged.next_back());
+        assert_eq!(None, merged.next());
+        assert_eq!(None, merged.next_back());
+    }
+
+    #[test]
+    fn tombstones() {
+        let old : Vec<(u64, u64)> = vec![(1, 10), (3, 30), (5, 50)];
+        let new = vec![(1, TOMBSTONE), (2, TOMBSTONE), (5, TOMBSTONE)];
+        let merge = || MergingIterator::new(vec![
+            Box::new(Infallible(old.clone().into_iter())) as Box<dyn Source>,
+            Box::new(Infallible(new.clone().into_iter())),
+        ]);
+
+        assert_eq!(vec![(3, 30)], merge().collect::<Vec<(u64, u64)>>());
+        assert_eq!(vec![(3, 30)], merge().rev().collect::<Vec<(u64, u64)>>());
+    }
+
+    #[test]
+    fn errors_stop_iteration() {
+        let mut merged = MergingIterator::new(vec![
+            Box::new(Infallible(vec![(1, 1), (2, 2)].into_iter())),
+            Box::new(Failing(1)),
+        ]);
+
+        assert_eq!(Some((0, 0)), merged.next());
+        assert_eq!(None, merged.next());
+        assert_eq!(io::ErrorKind::InvalidData,
+                   merged.take_status().unwrap_err().kind());
+    }
+}
@@ -0,0 +1,465 @@
+use block_storage::Device;
+use block_storage::check_alignment;
+use block_storage::check_bounds;
+
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum WritePolicy {
+    // Writes only update the cache, and dirty pages reach the device when
+    // they're evicted or flushed, in whatever order that happens.
+    WriteBack,
+    // Writes go straight to the device, and update any cached copy.
+    WriteThrough,
+}
+
+#[derive(Clone,Copy,Debug)]
+pub struct CachedDeviceOptions {
+    // Must be a multiple of the device's block size.
+    pub page_size: u64,
+    pub capacity_pages: usize,
+    pub policy: WritePolicy,
+}
+
+impl Default for CachedDeviceOptions {
+    fn default() -> CachedDeviceOptions {
+        return CachedDeviceOptions{
+            page_size: 4096,
+            capacity_pages: 256,
+            policy: WritePolicy::WriteBack,
+        }
+    }
+}
+
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct PageCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    // Dirty pages written to the device, when evicted or flushed.
+    pub writebacks: u64,
+    pub cached_pages: usize,
+    pub dirty_pages: usize,
+    pub pinned_pages: usize,
+    pub capacity_pages: usize,
+}
+
+impl PageCacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        if self.hits + self.misses == 0 {
+            return 0.0;
+        }
+        return self.hits as f64 / (self.hits + self.misses) as f64;
+    }
+}
+
+struct Page {
+    data: Vec<u8>,
+    dirty: bool,
+    pins: u32,
+    last_use: u64,
+}
+
+// A Device that keeps up to a fixed number of pages of another Device in
+// memory, evicting the least recently used unpinned page to make room for
+// a new one. With WritePolicy::WriteBack, writes aren't on the underlying
+// device until they're flushed (or synced, which flushes first), and
+// because eviction and flushing write pages back in their own order, only
+// sync orders writes: everything written before it is durable before
+// anything written after. Dropping the cache flushes it, but can't report
+// errors.
+pub struct CachedDevice<D: Device> {
+    // Only None once into_inner has taken it.
+    device: Option<D>,
+    options: CachedDeviceOptions,
+    // Keyed by page number, i.e. offset / page_size.
+    pages: HashMap<u64, Page>,
+    // Page numbers ordered by their last use, least recent first.
+    by_use: BTreeMap<u64, u64>,
+    clock: u64,
+    stats: PageCacheStats,
+}
+
+impl<D: Device> CachedDevice<D> {
+    pub fn new(device: D, options: &CachedDeviceOptions) -> io::Result<CachedDevice<D>> {
+        if options.page_size == 0 || !options.page_size.is_multiple_of(device.block_size()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Page size {} isn't a multiple of the block size {}",
+                                              options.page_size, device.block_size())));
+        }
+        if options.capacity_pages == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The cache needs room for a page"));
+        }
+
+        let stats = PageCacheStats{capacity_pages: options.capacity_pages, ..PageCacheStats::default()};
+        return Ok(CachedDevice{
+            device: Some(device),
+            options: *options,
+            pages: HashMap::new(),
+            by_use: BTreeMap::new(),
+            clock: 0,
+            stats: stats,
+        })
+    }
+
+    pub fn get_ref(&self) -> &D {
+        return self.device.as_ref().unwrap()
+    }
+
+    // Accesses the underlying device directly, bypassing (and without
+    // invalidating) the cache.
+    pub fn get_mut(&mut self) -> &mut D {
+        return self.device.as_mut().unwrap()
+    }
+
+    // Flushes dirty pages and returns the underlying device.
+    pub fn into_inner(mut self) -> io::Result<D> {
+        try!(self.flush());
+        return Ok(self.device.take().unwrap())
+    }
+
+    pub fn stats(&self) -> PageCacheStats {
+        let mut stats = self.stats;
+        stats.cached_pages = self.pages.len();
+        stats.dirty_pages = self.pages.values().filter(|p| p.dirty).count();
+        stats.pinned_pages = self.pages.values().filter(|p| p.pins > 0).count();
+        return stats
+    }
+
+    // Loads the page holding 'offset', if it isn't cached already, and
+    // keeps it cached until a matching unpin. Pins nest.
+    pub fn pin(&mut self, offset: u64) -> io::Result<()> {
+        if offset >= self.get_ref().size() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Offset {} is past the end of the device", offset)));
+        }
+        let page_no = offset / self.options.page_size;
+        try!(self.load(page_no, true)).pins += 1;
+        return Ok(())
+    }
+
+    pub fn unpin(&mut self, offset: u64) -> io::Result<()> {
+        let page_no = offset / self.options.page_size;
+        match self.pages.get_mut(&page_no) {
+            Some(ref mut page) if page.pins > 0 => {
+                page.pins -= 1;
+                return Ok(())
+            },
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                           format!("The page at {} isn't pinned", offset))),
+        }
+    }
+
+    // The last page is cut short if the device isn't a whole number of
+    // pages.
+    fn page_len(&self, page_no: u64) -> usize {
+        return min(self.options.page_size, self.get_ref().size() - page_no * self.options.page_size) as usize
+    }
+
+    // Finds page 'page_no' in the cache or adds it, reading its contents from
+    // the device unless 'fill' is false because the caller is about to
+    // overwrite all of it.
+    fn load(&mut self, page_no: u64, fill: bool) -> io::Result<&mut Page> {
+        self.clock += 1;
+        let now = self.clock;
+
+        if self.pages.contains_key(&page_no) {
+            self.stats.hits += 1;
+            let page = self.pages.get_mut(&page_no).unwrap();
+            self.by_use.remove(&page.last_use);
+            self.by_use.insert(now, page_no);
+            page.last_use = now;
+            return Ok(page)
+        }
+
+        self.stats.misses += 1;
+        if self.pages.len() >= self.options.capacity_pages {
+            try!(self.evict());
+        }
+        let len = self.page_len(page_no);
+        let mut data = vec![0; len];
+        if fill {
+            let offset = page_no * self.options.page_size;
+            let read = try!(self.get_mut().read(offset, len as u64, &mut data));
+            if read != len {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          format!("Short read of page {}", page_no)));
+            }
+        }
+        self.by_use.insert(now, page_no);
+        return Ok(self.pages.entry(page_no).or_insert(Page{
+            data: data,
+            dirty: false,
+            pins: 0,
+            last_use: now,
+        }))
+    }
+
+    // Drops the least recently used page that isn't pinned, writing it back
+    // first if it's dirty.
+    fn evict(&mut self) -> io::Result<()> {
+        let victim = {
+            let pages = &self.pages;
+            self.by_use.values().cloned().find(|page_no| pages[page_no].pins == 0)
+        };
+        let page_no = match victim {
+            Some(page_no) => page_no,
+            None => return Err(io::Error::other("Every cached page is pinned")),
+        };
+
+        try!(self.write_back(page_no));
+        let page = self.pages.remove(&page_no).unwrap();
+        self.by_use.remove(&page.last_use);
+        self.stats.evictions += 1;
+        return Ok(())
+    }
+
+    fn write_back(&mut self, page_no: u64) -> io::Result<()> {
+        let page = self.pages.get_mut(&page_no).unwrap();
+        if page.dirty {
+            try!(self.device.as_mut().unwrap().write(page_no * self.options.page_size, &page.data));
+            page.dirty = false;
+            self.stats.writebacks += 1;
+        }
+        return Ok(())
+    }
+}
+
+impl<D: Device> Device for CachedDevice<D> {
+    fn block_size(&self) -> u64 {
+        return self.get_ref().block_size()
+    }
+
+    fn size(&self) -> u64 {
+        return self.get_ref().size()
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        try!(check_alignment(self.block_size(), offset, data.len() as u64));
+        try!(check_bounds(self.size(), offset, data.len() as u64));
+
+        let write_through = self.options.policy == WritePolicy::WriteThrough;
+        if write_through {
+            try!(self.get_mut().write(offset, data));
+        }
+
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let page_no = pos / self.options.page_size;
+            let start = (pos - page_no * self.options.page_size) as usize;
+            let page_len = self.page_len(page_no);
+            let n = min(page_len - start, data.len() - done);
+
+            // Writing through doesn't bring pages into the cache, but
+            // mustn't leave a stale copy there either.
+            if !write_through || self.pages.contains_key(&page_no) {
+                let whole_page = start == 0 && n == page_len;
+                let page = try!(self.load(page_no, !whole_page));
+                page.data[start..(start + n)].copy_from_slice(&data[done..(done + n)]);
+                page.dirty |= !write_through;
+            }
+            done += n;
+        }
+        return Ok(())
+    }
+
+    fn read(&mut self, offset: u64, length: u64, buf: &mut [u8]) -> io::Result<usize> {
+        assert!(length <= (buf.len() as u64));
+        let len = min(length, self.size().saturating_sub(offset)) as usize;
+        try!(check_alignment(self.block_size(), offset, len as u64));
+
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let page_no = pos / self.options.page_size;
+            let start = (pos - page_no * self.options.page_size) as usize;
+            let page = try!(self.load(page_no, true));
+            let n = min(page.data.len() - start, len - done);
+            buf[done..(done + n)].copy_from_slice(&page.data[start..(start + n)]);
+            done += n;
+        }
+        return Ok(len)
+    }
+
+    // Writes back every dirty page, in order, then flushes the device.
+    fn flush(&mut self) -> io::Result<()> {
+        let mut dirty : Vec<u64> = self.pages.iter()
+            .filter(|&(_, page)| page.dirty).map(|(page_no, _)| *page_no).collect();
+        dirty.sort();
+        for page_no in dirty {
+            try!(self.write_back(page_no));
+        }
+        return self.get_mut().flush()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        try!(self.flush());
+        return self.get_mut().sync()
+    }
+}
+
+impl<D: Device> Drop for CachedDevice<D> {
+    fn drop(&mut self) {
+        if self.device.is_some() {
+            let _ = self.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CachedDevice;
+    use super::CachedDeviceOptions;
+    use super::WritePolicy;
+
+    use block_storage::Device;
+    use block_storage::FileDevice;
+    use block_storage::FileDeviceOptions;
+    use block_storage::InMemoryDevice;
+    use db::Db;
+    use db::Options;
+    use format::Rec;
+
+    use env;
+
+    use std::fs;
+    use std::io::ErrorKind;
+
+    fn options(capacity_pages: usize, policy: WritePolicy) -> CachedDeviceOptions {
+        return CachedDeviceOptions{page_size: 1024, capacity_pages: capacity_pages, policy: policy};
+    }
+
+    fn read(dev: &mut dyn Device, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        assert_eq!(len, dev.read(offset, len as u64, &mut buf).unwrap());
+        return buf;
+    }
+
+    #[test]
+    fn write_back() {
+        let inner = InMemoryDevice::new(8192, 512);
+        let mut dev = CachedDevice::new(inner, &options(4, WritePolicy::WriteBack)).unwrap();
+
+        // Half a page, then a write spanning two more.
+        dev.write(512, &[1; 512]).unwrap();
+        dev.write(1536, &[2; 1024]).unwrap();
+        assert_eq!(vec![0; 4096], read(dev.get_mut(), 0, 4096));
+        assert_eq!([0; 512], read(&mut dev, 0, 512)[..]);
+        assert_eq!([1; 512], read(&mut dev, 512, 512)[..]);
+        assert_eq!([2; 1024], read(&mut dev, 1536, 1024)[..]);
+        assert_eq!(3, dev.stats().dirty_pages);
+
+        dev.flush().unwrap();
+        assert_eq!(0, dev.stats().dirty_pages);
+        assert_eq!(3, dev.stats().writebacks);
+        let written = read(dev.get_mut(), 0, 4096);
+        assert_eq!([1; 512], written[512..1024]);
+        assert_eq!([2; 1024], written[1536..2560]);
+        assert_eq!([0; 1536], written[2560..]);
+
+        assert_eq!(ErrorKind::InvalidInput, dev.write(100, &[0; 512]).unwrap_err().kind());
+        assert_eq!(ErrorKind::InvalidInput, dev.write(7680, &[0; 1024]).unwrap_err().kind());
+        assert_eq!(ErrorKind::InvalidInput,
+                   CachedDevice::new(InMemoryDevice::new(8192, 512),
+                                     &CachedDeviceOptions{page_size: 768, ..options(4, WritePolicy::WriteBack)})
+                   .err().unwrap().kind());
+    }
+
+    #[test]
+    fn drop_flushes() {
+        let device_options = FileDeviceOptions{block_size: 512, direct: false};
+        let path = env::temp_path("cached-device-drop");
+        {
+            let file = FileDevice::create(&path, 8192, &device_options).unwrap();
+            let mut dev = CachedDevice::new(file, &options(4, WritePolicy::WriteBack)).unwrap();
+            dev.write(1024, &[7; 1024]).unwrap();
+            assert_eq!(1, dev.stats().dirty_pages);
+        }
+
+        let mut file = FileDevice::open(&path, &device_options).unwrap();
+        assert_eq!([7; 1024], read(&mut file, 1024, 1024)[..]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_through() {
+        let inner = InMemoryDevice::new(8192, 512);
+        let mut dev = CachedDevice::new(inner, &options(4, WritePolicy::WriteThrough)).unwrap();
+
+        // Uncached pages stay uncached.
+        dev.write(0, &[1; 512]).unwrap();
+        assert_eq!(0, dev.stats().cached_pages);
+        assert_eq!([1; 512], read(dev.get_mut(), 0, 512)[..]);
+
+        // Cached ones are updated along with the device.
+        assert_eq!([1; 512], read(&mut dev, 0, 512)[..]);
+        dev.write(512, &[3; 512]).unwrap();
+        assert_eq!([3; 512], read(dev.get_mut(), 512, 512)[..]);
+        assert_eq!([3; 512], read(&mut dev, 512, 512)[..]);
+        assert_eq!(0, dev.stats().dirty_pages);
+        assert_eq!(1, dev.stats().misses);
+        assert_eq!(2, dev.stats().hits);
+    }
+
+    #[test]
+    fn eviction_and_pinning() {
+        let inner = InMemoryDevice::new(8192, 512);
+        let mut dev = CachedDevice::new(inner, &options(2, WritePolicy::WriteBack)).unwrap();
+
+        dev.pin(100).unwrap();
+        dev.write(1024, &[5; 1024]).unwrap();
+        // The dirty page is evicted rather than the pinned one, and written
+        // back as it goes.
+        read(&mut dev, 2048, 1024);
+        let stats = dev.stats();
+        assert_eq!(1, stats.evictions);
+        assert_eq!(1, stats.writebacks);
+        assert_eq!(2, stats.cached_pages);
+        assert_eq!(1, stats.pinned_pages);
+        assert_eq!([5; 1024], read(dev.get_mut(), 1024, 1024)[..]);
+
+        dev.pin(2048).unwrap();
+        assert_eq!(ErrorKind::Other, dev.read(4096, 1024, &mut [0; 1024]).unwrap_err().kind());
+
+        // Pins nest.
+        dev.pin(2048).unwrap();
+        dev.unpin(2048).unwrap();
+        assert_eq!(ErrorKind::Other, dev.read(4096, 1024, &mut [0; 1024]).unwrap_err().kind());
+        dev.unpin(2048).unwrap();
+        read(&mut dev, 4096, 1024);
+        assert_eq!(2, dev.stats().evictions);
+        assert_eq!(ErrorKind::InvalidInput, dev.unpin(2048).unwrap_err().kind());
+
+        dev.unpin(0).unwrap();
+        assert_eq!(0, dev.stats().pinned_pages);
+        assert_eq!(ErrorKind::InvalidInput, dev.pin(8192).unwrap_err().kind());
+    }
+
+    #[test]
+    fn db_on_cached_device() {
+        let device_options = FileDeviceOptions{block_size: 512, direct: false};
+        let path = env::temp_path("db-cached-device");
+        {
+            let file = FileDevice::create(&path, 4 << 20, &device_options).unwrap();
+            let cached = CachedDevice::new(file, &CachedDeviceOptions::default()).unwrap();
+            let mut db = Db::with_device(Box::new(cached), Options::default()).unwrap();
+            for i in 0..1000 {
+                db.record(&Rec{timestamp: i, value: i * 2}).unwrap();
+            }
+            db.flush().unwrap();
+            db.record(&Rec{timestamp: 1000, value: 2000}).unwrap();
+            db.sync().unwrap();
+        }
+
+        // Everything reached the file.
+        let file = FileDevice::open(&path, &device_options).unwrap();
+        let mut db = Db::with_device(Box::new(file), Options::default()).unwrap();
+        assert_eq!(Some(1000), db.lookup(500).ok());
+        assert_eq!(Some(2000), db.lookup(1000).ok());
+        fs::remove_file(&path).unwrap();
+    }
+}
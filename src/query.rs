@@ -0,0 +1,511 @@
+use aggregate::Aggregator;
+use aggregate::Summary;
+use aggregate::Variance;
+use catalog::Catalog;
+use db::Lookup;
+use expr;
+use expr::Function;
+use expr::ParseError;
+use expr::Parser;
+use expr::TokenKind;
+use sketch::Sketch;
+
+use std::collections::BTreeMap;
+use std::io;
+
+// A small SQL-like query language over the series in a Catalog:
+//
+//   SELECT avg(value) FROM cpu WHERE host='a' AND time > now()-1h GROUP BY time(1m)
+//
+// Series are named "<measurement>,<tag>=<value>,...", so 'FROM cpu' selects
+// every series of the measurement 'cpu', and tag conditions narrow that
+// down. A query is parsed into a Query, planned against the catalog into a
+// Plan, and then executed.
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Selection {
+    // The raw points.
+    Points,
+    Aggregate(Aggregator),
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum TagFilter {
+    Equal(String, String),
+    NotEqual(String, String),
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+// A condition on time, against either a fixed timestamp or one relative to
+// now().
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct TimeBound {
+    pub comparison: Comparison,
+    pub from_now: bool,
+    pub offset: i128,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct Query {
+    pub selection: Selection,
+    pub measurement: String,
+    pub tags: Vec<TagFilter>,
+    pub times: Vec<TimeBound>,
+    // The width of the time buckets to aggregate over, if any.
+    pub group_by: Option<u64>,
+}
+
+// How a query will be answered.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Plan {
+    pub selection: Selection,
+    pub series: Vec<String>,
+    // Points with timestamps in [start, end) are considered.
+    pub start: u64,
+    pub end: u64,
+    // Buckets are aligned to multiples of their width, with the first and
+    // last clipped to the query's range. Without GROUP BY, there's a single
+    // bucket covering the whole range.
+    pub width: Option<u64>,
+}
+
+// One row of results: a point, or the aggregate of the bucket starting at
+// 'time'.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Row {
+    pub time: u64,
+    pub value: f64,
+}
+
+// A duration such as '5m', in timestamp units.
+fn duration(parser: &mut Parser, ticks_per_second: u64) -> Result<u128, ParseError> {
+    let n = try!(parser.expect_number());
+    let unit_position = parser.position();
+    let seconds = match parser.peek() {
+        Some(TokenKind::Ident(unit)) if parser.adjacent() => match unit.as_str() {
+            "ns" => 1e-9,
+            "us" => 1e-6,
+            "ms" => 1e-3,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            "d" => 86400.0,
+            "w" => 604800.0,
+            _ => return parser.error(format!("Unknown duration unit '{}'", unit)),
+        },
+        // A bare number is in timestamp units.
+        _ => return Ok(n as u128),
+    };
+    parser.next_token();
+
+    let ticks = (n * seconds * ticks_per_second as f64).round();
+    if ticks < 1.0 && n > 0.0 {
+        return Err(ParseError{
+            position: unit_position,
+            message: "Duration is finer than the timestamp resolution".to_string(),
+        });
+    }
+    return Ok(ticks as u128);
+}
+
+fn time_bound(parser: &mut Parser, ticks_per_second: u64) -> Result<TimeBound, ParseError> {
+    let comparison = if parser.eat_symbol("<=") {
+        Comparison::LessOrEqual
+    } else if parser.eat_symbol(">=") {
+        Comparison::GreaterOrEqual
+    } else if parser.eat_symbol("<") {
+        Comparison::Less
+    } else if parser.eat_symbol(">") {
+        Comparison::Greater
+    } else {
+        return parser.unexpected("a comparison");
+    };
+
+    let mut bound = TimeBound{comparison: comparison, from_now: false, offset: 0};
+    if parser.eat_keyword("now") {
+        try!(parser.expect_symbol("("));
+        try!(parser.expect_symbol(")"));
+        bound.from_now = true;
+    } else {
+        bound.offset = try!(duration(parser, ticks_per_second)) as i128;
+    }
+    loop {
+        if parser.eat_symbol("+") {
+            bound.offset += try!(duration(parser, ticks_per_second)) as i128;
+        } else if parser.eat_symbol("-") {
+            bound.offset -= try!(duration(parser, ticks_per_second)) as i128;
+        } else {
+            return Ok(bound);
+        }
+    }
+}
+
+fn selection(parser: &mut Parser) -> Result<Selection, ParseError> {
+    if parser.eat_keyword("value") {
+        return Ok(Selection::Points);
+    }
+
+    let position = parser.position();
+    let name = try!(parser.expect_ident());
+    try!(parser.expect_symbol("("));
+    let aggregator = match expr::parse_function(&name.to_lowercase()) {
+        Some(Function::Aggregate(aggregator)) => aggregator,
+        Some(Function::Window(_)) => return Err(ParseError{
+            position: position,
+            message: format!("'{}' can't be used in SELECT", name),
+        }),
+        None if name.eq_ignore_ascii_case("quantile") => {
+            let q_position = parser.position();
+            let q = try!(parser.expect_number());
+            if !(0.0..=1.0).contains(&q) {
+                return Err(ParseError{
+                    position: q_position,
+                    message: "Quantile must be between 0 and 1".to_string(),
+                });
+            }
+            try!(parser.expect_symbol(","));
+            Aggregator::Quantile(q)
+        },
+        None => return Err(ParseError{
+            position: position,
+            message: format!("Unknown function '{}'", name),
+        }),
+    };
+    try!(parser.expect_keyword("value"));
+    try!(parser.expect_symbol(")"));
+    return Ok(Selection::Aggregate(aggregator));
+}
+
+// Parses a query whose durations are converted to timestamps at
+// 'ticks_per_second'.
+pub fn parse(text: &str, ticks_per_second: u64) -> Result<Query, ParseError> {
+    let mut parser = try!(Parser::new(text));
+    try!(parser.expect_keyword("SELECT"));
+    let selection = try!(selection(&mut parser));
+    try!(parser.expect_keyword("FROM"));
+    let measurement = try!(parser.expect_series());
+
+    let mut query = Query{
+        selection: selection,
+        measurement: measurement,
+        tags: Vec::new(),
+        times: Vec::new(),
+        group_by: None,
+    };
+    if parser.eat_keyword("WHERE") {
+        loop {
+            let name = try!(parser.expect_ident());
+            if name.eq_ignore_ascii_case("time") {
+                query.times.push(try!(time_bound(&mut parser, ticks_per_second)));
+            } else if parser.eat_symbol("=") {
+                query.tags.push(TagFilter::Equal(name, try!(parser.expect_string())));
+            } else if parser.eat_symbol("!=") {
+                query.tags.push(TagFilter::NotEqual(name, try!(parser.expect_string())));
+            } else {
+                return parser.unexpected("'=' or '!='");
+            }
+
+            if !parser.eat_keyword("AND") {
+                break;
+            }
+        }
+    }
+    if parser.eat_keyword("GROUP") {
+        try!(parser.expect_keyword("BY"));
+        let position = parser.position();
+        try!(parser.expect_keyword("time"));
+        try!(parser.expect_symbol("("));
+        let width = try!(duration(&mut parser, ticks_per_second));
+        try!(parser.expect_symbol(")"));
+        if width == 0 || width > u64::MAX as u128 {
+            return Err(ParseError{position: position, message: "Invalid bucket width".to_string()});
+        }
+        if query.selection == Selection::Points {
+            return Err(ParseError{position: position, message: "GROUP BY needs an aggregate".to_string()});
+        }
+        query.group_by = Some(width as u64);
+    }
+    try!(parser.finish());
+    return Ok(query);
+}
+
+// Splits a series name into its measurement and tags.
+pub fn parse_series_name(name: &str) -> (String, BTreeMap<String, String>) {
+    let mut parts = name.split(',');
+    let measurement = parts.next().unwrap_or("").to_string();
+    let mut tags = BTreeMap::new();
+    for part in parts {
+        let mut kv = part.splitn(2, '=');
+        let k = kv.next().unwrap_or("");
+        tags.insert(k.to_string(), kv.next().unwrap_or("").to_string());
+    }
+    return (measurement, tags);
+}
+
+fn clamp(t: i128) -> u64 {
+    if t < 0 {
+        return 0;
+    } else if t > u64::MAX as i128 {
+        return u64::MAX;
+    }
+    return t as u64;
+}
+
+// Works out which series and time range 'query' covers. Without an upper
+// bound on time, queries stop at 'now'.
+pub fn plan(query: &Query, catalog: &Catalog, now: u64) -> io::Result<Plan> {
+    let mut series = Vec::new();
+    for name in try!(catalog.series_names()) {
+        let (measurement, tags) = parse_series_name(&name);
+        let matches = measurement == query.measurement && query.tags.iter().all(|f| match *f {
+            TagFilter::Equal(ref k, ref v) => tags.get(k) == Some(v),
+            TagFilter::NotEqual(ref k, ref v) => tags.get(k) != Some(v),
+        });
+        if matches {
+            series.push(name);
+        }
+    }
+
+    let mut start : u64 = 0;
+    let mut end : u64 = now.saturating_add(1);
+    for bound in &query.times {
+        let t = bound.offset + if bound.from_now { now as i128 } else { 0 };
+        match bound.comparison {
+            Comparison::Greater => start = start.max(clamp(t + 1)),
+            Comparison::GreaterOrEqual => start = start.max(clamp(t)),
+            Comparison::Less => end = end.min(clamp(t)),
+            Comparison::LessOrEqual => end = end.min(clamp(t + 1)),
+        }
+    }
+
+    return Ok(Plan{
+        selection: query.selection,
+        series: series,
+        start: start,
+        end: end,
+        width: query.group_by,
+    });
+}
+
+// The earliest timestamp >= 'ts' with a point in any of 'series'.
+fn next_point(catalog: &mut Catalog, series: &[String], ts: u64) -> io::Result<Option<u64>> {
+    let mut next : Option<u64> = None;
+    for name in series {
+        let db = match try!(catalog.get(name)) {
+            Some(db) => db,
+            None => continue,
+        };
+        match db.lookup_with_mode(ts, Lookup::Ceiling) {
+            Ok((k, _)) => if next.map(|n| k < n).unwrap_or(true) { next = Some(k) },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    return Ok(next);
+}
+
+// Aggregates the points of all of 'series' in [start, end).
+fn aggregate_bucket(catalog: &mut Catalog, series: &[String], aggregator: Aggregator, start: u64, end: u64) -> io::Result<Option<f64>> {
+    let mut summary = Summary::empty();
+    let mut sketch = Sketch::default();
+    let mut variance = Variance::default();
+    for name in series {
+        let db = match try!(catalog.get(name)) {
+            Some(db) => db,
+            None => continue,
+        };
+        match aggregator {
+            Aggregator::Quantile(_) => sketch.merge(&try!(db.sketch(start, end))),
+            Aggregator::Stddev => {
+                let mut merged = try!(db.scan(start, end));
+                for (_, v) in &mut merged {
+                    variance.add(v);
+                }
+                try!(merged.take_status());
+            },
+            _ => summary.merge(&try!(db.summarize(start, end))),
+        }
+    }
+
+    return Ok(match aggregator {
+        Aggregator::Quantile(q) => sketch.quantile(q),
+        Aggregator::Stddev => variance.stddev(),
+        a => a.apply(&summary),
+    });
+}
+
+impl Plan {
+    pub fn execute(&self, catalog: &mut Catalog) -> io::Result<Vec<Row>> {
+        let mut rows = Vec::new();
+        if self.start >= self.end || self.series.is_empty() {
+            return Ok(rows);
+        }
+
+        let aggregator = match self.selection {
+            Selection::Points => {
+                for name in &self.series {
+                    if let Some(db) = try!(catalog.get(name)) {
+                        let mut merged = try!(db.scan(self.start, self.end));
+                        rows.extend((&mut merged).map(|(k, v)| Row{time: k, value: v as f64}));
+                        try!(merged.take_status());
+                    }
+                }
+                rows.sort_by_key(|r| r.time);
+                return Ok(rows);
+            },
+            Selection::Aggregate(aggregator) => aggregator,
+        };
+
+        let width = match self.width {
+            Some(width) => width,
+            None => {
+                if let Some(value) = try!(aggregate_bucket(catalog, &self.series, aggregator, self.start, self.end)) {
+                    rows.push(Row{time: self.start, value: value});
+                }
+                return Ok(rows);
+            },
+        };
+
+        // A single series can use Db::aggregate, and so any rollups. Its
+        // buckets start at 'start', so a partial first bucket is done
+        // separately to keep the rest aligned.
+        if self.series.len() == 1 {
+            let db = match try!(catalog.get(&self.series[0])) {
+                Some(db) => db,
+                None => return Ok(rows),
+            };
+            let mut start = self.start;
+            if !start.is_multiple_of(width) {
+                let aligned = (start - start % width).saturating_add(width).min(self.end);
+                let mut buckets = try!(db.aggregate(start, aligned, width, aggregator));
+                rows.extend((&mut buckets).map(|b| Row{time: b.start - b.start % width, value: b.value}));
+                try!(buckets.take_status());
+                start = aligned;
+            }
+            let mut buckets = try!(db.aggregate(start, self.end, width, aggregator));
+            rows.extend((&mut buckets).map(|b| Row{time: b.start, value: b.value}));
+            try!(buckets.take_status());
+            return Ok(rows);
+        }
+
+        let mut next = self.start;
+        while next < self.end {
+            let ts = match try!(next_point(catalog, &self.series, next)) {
+                Some(ts) if ts < self.end => ts,
+                _ => break,
+            };
+            let bucket = ts - ts % width;
+            let start = bucket.max(self.start);
+            let end = bucket.saturating_add(width).min(self.end);
+            if let Some(value) = try!(aggregate_bucket(catalog, &self.series, aggregator, start, end)) {
+                rows.push(Row{time: bucket, value: value});
+            }
+            next = end;
+        }
+        return Ok(rows);
+    }
+}
+
+// Parses, plans and executes 'text' against 'catalog', with now() as 'now'.
+pub fn execute(catalog: &mut Catalog, text: &str, now: u64) -> io::Result<Vec<Row>> {
+    let query = try!(parse(text, catalog.options().ticks_per_second));
+    let plan = try!(plan(&query, catalog, now));
+    return plan.execute(catalog);
+}
+
+#[cfg(test)]
+mod test {
+    use aggregate::Aggregator;
+    use catalog::Catalog;
+    use db;
+    use env::MemEnv;
+    use format;
+    use std::io;
+    use std::sync::Arc;
+
+    use super::Comparison;
+    use super::Query;
+    use super::Row;
+    use super::Selection;
+    use super::TagFilter;
+    use super::TimeBound;
+    use super::execute;
+    use super::parse;
+
+    #[test]
+    fn parse_query() {
+        assert_eq!(Query{
+            selection: Selection::Aggregate(Aggregator::Avg),
+            measurement: "cpu".to_string(),
+            tags: vec![TagFilter::Equal("host".to_string(), "a".to_string())],
+            times: vec![TimeBound{comparison: Comparison::Greater, from_now: true, offset: -3600000}],
+            group_by: Some(60000),
+        }, parse("SELECT avg(value) FROM cpu WHERE host='a' AND time > now()-1h GROUP BY time(1m)", 1000).unwrap());
+
+        assert_eq!(Query{
+            selection: Selection::Points,
+            measurement: "mem".to_string(),
+            tags: vec![TagFilter::NotEqual("region".to_string(), "us".to_string())],
+            times: vec![TimeBound{comparison: Comparison::LessOrEqual, from_now: false, offset: 120}],
+            group_by: None,
+        }, parse("select value from mem where region != \"us\" and time <= 2m", 1).unwrap());
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |q: &str| parse(q, 1).unwrap_err().to_string();
+        assert_eq!("Expected FROM but found 'FORM' at column 19",
+                   error("SELECT avg(value) FORM cpu"));
+        assert_eq!("Unknown duration unit 'x' at column 43",
+                   error("SELECT avg(value) FROM cpu GROUP BY time(1x)"));
+        assert_eq!("'rate' can't be used in SELECT at column 8",
+                   error("SELECT rate(value) FROM cpu"));
+        assert_eq!("Expected a quoted string but found 'a' at column 36",
+                   error("SELECT value FROM cpu WHERE host = a"));
+        assert_eq!("Duration is finer than the timestamp resolution at column 37",
+                   error("SELECT value FROM cpu WHERE time > 1ms"));
+        assert_eq!("GROUP BY needs an aggregate at column 32",
+                   error("SELECT value FROM cpu GROUP BY time(1m)"));
+        assert_eq!("Expected the end of the query but found 'LIMIT' at column 23",
+                   error("SELECT value FROM cpu LIMIT 10"));
+    }
+
+    #[test]
+    fn execute_queries() {
+        let options = db::Options{env: Arc::new(MemEnv::new()), ..db::Options::default()};
+        let mut catalog = Catalog::open("/catalog", options).unwrap();
+        for ts in 0..720 {
+            catalog.record("cpu,host=a", &format::Rec{timestamp: ts * 10, value: 1}).unwrap();
+            catalog.record("cpu,host=b", &format::Rec{timestamp: ts * 10, value: 3}).unwrap();
+        }
+        catalog.record("mem", &format::Rec{timestamp: 0, value: 100}).unwrap();
+
+        let rows = execute(&mut catalog, "SELECT avg(value) FROM cpu WHERE host='a' AND time > now()-1h GROUP BY time(1m)", 7200).unwrap();
+        assert_eq!(60, rows.len());
+        assert_eq!(Row{time: 3600, value: 1.0}, rows[0]);
+        assert_eq!(Row{time: 7140, value: 1.0}, rows[59]);
+
+        assert_eq!(vec![Row{time: 0, value: 120.0}],
+                   execute(&mut catalog, "SELECT count(value) FROM cpu WHERE time >= 0 AND time < 600", 7200).unwrap());
+        assert_eq!(vec![Row{time: 0, value: 3.0}, Row{time: 600, value: 3.0}],
+                   execute(&mut catalog, "select max(value) from cpu group by time(10m)", 1199).unwrap());
+        assert_eq!(vec![Row{time: 0, value: 2.0}, Row{time: 600, value: 2.0}],
+                   execute(&mut catalog, "select mean(value) from cpu where time >= 300 group by time(10m)", 1199).unwrap());
+        assert_eq!(vec![Row{time: 0, value: 3.0}, Row{time: 10, value: 3.0}, Row{time: 20, value: 3.0}],
+                   execute(&mut catalog, "SELECT value FROM cpu WHERE host != 'a' AND time < 30", 7200).unwrap());
+
+        let median = execute(&mut catalog, "SELECT quantile(0.5, value) FROM cpu WHERE time < 100", 7200).unwrap();
+        assert!((median[0].value - 1.0).abs() < 0.02);
+
+        assert!(execute(&mut catalog, "SELECT value FROM disk", 7200).unwrap().is_empty());
+        assert_eq!(io::ErrorKind::InvalidInput,
+                   execute(&mut catalog, "SELECT", 7200).unwrap_err().kind());
+    }
+}
@@ -0,0 +1,325 @@
+use aggregate::Summary;
+use checksum;
+use env;
+use env::Env;
+use env::PosixEnv;
+use format;
+
+use std::io;
+use std::io::Write;
+use std::path;
+
+// Rollup file layout:
+//   [bucket 0]...[bucket N-1][footer]
+//
+// Each bucket is its start followed by its summary (count, sum as two words,
+// min, max, first_ts, first, last_ts, last). The footer holds (resolution,
+// covered end, raw start, bucket count, crc32c, magic); the checksum covers
+// everything before it.
+const BUCKET_SIZE : usize = 80;
+const FOOTER_SIZE : usize = 48;
+// "rts_rol2": the second version added the raw start.
+const ROLLUP_MAGIC : u64 = 0x7274735f726f6c32;
+
+// A resolution at which to keep summaries of the points, e.g. one minute,
+// and for how long.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Tier {
+    // Bucket width, in timestamp units. Buckets are aligned to multiples of
+    // it.
+    pub resolution: u64,
+    // How far behind the newest point buckets are kept. None keeps them
+    // forever.
+    pub retention: Option<u64>,
+}
+
+// The buckets of one tier that hold any points, in time order.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Rollup {
+    pub resolution: u64,
+    // Every bucket before this is complete; later points haven't been rolled
+    // up yet.
+    pub covered_end: u64,
+    // Raw points before this have aged out, so only the buckets here still
+    // account for them.
+    pub raw_start: u64,
+    pub buckets: Vec<(u64, Summary)>,
+}
+
+fn align_down(ts: u64, resolution: u64) -> u64 {
+    return ts - ts % resolution;
+}
+
+fn corrupt(path: &path::Path, what: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData,
+                          format!("Corrupt rollup '{:?}': {}", path, what));
+}
+
+impl Rollup {
+    pub fn empty(resolution: u64) -> Rollup {
+        return Rollup{resolution: resolution, covered_end: 0, raw_start: 0, buckets: Vec::new()};
+    }
+
+    // Summarizes the buckets starting in [start, end).
+    pub fn summarize(&self, start: u64, end: u64) -> Summary {
+        let first = self.buckets.partition_point(|b| b.0 < start);
+        let mut summary = Summary::empty();
+        for &(bucket_start, ref bucket) in &self.buckets[first..] {
+            if bucket_start >= end {
+                break;
+            }
+            summary.merge(bucket);
+        }
+        return summary;
+    }
+
+    // The start of the first bucket that starts at or after 'ts'.
+    pub fn next_bucket(&self, ts: u64) -> Option<u64> {
+        let i = self.buckets.partition_point(|b| b.0 < ts);
+        return self.buckets.get(i).map(|b| b.0);
+    }
+
+    pub fn read<P: AsRef<path::Path>>(filename: P) -> io::Result<Rollup> {
+        return Rollup::read_with_env(&PosixEnv, filename);
+    }
+
+    pub fn read_with_env<P: AsRef<path::Path>>(env: &dyn Env, filename: P) -> io::Result<Rollup> {
+        let path = filename.as_ref();
+        let buf = try!(env.read_all(path));
+        if buf.len() < FOOTER_SIZE || !(buf.len() - FOOTER_SIZE).is_multiple_of(BUCKET_SIZE) {
+            return Err(corrupt(path, "bad length"));
+        }
+
+        let footer = &buf[(buf.len() - FOOTER_SIZE)..];
+        let w = |b: &[u8], i: usize| format::load(&b[(i*8)..((i+1)*8)]);
+        let count = w(footer, 3) as usize;
+        if w(footer, 5) != ROLLUP_MAGIC {
+            return Err(corrupt(path, "bad magic"));
+        }
+        if count * BUCKET_SIZE != buf.len() - FOOTER_SIZE {
+            return Err(corrupt(path, "bad bucket count"));
+        }
+        if checksum::crc32c(&buf[0..(buf.len() - 16)]) as u64 != w(footer, 4) {
+            return Err(corrupt(path, "checksum mismatch"));
+        }
+
+        let mut buckets = Vec::with_capacity(count);
+        for b in buf[0..(count * BUCKET_SIZE)].chunks(BUCKET_SIZE) {
+            buckets.push((w(b, 0), Summary{
+                count: w(b, 1),
+                sum: (w(b, 2) as u128) | ((w(b, 3) as u128) << 64),
+                min: w(b, 4),
+                max: w(b, 5),
+                first_ts: w(b, 6),
+                first: w(b, 7),
+                last_ts: w(b, 8),
+                last: w(b, 9),
+            }));
+        }
+
+        return Ok(Rollup{
+            resolution: w(footer, 0),
+            covered_end: w(footer, 1),
+            raw_start: w(footer, 2),
+            buckets: buckets,
+        });
+    }
+
+    // Writes the rollup to 'filename', replacing any existing file
+    // atomically.
+    pub fn write<P: AsRef<path::Path>>(&self, filename: P) -> io::Result<()> {
+        return self.write_with_env(&PosixEnv, filename);
+    }
+
+    pub fn write_with_env<P: AsRef<path::Path>>(&self, env: &dyn Env, filename: P) -> io::Result<()> {
+        let mut buf = vec![0; self.buckets.len() * BUCKET_SIZE + FOOTER_SIZE];
+        for (i, &(start, ref s)) in self.buckets.iter().enumerate() {
+            let words = [start, s.count, s.sum as u64, (s.sum >> 64) as u64,
+                         s.min, s.max, s.first_ts, s.first, s.last_ts, s.last];
+            for (j, word) in words.iter().enumerate() {
+                let offset = i * BUCKET_SIZE + j * 8;
+                format::store(*word, &mut buf[offset..(offset + 8)]);
+            }
+        }
+
+        let footer_start = buf.len() - FOOTER_SIZE;
+        format::store(self.resolution, &mut buf[footer_start..(footer_start + 8)]);
+        format::store(self.covered_end, &mut buf[(footer_start + 8)..(footer_start + 16)]);
+        format::store(self.raw_start, &mut buf[(footer_start + 16)..(footer_start + 24)]);
+        format::store(self.buckets.len() as u64, &mut buf[(footer_start + 24)..(footer_start + 32)]);
+        let crc = checksum::crc32c(&buf[0..(footer_start + 32)]) as u64;
+        format::store(crc, &mut buf[(footer_start + 32)..(footer_start + 40)]);
+        format::store(ROLLUP_MAGIC, &mut buf[(footer_start + 40)..(footer_start + 48)]);
+
+        let mut tmp = filename.as_ref().as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = path::PathBuf::from(tmp);
+        {
+            let mut file = try!(env.create(&tmp));
+            try!(file.write_all(&buf));
+            try!(file.sync());
+        }
+        try!(env.rename(&tmp, filename.as_ref()));
+        return env::sync_parent(env, filename.as_ref());
+    }
+}
+
+// Rebuilds a tier's rollup from the points being compacted. Buckets from the
+// previous rollup are kept for times whose raw points have aged out, and
+// everything after is recomputed, so that deleted and replaced points drop
+// out of it.
+pub struct RollupBuilder {
+    old: Rollup,
+    tier: Tier,
+    // Points before this are already covered by a bucket from 'old'. A
+    // bucket that raw points only partly cover is kept whole, as it's
+    // better served by the old rollup.
+    skip_until: u64,
+    buckets: Vec<(u64, Summary)>,
+    current: Option<(u64, Summary)>,
+}
+
+impl RollupBuilder {
+    pub fn new(old: Rollup, tier: Tier) -> RollupBuilder {
+        let skip_until = old.raw_start.div_ceil(tier.resolution).saturating_mul(tier.resolution);
+        let kept = old.buckets.partition_point(|b| b.0 < skip_until);
+        return RollupBuilder{
+            buckets: old.buckets[0..kept].to_vec(),
+            old: old,
+            tier: tier,
+            skip_until: skip_until,
+            current: None,
+        };
+    }
+
+    // Adds the next point, in timestamp order.
+    pub fn add(&mut self, ts: u64, v: u64) {
+        if ts < self.skip_until {
+            return;
+        }
+        let bucket = align_down(ts, self.tier.resolution);
+
+        match self.current {
+            Some((start, ref mut summary)) if start == bucket => {
+                summary.add(ts, v);
+                return;
+            },
+            _ => (),
+        }
+        if let Some(done) = self.current.take() {
+            self.buckets.push(done);
+        }
+        let mut summary = Summary::empty();
+        summary.add(ts, v);
+        self.current = Some((bucket, summary));
+    }
+
+    // The rebuilt rollup. The bucket holding the newest point may still
+    // receive points, so it's left for the next rebuild. Buckets that have
+    // aged out relative to 'newest' are dropped. Raw points before
+    // 'raw_start' are about to age out too.
+    pub fn finish(mut self, newest: u64, raw_start: u64) -> Rollup {
+        let covered_end = match self.current {
+            Some((start, _)) => start,
+            // No points left to roll up.
+            None => self.old.covered_end.max(self.skip_until),
+        };
+
+        if let Some(retention) = self.tier.retention {
+            let cutoff = newest.saturating_sub(retention);
+            let resolution = self.tier.resolution;
+            self.buckets.retain(|b| b.0.saturating_add(resolution) > cutoff);
+        }
+
+        return Rollup{
+            resolution: self.tier.resolution,
+            covered_end: covered_end,
+            raw_start: raw_start.max(self.old.raw_start),
+            buckets: self.buckets,
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use env;
+    use super::Rollup;
+    use super::RollupBuilder;
+    use super::Tier;
+
+    #[test]
+    fn build_and_read() {
+        let tier = Tier{resolution: 10, retention: Some(100)};
+        let mut builder = RollupBuilder::new(Rollup::empty(10), tier);
+        for ts in 5..50 {
+            builder.add(ts, ts);
+        }
+        // Raw points before 23 are aging out.
+        let rollup = builder.finish(49, 23);
+
+        // The bucket holding the newest point isn't complete.
+        assert_eq!(40, rollup.covered_end);
+        assert_eq!(vec![0, 10, 20, 30], rollup.buckets.iter().map(|b| b.0).collect::<Vec<u64>>());
+        assert_eq!(5, rollup.buckets[0].1.count);
+        assert_eq!(10, rollup.summarize(10, 20).count);
+        assert_eq!(20 + 21 + 22 + 23 + 24 + 25 + 26 + 27 + 28 + 29, rollup.summarize(20, 30).sum);
+        assert_eq!(Some(10), rollup.next_bucket(6));
+        assert_eq!(None, rollup.next_bucket(31));
+
+        let path = env::temp_path("rollup-test");
+        rollup.write(&path).unwrap();
+        assert_eq!(rollup, Rollup::read(&path).unwrap());
+
+        // So the old buckets are kept for them, and for the bucket that the
+        // remaining ones only partly cover.
+        let mut builder = RollupBuilder::new(rollup, tier);
+        for ts in 23..100 {
+            builder.add(ts, 1);
+        }
+        let rollup = builder.finish(99, 23);
+        assert_eq!(90, rollup.covered_end);
+        assert_eq!(Some(0), rollup.next_bucket(0));
+        assert_eq!(10, rollup.summarize(20, 30).count);
+        assert_eq!(245, rollup.summarize(20, 30).sum);
+        assert_eq!(10, rollup.summarize(30, 40).sum);
+
+        // Buckets more than 100 behind the newest point age out.
+        let mut builder = RollupBuilder::new(rollup, tier);
+        for ts in 30..100 {
+            builder.add(ts, 1);
+        }
+        let rollup = builder.finish(135, 35);
+        assert_eq!(90, rollup.covered_end);
+        assert_eq!(Some(30), rollup.next_bucket(0));
+
+        let mut buf = fs::read(&path).unwrap();
+        buf[3] ^= 1;
+        fs::write(&path, &buf).unwrap();
+        assert!(Rollup::read(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn deleted_points() {
+        let tier = Tier{resolution: 10, retention: None};
+        let mut builder = RollupBuilder::new(Rollup::empty(10), tier);
+        for &ts in &[5, 15, 25, 35] {
+            builder.add(ts, 1);
+        }
+        let rollup = builder.finish(35, 0);
+        assert_eq!(Some(0), rollup.next_bucket(0));
+
+        // Raw points cover every bucket, so the ones whose points are gone
+        // go too.
+        let mut builder = RollupBuilder::new(rollup, tier);
+        builder.add(25, 1);
+        builder.add(35, 1);
+        let rollup = builder.finish(35, 0);
+        assert_eq!(Some(20), rollup.next_bucket(0));
+
+        let rollup = RollupBuilder::new(rollup, tier).finish(35, 0);
+        assert_eq!(None, rollup.next_bucket(0));
+    }
+}
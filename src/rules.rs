@@ -0,0 +1,105 @@
+use env;
+use env::Env;
+use expr;
+use expr::Expr;
+
+use std::io;
+use std::io::Write;
+use std::path;
+use std::str;
+
+// A recording rule: an expression evaluated every 'interval' timestamp
+// units, whose results are written to the series 'name'.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Rule {
+    pub name: String,
+    pub interval: u64,
+    pub expr: Expr,
+    // When the rule is next due, or None if it has never been evaluated.
+    pub next: Option<u64>,
+}
+
+// Rules are stored one per line, as "<name> <interval> <next> <expr>", with
+// a next time of '-' for rules that haven't been evaluated. Series names
+// can't contain whitespace, so splitting on it is safe.
+pub fn load<P: AsRef<path::Path>>(env: &dyn Env, filename: P) -> io::Result<Vec<Rule>> {
+    let contents = match env.read_all(filename.as_ref()) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let text = try!(str::from_utf8(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+
+    let mut rules = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let bad = |what: &str| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} line {}: {}", filename.as_ref(), n + 1, what));
+        let fields : Vec<&str> = line.splitn(4, ' ').collect();
+        if fields.len() != 4 {
+            return Err(bad("expected four fields"));
+        }
+        rules.push(Rule{
+            name: fields[0].to_string(),
+            interval: try!(fields[1].parse::<u64>().map_err(|_| bad("bad interval"))),
+            next: match fields[2] {
+                "-" => None,
+                next => Some(try!(next.parse::<u64>().map_err(|_| bad("bad next time")))),
+            },
+            expr: try!(expr::parse(fields[3]).map_err(|e| bad(&e.to_string()))),
+        });
+    }
+    return Ok(rules);
+}
+
+// Writes 'rules' to 'filename', replacing any existing file atomically.
+pub fn save<P: AsRef<path::Path>>(env: &dyn Env, filename: P, rules: &[Rule]) -> io::Result<()> {
+    let mut text = String::new();
+    for rule in rules {
+        let next = rule.next.map(|n| n.to_string()).unwrap_or("-".to_string());
+        text.push_str(&format!("{} {} {} {}\n", rule.name, rule.interval, next, rule.expr));
+    }
+
+    let mut tmp = filename.as_ref().as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = path::PathBuf::from(tmp);
+    {
+        let mut file = try!(env.create(&tmp));
+        try!(file.write_all(text.as_bytes()));
+        try!(file.sync());
+    }
+    try!(env.rename(&tmp, filename.as_ref()));
+    return env::sync_parent(env, filename.as_ref());
+}
+
+#[cfg(test)]
+mod test {
+    use env::Env;
+    use env::MemEnv;
+    use expr;
+
+    use std::io::Write;
+    use std::path::Path;
+
+    use super::Rule;
+    use super::load;
+    use super::save;
+
+    #[test]
+    fn round_trip() {
+        let rules = vec![
+            Rule{name: "ratio".to_string(), interval: 60,
+                 expr: expr::parse("rate(errors) / rate(requests)").unwrap(), next: None},
+            Rule{name: "doubled".to_string(), interval: 10,
+                 expr: expr::parse("max(\"cpu,host=a\") * 2").unwrap(), next: Some(120)},
+        ];
+        let env = MemEnv::new();
+        save(&env, "/rules", &rules).unwrap();
+        assert_eq!(rules, load(&env, "/rules").unwrap());
+
+        env.create(Path::new("/rules")).unwrap().write_all(b"ratio 60 - rate(\n").unwrap();
+        assert!(load(&env, "/rules").unwrap_err().to_string().contains("line 1"));
+        env.delete(Path::new("/rules")).unwrap();
+        assert_eq!(Vec::<Rule>::new(), load(&env, "/rules").unwrap());
+    }
+}
@@ -0,0 +1,1074 @@
+use aggregate::Aggregator;
+use db::Db;
+use db::Lookup;
+use expr;
+use expr::Function;
+use format::Rec;
+
+use std::collections::HashMap;
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::net;
+use std::panic;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+// An HTTP API over a Db:
+//
+//   GET  /health                       {"status": "ok"}
+//   GET  /stats                        request counts, Db and block cache stats
+//   POST /write                        points, as JSON or line protocol
+//   GET  /lookup?ts=&mode=&within=     {"timestamp": .., "value": ..}
+//   GET  /scan?start=&end=&reverse=&limit=
+//                                      {"points": [[timestamp, value], ...]}
+//   GET  /summary?start=&end=          count, sum, min, max, first and last
+//   GET  /aggregate?start=&end=&width=&fn=&q=
+//                                      {"buckets": [{"start": .., "value": ..}, ...]}
+//
+// Ranges are [start, end), defaulting to everything. Scans return at most
+// 'limit' points, 1000 unless asked for more, up to 100000. Aggregations
+// that would return more than 100000 buckets are refused. Lookup modes are
+// exact (the default), floor, ceiling, nearest (which needs 'within'), step
+// and linear. Aggregation functions are those of the query language, with
+// 'quantile' taking 'q'. Errors are returned as {"error": message}.
+//
+// JSON writes are an array of points, each either [timestamp, value] or
+// {"timestamp": .., "value": ..}. Line protocol is a point per line, as
+// "timestamp value"; blank lines and lines starting with '#' are skipped.
+
+// Requests with more than this in their request line and headers are
+// refused.
+const MAX_HEADER_BYTES : u64 = 64 * 1024;
+const MAX_BODY_BYTES : usize = 64 << 20;
+// How deeply JSON arrays and objects may nest. The parser recurses for each
+// level, so this keeps a hostile body from overflowing the stack.
+const MAX_JSON_DEPTH : usize = 64;
+const DEFAULT_SCAN_LIMIT : usize = 1000;
+const MAX_SCAN_LIMIT : usize = 100000;
+const MAX_BUCKETS : usize = 100000;
+// How often the accept loop checks whether it should shut down.
+const POLL_INTERVAL : Duration = Duration::from_millis(50);
+// How long a client has to send its whole request, and then to take the
+// whole response, however it spaces out the bytes.
+const CLIENT_TIMEOUT : Duration = Duration::from_secs(10);
+// Threads that read requests and write responses, so that a slow client
+// doesn't hold up the others.
+const WORKER_THREADS : usize = 4;
+
+fn bad_request(msg: String) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidInput, msg);
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct Request {
+    pub method: String,
+    // Without the query string.
+    pub path: String,
+    pub params: HashMap<String, String>,
+    // Keyed by lowercase name.
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    fn param<T: FromStr>(&self, name: &str) -> io::Result<Option<T>> {
+        return match self.params.get(name) {
+            Some(text) => text.parse().map(Some).map_err(
+                |_| bad_request(format!("Invalid value for '{}': '{}'", name, text))),
+            None => Ok(None),
+        };
+    }
+
+    fn required<T: FromStr>(&self, name: &str) -> io::Result<T> {
+        return match try!(self.param(name)) {
+            Some(value) => Ok(value),
+            None => Err(bad_request(format!("Missing parameter '{}'", name))),
+        };
+    }
+}
+
+// Decodes a percent-encoded URL component, in which '+' is a space.
+fn decode_component(text: &str) -> io::Result<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = bytes.get((i + 1)..(i + 3))
+                    .and_then(|h| ::std::str::from_utf8(h).ok())
+                    .and_then(|h| u8::from_str_radix(h, 16).ok());
+                match hex {
+                    Some(b) => decoded.push(b),
+                    None => return Err(bad_request(format!("Bad escape in '{}'", text))),
+                }
+                i += 2;
+            },
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    return String::from_utf8(decoded).map_err(|_| bad_request(format!("'{}' isn't UTF-8", text)));
+}
+
+// Reads one request. Bodies must have a Content-Length.
+pub fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Request> {
+    let mut request = Request{
+        method: String::new(),
+        path: String::new(),
+        params: HashMap::new(),
+        headers: HashMap::new(),
+        body: Vec::new(),
+    };
+
+    {
+        let mut head = reader.by_ref().take(MAX_HEADER_BYTES);
+        let mut line = String::new();
+        if try!(head.read_line(&mut line)) == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "No request"));
+        }
+        let parts : Vec<&str> = line.trim_end().split(' ').collect();
+        if parts.len() != 3 || !parts[2].starts_with("HTTP/1.") {
+            return Err(bad_request(format!("Bad request line '{}'", line.trim_end())));
+        }
+        request.method = parts[0].to_string();
+        let mut target = parts[1].splitn(2, '?');
+        request.path = try!(decode_component(target.next().unwrap()));
+        for pair in target.next().unwrap_or("").split('&').filter(|p| !p.is_empty()) {
+            let mut kv = pair.splitn(2, '=');
+            let key = try!(decode_component(kv.next().unwrap()));
+            let value = try!(decode_component(kv.next().unwrap_or("")));
+            request.params.insert(key, value);
+        }
+
+        loop {
+            line.clear();
+            if try!(head.read_line(&mut line)) == 0 {
+                return Err(bad_request("Headers are too long or cut short".to_string()));
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let mut kv = header.splitn(2, ':');
+            let name = kv.next().unwrap().trim().to_lowercase();
+            let value = match kv.next() {
+                Some(value) => value.trim().to_string(),
+                None => return Err(bad_request(format!("Bad header '{}'", header))),
+            };
+            request.headers.insert(name, value);
+        }
+    }
+
+    if request.headers.contains_key("transfer-encoding") {
+        return Err(bad_request("Bodies need a Content-Length".to_string()));
+    }
+    let length = match request.headers.get("content-length") {
+        Some(length) => try!(length.parse::<usize>().map_err(
+            |_| bad_request(format!("Bad Content-Length '{}'", length)))),
+        None => 0,
+    };
+    if length > MAX_BODY_BYTES {
+        return Err(bad_request(format!("Body of {} bytes is too big", length)));
+    }
+    request.body = vec![0; length];
+    try!(reader.read_exact(&mut request.body));
+    return Ok(request);
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: String) -> Response {
+        return Response{status: status, body: body};
+    }
+
+    fn error(status: u16, msg: &str) -> Response {
+        return Response::json(status, format!("{{\"error\": {}}}", json_string(msg)));
+    }
+
+    fn reason(&self) -> &'static str {
+        return match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Internal Server Error",
+        };
+    }
+
+    pub fn write_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        try!(write!(w, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    self.status, self.reason(), self.body.len()));
+        try!(w.write_all(self.body.as_bytes()));
+        return w.flush();
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    return out;
+}
+
+// JSON has no NaN or infinity.
+fn json_f64(v: f64) -> String {
+    if v.is_finite() {
+        return format!("{}", v);
+    }
+    return "null".to_string();
+}
+
+#[derive(Clone,Debug,PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    // Kept as text, since the numbers wanted are u64s, which don't all fit
+    // in an f64.
+    Number(String),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+struct JsonParser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    // Arrays and objects open around the current position.
+    depth: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn error<T>(&self, msg: &str) -> Result<T, String> {
+        return Err(format!("{} at byte {}", msg, self.pos));
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        while self.pos < self.text.len() && (self.text[self.pos] as char).is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        return self.text.get(self.pos).cloned();
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            return true;
+        }
+        return false;
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        if self.depth == MAX_JSON_DEPTH {
+            return self.error("Nested too deeply");
+        }
+        self.depth += 1;
+        let value = self.unnested_value();
+        self.depth -= 1;
+        return value;
+    }
+
+    // A value, without checking how deeply it's nested.
+    fn unnested_value(&mut self) -> Result<Json, String> {
+        match self.peek() {
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if self.eat(b']') {
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(try!(self.value()));
+                    if self.eat(b']') {
+                        return Ok(Json::Array(items));
+                    }
+                    if !self.eat(b',') {
+                        return self.error("Expected ',' or ']'");
+                    }
+                }
+            },
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if self.eat(b'}') {
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    if self.peek() != Some(b'"') {
+                        return self.error("Expected a string");
+                    }
+                    let key = try!(self.string());
+                    if !self.eat(b':') {
+                        return self.error("Expected ':'");
+                    }
+                    fields.push((key, try!(self.value())));
+                    if self.eat(b'}') {
+                        return Ok(Json::Object(fields));
+                    }
+                    if !self.eat(b',') {
+                        return self.error("Expected ',' or '}'");
+                    }
+                }
+            },
+            Some(b'"') => return self.string().map(Json::Str),
+            Some(c) if c == b'-' || c.is_ascii_digit() => {
+                let start = self.pos;
+                while self.pos < self.text.len() &&
+                    (self.text[self.pos].is_ascii_digit() || b"+-.eE".contains(&self.text[self.pos])) {
+                    self.pos += 1;
+                }
+                let number = String::from_utf8_lossy(&self.text[start..self.pos]).into_owned();
+                return Ok(Json::Number(number));
+            },
+            Some(_) => {
+                for &(word, ref value) in &[("true", Json::Bool(true)), ("false", Json::Bool(false)), ("null", Json::Null)] {
+                    if self.text[self.pos..].starts_with(word.as_bytes()) {
+                        self.pos += word.len();
+                        return Ok(value.clone());
+                    }
+                }
+                return self.error("Unexpected character");
+            },
+            None => return self.error("Unexpected end"),
+        }
+    }
+
+    // A string, starting at its opening quote.
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut out = Vec::new();
+        loop {
+            let c = match self.text.get(self.pos) {
+                Some(c) => *c,
+                None => return self.error("Unterminated string"),
+            };
+            self.pos += 1;
+            match c {
+                b'"' => return String::from_utf8(out).or_else(|_| self.error("Invalid UTF-8")),
+                b'\\' => {
+                    let escaped = match self.text.get(self.pos) {
+                        Some(&b'n') => '\n',
+                        Some(&b't') => '\t',
+                        Some(&b'r') => '\r',
+                        Some(&b'b') => '\u{8}',
+                        Some(&b'f') => '\u{c}',
+                        Some(&b'u') => {
+                            let code = self.text.get((self.pos + 1)..(self.pos + 5))
+                                .and_then(|h| ::std::str::from_utf8(h).ok())
+                                .and_then(|h| u32::from_str_radix(h, 16).ok())
+                                .and_then(::std::char::from_u32);
+                            match code {
+                                Some(c) => {
+                                    self.pos += 4;
+                                    c
+                                },
+                                None => return self.error("Unsupported \\u escape"),
+                            }
+                        },
+                        Some(&c) if c == b'"' || c == b'\\' || c == b'/' => c as char,
+                        _ => return self.error("Bad escape"),
+                    };
+                    self.pos += 1;
+                    let mut buf = [0; 4];
+                    out.extend_from_slice(escaped.encode_utf8(&mut buf).as_bytes());
+                },
+                c => out.push(c),
+            }
+        }
+    }
+}
+
+fn parse_json(text: &[u8]) -> Result<Json, String> {
+    let mut parser = JsonParser{text: text, pos: 0, depth: 0};
+    let value = try!(parser.value());
+    if parser.peek().is_some() {
+        return parser.error("Trailing characters");
+    }
+    return Ok(value);
+}
+
+fn json_u64(value: &Json, what: &str) -> Result<u64, String> {
+    return match *value {
+        Json::Number(ref n) => n.parse().map_err(
+            |_| format!("The {} must be an unsigned integer, not {}", what, n)),
+        _ => Err(format!("The {} must be a number", what)),
+    };
+}
+
+fn json_point(value: &Json) -> Result<Rec, String> {
+    match *value {
+        Json::Array(ref pair) if pair.len() == 2 => {
+            return Ok(Rec{
+                timestamp: try!(json_u64(&pair[0], "timestamp")),
+                value: try!(json_u64(&pair[1], "value")),
+            });
+        },
+        Json::Object(ref fields) => {
+            let field = |name: &str| match fields.iter().find(|f| f.0 == name) {
+                Some(f) => json_u64(&f.1, name),
+                None => Err(format!("Point has no '{}'", name)),
+            };
+            return Ok(Rec{
+                timestamp: try!(field("timestamp")),
+                value: try!(field("value")),
+            });
+        },
+        _ => return Err("A point must be [timestamp, value] or {\"timestamp\": .., \"value\": ..}".to_string()),
+    }
+}
+
+// Parses the body of a write: JSON if it says so, or if it looks like it,
+// and line protocol otherwise.
+pub fn parse_points(body: &[u8], content_type: Option<&str>) -> Result<Vec<Rec>, String> {
+    let first = body.iter().find(|c| !(**c as char).is_ascii_whitespace());
+    let is_json = content_type.map(|t| t.contains("json")).unwrap_or(false) ||
+        first == Some(&b'[') || first == Some(&b'{');
+
+    if is_json {
+        return match try!(parse_json(body)) {
+            Json::Array(ref points) => points.iter().map(json_point).collect(),
+            _ => Err("Expected an array of points".to_string()),
+        };
+    }
+
+    let text = try!(::std::str::from_utf8(body).map_err(|_| "Body isn't UTF-8".to_string()));
+    let mut points = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields : Vec<&str> = line.split_whitespace().collect();
+        let parsed = if fields.len() == 2 {
+            fields[0].parse().and_then(|ts| fields[1].parse().map(|v| Rec{timestamp: ts, value: v})).ok()
+        } else {
+            None
+        };
+        match parsed {
+            Some(rec) => points.push(rec),
+            None => return Err(format!("Line {}: expected 'timestamp value', got '{}'", i + 1, line)),
+        }
+    }
+    return Ok(points);
+}
+
+fn parse_mode(request: &Request) -> io::Result<Lookup> {
+    let mode = request.params.get("mode").map(|m| m.as_str()).unwrap_or("exact");
+    return match mode {
+        "exact" => Ok(Lookup::Exact),
+        "floor" => Ok(Lookup::Floor),
+        "ceiling" => Ok(Lookup::Ceiling),
+        "nearest" => Ok(Lookup::Nearest(try!(request.required("within")))),
+        "step" => Ok(Lookup::Step),
+        "linear" => Ok(Lookup::Linear),
+        _ => Err(bad_request(format!("Unknown lookup mode '{}'", mode))),
+    };
+}
+
+fn parse_aggregator(request: &Request) -> io::Result<Aggregator> {
+    let name : String = try!(request.required("fn"));
+    return match expr::parse_function(&name.to_lowercase()) {
+        Some(Function::Aggregate(aggregator)) => Ok(aggregator),
+        None if name.eq_ignore_ascii_case("quantile") => {
+            let q : f64 = try!(request.required("q"));
+            if !(0.0..=1.0).contains(&q) {
+                return Err(bad_request("Quantile must be between 0 and 1".to_string()));
+            }
+            Ok(Aggregator::Quantile(q))
+        },
+        _ => Err(bad_request(format!("Unknown aggregation '{}'", name))),
+    };
+}
+
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct ServiceStats {
+    pub requests: u64,
+    // Requests answered with an error.
+    pub errors: u64,
+    pub points_written: u64,
+}
+
+// Answers requests against a Db. See the top of this file for the API.
+pub struct Service {
+    db: Db,
+    stats: ServiceStats,
+}
+
+impl Service {
+    pub fn new(db: Db) -> Service {
+        return Service{db: db, stats: ServiceStats::default()};
+    }
+
+    pub fn db(&mut self) -> &mut Db {
+        return &mut self.db;
+    }
+
+    pub fn into_db(self) -> Db {
+        return self.db;
+    }
+
+    pub fn stats(&self) -> ServiceStats {
+        return self.stats;
+    }
+
+    pub fn handle(&mut self, request: &Request) -> Response {
+        self.stats.requests += 1;
+        let endpoint : fn(&mut Service, &Request) -> io::Result<Response> = match request.path.as_str() {
+            "/health" => Service::health,
+            "/stats" => Service::report_stats,
+            "/write" => Service::write,
+            "/lookup" => Service::lookup,
+            "/scan" => Service::scan,
+            "/summary" => Service::summary,
+            "/aggregate" => Service::aggregate,
+            path => {
+                self.stats.errors += 1;
+                return Response::error(404, &format!("No such endpoint: {}", path));
+            },
+        };
+        let method = if request.path == "/write" { "POST" } else { "GET" };
+        if request.method != method {
+            self.stats.errors += 1;
+            return Response::error(405, &format!("{} needs {}", request.path, method));
+        }
+
+        return match endpoint(self, request) {
+            Ok(response) => response,
+            Err(e) => {
+                self.stats.errors += 1;
+                let status = match e.kind() {
+                    io::ErrorKind::InvalidInput => 400,
+                    io::ErrorKind::NotFound => 404,
+                    _ => 500,
+                };
+                Response::error(status, &e.to_string())
+            },
+        };
+    }
+
+    fn health(&mut self, _: &Request) -> io::Result<Response> {
+        return Ok(Response::json(200, "{\"status\": \"ok\"}".to_string()));
+    }
+
+    fn report_stats(&mut self, _: &Request) -> io::Result<Response> {
+        let db = self.db.stats();
+        let cache = match self.db.options().block_cache {
+            Some(ref cache) => {
+                let c = cache.stats();
+                format!("{{\"hits\": {}, \"misses\": {}, \"evictions\": {}, \"usage_bytes\": {}, \"capacity_bytes\": {}}}",
+                        c.hits, c.misses, c.evictions, c.usage_bytes, c.capacity_bytes)
+            },
+            None => "null".to_string(),
+        };
+        return Ok(Response::json(200, format!(
+            "{{\"requests\": {}, \"errors\": {}, \"points_written\": {}, \"bloom_checks\": {}, \"bloom_negatives\": {}, \"bloom_false_positives\": {}, \"block_cache\": {}}}",
+            self.stats.requests, self.stats.errors, self.stats.points_written,
+            db.bloom_checks, db.bloom_negatives, db.bloom_false_positives, cache)));
+    }
+
+    fn write(&mut self, request: &Request) -> io::Result<Response> {
+        let content_type = request.headers.get("content-type").map(|t| t.as_str());
+        let points = try!(parse_points(&request.body, content_type).map_err(bad_request));
+        try!(self.db.record_batch(&points));
+        self.stats.points_written += points.len() as u64;
+        return Ok(Response::json(200, format!("{{\"written\": {}}}", points.len())));
+    }
+
+    fn lookup(&mut self, request: &Request) -> io::Result<Response> {
+        let ts = try!(request.required("ts"));
+        let mode = try!(parse_mode(request));
+        let (ts, value) = try!(self.db.lookup_with_mode(ts, mode));
+        return Ok(Response::json(200, format!("{{\"timestamp\": {}, \"value\": {}}}", ts, value)));
+    }
+
+    fn scan(&mut self, request: &Request) -> io::Result<Response> {
+        let start = try!(request.param("start")).unwrap_or(0);
+        let end = try!(request.param("end")).unwrap_or(u64::MAX);
+        let reverse = try!(request.param("reverse")).unwrap_or(false);
+        let limit = try!(request.param("limit")).unwrap_or(DEFAULT_SCAN_LIMIT);
+        if limit > MAX_SCAN_LIMIT {
+            return Err(bad_request(format!("limit can be at most {}", MAX_SCAN_LIMIT)));
+        }
+
+        let mut merged = try!(self.db.scan(start, end));
+        let points : Vec<String> = if reverse {
+            (&mut merged).rev().take(limit).map(|(ts, v)| format!("[{}, {}]", ts, v)).collect()
+        } else {
+            (&mut merged).take(limit).map(|(ts, v)| format!("[{}, {}]", ts, v)).collect()
+        };
+        try!(merged.take_status());
+        return Ok(Response::json(200, format!("{{\"points\": [{}]}}", points.join(", "))));
+    }
+
+    fn summary(&mut self, request: &Request) -> io::Result<Response> {
+        let start = try!(request.param("start")).unwrap_or(0);
+        let end = try!(request.param("end")).unwrap_or(u64::MAX);
+        let s = try!(self.db.summarize(start, end));
+        if s.is_empty() {
+            return Ok(Response::json(200, "{\"count\": 0}".to_string()));
+        }
+        return Ok(Response::json(200, format!(
+            "{{\"count\": {}, \"sum\": {}, \"min\": {}, \"max\": {}, \"mean\": {}, \"first\": [{}, {}], \"last\": [{}, {}]}}",
+            s.count, s.sum, s.min, s.max, json_f64(s.mean().unwrap_or(0.0)),
+            s.first_ts, s.first, s.last_ts, s.last)));
+    }
+
+    fn aggregate(&mut self, request: &Request) -> io::Result<Response> {
+        let start = try!(request.param("start")).unwrap_or(0);
+        let end = try!(request.param("end")).unwrap_or(u64::MAX);
+        let width = try!(request.required("width"));
+        let aggregator = try!(parse_aggregator(request));
+
+        let mut aggregation = try!(self.db.aggregate(start, end, width, aggregator));
+        let buckets : Vec<String> = (&mut aggregation)
+            .take(MAX_BUCKETS + 1)
+            .map(|b| format!("{{\"start\": {}, \"value\": {}}}", b.start, json_f64(b.value)))
+            .collect();
+        try!(aggregation.take_status());
+        if buckets.len() > MAX_BUCKETS {
+            return Err(bad_request(format!(
+                "More than {} buckets; use a wider width or a shorter range", MAX_BUCKETS)));
+        }
+        return Ok(Response::json(200, format!("{{\"buckets\": [{}]}}", buckets.join(", "))));
+    }
+}
+
+// Serves a Service over HTTP, one request per connection. Connections are
+// read and answered on worker threads, but a Db can't be used concurrently,
+// so the requests themselves are handled one at a time.
+pub struct Server {
+    listener: net::TcpListener,
+    service: Arc<Mutex<Service>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl Server {
+    pub fn bind<A: net::ToSocketAddrs>(address: A, db: Db) -> io::Result<Server> {
+        let listener = try!(net::TcpListener::bind(address));
+        try!(listener.set_nonblocking(true));
+        return Ok(Server{
+            listener: listener,
+            service: Arc::new(Mutex::new(Service::new(db))),
+            shutdown: Arc::new(AtomicBool::new(false)),
+        });
+    }
+
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        return self.listener.local_addr();
+    }
+
+    // Setting this makes run() return, once it has finished the request in
+    // progress.
+    pub fn shutdown_flag(&self) -> Arc<AtomicBool> {
+        return self.shutdown.clone();
+    }
+
+    // Serves requests until shut down, then flushes the memtable to a table
+    // and returns the Db.
+    pub fn run(self) -> io::Result<Db> {
+        let (sender, receiver) = mpsc::channel::<net::TcpStream>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut workers = Vec::new();
+        for _ in 0..WORKER_THREADS {
+            let connections = receiver.clone();
+            let service = self.service.clone();
+            workers.push(thread::spawn(move || {
+                loop {
+                    // Workers exit once the accept loop, and with it the
+                    // sending half of the queue, is gone.
+                    let stream = match connections.lock().unwrap_or_else(PoisonError::into_inner).recv() {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+                    // A client that goes away, or sends garbage, only
+                    // affects its own connection.
+                    let _ = serve(&service, stream);
+                }
+            }));
+        }
+
+        while !self.shutdown.load(Ordering::SeqCst) {
+            match self.listener.accept() {
+                Ok((stream, _)) => sender.send(stream).unwrap(),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                // E.g. out of file descriptors. Back off and try again.
+                Err(_) => thread::sleep(POLL_INTERVAL),
+            }
+        }
+
+        // Connections already accepted are answered first.
+        drop(sender);
+        for worker in workers {
+            let _ = worker.join();
+        }
+        // Even if a request panicked, the points recorded before it are
+        // flushed.
+        {
+            let mut service = self.service.lock().unwrap_or_else(PoisonError::into_inner);
+            try!(service.db().flush());
+            try!(service.db().sync());
+        }
+        let service = try!(Arc::try_unwrap(self.service).map_err(
+            |_| io::Error::other("A worker still holds the Db")));
+        return Ok(service.into_inner().unwrap_or_else(PoisonError::into_inner).into_db());
+    }
+}
+
+// Reads a request from 'stream' and answers it, holding the service only
+// while the request is handled.
+fn serve(service: &Mutex<Service>, stream: net::TcpStream) -> io::Result<()> {
+    try!(stream.set_nonblocking(false));
+
+    let mut reader = io::BufReader::new(Deadline::after(try!(stream.try_clone()), CLIENT_TIMEOUT));
+    let response = match read_request(&mut reader) {
+        Ok(request) => {
+            let mut service = service.lock().unwrap_or_else(PoisonError::into_inner);
+            // A request that hits a bug gets an error, rather than taking
+            // the worker (and with it the server) down.
+            match panic::catch_unwind(panic::AssertUnwindSafe(|| service.handle(&request))) {
+                Ok(response) => response,
+                Err(_) => {
+                    service.stats.errors += 1;
+                    Response::error(500, "The request failed unexpectedly")
+                },
+            }
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidInput || e.kind() == io::ErrorKind::InvalidData => {
+            Response::error(400, &e.to_string())
+        },
+        Err(e) => return Err(e),
+    };
+    return response.write_to(&mut Deadline::after(stream, CLIENT_TIMEOUT));
+}
+
+// A connection that fails with TimedOut once a deadline passes, rather than
+// only when a single read or write takes too long.
+struct Deadline {
+    stream: net::TcpStream,
+    deadline: Instant,
+}
+
+impl Deadline {
+    fn after(stream: net::TcpStream, timeout: Duration) -> Deadline {
+        return Deadline{stream: stream, deadline: Instant::now() + timeout};
+    }
+
+    fn remaining(&self) -> io::Result<Duration> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Err(timed_out());
+        }
+        return Ok(self.deadline - now);
+    }
+}
+
+fn timed_out() -> io::Error {
+    return io::Error::new(io::ErrorKind::TimedOut, "The client took too long");
+}
+
+// Sockets report running out of time as WouldBlock on some platforms.
+fn or_timed_out<T>(result: io::Result<T>) -> io::Result<T> {
+    return result.map_err(|e| match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => timed_out(),
+        _ => e,
+    });
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        try!(self.stream.set_read_timeout(Some(try!(self.remaining()))));
+        return or_timed_out(self.stream.read(buf));
+    }
+}
+
+impl Write for Deadline {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.stream.set_write_timeout(Some(try!(self.remaining()))));
+        return or_timed_out(self.stream.write(buf));
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.stream.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Deadline;
+    use super::Request;
+    use super::Server;
+    use super::Service;
+    use super::parse_points;
+    use super::read_request;
+
+    use db::Db;
+    use db::Options;
+    use env::Env;
+    use env::MemEnv;
+    use format::Rec;
+
+    use std::collections::HashMap;
+    use std::io::Read;
+    use std::io::Write;
+    use std::net;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    fn in_memory(env: &Arc<MemEnv>) -> Db {
+        return Db::with_options("/server", Options{env: env.clone(), ..Options::default()}).unwrap();
+    }
+
+    fn get(path: &str, params: &[(&str, &str)]) -> Request {
+        return Request{
+            method: "GET".to_string(),
+            path: path.to_string(),
+            params: params.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect(),
+            headers: HashMap::new(),
+            body: Vec::new(),
+        };
+    }
+
+    fn post(path: &str, body: &str) -> Request {
+        let mut request = get(path, &[]);
+        request.method = "POST".to_string();
+        request.body = body.as_bytes().to_vec();
+        return request;
+    }
+
+    #[test]
+    fn points() {
+        let expected = || vec![Rec{timestamp: 1, value: 10}, Rec{timestamp: 2, value: 20}];
+        assert_eq!(Ok(expected()), parse_points(b"1 10\n# comment\n\n 2  20 \n", None));
+        assert_eq!(Ok(expected()), parse_points(b" [[1, 10], {\"value\": 20, \"timestamp\": 2}]", None));
+        assert_eq!(Ok(expected()), parse_points(b"[[1,10],[2,20]]", Some("application/json")));
+        assert_eq!(Ok(vec![Rec{timestamp: 18446744073709551614, value: 0}]),
+                   parse_points(b"[{\"timestamp\": 18446744073709551614, \"value\": 0, \"n\\u00e9\": [null, true]}]", None));
+
+        assert!(parse_points(b"1 10\n2\n", None).unwrap_err().starts_with("Line 2:"));
+        assert!(parse_points(b"1 -10\n", None).is_err());
+        assert!(parse_points(b"[[1, 1.5]]", None).is_err());
+        assert!(parse_points(b"[[1, 10]", None).is_err());
+        assert!(parse_points(b"[[1, 10]] x", None).is_err());
+        assert!(parse_points(b"{\"timestamp\": 1}", None).is_err());
+        assert!(parse_points(b"[{\"timestamp\": 1}]", None).is_err());
+        assert!(parse_points(&[b'['; 100000], None).unwrap_err().starts_with("Nested too deeply"));
+    }
+
+    #[test]
+    fn requests() {
+        let text = "POST /write%2F?a=1&b=x+y%21&c HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\n1 2\r\nextra";
+        let request = read_request(&mut text.as_bytes()).unwrap();
+        assert_eq!("POST", request.method);
+        assert_eq!("/write/", request.path);
+        assert_eq!("x y!", request.params["b"]);
+        assert_eq!("", request.params["c"]);
+        assert_eq!("localhost", request.headers["host"]);
+        assert_eq!(b"1 2\r\n".to_vec(), request.body);
+
+        assert!(read_request(&mut "GET /\r\n\r\n".as_bytes()).is_err());
+        assert!(read_request(&mut "GET /%zz HTTP/1.1\r\n\r\n".as_bytes()).is_err());
+        assert!(read_request(&mut "GET / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn endpoints() {
+        let env = Arc::new(MemEnv::new());
+        let mut service = Service::new(in_memory(&env));
+        let mut call = |request: Request| {
+            let response = service.handle(&request);
+            (response.status, response.body)
+        };
+
+        assert_eq!((200, "{\"status\": \"ok\"}".to_string()), call(get("/health", &[])));
+        assert_eq!((200, "{\"written\": 4}".to_string()), call(post("/write", "10 1\n20 2\n30 3\n40 4\n")));
+        assert_eq!(400, call(post("/write", "10")).0);
+        assert_eq!(400, call(post("/write", &"[".repeat(1 << 20))).0);
+        // A batch with a reserved value is refused whole.
+        assert_eq!(400, call(post("/write", "[[50, 5], [60, 18446744073709551615]]")).0);
+        assert_eq!(404, call(get("/lookup", &[("ts", "50")])).0);
+
+        assert_eq!((200, "{\"timestamp\": 20, \"value\": 2}".to_string()), call(get("/lookup", &[("ts", "20")])));
+        assert_eq!(404, call(get("/lookup", &[("ts", "25")])).0);
+        assert_eq!((200, "{\"timestamp\": 20, \"value\": 2}".to_string()),
+                   call(get("/lookup", &[("ts", "25"), ("mode", "floor")])));
+        assert_eq!((200, "{\"timestamp\": 25, \"value\": 3}".to_string()),
+                   call(get("/lookup", &[("ts", "25"), ("mode", "linear")])));
+        assert_eq!(400, call(get("/lookup", &[("ts", "25"), ("mode", "nearest")])).0);
+        assert_eq!(400, call(get("/lookup", &[("ts", "x")])).0);
+        assert_eq!(400, call(get("/lookup", &[])).0);
+
+        assert_eq!((200, "{\"points\": [[10, 1], [20, 2], [30, 3], [40, 4]]}".to_string()), call(get("/scan", &[])));
+        assert_eq!((200, "{\"points\": [[30, 3], [20, 2]]}".to_string()),
+                   call(get("/scan", &[("start", "15"), ("end", "40"), ("reverse", "true")])));
+        assert_eq!((200, "{\"points\": [[10, 1]]}".to_string()), call(get("/scan", &[("limit", "1")])));
+        assert_eq!(400, call(get("/scan", &[("limit", "100001")])).0);
+
+        assert_eq!((200, "{\"count\": 4, \"sum\": 10, \"min\": 1, \"max\": 4, \"mean\": 2.5, \"first\": [10, 1], \"last\": [40, 4]}".to_string()),
+                   call(get("/summary", &[])));
+        assert_eq!((200, "{\"count\": 0}".to_string()), call(get("/summary", &[("start", "100")])));
+
+        assert_eq!((200, "{\"buckets\": [{\"start\": 0, \"value\": 1}, {\"start\": 20, \"value\": 5}, {\"start\": 40, \"value\": 4}]}".to_string()),
+                   call(get("/aggregate", &[("width", "20"), ("fn", "sum")])));
+        assert_eq!(200, call(get("/aggregate", &[("width", "20"), ("fn", "quantile"), ("q", "0.5")])).0);
+        assert_eq!(400, call(get("/aggregate", &[("width", "20"), ("fn", "quantile"), ("q", "2")])).0);
+        assert_eq!(400, call(get("/aggregate", &[("width", "20"), ("fn", "rate")])).0);
+        assert_eq!(400, call(get("/aggregate", &[("width", "0"), ("fn", "sum")])).0);
+
+        assert_eq!(404, call(get("/nothing", &[])).0);
+        assert_eq!(405, call(get("/write", &[])).0);
+        assert_eq!(405, call(post("/scan", "")).0);
+
+        let (status, stats) = call(get("/stats", &[]));
+        assert_eq!(200, status);
+        assert!(stats.starts_with("{\"requests\": 28, \"errors\": 15, \"points_written\": 4,"), "{}", stats);
+    }
+
+    #[test]
+    fn scan_limit() {
+        let env = Arc::new(MemEnv::new());
+        let mut service = Service::new(in_memory(&env));
+        let points : Vec<String> = (0..1500).map(|i| format!("{} {}", i, i)).collect();
+        assert_eq!(200, service.handle(&post("/write", &points.join("\n"))).status);
+
+        let count = |body: String| body.matches('[').count() - 1;
+        assert_eq!(1000, count(service.handle(&get("/scan", &[])).body));
+        assert_eq!(1500, count(service.handle(&get("/scan", &[("limit", "2000")])).body));
+    }
+
+    #[test]
+    fn bucket_limit() {
+        let env = Arc::new(MemEnv::new());
+        let mut service = Service::new(in_memory(&env));
+        let points : Vec<Rec> = (0..100001).map(|i| Rec{timestamp: i, value: i}).collect();
+        service.db().record_batch(&points).unwrap();
+
+        assert_eq!(400, service.handle(&get("/aggregate", &[("width", "1"), ("fn", "sum")])).status);
+        assert_eq!(200, service.handle(&get("/aggregate", &[("width", "2"), ("fn", "sum")])).status);
+        assert_eq!(200, service.handle(&get("/aggregate", &[("width", "1"), ("fn", "sum"), ("end", "100000")])).status);
+    }
+
+    #[test]
+    fn poisoned() {
+        let env = Arc::new(MemEnv::new());
+        let server = Server::bind("127.0.0.1:0", in_memory(&env)).unwrap();
+        let address = server.local_addr().unwrap();
+        let shutdown = server.shutdown_flag();
+        let service = server.service.clone();
+        assert!(thread::spawn(move || {
+            let _locked = service.lock().unwrap();
+            panic!("Poisoning the service");
+        }).join().is_err());
+
+        let client = thread::spawn(move || {
+            let response = http(address, "POST /write HTTP/1.1\r\nContent-Length: 5\r\n\r\n1 100");
+            shutdown.store(true, Ordering::SeqCst);
+            return response;
+        });
+        // The Db is still flushed.
+        drop(server.run().unwrap());
+        assert_eq!((200, "{\"written\": 1}".to_string()), client.join().unwrap());
+        assert_eq!(100, in_memory(&env).lookup(1).unwrap());
+    }
+
+    #[test]
+    fn slow_client() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = net::TcpStream::connect(address).unwrap();
+            // Each byte arrives well within any per-read timeout.
+            for c in "GET /health HTTP/1.1\r\n\r\n".bytes() {
+                if stream.write_all(&[c]).is_err() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let stream = listener.accept().unwrap().0;
+        let started = Instant::now();
+        let mut reader = ::std::io::BufReader::new(Deadline::after(stream, Duration::from_millis(300)));
+        assert_eq!(::std::io::ErrorKind::TimedOut, read_request(&mut reader).unwrap_err().kind());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        drop(reader);
+        client.join().unwrap();
+    }
+
+    fn http(address: net::SocketAddr, request: &str) -> (u16, String) {
+        let mut stream = net::TcpStream::connect(address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1.to_string();
+        return (status, body);
+    }
+
+    #[test]
+    fn localhost() {
+        let env = Arc::new(MemEnv::new());
+        let server = Server::bind("127.0.0.1:0", in_memory(&env)).unwrap();
+        let address = server.local_addr().unwrap();
+        let shutdown = server.shutdown_flag();
+
+        let client = thread::spawn(move || {
+            // A client that never sends its request doesn't hold up the
+            // others.
+            let idle = net::TcpStream::connect(address).unwrap();
+            let started = Instant::now();
+            let body = "[[1, 100], [2, 200]]";
+            let responses = vec![
+                http(address, &format!("POST /write HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                                       body.len(), body)),
+                http(address, "GET /lookup?ts=2 HTTP/1.1\r\n\r\n"),
+                http(address, "nonsense\r\n\r\n"),
+                http(address, "GET /health HTTP/1.0\r\n\r\n"),
+            ];
+            assert!(started.elapsed() < Duration::from_secs(5));
+            drop(idle);
+            shutdown.store(true, Ordering::SeqCst);
+            return responses;
+        });
+        // Shutting down flushes the memtable to a table.
+        drop(server.run().unwrap());
+
+        let responses = client.join().unwrap();
+        assert_eq!((200, "{\"written\": 2}".to_string()), responses[0]);
+        assert_eq!((200, "{\"timestamp\": 2, \"value\": 200}".to_string()), responses[1]);
+        assert_eq!(400, responses[2].0);
+        assert_eq!(200, responses[3].0);
+        let files = env.list(Path::new("/server")).unwrap();
+        assert!(files.iter().any(|f| f.to_string_lossy().contains("table_")), "{:?}", files);
+        assert_eq!(200, in_memory(&env).lookup(2).unwrap());
+    }
+}
@@ -0,0 +1,323 @@
+use aggregate::Aggregator;
+use aggregate::Bucket;
+use aggregate::Summary;
+use aggregate::Variance;
+use db::Db;
+use db::Lookup;
+use db::Options;
+use env::MemEnv;
+use fault_env::next_random;
+use format;
+use sketch::Sketch;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+
+// Timestamps are drawn from [0, KEYS), or, for half the single points, from
+// [0, HOT_KEYS) so that points are often overwritten and deleted.
+const KEYS : u64 = 10000;
+const HOT_KEYS : u64 = 64;
+
+// One step of a simulated workload.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Op {
+    Record(u64, u64),
+    // (start, count, value): points at consecutive timestamps, enough to
+    // fill several table blocks, with values counting up from 'value'.
+    RecordRun(u64, u64, u64),
+    Delete(u64),
+    Lookup(u64, Lookup),
+    // (start, end, reverse)
+    Scan(u64, u64, bool),
+    Summarize(u64, u64),
+    // (start, end, width, aggregator)
+    Aggregate(u64, u64, u64, Aggregator),
+    Flush,
+    Compact,
+    Reopen,
+}
+
+// The same workload for the same seed, every time.
+pub fn generate(seed: u64, steps: usize) -> Vec<Op> {
+    let mut random = seed;
+    let mut r = |n: u64| next_random(&mut random) % n;
+    let mut ops = Vec::with_capacity(steps);
+    for _ in 0..steps {
+        let key = |r: &mut dyn FnMut(u64) -> u64| if r(2) == 0 { r(HOT_KEYS) } else { r(KEYS) };
+        let op = match r(100) {
+            0..=34 => Op::Record(key(&mut r), r(format::TOMBSTONE)),
+            35..=39 => Op::RecordRun(r(KEYS), r(5000), r(1 << 32)),
+            40..=51 => Op::Delete(key(&mut r)),
+            52..=62 => {
+                let mode = [Lookup::Exact, Lookup::Floor, Lookup::Ceiling][r(3) as usize];
+                Op::Lookup(key(&mut r), mode)
+            },
+            63..=70 => {
+                let start = r(KEYS);
+                Op::Scan(start, start + r(3000), r(2) == 0)
+            },
+            71..=74 => {
+                let start = r(KEYS);
+                Op::Summarize(start, start + r(KEYS))
+            },
+            75..=80 => {
+                // Half are aligned so that the rollup tiers can serve them.
+                let start = r(KEYS);
+                let start = if r(2) == 0 { start - start % 100 } else { start };
+                let width = [7, 10, 100, 300, 1000][r(5) as usize];
+                let aggregator = [
+                    Aggregator::Sum, Aggregator::Avg, Aggregator::Min, Aggregator::Max,
+                    Aggregator::Count, Aggregator::First, Aggregator::Last,
+                    Aggregator::Stddev, Aggregator::Quantile(0.5),
+                ][r(9) as usize];
+                Op::Aggregate(start, start + r(KEYS), width, aggregator)
+            },
+            81..=88 => Op::Flush,
+            89..=93 => Op::Compact,
+            _ => Op::Reopen,
+        };
+        ops.push(op);
+    }
+    return ops;
+}
+
+fn not_found<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    return match result {
+        Ok(t) => Ok(Some(t)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    };
+}
+
+fn expect<T: PartialEq + ::std::fmt::Debug>(what: &str, expected: T, actual: T) -> Result<(), String> {
+    if expected != actual {
+        return Err(format!("{}: expected {:?}, got {:?}", what, expected, actual));
+    }
+    return Ok(());
+}
+
+// What Db::aggregate should return, computed from every point in the model.
+fn aggregate(model: &BTreeMap<u64, u64>, start: u64, end: u64, width: u64, aggregator: Aggregator) -> Vec<Bucket> {
+    let mut buckets = Vec::new();
+    let mut bucket_start = start;
+    while bucket_start < end {
+        let bucket_end = bucket_start.saturating_add(width).min(end);
+        let mut summary = Summary::empty();
+        let mut variance = Variance::default();
+        let mut sketch = Sketch::default();
+        for (k, v) in model.range(bucket_start..bucket_end) {
+            summary.add(*k, *v);
+            variance.add(*v);
+            sketch.add(*v);
+        }
+        let value = match aggregator {
+            Aggregator::Stddev => variance.stddev(),
+            Aggregator::Quantile(q) => sketch.quantile(q),
+            a => a.apply(&summary),
+        };
+        if let Some(value) = value {
+            buckets.push(Bucket{start: bucket_start, value: value});
+        }
+        bucket_start = bucket_end;
+    }
+    return buckets;
+}
+
+// Compares every point in the Db with the model.
+fn check_all(db: &mut Db, model: &BTreeMap<u64, u64>) -> Result<(), String> {
+    let mut merged = try!(db.scan(0, u64::MAX).map_err(|e| e.to_string()));
+    let points : Vec<(u64, u64)> = (&mut merged).collect();
+    try!(merged.take_status().map_err(|e| e.to_string()));
+    let expected : Vec<(u64, u64)> = model.iter().map(|(k, v)| (*k, *v)).collect();
+    if points != expected {
+        let diff = points.iter().zip(&expected).position(|(a, b)| a != b)
+            .unwrap_or(points.len().min(expected.len()));
+        return Err(format!("{} points, but the model has {}; they differ from #{}: {:?} vs {:?}",
+                           points.len(), expected.len(), diff, points.get(diff), expected.get(diff)));
+    }
+    return Ok(());
+}
+
+fn step(db: &mut Db, model: &mut BTreeMap<u64, u64>, op: Op) -> io::Result<Result<(), String>> {
+    match op {
+        Op::Record(ts, value) => {
+            try!(db.record(&format::Rec{timestamp: ts, value: value}));
+            model.insert(ts, value);
+            return Ok(expect("lookup", Some(value), try!(not_found(db.lookup(ts)))));
+        },
+        Op::RecordRun(start, count, value) => {
+            for i in 0..count {
+                try!(db.record(&format::Rec{timestamp: start + i, value: value + i}));
+                model.insert(start + i, value + i);
+            }
+            return Ok(Ok(()));
+        },
+        Op::Delete(ts) => {
+            try!(db.delete(ts));
+            model.remove(&ts);
+            return Ok(expect("lookup", None, try!(not_found(db.lookup(ts)))));
+        },
+        Op::Lookup(ts, mode) => {
+            let expected = match mode {
+                Lookup::Floor => model.range(..=ts).next_back(),
+                Lookup::Ceiling => model.range(ts..).next(),
+                _ => model.get_key_value(&ts),
+            };
+            let actual = try!(not_found(db.lookup_with_mode(ts, mode)));
+            return Ok(expect("lookup", expected.map(|(k, v)| (*k, *v)), actual));
+        },
+        Op::Scan(start, end, reverse) => {
+            let mut expected : Vec<(u64, u64)> = model.range(start..end).map(|(k, v)| (*k, *v)).collect();
+            let mut merged = try!(db.scan(start, end));
+            let actual : Vec<(u64, u64)> = if reverse {
+                expected.reverse();
+                (&mut merged).rev().collect()
+            } else {
+                (&mut merged).collect()
+            };
+            try!(merged.take_status());
+            return Ok(expect("scan", expected, actual));
+        },
+        Op::Summarize(start, end) => {
+            let mut expected = Summary::empty();
+            for (k, v) in model.range(start..end) {
+                expected.add(*k, *v);
+            }
+            return Ok(expect("summary", expected, try!(db.summarize(start, end))));
+        },
+        Op::Aggregate(start, end, width, aggregator) => {
+            let expected = aggregate(model, start, end, width, aggregator);
+            let mut aggregation = try!(db.aggregate(start, end, width, aggregator));
+            let actual : Vec<Bucket> = (&mut aggregation).collect();
+            try!(aggregation.take_status());
+            return Ok(expect("aggregate", expected, actual));
+        },
+        Op::Flush => try!(db.flush()),
+        Op::Compact => try!(db.compact()),
+        Op::Reopen => unreachable!(),
+    }
+    return Ok(check_all(db, model));
+}
+
+// Runs 'ops' against a fresh in-memory Db (otherwise set up with 'options')
+// and a BTreeMap, checking after every step that they agree. Each step
+// checks what it touched; flushes, compactions and reopening check
+// everything.
+pub fn run(ops: &[Op], options: &Options) -> Result<(), String> {
+    let mut options = options.clone();
+    options.env = Arc::new(MemEnv::new());
+    let open = |options: &Options| Db::with_options("/simulation", options.clone())
+        .map_err(|e| format!("opening: {}", e));
+
+    let mut db = try!(open(&options));
+    let mut model = BTreeMap::new();
+    for (i, op) in ops.iter().enumerate() {
+        let checked = match *op {
+            Op::Reopen => {
+                db = try!(open(&options));
+                check_all(&mut db, &model)
+            },
+            op => match step(&mut db, &mut model, op) {
+                Ok(checked) => checked,
+                Err(e) => Err(e.to_string()),
+            },
+        };
+        if let Err(e) = checked {
+            return Err(format!("step {} ({:?}): {}", i, op, e));
+        }
+    }
+    return Ok(());
+}
+
+// Finds a short subsequence of 'ops' that still fails, by repeatedly
+// trying to drop chunks of it, halving the chunk size whenever no chunk
+// can go.
+pub fn shrink<F: FnMut(&[Op]) -> bool>(ops: Vec<Op>, mut fails: F) -> Vec<Op> {
+    let mut ops = ops;
+    let mut chunk = ops.len() / 2;
+    while chunk > 0 {
+        let mut removed = false;
+        let mut start = 0;
+        while start < ops.len() {
+            let end = (start + chunk).min(ops.len());
+            let mut candidate = ops[..start].to_vec();
+            candidate.extend_from_slice(&ops[end..]);
+            if fails(&candidate) {
+                ops = candidate;
+                removed = true;
+            } else {
+                start += chunk;
+            }
+        }
+        if !removed {
+            chunk /= 2;
+        }
+    }
+    return ops;
+}
+
+// Runs the workload for 'seed', and if it fails, shrinks it to a minimal
+// reproduction.
+pub fn check_seed(seed: u64, steps: usize, options: &Options) -> Result<(), String> {
+    let ops = generate(seed, steps);
+    let error = match run(&ops, options) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    let minimal = shrink(ops, |ops| run(ops, options).is_err());
+    return Err(format!("seed {} failed: {}\nminimal reproduction: {:?}\nwhich fails with: {}",
+                       seed, error, minimal, run(&minimal, options).unwrap_err()));
+}
+
+#[cfg(test)]
+mod test {
+    use super::Op;
+    use super::check_seed;
+    use super::generate;
+    use super::shrink;
+
+    use db::Options;
+    use rollup::Tier;
+
+    #[test]
+    fn deterministic() {
+        assert_eq!(generate(7, 100), generate(7, 100));
+        assert!(generate(7, 100) != generate(8, 100));
+    }
+
+    #[test]
+    fn shrinks_failures() {
+        // Pretend that a delete followed, at any distance, by a compaction
+        // fails.
+        let fails = |ops: &[Op]| {
+            let delete = ops.iter().position(|op| matches!(*op, Op::Delete(_)));
+            return delete.map(|i| ops[i..].contains(&Op::Compact)).unwrap_or(false);
+        };
+        let ops = generate(1, 300);
+        assert!(fails(&ops));
+        let minimal = shrink(ops, fails);
+        assert_eq!(2, minimal.len());
+        assert_eq!(Op::Compact, minimal[1]);
+    }
+
+    #[test]
+    fn simulate() {
+        for seed in 0..8 {
+            let mut options = Options{
+                rollups: vec![Tier{resolution: 10, retention: None},
+                              Tier{resolution: 100, retention: None}],
+                ..Options::default()
+            };
+            if seed % 2 == 1 {
+                options.table.bloom_bits_per_key = Some(10);
+            }
+            if seed % 4 >= 2 {
+                options.read_ahead = 2;
+            }
+            if let Err(e) = check_seed(seed, 150, &options) {
+                panic!("{}", e);
+            }
+        }
+    }
+}
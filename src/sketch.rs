@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+
+// Relative accuracy of sketches built by Sketch::default().
+pub const DEFAULT_RELATIVE_ACCURACY : f64 = 0.01;
+
+// A mergeable quantile sketch (DDSketch; see Masson et al., "DDSketch: A
+// Fast and Fully-Mergeable Quantile Sketch with Relative-Error Guarantees").
+//
+// Values are counted in logarithmically sized bins, so any quantile is
+// reported within the sketch's relative accuracy of the true value, however
+// many values are added. Sketches with the same accuracy can be merged, e.g.
+// to combine sketches of different tables.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Sketch {
+    gamma: f64,
+    ln_gamma: f64,
+    // Bin i counts values in (gamma^(i-1), gamma^i].
+    bins: BTreeMap<i32, u64>,
+    zeros: u64,
+    count: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Default for Sketch {
+    fn default() -> Sketch {
+        return Sketch::new(DEFAULT_RELATIVE_ACCURACY);
+    }
+}
+
+impl Sketch {
+    pub fn new(relative_accuracy: f64) -> Sketch {
+        assert!(relative_accuracy > 0.0 && relative_accuracy < 1.0);
+        let gamma = (1.0 + relative_accuracy) / (1.0 - relative_accuracy);
+        return Sketch{
+            gamma: gamma,
+            ln_gamma: gamma.ln(),
+            bins: BTreeMap::new(),
+            zeros: 0,
+            count: 0,
+            min: u64::MAX,
+            max: 0,
+        };
+    }
+
+    pub fn add(&mut self, v: u64) {
+        if v == 0 {
+            self.zeros += 1;
+        } else {
+            let bin = ((v as f64).ln() / self.ln_gamma).ceil() as i32;
+            *self.bins.entry(bin).or_insert(0) += 1;
+        }
+
+        self.count += 1;
+        if v < self.min {
+            self.min = v;
+        }
+        if v > self.max {
+            self.max = v;
+        }
+    }
+
+    // Adds the values counted by 'other', which must have the same accuracy.
+    pub fn merge(&mut self, other: &Sketch) {
+        assert_eq!(self.gamma, other.gamma, "Can't merge sketches of different accuracy");
+        for (bin, count) in &other.bins {
+            *self.bins.entry(*bin).or_insert(0) += *count;
+        }
+        self.zeros += other.zeros;
+        self.count += other.count;
+        if other.min < self.min {
+            self.min = other.min;
+        }
+        if other.max > self.max {
+            self.max = other.max;
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        return self.count;
+    }
+
+    // The value at quantile 'q' (between 0 and 1), or None if the sketch is
+    // empty.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 || !(0.0..=1.0).contains(&q) {
+            return None;
+        }
+
+        // The extremes are known exactly.
+        if q == 0.0 {
+            return Some(self.min as f64);
+        } else if q == 1.0 {
+            return Some(self.max as f64);
+        }
+
+        let rank = q * (self.count - 1) as f64;
+        let mut seen = self.zeros;
+        if (seen as f64) > rank {
+            return Some(0.0);
+        }
+        for (bin, count) in &self.bins {
+            seen += *count;
+            if (seen as f64) > rank {
+                let estimate = 2.0 * self.gamma.powi(*bin) / (self.gamma + 1.0);
+                return Some(estimate.max(self.min as f64).min(self.max as f64));
+            }
+        }
+        return Some(self.max as f64);
+    }
+
+    // The distribution as (upper bound, count) pairs in increasing order of
+    // bound. Each bin counts the values above the previous bin's bound.
+    pub fn histogram(&self) -> Vec<(f64, u64)> {
+        let mut histogram = Vec::with_capacity(self.bins.len() + 1);
+        if self.zeros > 0 {
+            histogram.push((0.0, self.zeros));
+        }
+        for (bin, count) in &self.bins {
+            histogram.push((self.gamma.powi(*bin), *count));
+        }
+        return histogram;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Sketch;
+
+    fn within(expected: f64, actual: Option<f64>, accuracy: f64) -> bool {
+        return actual.map(|a| (a - expected).abs() <= expected * accuracy).unwrap_or(false);
+    }
+
+    #[test]
+    fn quantiles() {
+        let mut sketch = Sketch::default();
+        assert_eq!(None, sketch.quantile(0.5));
+
+        for v in 1..10001 {
+            sketch.add(v);
+        }
+        assert_eq!(10000, sketch.count());
+        assert!(within(5000.0, sketch.quantile(0.5), 0.01));
+        assert!(within(9900.0, sketch.quantile(0.99), 0.01));
+        assert_eq!(Some(1.0), sketch.quantile(0.0));
+        assert_eq!(Some(10000.0), sketch.quantile(1.0));
+    }
+
+    #[test]
+    fn merge() {
+        let mut whole = Sketch::default();
+        let mut low = Sketch::default();
+        let mut high = Sketch::default();
+        for v in 0..1000 {
+            whole.add(v * v);
+            if v < 300 {
+                low.add(v * v);
+            } else {
+                high.add(v * v);
+            }
+        }
+
+        high.merge(&low);
+        assert_eq!(whole, high);
+        assert_eq!(1000, high.histogram().iter().map(|&(_, c)| c).sum::<u64>());
+        assert_eq!((0.0, 1), high.histogram()[0]);
+    }
+}
@@ -1,71 +1,542 @@
+use aggregate::Summary;
+use async_io;
+use async_io::AsyncDevice;
+use async_io::ReadRequest;
+use block_cache;
+use block_cache::BlockCache;
+use bloom::BloomFilter;
+use checksum;
+use compression;
+use env::Env;
+use env::FileContents;
+use env::PosixEnv;
+use env::ReadableFile;
 use format;
-use std::fs;
+use merge;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::io;
 use std::io::Read;
+use std::io::Seek;
 use std::io::Write;
+use std::mem;
+use std::ops;
 use std::path;
+use std::sync::Arc;
+use std::task::Poll;
+
+pub use compression::Compression;
 
 pub struct TableBuilder;
 
+#[derive(Clone,Copy,Debug)]
+pub struct TableOptions {
+    pub compression: Compression,
+    // If set, a bloom filter over the table's timestamps is stored alongside
+    // the index, so that point lookups can skip tables without reading data.
+    pub bloom_bits_per_key: Option<usize>,
+}
+
+impl Default for TableOptions {
+    fn default() -> TableOptions {
+        return TableOptions{
+            compression: Compression::None,
+            bloom_bits_per_key: None,
+        };
+    }
+}
+
+#[derive(Clone,Copy,Debug)]
+pub struct ReadOptions {
+    // Skipping verification saves a pass over each block, and should only be
+    // used when the data is already trusted.
+    pub verify_checksums: bool,
+    // Whether blocks read should be added to the block cache. Large scans
+    // (e.g. compactions) can turn this off to avoid evicting hot blocks.
+    pub fill_cache: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> ReadOptions {
+        return ReadOptions{
+            verify_checksums: true,
+            fill_cache: true,
+        };
+    }
+}
+
+// Table layout:
+//   [block 0]...[block N-1][filter][index][table footer]
+//
+// Each block holds up to BLOCK_SIZE bytes of (possibly compressed) records,
+// followed by a block footer of (record count, codec id, crc32c). The checksum
+// covers everything in the block before it. Blocks are not padded, so the
+// index records the offset and on-disk length of each one, along with a
+// summary (count, sum, min, max, first, last) of the block's points.
+//
+// The filter is an optional bloom filter over all timestamps in the table,
+// followed by its crc32c. The footer holds (index offset, block count,
+// filter offset, filter length, crc32c, magic), with a filter length of zero
+// if the table has no filter. Its crc32c covers the filter, the index and the
+// footer up to the crc32c itself, since a bad index entry would otherwise
+// silently send reads to the wrong place.
 const BLOCK_SIZE : usize = 32768;
-const FOOTER_SIZE : usize = 8;
+const BLOCK_FOOTER_SIZE : usize = 24;
 const REC_SIZE : usize = 16;
+const MAX_RECORDS_PER_BLOCK : u64 = ((BLOCK_SIZE - BLOCK_FOOTER_SIZE) / REC_SIZE) as u64;
+const INDEX_ENTRY_SIZE : usize = 88;
+const TABLE_FOOTER_SIZE : usize = 48;
+// Where the crc32c is in the footer.
+const TABLE_FOOTER_CRC : usize = 32;
+// "rts_tab3": since the third version, a value of format::TOMBSTONE marks
+// a deleted point, where older tables could hold it as a point's value.
+const TABLE_MAGIC : u64 = 0x7274735f74616233;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct BlockHandle {
+    pub offset: u64,
+    pub length: u64,
+    pub summary: Summary,
+}
+
+fn store_handle(handle: &BlockHandle, buf: &mut [u8]) {
+    assert_eq!(INDEX_ENTRY_SIZE, buf.len());
+
+    let s = &handle.summary;
+    let words = [handle.offset, handle.length, s.count,
+                 s.sum as u64, (s.sum >> 64) as u64,
+                 s.min, s.max, s.first_ts, s.first, s.last_ts, s.last];
+    for i in 0..words.len() {
+        format::store(words[i], &mut buf[(i*8)..((i+1)*8)]);
+    }
+}
+
+fn load_handle(buf: &[u8]) -> BlockHandle {
+    assert_eq!(INDEX_ENTRY_SIZE, buf.len());
+
+    let w = |i: usize| format::load(&buf[(i*8)..((i+1)*8)]);
+    return BlockHandle{
+        offset: w(0),
+        length: w(1),
+        summary: Summary{
+            count: w(2),
+            sum: (w(3) as u128) | ((w(4) as u128) << 64),
+            min: w(5),
+            max: w(6),
+            first_ts: w(7),
+            first: w(8),
+            last_ts: w(9),
+            last: w(10),
+        },
+    };
+}
 
 impl TableBuilder {
     pub fn write<'a, P: AsRef<path::Path>, I: Iterator<Item=(&'a u64, &'a u64)>>(filename: P, data: I) -> io::Result<()> {
-        let mut file = try!(fs::File::create(filename));
+        return TableBuilder::write_with_options(
+            filename, data, &TableOptions::default());
+    }
+
+    pub fn write_with_options<'a, P: AsRef<path::Path>, I: Iterator<Item=(&'a u64, &'a u64)>>(filename: P, data: I, options: &TableOptions) -> io::Result<()> {
+        return TableBuilder::write_records(
+            filename, data.map(|(k, v)| (*k, *v)), options);
+    }
 
+    pub fn write_records<P: AsRef<path::Path>, I: Iterator<Item=(u64, u64)>>(filename: P, data: I, options: &TableOptions) -> io::Result<()> {
+        return TableBuilder::write_records_with_env(&PosixEnv, filename, data, options);
+    }
+
+    pub fn write_records_with_env<P: AsRef<path::Path>, I: Iterator<Item=(u64, u64)>>(env: &dyn Env, filename: P, data: I, options: &TableOptions) -> io::Result<()> {
+        let mut file = try!(env.create(filename.as_ref()));
+        try!(TableBuilder::write_records_to(&mut file, data, options));
+        return file.sync();
+    }
+
+    fn write_records_to<W: Write, I: Iterator<Item=(u64, u64)>>(file: &mut W, data: I, options: &TableOptions) -> io::Result<()> {
         let mut rec_count = 0;
         let mut block = [0; BLOCK_SIZE];
         let mut block_ptr = 0;
+        let mut index = Vec::new();
+        let mut offset = 0;
+        let mut keys = Vec::new();
+        let mut summary = Summary::empty();
 
         let mut prev_k = 0;
         
         for (k, v) in data {
-            if rec_count > 0 && *k < prev_k {
+            if (rec_count > 0 || !index.is_empty()) && k < prev_k {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidInput,
                     format!("Keys must be ordered. {} is not greater than {}",
-                            *k, prev_k)));
+                            k, prev_k)));
+            }
+            prev_k = k;
+            if options.bloom_bits_per_key.is_some() {
+                keys.push(k);
             }
-            prev_k = *k;
             
-            assert!(block_ptr + REC_SIZE <= (BLOCK_SIZE - FOOTER_SIZE));
-            format::store(*k, &mut block[block_ptr..(block_ptr + 8)]);
-            format::store(*v, &mut block[(block_ptr + 8)..(block_ptr+16)]);
+            assert!(block_ptr + REC_SIZE <= (BLOCK_SIZE - BLOCK_FOOTER_SIZE));
+            format::store(k, &mut block[block_ptr..(block_ptr + 8)]);
+            format::store(v, &mut block[(block_ptr + 8)..(block_ptr+16)]);
+            summary.add(k, v);
             rec_count += 1;
             block_ptr += REC_SIZE;
-            if BLOCK_SIZE - FOOTER_SIZE - block_ptr < REC_SIZE {
-                try!(TableBuilder::pad_and_write(&mut block, block_ptr, rec_count, &mut file));
+            if BLOCK_SIZE - BLOCK_FOOTER_SIZE - block_ptr < REC_SIZE {
+                let handle = try!(TableBuilder::compress_and_write(
+                    &block[0..block_ptr], rec_count, &summary, options, offset, file));
+                offset += handle.length;
+                index.push(handle);
                 block_ptr = 0;
                 rec_count = 0;
+                summary = Summary::empty();
             }
         }
 
-        try!(TableBuilder::pad_and_write(&mut block, block_ptr, rec_count, &mut file));
+        if rec_count > 0 {
+            let handle = try!(TableBuilder::compress_and_write(
+                &block[0..block_ptr], rec_count, &summary, options, offset, file));
+            offset += handle.length;
+            index.push(handle);
+        }
+
+        let filter = options.bloom_bits_per_key.map(
+            |bits_per_key| BloomFilter::build(&keys, bits_per_key));
+        return TableBuilder::write_meta(&index, filter, offset, file);
+    }
+
+    fn compress_and_write<W: Write>(records: &[u8], rec_count : usize, summary: &Summary, options: &TableOptions, offset: u64, file: &mut W) -> io::Result<BlockHandle> {
+        let mut block = try!(compression::compress(options.compression, records));
+
+        let mut footer = [0; BLOCK_FOOTER_SIZE];
+        format::store(rec_count as u64, &mut footer[0..8]);
+        format::store(options.compression.id(), &mut footer[8..16]);
+        block.extend_from_slice(&footer[0..16]);
+        let crc = checksum::crc32c(&block);
+        format::store(crc as u64, &mut footer[16..24]);
+        block.extend_from_slice(&footer[16..24]);
         
-        return Ok(());
+        try!(file.write_all(&block));
+        return Ok(BlockHandle{
+            offset: offset,
+            length: block.len() as u64,
+            summary: *summary,
+        });
     }
 
-    fn pad_and_write(block : &mut [u8; BLOCK_SIZE], block_ptr: usize, rec_count : usize, file: &mut fs::File) -> io::Result<()> {
-        let bytes_remaining = BLOCK_SIZE - FOOTER_SIZE - block_ptr;
-        for i in 0..bytes_remaining {
-            block[block_ptr + i] = 0;
+    fn write_meta<W: Write>(index: &[BlockHandle], filter: Option<BloomFilter>, offset: u64, file: &mut W) -> io::Result<()> {
+        let mut buf = Vec::new();
+        if let Some(filter) = filter {
+            buf = filter.encode();
+            let mut crc = [0; 8];
+            format::store(checksum::crc32c(&buf) as u64, &mut crc);
+            buf.extend_from_slice(&crc);
         }
+        let filter_length = buf.len();
 
-        let footer_ptr = BLOCK_SIZE - FOOTER_SIZE;
-        format::store(rec_count as u64, &mut block[footer_ptr..(footer_ptr+8)]);
-        
-        try!(file.write_all(block));
-        return Ok(());
+        buf.resize(filter_length + index.len() * INDEX_ENTRY_SIZE + TABLE_FOOTER_SIZE, 0);
+        let mut ptr = filter_length;
+        for handle in index {
+            store_handle(handle, &mut buf[ptr..(ptr+INDEX_ENTRY_SIZE)]);
+            ptr += INDEX_ENTRY_SIZE;
+        }
+
+        let mut footer = Footer{
+            index_offset: offset + filter_length as u64,
+            block_count: index.len(),
+            filter_offset: offset,
+            filter_length: filter_length,
+            crc: 0,
+        };
+        footer.store(&mut buf[ptr..]);
+        footer.crc = checksum::crc32c(&buf[0..(ptr + TABLE_FOOTER_CRC)]) as u64;
+        footer.store(&mut buf[ptr..]);
+
+        return file.write_all(&buf);
+    }
+}
+
+fn corrupt(msg: String) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, msg);
+}
+
+struct Footer {
+    index_offset: u64,
+    block_count: usize,
+    filter_offset: u64,
+    filter_length: usize,
+    crc: u64,
+}
+
+impl Footer {
+    fn parse(buf: &[u8], file_size: u64) -> io::Result<Footer> {
+        assert_eq!(TABLE_FOOTER_SIZE, buf.len());
+
+        let footer = Footer{
+            index_offset: format::load(&buf[0..8]),
+            block_count: format::load(&buf[8..16]) as usize,
+            filter_offset: format::load(&buf[16..24]),
+            filter_length: format::load(&buf[24..32]) as usize,
+            crc: format::load(&buf[32..40]),
+        };
+        if format::load(&buf[40..48]) != TABLE_MAGIC {
+            return Err(corrupt("Bad table magic number".to_string()));
+        }
+        // A corrupt footer can hold anything, so none of this may overflow.
+        let index_end = (footer.block_count as u64).checked_mul(INDEX_ENTRY_SIZE as u64)
+            .and_then(|n| n.checked_add(TABLE_FOOTER_SIZE as u64))
+            .and_then(|n| n.checked_add(footer.index_offset));
+        if index_end != Some(file_size) {
+            return Err(corrupt(format!(
+                "Index of {} blocks at {} doesn't fit in {} bytes",
+                footer.block_count, footer.index_offset, file_size)));
+        }
+        if footer.filter_offset.checked_add(footer.filter_length as u64) != Some(footer.index_offset) {
+            return Err(corrupt(format!(
+                "Filter of {} bytes at {} doesn't end at the index ({})",
+                footer.filter_length, footer.filter_offset, footer.index_offset)));
+        }
+        return Ok(footer);
+    }
+
+    fn store(&self, buf: &mut [u8]) {
+        assert_eq!(TABLE_FOOTER_SIZE, buf.len());
+
+        format::store(self.index_offset, &mut buf[0..8]);
+        format::store(self.block_count as u64, &mut buf[8..16]);
+        format::store(self.filter_offset, &mut buf[16..24]);
+        format::store(self.filter_length as u64, &mut buf[24..32]);
+        format::store(self.crc, &mut buf[32..40]);
+        format::store(TABLE_MAGIC, &mut buf[40..48]);
+    }
+
+    // Size of the filter and index, which are stored back to back.
+    fn meta_length(&self) -> usize {
+        return self.filter_length + self.block_count * INDEX_ENTRY_SIZE;
+    }
+}
+
+struct TableMeta {
+    index: Vec<BlockHandle>,
+    filter: Option<BloomFilter>,
+}
+
+// 'buf' holds the filter and index described by 'footer'.
+fn parse_meta(buf: &[u8], footer: &Footer) -> io::Result<TableMeta> {
+    assert_eq!(footer.meta_length(), buf.len());
+
+    let mut footer_buf = [0; TABLE_FOOTER_SIZE];
+    footer.store(&mut footer_buf);
+    let mut crc = checksum::Crc32c::new();
+    crc.update(buf);
+    crc.update(&footer_buf[0..TABLE_FOOTER_CRC]);
+    if crc.finish() as u64 != footer.crc {
+        return Err(corrupt(format!(
+            "Corrupt table metadata at offset {}: checksum mismatch", footer.filter_offset)));
+    }
+
+    let mut filter = None;
+    if footer.filter_length > 0 {
+        if footer.filter_length < 8 {
+            return Err(corrupt(format!("Filter too short: {} bytes", footer.filter_length)));
+        }
+        let crc_ptr = footer.filter_length - 8;
+        if checksum::crc32c(&buf[0..crc_ptr]) as u64 != format::load(&buf[crc_ptr..footer.filter_length]) {
+            return Err(corrupt(format!(
+                "Corrupt filter at offset {}: checksum mismatch", footer.filter_offset)));
+        }
+        filter = Some(BloomFilter::decode(&buf[0..crc_ptr]));
+    }
+
+    let mut index : Vec<BlockHandle> = Vec::with_capacity(footer.block_count);
+    let mut expected_offset = 0;
+    for i in 0..footer.block_count {
+        let ptr = footer.filter_length + i * INDEX_ENTRY_SIZE;
+        let handle = load_handle(&buf[ptr..(ptr+INDEX_ENTRY_SIZE)]);
+
+        if handle.offset != expected_offset || handle.length < BLOCK_FOOTER_SIZE as u64 {
+            return Err(corrupt(format!(
+                "Bad index entry {}: {} bytes at offset {}",
+                i, handle.length, handle.offset)));
+        }
+        if handle.summary.is_empty() ||
+            handle.summary.count > MAX_RECORDS_PER_BLOCK ||
+            handle.summary.first_ts > handle.summary.last_ts ||
+            index.last().map(|h| h.summary.last_ts > handle.summary.first_ts).unwrap_or(false) {
+            return Err(corrupt(format!(
+                "Bad index entry {}: blocks out of order", i)));
+        }
+
+        expected_offset += handle.length;
+        index.push(handle);
+    }
+    if expected_offset != footer.filter_offset {
+        return Err(corrupt(format!(
+            "Blocks end at {}, but the filter starts at {}",
+            expected_offset, footer.filter_offset)));
+    }
+
+    return Ok(TableMeta{
+        index: index,
+        filter: filter,
+    });
+}
+
+fn read_meta(file: &mut dyn ReadableFile) -> io::Result<TableMeta> {
+    let file_size = try!(file.seek(io::SeekFrom::End(0)));
+    if file_size < TABLE_FOOTER_SIZE as u64 {
+        return Err(corrupt(format!("Table too short: {} bytes", file_size)));
+    }
+
+    let mut buf = [0; TABLE_FOOTER_SIZE];
+    try!(file.seek(io::SeekFrom::End(-(TABLE_FOOTER_SIZE as i64))));
+    try!(file.read_exact(&mut buf));
+    let footer = try!(Footer::parse(&buf, file_size));
+
+    let mut buf = vec![0; footer.meta_length()];
+    try!(file.seek(io::SeekFrom::Start(footer.filter_offset)));
+    try!(file.read_exact(&mut buf));
+
+    return parse_meta(&buf, &footer);
+}
+
+// Verifies and decompresses the on-disk block 'buf', which was read from
+// 'handle' in 'filename'. Uncompressed blocks are returned without a copy.
+fn decode_block<'a>(buf: &'a [u8], filename: &path::Path, handle: &BlockHandle, options: &ReadOptions) -> io::Result<Cow<'a, [u8]>> {
+    let offset = handle.offset;
+    if buf.len() < BLOCK_FOOTER_SIZE {
+        return Err(corrupt(format!(
+            "Block in {:?} at {} is too short: {} bytes", filename, offset, buf.len())));
+    }
+
+    let footer_ptr = buf.len() - BLOCK_FOOTER_SIZE;
+    if options.verify_checksums {
+        let expected = format::load(&buf[(footer_ptr+16)..(footer_ptr+24)]);
+        let actual = checksum::crc32c(&buf[0..(footer_ptr+16)]) as u64;
+        if expected != actual {
+            return Err(corrupt(format!(
+                "Corrupt block in {:?} at offset {}: checksum mismatch ({:x} != {:x})",
+                filename, offset, actual, expected)));
+        }
+    }
+
+    let rec_count = format::load(&buf[footer_ptr..(footer_ptr+8)]);
+    // Readers trust the index's count, e.g. to find records by ordinal.
+    if rec_count != handle.summary.count {
+        return Err(corrupt(format!(
+            "Block in {:?} at {} has {} records, but the index says {}",
+            filename, offset, rec_count, handle.summary.count)));
+    }
+    let rec_count = rec_count as usize;
+    let codec = try!(Compression::from_id(
+        format::load(&buf[(footer_ptr+8)..(footer_ptr+16)])));
+    if codec == Compression::None {
+        if footer_ptr != rec_count * REC_SIZE {
+            return Err(corrupt(format!(
+                "Block in {:?} at {} has {} bytes for {} records",
+                filename, offset, footer_ptr, rec_count)));
+        }
+        return Ok(Cow::Borrowed(&buf[0..footer_ptr]));
+    }
+
+    return compression::decompress(codec, &buf[0..footer_ptr], rec_count * REC_SIZE)
+        .map(Cow::Owned);
+}
+
+// Reads a table's blocks through an AsyncDevice ahead of a sequential scan,
+// up to 'depth' at a time, tagged with their position in the index.
+struct ReadAhead {
+    device: Box<dyn AsyncDevice>,
+    depth: usize,
+    // The block a sequential scan reads next.
+    next_read: usize,
+    next_submit: usize,
+    ready: HashMap<u64, io::Result<Vec<u8>>>,
+}
+
+impl ReadAhead {
+    fn new(device: Box<dyn AsyncDevice>, depth: usize) -> ReadAhead {
+        return ReadAhead{
+            device: device,
+            depth: depth.max(1),
+            next_read: 0,
+            next_submit: 0,
+            ready: HashMap::new(),
+        };
+    }
+
+    // Whether 'block' has been read (or failed to be), submitting it and
+    // the blocks after it if they aren't in flight yet. Never waits.
+    fn poll(&mut self, index: &[BlockHandle], block: usize) -> io::Result<bool> {
+        // Start over from 'block' after a seek. Reads still in flight from
+        // before it are harmless: blocks are tagged with their position, and
+        // tables don't change.
+        if block != self.next_read {
+            self.next_read = block;
+            self.next_submit = block;
+            self.ready.clear();
+        }
+        while self.next_submit < index.len() && self.next_submit < block + self.depth {
+            let handle = index[self.next_submit];
+            try!(self.device.submit(ReadRequest{
+                tag: self.next_submit as u64,
+                offset: handle.offset,
+                len: handle.length as usize,
+            }));
+            self.next_submit += 1;
+        }
+        for completion in try!(self.device.complete(0)) {
+            self.ready.insert(completion.tag, completion.result);
+        }
+        return Ok(self.ready.contains_key(&(block as u64)));
+    }
+
+    // Waits for 'block' to be read.
+    fn read(&mut self, index: &[BlockHandle], block: usize) -> io::Result<Vec<u8>> {
+        while !try!(self.poll(index, block)) {
+            for completion in try!(self.device.complete(1)) {
+                self.ready.insert(completion.tag, completion.result);
+            }
+        }
+        let result = self.ready.remove(&(block as u64)).unwrap();
+        // Anything earlier was read before a seek.
+        self.ready.retain(|tag, _| *tag > block as u64);
+        self.next_read = block + 1;
+        return result;
+    }
+}
+
+// Where a TableIterator's blocks come from.
+enum BlockSource {
+    File(Box<dyn ReadableFile>),
+    Async(ReadAhead),
+}
+
+impl BlockSource {
+    fn read(&mut self, index: &[BlockHandle], block: usize) -> io::Result<Vec<u8>> {
+        match *self {
+            BlockSource::File(ref mut file) => {
+                let handle = index[block];
+                let mut buf = vec![0; handle.length as usize];
+                try!(file.seek(io::SeekFrom::Start(handle.offset)));
+                try!(file.read_exact(&mut buf));
+                return Ok(buf);
+            },
+            BlockSource::Async(ref mut read_ahead) => return read_ahead.read(index, block),
+        }
     }
 }
 
 pub struct TableIterator {
-    block: [u8; BLOCK_SIZE],
+    filename: path::PathBuf,
+    options: ReadOptions,
+    block: Vec<u8>,
     status: io::Result<()>,
     block_ptr: usize,
-    file: fs::File,
+    source: BlockSource,
+    index: Vec<BlockHandle>,
+    filter: Option<BloomFilter>,
+    next_block: usize,
     records_in_block: usize,
     records_read_from_block: usize,
     done: bool,
@@ -73,13 +544,63 @@ pub struct TableIterator {
 
 impl TableIterator {
     pub fn new<P: AsRef<path::Path>>(filename: P) -> io::Result<TableIterator> {
-        let f = try!(fs::File::open(filename));
+        return TableIterator::with_options(filename, &ReadOptions::default());
+    }
+
+    pub fn with_options<P: AsRef<path::Path>>(filename: P, options: &ReadOptions) -> io::Result<TableIterator> {
+        return TableIterator::with_env(&PosixEnv, filename, options);
+    }
+
+    pub fn with_env<P: AsRef<path::Path>>(env: &dyn Env, filename: P, options: &ReadOptions) -> io::Result<TableIterator> {
+        let mut f = try!(env.open(filename.as_ref()));
+        let meta = try!(read_meta(&mut *f));
 
         return Ok(TableIterator{
-            block: [0; BLOCK_SIZE],
+            filename: filename.as_ref().to_path_buf(),
+            options: *options,
+            block: Vec::new(),
             status: Ok(()),
-            block_ptr: BLOCK_SIZE,
-            file: f,
+            block_ptr: 0,
+            source: BlockSource::File(f),
+            index: meta.index,
+            filter: meta.filter,
+            next_block: 0,
+            records_in_block: 0,
+            records_read_from_block: 0,
+            done: false,
+        });
+    }
+
+    // Reads the table through 'device', keeping up to 'depth' block reads in
+    // flight ahead of the iterator.
+    pub fn with_async<P: AsRef<path::Path>>(mut device: Box<dyn AsyncDevice>, filename: P, options: &ReadOptions, depth: usize) -> io::Result<TableIterator> {
+        let file_size = device.size();
+        if file_size < TABLE_FOOTER_SIZE as u64 {
+            return Err(corrupt(format!("Table too short: {} bytes", file_size)));
+        }
+        let (buf, _) = try!(async_io::read_now(&mut *device, ReadRequest{
+            tag: 0,
+            offset: file_size - TABLE_FOOTER_SIZE as u64,
+            len: TABLE_FOOTER_SIZE,
+        }));
+        let footer = try!(Footer::parse(&buf, file_size));
+        let (buf, _) = try!(async_io::read_now(&mut *device, ReadRequest{
+            tag: 0,
+            offset: footer.filter_offset,
+            len: footer.meta_length(),
+        }));
+        let meta = try!(parse_meta(&buf, &footer));
+
+        return Ok(TableIterator{
+            filename: filename.as_ref().to_path_buf(),
+            options: *options,
+            block: Vec::new(),
+            status: Ok(()),
+            block_ptr: 0,
+            source: BlockSource::Async(ReadAhead::new(device, depth)),
+            index: meta.index,
+            filter: meta.filter,
+            next_block: 0,
             records_in_block: 0,
             records_read_from_block: 0,
             done: false,
@@ -87,20 +608,73 @@ impl TableIterator {
     }
 
     fn read_block(&mut self) -> io::Result<()> {
-        println!("Read block");
-        let read_size = try!(self.file.read(&mut self.block));
-        if read_size == 0 {
+        if self.next_block >= self.index.len() {
             self.done = true;
             return Ok(());
         }
-        assert_eq!(read_size, BLOCK_SIZE);
 
-        let footer_ptr = BLOCK_SIZE - FOOTER_SIZE;
-        self.records_in_block = format::load(&self.block[footer_ptr..(footer_ptr+8)]) as usize;
+        let handle = self.index[self.next_block];
+        let buf = try!(self.source.read(&self.index, self.next_block));
+        self.next_block += 1;
 
+        self.block = try!(decode_block(
+            &buf, &self.filename, &handle, &self.options)).into_owned();
+        self.records_in_block = self.block.len() / REC_SIZE;
         self.block_ptr = 0;
+        self.records_read_from_block = 0;
         return Ok(());
     }
+
+    pub fn index(&self) -> &[BlockHandle] {
+        return &self.index;
+    }
+
+    // Skips ahead (or back) so that the next record returned is the first
+    // one in the given block.
+    pub fn seek_block(&mut self, block: usize) {
+        self.next_block = block;
+        self.records_in_block = 0;
+        self.records_read_from_block = 0;
+        self.done = false;
+    }
+
+    // False means 'ts' is definitely not in the table. True means it might be,
+    // or that the table has no filter.
+    pub fn may_contain(&self, ts: u64) -> bool {
+        return self.filter.as_ref().map(|f| f.may_contain(ts)).unwrap_or(true);
+    }
+
+    pub fn has_filter(&self) -> bool {
+        return self.filter.is_some();
+    }
+
+    // Ready once next() can return without waiting for a read. Only an
+    // iterator reading through an AsyncDevice is ever pending.
+    pub fn poll_ready(&mut self) -> Poll<()> {
+        if self.done || self.records_read_from_block < self.records_in_block ||
+            self.next_block >= self.index.len() {
+            return Poll::Ready(());
+        }
+        let polled = match self.source {
+            BlockSource::Async(ref mut read_ahead) => read_ahead.poll(&self.index, self.next_block),
+            BlockSource::File(_) => return Poll::Ready(()),
+        };
+        return match polled {
+            Ok(true) => Poll::Ready(()),
+            Ok(false) => Poll::Pending,
+            Err(e) => {
+                self.status = Err(e);
+                self.done = true;
+                Poll::Ready(())
+            },
+        };
+    }
+
+    // Iteration stops early if a block can't be read. This returns the error
+    // that stopped it, if any.
+    pub fn take_status(&mut self) -> io::Result<()> {
+        return mem::replace(&mut self.status, Ok(()));
+    }
 }
 
 impl Iterator for TableIterator {
@@ -111,9 +685,9 @@ impl Iterator for TableIterator {
             return None;
         }
 
-        if self.records_read_from_block >= self.records_in_block {           
+        while self.records_read_from_block >= self.records_in_block {
             self.status = self.read_block();
-            if !self.status.is_ok() {
+            if self.status.is_err() {
                 self.done = true;
                 return None;
             }
@@ -133,11 +707,486 @@ impl Iterator for TableIterator {
     }
 }
 
+enum BlockData {
+    // Uncompressed records, read in place from [start, end) of a table's
+    // data.
+    Mapped(Arc<FileContents>, usize, usize),
+    Cached(Arc<Vec<u8>>),
+}
+
+// The decoded records of a single block.
+pub struct Block {
+    data: BlockData,
+}
+
+impl Block {
+    fn bytes(&self) -> &[u8] {
+        return match self.data {
+            BlockData::Mapped(ref map, start, end) => &map[start..end],
+            BlockData::Cached(ref data) => &data[..],
+        };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.bytes().len() / REC_SIZE;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn get(&self, i: usize) -> (u64, u64) {
+        let data = self.bytes();
+        let ptr = i * REC_SIZE;
+        return (format::load(&data[ptr..(ptr+8)]),
+                format::load(&data[(ptr+8)..(ptr+16)]));
+    }
+
+    // The position of the first record with a key >= 'ts', or len() if
+    // there is none.
+    pub fn lower_bound(&self, ts: u64) -> usize {
+        let mut lo = 0;
+        let mut hi = self.len();
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.get(mid).0 < ts {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        return lo;
+    }
+}
+
+// Random access to a table through a read-only memory map (or, for Envs
+// that can't map files, a copy of the table in memory). Blocks which
+// aren't compressed are decoded in place, without copying, unless there is a
+// block cache to fill.
+//
+// Records are addressed by their ordinal position in the table, so that
+// iterators can be positioned without decoding blocks.
+pub struct TableReader {
+    filename: path::PathBuf,
+    // Identifies the table in the block cache; see Env::cache_key and
+    // block_cache::next_reader_id.
+    cache_key: path::PathBuf,
+    reader_id: u64,
+    options: ReadOptions,
+    data: Arc<FileContents>,
+    index: Vec<BlockHandle>,
+    // The ordinal of the first record in each block, plus one past the end.
+    block_starts: Vec<u64>,
+    filter: Option<BloomFilter>,
+    cache: Option<Arc<BlockCache>>,
+}
+
+impl TableReader {
+    pub fn open<P: AsRef<path::Path>>(filename: P, options: &ReadOptions) -> io::Result<TableReader> {
+        return TableReader::open_with_cache(filename, options, None);
+    }
+
+    pub fn open_with_cache<P: AsRef<path::Path>>(filename: P, options: &ReadOptions, cache: Option<Arc<BlockCache>>) -> io::Result<TableReader> {
+        return TableReader::open_with_env(&PosixEnv, filename, options, cache);
+    }
+
+    pub fn open_with_env<P: AsRef<path::Path>>(env: &dyn Env, filename: P, options: &ReadOptions, cache: Option<Arc<BlockCache>>) -> io::Result<TableReader> {
+        // Tables are never modified after they're written, so the contents
+        // can be shared by every block read from them.
+        let data = try!(env.read_all(filename.as_ref()));
+        if data.len() < TABLE_FOOTER_SIZE {
+            return Err(corrupt(format!(
+                "Table {:?} too short: {} bytes", filename.as_ref(), data.len())));
+        }
+
+        let file_size = data.len() as u64;
+        let footer_ptr = data.len() - TABLE_FOOTER_SIZE;
+        let footer = try!(Footer::parse(&data[footer_ptr..], file_size));
+        let meta_ptr = footer.filter_offset as usize;
+        let meta = try!(parse_meta(
+            &data[meta_ptr..(meta_ptr + footer.meta_length())], &footer));
+
+        let mut block_starts = vec![0];
+        for handle in &meta.index {
+            let next = block_starts[block_starts.len() - 1] + handle.summary.count;
+            block_starts.push(next);
+        }
+
+        return Ok(TableReader{
+            filename: filename.as_ref().to_path_buf(),
+            cache_key: env.cache_key(filename.as_ref()),
+            reader_id: block_cache::next_reader_id(),
+            options: *options,
+            data: Arc::new(data),
+            index: meta.index,
+            block_starts: block_starts,
+            filter: meta.filter,
+            cache: cache,
+        });
+    }
+
+    pub fn filename(&self) -> &path::Path {
+        return &self.filename;
+    }
+
+    // The whole table, e.g. to read it through a ThreadPoolDevice.
+    pub fn contents(&self) -> Arc<FileContents> {
+        return self.data.clone();
+    }
+
+    pub fn index(&self) -> &[BlockHandle] {
+        return &self.index;
+    }
+
+    pub fn num_records(&self) -> u64 {
+        return self.block_starts[self.index.len()];
+    }
+
+    // The table's smallest timestamp, from the index.
+    pub fn first_ts(&self) -> Option<u64> {
+        return self.index.first().map(|h| h.summary.first_ts);
+    }
+
+    // The table's largest timestamp, from the index.
+    pub fn last_ts(&self) -> Option<u64> {
+        return self.index.last().map(|h| h.summary.last_ts);
+    }
+
+    pub fn may_contain(&self, ts: u64) -> bool {
+        return self.filter.as_ref().map(|f| f.may_contain(ts)).unwrap_or(true);
+    }
+
+    pub fn has_filter(&self) -> bool {
+        return self.filter.is_some();
+    }
+
+    pub fn block(&self, i: usize) -> io::Result<Block> {
+        return self.block_with_options(i, &self.options);
+    }
+
+    pub fn block_with_options(&self, i: usize, options: &ReadOptions) -> io::Result<Block> {
+        if let Some(block) = self.cached(i) {
+            return Ok(block);
+        }
+
+        let handle = &self.index[i];
+        let start = handle.offset as usize;
+        let end = start + handle.length as usize;
+        let data = try!(decode_block(
+            &self.data[start..end], &self.filename, handle, options));
+
+        if let Some(ref cache) = self.cache {
+            if options.fill_cache {
+                let data = Arc::new(data.into_owned());
+                cache.insert(&self.cache_key, self.reader_id, handle.offset, data.clone());
+                return Ok(Block{data: BlockData::Cached(data)});
+            }
+        }
+
+        return Ok(match data {
+            Cow::Borrowed(records) => Block{data: BlockData::Mapped(
+                self.data.clone(), start, start + records.len())},
+            Cow::Owned(records) => Block{data: BlockData::Cached(Arc::new(records))},
+        });
+    }
+
+    // Block 'i' from the block cache, if it's there.
+    fn cached(&self, i: usize) -> Option<Block> {
+        let cache = self.cache.as_ref()?;
+        let data = cache.get(&self.cache_key, self.reader_id, self.index[i].offset)?;
+        return Some(Block{data: BlockData::Cached(data)});
+    }
+
+    // Block 'i', decoded from a copy of its data read some other way.
+    fn block_from(&self, i: usize, buf: &[u8], options: &ReadOptions) -> io::Result<Block> {
+        let handle = &self.index[i];
+        let data = Arc::new(try!(decode_block(buf, &self.filename, handle, options)).into_owned());
+        if let Some(ref cache) = self.cache {
+            if options.fill_cache {
+                cache.insert(&self.cache_key, self.reader_id, handle.offset, data.clone());
+            }
+        }
+        return Ok(Block{data: BlockData::Cached(data)});
+    }
+
+    // The ordinals of the blocks that may hold points in [start, end).
+    pub fn blocks_in(&self, start: u64, end: u64) -> ops::Range<usize> {
+        let first = self.find_block(start);
+        let count = self.index[first..].partition_point(|h| h.summary.first_ts < end);
+        return first..(first + count);
+    }
+
+    // The first block that could hold a key >= 'ts', or index.len() if
+    // there is none.
+    fn find_block(&self, ts: u64) -> usize {
+        let mut lo = 0;
+        let mut hi = self.index.len();
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.index[mid].summary.last_ts < ts {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        return lo;
+    }
+
+    // The block holding the record at 'ordinal'.
+    fn block_containing(&self, ordinal: u64) -> usize {
+        let mut lo = 0;
+        let mut hi = self.index.len();
+        while lo + 1 < hi {
+            let mid = (lo + hi) / 2;
+            if self.block_starts[mid] <= ordinal {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        return lo;
+    }
+
+    pub fn get(&self, ts: u64) -> io::Result<Option<u64>> {
+        return self.ceiling(ts).map(
+            |r| r.and_then(|(k, v)| if k == ts { Some(v) } else { None }));
+    }
+
+    // The record with the smallest key >= 'ts'. Reads at most one block, and
+    // none if the answer is at the start of a block.
+    pub fn ceiling(&self, ts: u64) -> io::Result<Option<(u64, u64)>> {
+        let b = self.find_block(ts);
+        if b >= self.index.len() {
+            return Ok(None);
+        }
+
+        let summary = &self.index[b].summary;
+        if summary.first_ts >= ts {
+            return Ok(Some((summary.first_ts, summary.first)));
+        }
+
+        let block = try!(self.block(b));
+        let i = block.lower_bound(ts);
+        if i == block.len() {
+            return Err(self.disagrees_with_index(b));
+        }
+        return Ok(Some(block.get(i)));
+    }
+
+    // The record with the largest key <= 'ts'. Reads at most one block, and
+    // none if the answer is at the end of a block.
+    pub fn floor(&self, ts: u64) -> io::Result<Option<(u64, u64)>> {
+        let b = self.find_block(ts);
+        if b >= self.index.len() || self.index[b].summary.first_ts > ts {
+            if b == 0 {
+                return Ok(None);
+            }
+            let summary = &self.index[b - 1].summary;
+            return Ok(Some((summary.last_ts, summary.last)));
+        }
+
+        let summary = &self.index[b].summary;
+        if summary.last_ts == ts {
+            return Ok(Some((summary.last_ts, summary.last)));
+        }
+
+        // The block has keys on both sides of 'ts'.
+        let block = try!(self.block(b));
+        let i = block.lower_bound(ts + 1);
+        if i == 0 {
+            return Err(self.disagrees_with_index(b));
+        }
+        return Ok(Some(block.get(i - 1)));
+    }
+
+    // For blocks whose keys aren't where the index says. Checksums catch
+    // this unless they're not being verified.
+    fn disagrees_with_index(&self, b: usize) -> io::Error {
+        return corrupt(format!(
+            "Block in {:?} at {} doesn't match its index entry",
+            self.filename, self.index[b].offset));
+    }
+
+    pub fn iter(self: &Arc<Self>) -> TableReaderIterator {
+        return self.iter_with_options(&self.options);
+    }
+
+    pub fn iter_with_options(self: &Arc<Self>, options: &ReadOptions) -> TableReaderIterator {
+        return TableReaderIterator{
+            reader: self.clone(),
+            options: *options,
+            bounds: None,
+            front: 0,
+            back: self.num_records(),
+            front_block: None,
+            back_block: None,
+            read_ahead: None,
+            status: Ok(()),
+        };
+    }
+
+    // An iterator starting at the first record with a key >= 'ts'.
+    pub fn seek(self: &Arc<Self>, ts: u64) -> TableReaderIterator {
+        return self.range(ts, u64::MAX);
+    }
+
+    // An iterator over records with keys in [start, end). Only blocks which
+    // are actually visited get read, from either end, and none until then.
+    pub fn range(self: &Arc<Self>, start: u64, end: u64) -> TableReaderIterator {
+        let mut iter = self.iter();
+        let blocks = self.blocks_in(start, end);
+        iter.bounds = Some(start..end);
+        iter.front = self.block_starts[blocks.start];
+        iter.back = self.block_starts[blocks.end];
+        return iter;
+    }
+}
+
+pub struct TableReaderIterator {
+    reader: Arc<TableReader>,
+    options: ReadOptions,
+    // Records in the blocks at either end that fall outside these keys are
+    // skipped.
+    bounds: Option<ops::Range<u64>>,
+    // The ordinal of the next record to return from the front, and one past
+    // the next record to return from the back.
+    front: u64,
+    back: u64,
+    front_block: Option<(usize, Block)>,
+    back_block: Option<(usize, Block)>,
+    // Used for blocks read from the front that aren't cached.
+    read_ahead: Option<ReadAhead>,
+    status: io::Result<()>,
+}
+
+impl TableReaderIterator {
+    // Reads blocks from the front through 'device', up to 'depth' ahead of
+    // the iterator, so that poll_ready can say whether next() would wait.
+    pub fn with_read_ahead(mut self, device: Box<dyn AsyncDevice>, depth: usize) -> TableReaderIterator {
+        self.read_ahead = Some(ReadAhead::new(device, depth));
+        return self;
+    }
+
+    // Whether 'k' is before, in or after the iterator's range.
+    fn placement(&self, k: u64) -> Ordering {
+        return match self.bounds {
+            Some(ref r) if k < r.start => Ordering::Less,
+            Some(ref r) if k >= r.end => Ordering::Greater,
+            _ => Ordering::Equal,
+        };
+    }
+
+    fn load(&mut self, ordinal: u64, from_back: bool) -> Option<(u64, u64)> {
+        let b = self.reader.block_containing(ordinal);
+        let slot = if from_back { &mut self.back_block } else { &mut self.front_block };
+
+        let loaded = slot.as_ref().map(|&(i, _)| i == b).unwrap_or(false);
+        if !loaded {
+            let (reader, options) = (&self.reader, &self.options);
+            let block = match self.read_ahead {
+                Some(ref mut read_ahead) if !from_back => match reader.cached(b) {
+                    Some(block) => Ok(block),
+                    None => read_ahead.read(&reader.index, b)
+                        .and_then(|buf| reader.block_from(b, &buf, options)),
+                },
+                _ => reader.block_with_options(b, options),
+            };
+            match block {
+                Ok(block) => *slot = Some((b, block)),
+                Err(e) => {
+                    self.status = Err(e);
+                    return None;
+                }
+            }
+        }
+
+        let block = &slot.as_ref().unwrap().1;
+        return Some(block.get((ordinal - self.reader.block_starts[b]) as usize));
+    }
+}
+
+impl merge::Source for TableReaderIterator {
+    fn take_status(&mut self) -> io::Result<()> {
+        return mem::replace(&mut self.status, Ok(()));
+    }
+
+    fn poll_ready(&mut self) -> Poll<()> {
+        if self.status.is_err() || self.front >= self.back {
+            return Poll::Ready(());
+        }
+        let b = self.reader.block_containing(self.front);
+        if self.front_block.as_ref().map(|&(i, _)| i == b).unwrap_or(false) {
+            return Poll::Ready(());
+        }
+        let polled = match self.read_ahead {
+            Some(ref mut read_ahead) => read_ahead.poll(&self.reader.index, b),
+            None => return Poll::Ready(()),
+        };
+        return match polled {
+            Ok(true) => Poll::Ready(()),
+            Ok(false) => Poll::Pending,
+            Err(e) => {
+                self.status = Err(e);
+                Poll::Ready(())
+            },
+        };
+    }
+}
+
+impl Iterator for TableReaderIterator {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        while self.status.is_ok() && self.front < self.back {
+            let ordinal = self.front;
+            let (k, v) = self.load(ordinal, false)?;
+            match self.placement(k) {
+                Ordering::Less => self.front += 1,
+                Ordering::Equal => {
+                    self.front += 1;
+                    return Some((k, v));
+                },
+                Ordering::Greater => self.front = self.back,
+            }
+        }
+        return None;
+    }
+}
+
+impl DoubleEndedIterator for TableReaderIterator {
+    fn next_back(&mut self) -> Option<(u64, u64)> {
+        while self.status.is_ok() && self.front < self.back {
+            let ordinal = self.back - 1;
+            let (k, v) = self.load(ordinal, true)?;
+            match self.placement(k) {
+                Ordering::Greater => self.back -= 1,
+                Ordering::Equal => {
+                    self.back -= 1;
+                    return Some((k, v));
+                },
+                Ordering::Less => self.back = self.front,
+            }
+        }
+        return None;
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use block_cache::BlockCache;
+    use checksum;
+    use env;
+    use env::Env;
+    use env::MemEnv;
+    use format;
+    use merge::Source;
     use std::collections::BTreeMap;
     use std::collections::HashMap;
+    use std::fs;
     use std::io;
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Arc;
 
     #[test]
     fn write_table() {
@@ -145,20 +1194,485 @@ mod test {
         for i in 0..1000 {
             map.insert(i, i+1);
         }
+        let path = env::temp_path("table");
         
         {
-            super::TableBuilder::write("/tmp/table", map.iter())
+            super::TableBuilder::write(&path, map.iter())
                 .expect("TableWriter::write");
         }
 
-        let mut iter = super::TableIterator::new("/tmp/table")
+        let mut iter = super::TableIterator::new(&path)
             .expect("TableIterator::new");
         for i in 0..1000 {
-            assert_eq!((i,i+1), iter.next().expect(&format!("Val {}", i)));
+            assert_eq!((i,i+1), iter.next().unwrap_or_else(|| panic!("Val {}", i)));
         }
 
         assert_eq!(None, iter.next());
         assert_eq!(None, iter.next());
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn write_and_read_multi_block(filename: &Path, compression: super::Compression) {
+        let mut map = BTreeMap::new();
+        for i in 0..10000 {
+            map.insert(i, i % 10);
+        }
+
+        let options = super::TableOptions{
+            compression: compression,
+            bloom_bits_per_key: None,
+        };
+        super::TableBuilder::write_with_options(filename, map.iter(), &options)
+            .expect("TableWriter::write_with_options");
+
+        let iter = super::TableIterator::new(filename)
+            .expect("TableIterator::new");
+        let read : Vec<(u64, u64)> = iter.collect();
+        assert_eq!(10000, read.len());
+        for i in 0..10000 {
+            assert_eq!((i, i % 10), read[i as usize]);
+        }
+    }
+
+    #[test]
+    fn multi_block_table() {
+        let path = env::temp_path("table-multi");
+        write_and_read_multi_block(&path, super::Compression::None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn async_table_iterator() {
+        use async_io::ReadAt;
+        use async_io::ThreadPoolDevice;
+
+        let mut map = BTreeMap::new();
+        for i in 0..100000 {
+            map.insert(i, i * 3);
+        }
+        let env = MemEnv::new();
+        super::TableBuilder::write_records_with_env(
+            &env, "/table", map.iter().map(|(k, v)| (*k, *v)), &super::TableOptions::default()).unwrap();
+        let expected : Vec<(u64, u64)> = map.iter().map(|(k, v)| (*k, *v)).collect();
+
+        for depth in &[1, 4, 100] {
+            let file : Arc<dyn ReadAt> = Arc::new(env.read_all(Path::new("/table")).unwrap());
+            let device = Box::new(ThreadPoolDevice::new(file, 4).unwrap());
+            let mut iter = super::TableIterator::with_async(
+                device, "/table", &super::ReadOptions::default(), *depth).unwrap();
+            assert!(iter.index().len() > 40);
+            let read : Vec<(u64, u64)> = (&mut iter).collect();
+            assert_eq!(expected, read);
+            iter.take_status().unwrap();
+
+            // Seeking discards the blocks read ahead.
+            let per_block = iter.index()[0].summary.count as usize;
+            iter.seek_block(2);
+            assert_eq!(Some(expected[2 * per_block]), iter.next());
+            iter.seek_block(1);
+            assert_eq!(Some(expected[per_block]), iter.next());
+        }
+    }
+
+    #[test]
+    fn read_ahead() {
+        use async_io::AsyncDevice;
+        use async_io::Completion;
+        use async_io::ReadRequest;
+        use async_io::ThreadPoolDevice;
+        use std::sync::atomic::AtomicBool;
+        use std::sync::atomic::Ordering;
+
+        // Holds back completed reads until it's opened.
+        struct Gated {
+            device: ThreadPoolDevice,
+            open: Arc<AtomicBool>,
+        }
+
+        impl AsyncDevice for Gated {
+            fn size(&self) -> u64 {
+                return self.device.size();
+            }
+
+            fn submit(&mut self, request: ReadRequest) -> io::Result<()> {
+                return self.device.submit(request);
+            }
+
+            fn complete(&mut self, min: usize) -> io::Result<Vec<Completion>> {
+                if !self.open.load(Ordering::SeqCst) {
+                    assert_eq!(0, min, "Waited for a read");
+                    return Ok(Vec::new());
+                }
+                return self.device.complete(min);
+            }
+
+            fn in_flight(&self) -> usize {
+                return self.device.in_flight();
+            }
+        }
+
+        let env = MemEnv::new();
+        super::TableBuilder::write_records_with_env(
+            &env, "/read-ahead", (0..100000).map(|i| (i, i * 3)), &super::TableOptions::default()).unwrap();
+        let reader = Arc::new(super::TableReader::open_with_env(
+            &env, "/read-ahead", &super::ReadOptions::default(), None).unwrap());
+
+        let open = Arc::new(AtomicBool::new(false));
+        let device = Gated{
+            device: ThreadPoolDevice::new(reader.contents(), 2).unwrap(),
+            open: open.clone(),
+        };
+        let mut iter = reader.range(1000, 90000).with_read_ahead(Box::new(device), 4);
+        assert!(iter.poll_ready().is_pending());
+        assert!(iter.poll_ready().is_pending());
+
+        open.store(true, Ordering::SeqCst);
+        while iter.poll_ready().is_pending() {}
+        assert_eq!(Some((1000, 3000)), iter.next());
+        let mut read = vec![(1000, 3000)];
+        loop {
+            while iter.poll_ready().is_pending() {}
+            match iter.next() {
+                Some(record) => read.push(record),
+                None => break,
+            }
+        }
+        iter.take_status().unwrap();
+        assert_eq!((1000..90000).map(|i| (i, i * 3)).collect::<Vec<(u64, u64)>>(), read);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_table() {
+        let path = env::temp_path("table-lz4");
+        write_and_read_multi_block(&path, super::Compression::Lz4);
+        // 10000 records don't fit in one uncompressed 32KiB block, but
+        // compressed blocks aren't padded, so the file should be much smaller.
+        let len = fs::metadata(&path).unwrap().len();
+        fs::remove_file(&path).unwrap();
+        assert!(len < 10000 * 16, "Table wasn't compressed: {} bytes", len);
+    }
+
+    #[test]
+    fn corrupt_block() {
+        let mut map = BTreeMap::new();
+        for i in 0..10000 {
+            map.insert(i, i);
+        }
+        let path = env::temp_path("table-corrupt");
+        super::TableBuilder::write(&path, map.iter()).unwrap();
+
+        // Flip a bit in the first record of the second block.
+        {
+            let mut f = ::std::fs::OpenOptions::new()
+                .read(true).write(true).open(&path).unwrap();
+            let iter = super::TableIterator::new(&path).unwrap();
+            let offset = iter.index[1].offset;
+            ::std::io::Seek::seek(&mut f, ::std::io::SeekFrom::Start(offset)).unwrap();
+            ::std::io::Write::write_all(&mut f, &[1]).unwrap();
+        }
+
+        let mut iter = super::TableIterator::new(&path).unwrap();
+        let second_block = iter.index[1].offset;
+        assert_eq!(super::BLOCK_SIZE / super::REC_SIZE - 2, (&mut iter).count());
+        let err = iter.take_status().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(format!("{}", err).contains(path.to_str().unwrap()));
+        assert!(format!("{}", err).contains(&format!("offset {}", second_block)));
+
+        // Without verification the flipped bit is silently returned.
+        let options = super::ReadOptions{
+            verify_checksums: false,
+            fill_cache: true,
+        };
+        let mut iter = super::TableIterator::with_options(&path, &options).unwrap();
+        assert_eq!(10000, (&mut iter).count());
+        assert!(iter.take_status().is_ok());
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn write_to_memory(env: &MemEnv, filename: &str, records: u64) -> Vec<u8> {
+        let options = super::TableOptions{bloom_bits_per_key: Some(10), ..Default::default()};
+        super::TableBuilder::write_records_with_env(
+            env, filename, (0..records).map(|i| (i, i)), &options).unwrap();
+        return env.read_all(Path::new(filename)).unwrap().to_vec();
+    }
+
+    fn replace(env: &MemEnv, filename: &str, data: &[u8]) {
+        env.create(Path::new(filename)).unwrap().write_all(data).unwrap();
+    }
+
+    #[test]
+    fn corrupt_metadata() {
+        let env = MemEnv::new();
+        let table = write_to_memory(&env, "/meta", 1000);
+        let footer = super::Footer::parse(
+            &table[(table.len() - super::TABLE_FOOTER_SIZE)..], table.len() as u64).unwrap();
+
+        // Every flipped bit in the filter, index or footer is caught when the
+        // table is opened.
+        for bit in (footer.filter_offset as usize * 8)..(table.len() * 8) {
+            let mut data = table.clone();
+            data[bit / 8] ^= 1 << (bit % 8);
+            replace(&env, "/meta", &data);
+            let res = super::TableReader::open_with_env(
+                &env, "/meta", &super::ReadOptions::default(), None);
+            assert_eq!(io::ErrorKind::InvalidData, res.err().unwrap().kind(), "bit {}", bit);
+        }
+    }
+
+    #[test]
+    fn older_versions() {
+        // A table from before deletions could hold the tombstone as a value.
+        let env = MemEnv::new();
+        let mut data = write_to_memory(&env, "/old", 1000);
+        let len = data.len();
+        format::store(0x7274735f74616232, &mut data[(len - 8)..]);
+        replace(&env, "/old", &data);
+        let res = super::TableReader::open_with_env(&env, "/old", &super::ReadOptions::default(), None);
+        assert_eq!(io::ErrorKind::InvalidData, res.err().unwrap().kind());
+    }
+
+    #[test]
+    fn index_disagrees_with_block() {
+        let env = MemEnv::new();
+        let mut data = write_to_memory(&env, "/count", 1000);
+        let len = data.len();
+        let footer = super::Footer::parse(
+            &data[(len - super::TABLE_FOOTER_SIZE)..], len as u64).unwrap();
+
+        // Claim the first block has one record fewer than it does, and fix up
+        // the metadata's checksum to match.
+        let count_ptr = footer.index_offset as usize + 16;
+        let count = format::load(&data[count_ptr..(count_ptr + 8)]);
+        format::store(count - 1, &mut data[count_ptr..(count_ptr + 8)]);
+        let crc_ptr = len - super::TABLE_FOOTER_SIZE + super::TABLE_FOOTER_CRC;
+        let crc = checksum::crc32c(&data[(footer.filter_offset as usize)..crc_ptr]);
+        format::store(crc as u64, &mut data[crc_ptr..(crc_ptr + 8)]);
+        replace(&env, "/count", &data);
+
+        let options = super::ReadOptions{verify_checksums: false, fill_cache: false};
+        let reader = Arc::new(super::TableReader::open_with_env(
+            &env, "/count", &options, None).unwrap());
+        assert_eq!(io::ErrorKind::InvalidData, reader.ceiling(10).unwrap_err().kind());
+        let mut iter = reader.iter();
+        assert_eq!(None, iter.next());
+        assert_eq!(io::ErrorKind::InvalidData, iter.take_status().unwrap_err().kind());
+    }
+
+    #[test]
+    fn bloom_filter() {
+        let mut map = BTreeMap::new();
+        for i in 0..1000 {
+            map.insert(i * 2, i);
+        }
+
+        let options = super::TableOptions{
+            compression: super::Compression::None,
+            bloom_bits_per_key: Some(10),
+        };
+        let path = env::temp_path("table-bloom");
+        super::TableBuilder::write_with_options(&path, map.iter(), &options)
+            .unwrap();
+
+        let iter = super::TableIterator::new(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(iter.has_filter());
+        for i in 0..1000 {
+            assert!(iter.may_contain(i * 2));
+        }
+        let misses = (0..1000).filter(|i| !iter.may_contain(i * 2 + 1)).count();
+        assert!(misses > 900, "Only {} misses", misses);
+        assert_eq!(1000, iter.count());
+    }
+
+    #[test]
+    fn block_summaries() {
+        let mut map = BTreeMap::new();
+        for i in 0..5000 {
+            map.insert(i, 5000 - i);
+        }
+        let path = env::temp_path("table-summaries");
+        super::TableBuilder::write(&path, map.iter()).unwrap();
+
+        let mut iter = super::TableIterator::new(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let index = iter.index().to_vec();
+        assert_eq!(3, index.len());
+
+        let mut total = ::aggregate::Summary::empty();
+        for handle in &index {
+            total.merge(&handle.summary);
+        }
+        assert_eq!(5000, total.count);
+        assert_eq!((1..5001).sum::<u128>(), total.sum);
+        assert_eq!((1, 5000), (total.min, total.max));
+        assert_eq!((0, 5000), (total.first_ts, total.first));
+        assert_eq!((4999, 1), (total.last_ts, total.last));
+
+        iter.seek_block(2);
+        assert_eq!(Some((index[2].summary.first_ts, index[2].summary.first)),
+                   iter.next());
+    }
+
+    #[test]
+    fn table_reader() {
+        let mut map = BTreeMap::new();
+        for i in 0..10000 {
+            map.insert(i * 2, i);
+        }
+        let path = env::temp_path("table-reader");
+        super::TableBuilder::write(&path, map.iter()).unwrap();
+
+        let reader = Arc::new(super::TableReader::open(&path, &super::ReadOptions::default()).unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(reader.index().len() > 1);
+
+        assert_eq!(Some(0), reader.get(0).unwrap());
+        assert_eq!(Some(4321), reader.get(8642).unwrap());
+        assert_eq!(Some(9999), reader.get(19998).unwrap());
+        assert_eq!(None, reader.get(8643).unwrap());
+        assert_eq!(None, reader.get(20000).unwrap());
+
+        let mut iter = reader.iter();
+        assert_eq!(10000, (&mut iter).count());
+        assert!(iter.take_status().is_ok());
+
+        let from : Vec<(u64, u64)> = reader.seek(8641).take(3).collect();
+        assert_eq!(vec![(8642, 4321), (8644, 4322), (8646, 4323)], from);
+        assert_eq!(0, reader.seek(20000).count());
+    }
+
+    #[test]
+    fn reverse_iteration() {
+        let mut map = BTreeMap::new();
+        for i in 1..10001 {
+            map.insert(i * 2, i);
+        }
+        let path = env::temp_path("table-reverse");
+        super::TableBuilder::write(&path, map.iter()).unwrap();
+        let reader = Arc::new(super::TableReader::open(&path, &super::ReadOptions::default()).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let backwards : Vec<(u64, u64)> = reader.iter().rev().collect();
+        let mut forwards : Vec<(u64, u64)> = reader.iter().collect();
+        forwards.reverse();
+        assert_eq!(10000, backwards.len());
+        assert_eq!(forwards, backwards);
+
+        // Both ends of a range, meeting in the middle.
+        let mut range = reader.range(4093, 4101);
+        assert_eq!(Some((4094, 2047)), range.next());
+        assert_eq!(Some((4100, 2050)), range.next_back());
+        assert_eq!(Some((4096, 2048)), range.next());
+        assert_eq!(Some((4098, 2049)), range.next_back());
+        assert_eq!(None, range.next());
+        assert_eq!(None, range.next_back());
+        assert_eq!(0, reader.range(100, 100).count());
+        assert_eq!(0, reader.range(200, 100).count());
+
+        assert_eq!(None, reader.floor(1).unwrap());
+        assert_eq!(Some((2, 1)), reader.floor(3).unwrap());
+        assert_eq!(Some((4092, 2046)), reader.floor(4093).unwrap());
+        assert_eq!(Some((4094, 2047)), reader.floor(4094).unwrap());
+        assert_eq!(Some((20000, 10000)), reader.floor(u64::MAX).unwrap());
+        assert_eq!(Some((2, 1)), reader.ceiling(0).unwrap());
+        assert_eq!(Some((4094, 2047)), reader.ceiling(4093).unwrap());
+        assert_eq!(None, reader.ceiling(20001).unwrap());
+
+        // Block boundaries, which are answered from the index.
+        let first_block = reader.index()[0].summary;
+        let next = reader.index()[1].summary.first_ts;
+        assert_eq!(Some((first_block.last_ts, first_block.last)),
+                   reader.floor(next - 1).unwrap());
+        assert_eq!(Some((next, next / 2)),
+                   reader.ceiling(first_block.last_ts + 1).unwrap());
+    }
+
+    #[test]
+    fn block_cache() {
+        let mut map = BTreeMap::new();
+        for i in 0..10000 {
+            map.insert(i, i);
+        }
+        let path = env::temp_path("table-block-cache");
+        super::TableBuilder::write(&path, map.iter()).unwrap();
+
+        let cache = Arc::new(BlockCache::new(1024 * 1024));
+        let reader = Arc::new(super::TableReader::open_with_cache(
+            &path, &super::ReadOptions::default(), Some(cache.clone())).unwrap());
+        fs::remove_file(&path).unwrap();
+        let blocks = reader.index().len() as u64;
+
+        // Bypassing the cache doesn't fill it.
+        let options = super::ReadOptions{
+            verify_checksums: true,
+            fill_cache: false,
+        };
+        assert_eq!(10000, reader.iter_with_options(&options).count());
+        assert_eq!(0, cache.stats().inserts);
+        assert_eq!(blocks, cache.stats().misses);
+
+        assert_eq!(10000, reader.iter().count());
+        assert_eq!(Some(1234), reader.get(1234).unwrap());
+        let stats = cache.stats();
+        assert_eq!(blocks, stats.inserts);
+        assert_eq!(1, stats.hits);
+        assert_eq!(2 * blocks, stats.misses);
+    }
+
+    #[test]
+    fn block_cache_after_replacing_table() {
+        let env = MemEnv::new();
+        let cache = Arc::new(BlockCache::new(1024 * 1024));
+        let read = |env: &MemEnv| {
+            let reader = super::TableReader::open_with_env(
+                env, "/replaced", &super::ReadOptions::default(), Some(cache.clone())).unwrap();
+            return reader.get(1234).unwrap();
+        };
+
+        super::TableBuilder::write_records_with_env(
+            &env, "/replaced", (0..10000).map(|i| (i, i)), &Default::default()).unwrap();
+        assert_eq!(Some(1234), read(&env));
+
+        // A new table under the same name (in the same Env) isn't served the
+        // old one's blocks.
+        super::TableBuilder::write_records_with_env(
+            &env, "/replaced", (0..10000).map(|i| (i, i + 1)), &Default::default()).unwrap();
+        assert_eq!(Some(1235), read(&env));
+        assert_eq!(0, cache.stats().hits);
+    }
+
+    #[test]
+    fn not_a_table() {
+        let path = env::temp_path("table-garbage");
+        fs::File::create(&path).unwrap().write_all(&[1; 100]).unwrap();
+
+        let res = super::TableIterator::new(&path);
+        assert_eq!(io::ErrorKind::InvalidData, res.err().unwrap().kind());
+
+        let res = super::TableReader::open(&path, &super::ReadOptions::default());
+        assert_eq!(io::ErrorKind::InvalidData, res.err().unwrap().kind());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn footer_overflow() {
+        // (index offset, block count, filter offset, filter length, file size)
+        let footers = [
+            [u64::MAX, 1, 0, 0, 1000],
+            [0, u64::MAX / 8, 0, 0, 1000],
+            [8, 0, u64::MAX, 9, 8 + super::TABLE_FOOTER_SIZE as u64],
+        ];
+        for f in footers.iter() {
+            let mut buf = [0; super::TABLE_FOOTER_SIZE];
+            for i in 0..4 {
+                format::store(f[i], &mut buf[(i*8)..((i+1)*8)]);
+            }
+            format::store(super::TABLE_MAGIC, &mut buf[(super::TABLE_FOOTER_SIZE - 8)..]);
+            let res = super::Footer::parse(&buf, f[4]);
+            assert_eq!(io::ErrorKind::InvalidData, res.err().unwrap().kind());
+        }
     }
 
     #[test]
@@ -168,7 +1682,10 @@ mod test {
             map.insert(i, i);
         }
 
-        let res = super::TableBuilder::write("/tmp/table-unordered", map.iter());
+        let env = MemEnv::new();
+        let records = map.iter().map(|(k, v)| (*k, *v));
+        let res = super::TableBuilder::write_records_with_env(
+            &env, "/table-unordered", records, &super::TableOptions::default());
 
         assert!(res.is_err());
         assert_eq!(io::ErrorKind::InvalidInput, res.unwrap_err().kind());
@@ -0,0 +1,145 @@
+use block_cache::BlockCache;
+use env::Env;
+use env::PosixEnv;
+use table::ReadOptions;
+use table::TableReader;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io;
+use std::path;
+use std::sync::Arc;
+
+// Keeps up to 'capacity' tables open between queries, so that repeated
+// lookups don't have to reopen files and re-parse their index and filter.
+// The least recently used table is closed to make room for a new one.
+pub struct TableCache {
+    env: Arc<dyn Env>,
+    options: ReadOptions,
+    block_cache: Option<Arc<BlockCache>>,
+    capacity: usize,
+    tables: HashMap<String, (Arc<TableReader>, u64)>,
+    // Filenames ordered by their last use, least recent first.
+    by_use: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl TableCache {
+    pub fn new(options: ReadOptions, block_cache: Option<Arc<BlockCache>>, capacity: usize) -> TableCache {
+        return TableCache::with_env(PosixEnv::shared(), options, block_cache, capacity);
+    }
+
+    pub fn with_env(env: Arc<dyn Env>, options: ReadOptions, block_cache: Option<Arc<BlockCache>>, capacity: usize) -> TableCache {
+        assert!(capacity > 0);
+        return TableCache{
+            env: env,
+            options: options,
+            block_cache: block_cache,
+            capacity: capacity,
+            tables: HashMap::new(),
+            by_use: BTreeMap::new(),
+            clock: 0,
+        };
+    }
+
+    pub fn get(&mut self, filename: &str) -> io::Result<Arc<TableReader>> {
+        self.clock += 1;
+        let now = self.clock;
+
+        if let Some(&mut (ref reader, ref mut last_use)) = self.tables.get_mut(filename) {
+            self.by_use.remove(last_use);
+            self.by_use.insert(now, filename.to_string());
+            *last_use = now;
+            return Ok(reader.clone());
+        }
+
+        let reader = Arc::new(try!(TableReader::open_with_env(
+            &*self.env, filename, &self.options, self.block_cache.clone())));
+        while self.tables.len() >= self.capacity {
+            let oldest = *self.by_use.keys().next().unwrap();
+            let victim = self.by_use.remove(&oldest).unwrap();
+            self.tables.remove(&victim);
+        }
+        self.tables.insert(filename.to_string(), (reader.clone(), now));
+        self.by_use.insert(now, filename.to_string());
+        return Ok(reader);
+    }
+
+    // Forgets 'filename', and its blocks, e.g. because the table has been
+    // deleted. Readers that are still in use stay valid until they're
+    // dropped.
+    pub fn evict(&mut self, filename: &str) {
+        if let Some((_, last_use)) = self.tables.remove(filename) {
+            self.by_use.remove(&last_use);
+        }
+        if let Some(ref cache) = self.block_cache {
+            cache.erase_file(&self.env.cache_key(path::Path::new(filename)));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.tables.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.tables.is_empty();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use env::MemEnv;
+    use table;
+    use super::TableCache;
+
+    fn write_table(env: &MemEnv, filename: &str) {
+        table::TableBuilder::write_records_with_env(
+            env, filename, vec![(1, 2)].into_iter(), &table::TableOptions::default()).unwrap();
+    }
+
+    fn cache(env: &MemEnv, capacity: usize) -> TableCache {
+        return TableCache::with_env(Arc::new(env.clone()), table::ReadOptions::default(), None, capacity);
+    }
+
+    #[test]
+    fn reuses_readers() {
+        let env = MemEnv::new();
+        write_table(&env, "/table-cache");
+
+        let mut cache = cache(&env, 10);
+        let a = cache.get("/table-cache").unwrap();
+        let b = cache.get("/table-cache").unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(1, cache.len());
+        assert_eq!(Some(2), b.get(1).unwrap());
+
+        assert!(cache.get("/table-cache-missing").is_err());
+        assert_eq!(1, cache.len());
+
+        cache.evict("/table-cache");
+        assert_eq!(0, cache.len());
+        let c = cache.get("/table-cache").unwrap();
+        assert!(!Arc::ptr_eq(&a, &c));
+    }
+
+    #[test]
+    fn bounded() {
+        let env = MemEnv::new();
+        for i in 0..3 {
+            write_table(&env, &format!("/table-cache-{}", i));
+        }
+
+        let mut cache = cache(&env, 2);
+        let t0 = cache.get("/table-cache-0").unwrap();
+        let t1 = cache.get("/table-cache-1").unwrap();
+        assert!(Arc::ptr_eq(&t0, &cache.get("/table-cache-0").unwrap()));
+
+        // table-cache-1 is the least recently used, so it's closed.
+        cache.get("/table-cache-2").unwrap();
+        assert_eq!(2, cache.len());
+        assert!(Arc::ptr_eq(&t0, &cache.get("/table-cache-0").unwrap()));
+        assert!(!Arc::ptr_eq(&t1, &cache.get("/table-cache-1").unwrap()));
+    }
+}
@@ -0,0 +1,125 @@
+// Functions over the points in a window [start, end), modelled on
+// Prometheus' rate(), irate(), increase() and delta().
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum WindowFunction {
+    // Per-second rate of increase of a counter, extrapolated to the edges of
+    // the window.
+    Rate,
+    // Per-second rate of increase between the last two points.
+    Irate,
+    // Increase of a counter over the window, i.e. Rate times its length.
+    Increase,
+    // Change in a gauge over the window, extrapolated to its edges.
+    Delta,
+}
+
+impl WindowFunction {
+    // Evaluates the function over 'points', which must be sorted and fall in
+    // [start, end). Timestamps are in units of 1/ticks_per_second seconds.
+    // Returns None if there are fewer than two points.
+    pub fn evaluate(&self, points: &[(u64, u64)], start: u64, end: u64, ticks_per_second: u64) -> Option<f64> {
+        let n = points.len();
+        if n < 2 {
+            return None;
+        }
+
+        let ticks_per_second = ticks_per_second as f64;
+        match *self {
+            WindowFunction::Rate => {
+                let seconds = (end - start) as f64 / ticks_per_second;
+                return Some(extrapolate(points, start, end, true) / seconds);
+            },
+            WindowFunction::Irate => {
+                let (t0, v0) = points[n - 2];
+                let (t1, v1) = points[n - 1];
+                // After a reset, the counter counted up from zero.
+                let increase = if v1 < v0 { v1 } else { v1 - v0 };
+                return Some(increase as f64 / ((t1 - t0) as f64 / ticks_per_second));
+            },
+            WindowFunction::Increase => return Some(extrapolate(points, start, end, true)),
+            WindowFunction::Delta => return Some(extrapolate(points, start, end, false)),
+        }
+    }
+}
+
+// The change in value between the first and last points, extrapolated
+// towards the edges of the window. Points are assumed to continue at their
+// average spacing, so the change is only extrapolated all the way to an edge
+// if that edge is within 110% of the average spacing of the nearest point;
+// otherwise it's extrapolated by half the average spacing.
+fn extrapolate(points: &[(u64, u64)], start: u64, end: u64, is_counter: bool) -> f64 {
+    let n = points.len();
+    let (first_ts, first) = points[0];
+    let (last_ts, last) = points[n - 1];
+
+    let mut change = last as f64 - first as f64;
+    if is_counter {
+        // A drop means the counter was reset and counted up again from zero.
+        for pair in points.windows(2) {
+            if pair[1].1 < pair[0].1 {
+                change += pair[0].1 as f64;
+            }
+        }
+    }
+
+    let sampled = (last_ts - first_ts) as f64;
+    let average_spacing = sampled / (n - 1) as f64;
+    let mut to_start = (first_ts - start) as f64;
+    let to_end = (end - last_ts) as f64;
+    if is_counter && change > 0.0 {
+        // Counters can't go below zero, so don't extrapolate back past the
+        // point where this one would have been zero.
+        let to_zero = sampled * (first as f64 / change);
+        if to_zero < to_start {
+            to_start = to_zero;
+        }
+    }
+
+    let threshold = average_spacing * 1.1;
+    let mut interval = sampled;
+    interval += if to_start < threshold { to_start } else { average_spacing / 2.0 };
+    interval += if to_end < threshold { to_end } else { average_spacing / 2.0 };
+    return change * (interval / sampled);
+}
+
+#[cfg(test)]
+mod test {
+    use super::WindowFunction;
+
+    fn close(expected: f64, actual: Option<f64>) -> bool {
+        return actual.map(|a| (a - expected).abs() < 1e-9).unwrap_or(false);
+    }
+
+    #[test]
+    fn counter_reset() {
+        // The counter resets between 25s and 35s.
+        let points = vec![(5, 10), (15, 20), (25, 30), (35, 5), (45, 15), (55, 25)];
+
+        // 45 over 50s of samples, extrapolated 5s to each edge.
+        assert!(close(54.0, WindowFunction::Increase.evaluate(&points, 0, 60, 1)));
+        assert!(close(0.9, WindowFunction::Rate.evaluate(&points, 0, 60, 1)));
+        assert!(close(1.0, WindowFunction::Irate.evaluate(&points, 0, 60, 1)));
+        assert!(close(0.5, WindowFunction::Irate.evaluate(&points[0..4], 0, 60, 1)));
+        // Gauges don't reset.
+        assert!(close(18.0, WindowFunction::Delta.evaluate(&points, 0, 60, 1)));
+
+        // Millisecond timestamps give the same per-second rates.
+        let millis : Vec<(u64, u64)> = points.iter().map(|&(t, v)| (t * 1000, v)).collect();
+        assert!(close(0.9, WindowFunction::Rate.evaluate(&millis, 0, 60000, 1000)));
+        assert!(close(54.0, WindowFunction::Increase.evaluate(&millis, 0, 60000, 1000)));
+
+        assert_eq!(None, WindowFunction::Rate.evaluate(&points[0..1], 0, 60, 1));
+    }
+
+    #[test]
+    fn extrapolation_limits() {
+        // The window extends far beyond the points, so each side is only
+        // extrapolated by half the average spacing.
+        let points = vec![(100, 100), (110, 110), (120, 120)];
+        assert!(close(30.0, WindowFunction::Increase.evaluate(&points, 0, 1000, 1)));
+
+        // A counter starting near zero isn't extrapolated below zero.
+        let points = vec![(10, 1), (20, 11), (30, 21)];
+        assert!(close(21.0, WindowFunction::Increase.evaluate(&points, 0, 30, 1)));
+    }
+}